@@ -0,0 +1,614 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::parse_duration;
+use crate::http::{status_code, HttpUrl};
+use crate::window::ResultWindow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+// Deliveries that hang (e.g. an unreachable mail relay) are abandoned after this long
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where an alert is delivered.
+#[derive(Clone, PartialEq)]
+pub enum Channel {
+    /// Desktop notification through `notify-send` (or `osascript` on macOS)
+    Desktop,
+    /// JSON POST to a URL
+    Webhook(HttpUrl),
+    /// Mail to an address through the local `sendmail`
+    Email(String),
+}
+
+impl Channel {
+    /// Parse a channel name and its destination (URL or address), returning why they are invalid
+    /// if they are.
+    fn parse(name: &str, destination: Option<&str>) -> Result<Self, String> {
+        match (name, destination) {
+            ("desktop", None) if cfg!(windows) => {
+                Err("desktop notifications aren't supported on Windows".to_string())
+            }
+            ("desktop", None) => Ok(Channel::Desktop),
+            ("webhook", Some(url)) => Ok(Channel::Webhook(HttpUrl::parse(url)?)),
+            ("email", Some(address)) if address.contains('@') => {
+                Ok(Channel::Email(address.to_string()))
+            }
+            ("desktop", Some(_)) => Err("desktop takes no destination".to_string()),
+            ("webhook" | "email", _) => Err(format!("{name} needs a destination")),
+            _ => Err("expected desktop, webhook or email".to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Channel::Desktop => "desktop",
+            Channel::Webhook(_) => "webhook",
+            Channel::Email(_) => "email",
+        }
+    }
+}
+
+/// A channel on its own, written `CHANNEL[:DESTINATION]`, e.g. `desktop` or
+/// `email:ops@example.com`.
+impl FromStr for Channel {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, destination) = match spec.split_once(':') {
+            Some((name, destination)) => (name, Some(destination)),
+            None => (spec, None),
+        };
+        Channel::parse(name, destination)
+            .map_err(|reason| format!("Invalid channel \"{spec}\" ({reason})"))
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Channel::Desktop => f.write_str("desktop"),
+            Channel::Webhook(url) => write!(f, "webhook:{}", url.as_str()),
+            Channel::Email(address) => write!(f, "email:{address}"),
+        }
+    }
+}
+
+/// How far into an outage an alert rule fires.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Threshold {
+    /// Consecutive failed probes
+    Failures(u32),
+    /// Time since the first failed probe of the outage
+    Down(Duration),
+}
+
+/// One tier of an escalation policy, written `CHANNEL:AFTER[:DESTINATION]`, e.g. `desktop:1`,
+/// `webhook:5m:https://example.com/hook`, or `email:30m:ops@example.com`. AFTER is a number of
+/// consecutive failed probes or a duration.
+#[derive(Clone, PartialEq)]
+pub struct AlertRule {
+    pub channel: Channel,
+    pub after: Threshold,
+    spec: String,
+}
+
+impl AlertRule {
+    /// Whether an outage of `failures` consecutive failed probes over `down_for` reached the
+    /// threshold.
+    pub fn is_due(&self, failures: u32, down_for: Duration) -> bool {
+        match self.after {
+            Threshold::Failures(threshold) => failures >= threshold,
+            Threshold::Down(threshold) => failures > 0 && down_for >= threshold,
+        }
+    }
+}
+
+impl FromStr for AlertRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("Invalid alert \"{spec}\" ({reason})");
+        let mut parts = spec.splitn(3, ':');
+        let channel = parts.next().unwrap_or_default();
+        let after = parts
+            .next()
+            .ok_or_else(|| invalid("expected CHANNEL:AFTER[:DESTINATION]"))?;
+        let after = match after.parse::<u32>() {
+            Ok(0) => return Err(invalid("at least 1 failure is needed")),
+            Ok(failures) => Threshold::Failures(failures),
+            Err(_) => Threshold::Down(
+                parse_duration(after)
+                    .ok_or_else(|| invalid("expected a number of failures or e.g. 5m"))?,
+            ),
+        };
+        let channel = Channel::parse(channel, parts.next()).map_err(|reason| invalid(&reason))?;
+        Ok(AlertRule {
+            channel,
+            after,
+            spec: spec.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for AlertRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// Statistic of the results in a rolling window that a window rule watches.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Metric {
+    /// Percentage of failed probes
+    Loss,
+    /// Mean latency (ms)
+    Avg,
+    /// Latency percentile (ms), e.g. 95 for p95
+    Percentile(u8),
+}
+
+/// Alert while a statistic over a rolling window exceeds a threshold, written
+/// `METRIC>THRESHOLD/WINDOW:CHANNEL[:DESTINATION]`, e.g. `loss>2%/15m:desktop` or
+/// `p95>100ms/1h:email:ops@example.com`. METRIC is `loss`, `avg` or a percentile such as `p95`.
+#[derive(Clone, PartialEq)]
+pub struct WindowRule {
+    pub metric: Metric,
+    /// Percent for loss, milliseconds for latency
+    pub threshold: f64,
+    pub window: Duration,
+    pub channel: Channel,
+    spec: String,
+}
+
+impl WindowRule {
+    /// Describe the rule's condition, e.g. "p95 latency over the last 1h above 100ms".
+    pub fn describe(&self) -> String {
+        let window = crate::tui::format_duration(self.window);
+        match self.metric {
+            Metric::Loss => format!("loss over the last {window} above {}%", self.threshold),
+            Metric::Avg => format!(
+                "average latency over the last {window} above {}ms",
+                self.threshold
+            ),
+            Metric::Percentile(percentile) => format!(
+                "p{percentile} latency over the last {window} above {}ms",
+                self.threshold
+            ),
+        }
+    }
+
+    /// Current value of the rule's metric, or None until the window has filled up (or if it holds
+    /// no successful probes to measure latency from).
+    pub fn evaluate(&self, results: &ResultWindow, now: Instant) -> Option<f64> {
+        if !results.covers(self.window, now) {
+            return None;
+        }
+        let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+        match self.metric {
+            Metric::Loss => results.loss_percent(self.window, now),
+            Metric::Avg => results.avg_rtt(self.window, now).map(ms),
+            Metric::Percentile(percentile) => {
+                results.rtt_percentile(percentile, self.window, now).map(ms)
+            }
+        }
+    }
+
+    /// Format a value of the rule's metric with its unit.
+    pub fn format_value(&self, value: f64) -> String {
+        match self.metric {
+            Metric::Loss => format!("{value:.2}%"),
+            Metric::Avg | Metric::Percentile(_) => format!("{value:.3}ms"),
+        }
+    }
+}
+
+impl FromStr for WindowRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("Invalid window alert \"{spec}\" ({reason})");
+        let expected =
+            "expected METRIC>THRESHOLD/WINDOW:CHANNEL[:DESTINATION], e.g. loss>2%/15m:desktop";
+        let mut parts = spec.splitn(3, ':');
+        let condition = parts.next().unwrap_or_default();
+        let channel = parts.next().ok_or_else(|| invalid(expected))?;
+        let channel = Channel::parse(channel, parts.next()).map_err(|reason| invalid(&reason))?;
+        let (metric, rest) = condition.split_once('>').ok_or_else(|| invalid(expected))?;
+        let (threshold, window) = rest.split_once('/').ok_or_else(|| invalid(expected))?;
+        let (metric, threshold) = match metric {
+            "loss" => (Metric::Loss, threshold.strip_suffix('%')),
+            "avg" => (Metric::Avg, threshold.strip_suffix("ms")),
+            _ => {
+                let percentile = metric
+                    .strip_prefix('p')
+                    .and_then(|percentile| percentile.parse().ok())
+                    .filter(|percentile| (1..=99).contains(percentile))
+                    .ok_or_else(|| invalid("expected loss, avg or p1 to p99"))?;
+                (Metric::Percentile(percentile), threshold.strip_suffix("ms"))
+            }
+        };
+        let threshold = threshold
+            .and_then(|threshold| threshold.parse::<f64>().ok())
+            .filter(|threshold| threshold.is_finite() && *threshold >= 0.0)
+            .ok_or_else(|| {
+                invalid("expected a threshold such as 2% for loss or 100ms for latency")
+            })?;
+        let window = parse_duration(window)
+            .filter(|window| !window.is_zero())
+            .ok_or_else(|| invalid("expected a window such as 15m"))?;
+        Ok(WindowRule {
+            metric,
+            threshold,
+            window,
+            channel,
+            spec: spec.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for WindowRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// At most `count` alerts per `period`, on one channel or (without a channel) on all of them
+/// together. Written `[CHANNEL=]COUNT/PERIOD`, e.g. `10/1h` or `email=2/1h`.
+#[derive(Clone, PartialEq)]
+pub struct RateLimit {
+    pub channel: Option<String>,
+    pub count: usize,
+    pub period: Duration,
+    spec: String,
+}
+
+impl FromStr for RateLimit {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("Invalid alert rate limit \"{spec}\" (expected e.g. 10/1h or email=2/1h)");
+        let (channel, limit) = match spec.split_once('=') {
+            Some((channel, limit)) if ["desktop", "webhook", "email"].contains(&channel) => {
+                (Some(channel.to_string()), limit)
+            }
+            Some(_) => return Err(invalid()),
+            None => (None, spec),
+        };
+        let (count, period) = limit.split_once('/').ok_or_else(invalid)?;
+        let count = count.parse().map_err(|_| invalid())?;
+        let period = parse_duration(period)
+            .filter(|period| !period.is_zero())
+            .ok_or_else(invalid)?;
+        Ok(RateLimit {
+            channel,
+            count,
+            period,
+            spec: spec.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.spec)
+    }
+}
+
+/// Why an alert was held back.
+pub enum Suppressed {
+    /// The same alert was sent within the dedup window
+    Duplicate,
+    RateLimited(RateLimit),
+}
+
+impl fmt::Display for Suppressed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Suppressed::Duplicate => f.write_str("duplicate of a recent alert"),
+            Suppressed::RateLimited(limit) => write!(f, "rate limit {limit} reached"),
+        }
+    }
+}
+
+/// Holds back alerts that repeat one sent within the dedup window or exceed a rate limit, so a
+/// flapping link can't flood the alert channels.
+pub struct AlertLimiter {
+    limits: Vec<RateLimit>,
+    dedup: Duration,
+    /// Send time, channel and content of recent alerts, oldest first
+    sent: VecDeque<(Instant, &'static str, String)>,
+}
+
+impl AlertLimiter {
+    pub fn new(limits: Vec<RateLimit>, dedup: Duration) -> Self {
+        AlertLimiter {
+            limits,
+            dedup,
+            sent: VecDeque::new(),
+        }
+    }
+
+    pub fn dedup(&self) -> Duration {
+        self.dedup
+    }
+
+    pub fn limits(&self) -> &[RateLimit] {
+        &self.limits
+    }
+
+    /// Change the limits, keeping track of the alerts already sent.
+    pub fn set_limits(&mut self, limits: Vec<RateLimit>, dedup: Duration) {
+        self.limits = limits;
+        self.dedup = dedup;
+    }
+
+    /// Check whether an alert identified by `key` may be sent on `channel` at `now`, and if so
+    /// count it as sent.
+    pub fn allow(
+        &mut self,
+        now: Instant,
+        channel: &'static str,
+        key: &str,
+    ) -> Result<(), Suppressed> {
+        let horizon = self
+            .limits
+            .iter()
+            .map(|limit| limit.period)
+            .fold(self.dedup, Duration::max);
+        while self
+            .sent
+            .front()
+            .is_some_and(|&(at, _, _)| now - at > horizon)
+        {
+            self.sent.pop_front();
+        }
+        let within = |period: Duration| {
+            self.sent
+                .iter()
+                .filter(move |&&(at, _, _)| now - at < period)
+        };
+        if within(self.dedup).any(|(_, _, sent)| sent == key) {
+            return Err(Suppressed::Duplicate);
+        }
+        for limit in &self.limits {
+            let count = within(limit.period)
+                .filter(|(_, sent_channel, _)| {
+                    limit
+                        .channel
+                        .as_deref()
+                        .is_none_or(|limited| limited == *sent_channel)
+                })
+                .count();
+            if limit
+                .channel
+                .as_deref()
+                .is_none_or(|limited| limited == channel)
+                && count >= limit.count
+            {
+                return Err(Suppressed::RateLimited(limit.clone()));
+            }
+        }
+        self.record(now, channel, key);
+        Ok(())
+    }
+
+    /// Count an alert that is sent regardless of the limits.
+    pub fn record(&mut self, now: Instant, channel: &'static str, key: &str) {
+        self.sent.push_back((now, channel, key.to_string()));
+    }
+}
+
+/// Variables that can be used in alert templates as `{{name}}`.
+pub const TEMPLATE_VARIABLES: &[&str] = &[
+    "target",
+    "address",
+    "state",
+    "failures",
+    "outage_duration",
+    "since",
+    "loss_pct_1h",
+    "time",
+    "run_id",
+    "probe_name",
+];
+
+/// Message body used for alerts on one channel, written `CHANNEL=TEMPLATE`, e.g.
+/// `webhook={{target}} {{state}} for {{outage_duration}}`.
+#[derive(Clone, PartialEq)]
+pub struct AlertTemplate {
+    pub channel: String,
+    template: String,
+}
+
+impl AlertTemplate {
+    /// Fill in the template's `{{name}}` placeholders from `variables`.
+    pub fn render(&self, variables: &[(&str, String)]) -> String {
+        let mut rendered = String::new();
+        let mut rest = self.template.as_str();
+        while let Some((before, after)) = rest.split_once("{{") {
+            // Placeholders were checked when parsing, so each is closed and known
+            let (name, after) = after.split_once("}}").unwrap();
+            rendered.push_str(before);
+            if let Some((_, value)) = variables.iter().find(|(known, _)| *known == name.trim()) {
+                rendered.push_str(value);
+            }
+            rest = after;
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+impl FromStr for AlertTemplate {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: String| format!("Invalid alert template \"{spec}\" ({reason})");
+        let (channel, template) = spec
+            .split_once('=')
+            .ok_or_else(|| invalid("expected CHANNEL=TEMPLATE".to_string()))?;
+        if !["desktop", "webhook", "email"].contains(&channel) {
+            return Err(invalid("expected desktop, webhook or email".to_string()));
+        }
+        let mut rest = template;
+        while let Some((_, after)) = rest.split_once("{{") {
+            let (name, after) = after
+                .split_once("}}")
+                .ok_or_else(|| invalid("unclosed {{".to_string()))?;
+            if !TEMPLATE_VARIABLES.contains(&name.trim()) {
+                return Err(invalid(format!(
+                    "unknown variable {name}, expected one of {}",
+                    TEMPLATE_VARIABLES.join(", ")
+                )));
+            }
+            rest = after;
+        }
+        Ok(AlertTemplate {
+            channel: channel.to_string(),
+            template: template.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for AlertTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.channel, self.template)
+    }
+}
+
+/// An alert ready to be delivered.
+#[derive(Clone)]
+pub struct Notification {
+    pub channel: Channel,
+    pub subject: String,
+    pub body: String,
+    /// ID of the run that raised the alert
+    pub run_id: String,
+    /// Name of the machine that raised the alert
+    pub probe_name: String,
+}
+
+/// Deliver a notification on its channel.
+pub async fn deliver(notification: &Notification) -> Result<(), String> {
+    let channel = &notification.channel;
+    // Say which machine saw it, as several may be watching the same target
+    let subject = if notification.probe_name.is_empty() {
+        notification.subject.clone()
+    } else {
+        format!(
+            "{} (from {})",
+            notification.subject, notification.probe_name
+        )
+    };
+    let result = tokio::time::timeout(DELIVERY_TIMEOUT, async {
+        match channel {
+            Channel::Desktop => desktop(&subject, &notification.body).await,
+            Channel::Webhook(url) => webhook(url, notification).await,
+            Channel::Email(address) => email(address, &subject, &notification.body).await,
+        }
+    })
+    .await
+    .unwrap_or_else(|_| Err("timed out".to_string()));
+    result.map_err(|e| format!("{} alert failed: {e}", channel.name()))
+}
+
+async fn desktop(subject: &str, body: &str) -> Result<(), String> {
+    let mut command = if cfg!(target_os = "macos") {
+        let mut command = Command::new("osascript");
+        command.arg("-e").arg(format!(
+            "display notification {} with title {}",
+            apple_script_string(body),
+            apple_script_string(subject)
+        ));
+        command
+    } else {
+        let mut command = Command::new("notify-send");
+        command.arg(subject).arg(body);
+        command
+    };
+    run(&mut command, None).await
+}
+
+fn apple_script_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+async fn webhook(url: &HttpUrl, notification: &Notification) -> Result<(), String> {
+    let payload = serde_json::json!({
+        "subject": notification.subject,
+        "text": notification.body,
+        "run_id": notification.run_id,
+        "probe_name": notification.probe_name,
+    });
+    let status_line = url
+        .post("application/json", payload.to_string().as_bytes())
+        .await
+        .map_err(|e| format!("{}: {e}", url.as_str()))?;
+    if status_code(&status_line).starts_with('2') {
+        Ok(())
+    } else {
+        Err(format!("{} answered \"{status_line}\"", url.as_str()))
+    }
+}
+
+async fn email(address: &str, subject: &str, body: &str) -> Result<(), String> {
+    let message = format!("To: {address}\nSubject: {subject}\n\n{body}\n");
+    run(Command::new("sendmail").arg("-t"), Some(&message)).await
+}
+
+/// Run `command`, feeding it `input` if given, and check that it succeeded.
+pub(crate) async fn run(command: &mut Command, input: Option<&str>) -> Result<(), String> {
+    let program = command
+        .as_std()
+        .get_program()
+        .to_string_lossy()
+        .into_owned();
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("could not run {program}: {e}"))?;
+    if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+        stdin
+            .write_all(input.as_bytes())
+            .await
+            .map_err(|e| format!("could not write to {program}: {e}"))?;
+    }
+    let status = child
+        .wait()
+        .await
+        .map_err(|e| format!("{program} failed: {e}"))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("{program} exited with {status}"))
+    }
+}
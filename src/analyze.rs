@@ -0,0 +1,321 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::report::{outages, read_results};
+use crate::theme::{ascii_only, bold, failure};
+use crate::tui::format_duration;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+use time::{format_description, OffsetDateTime};
+
+// Rows of the latency timeline; each character cell has 8 levels
+const TIMELINE_HEIGHT: usize = 8;
+// Width of the longest bar of the loss-per-day chart
+const BAR_WIDTH: usize = 40;
+// Width taken by the axis labels left of the timeline
+const AXIS_WIDTH: usize = 10;
+
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Probes that fell into one column of the timeline.
+#[derive(Clone, Default)]
+struct Bucket {
+    total_latency: f64,
+    received: u64,
+    failed: u64,
+}
+
+/// Read every result file in `output_path` and describe the run for a post-mortem: overall
+/// statistics, a latency timeline `width` characters wide with failed probes marked below it, and
+/// a bar chart of the loss on each day.
+pub fn run(output_path: &Path, width: usize) -> Result<String, String> {
+    let runs: Vec<_> = read_results(output_path)?
+        .iter()
+        .map(|file| file.probes())
+        .filter(|probes| !probes.is_empty())
+        .collect();
+    let probes: Vec<(OffsetDateTime, Option<f64>)> = runs.iter().flatten().copied().collect();
+    if probes.is_empty() {
+        return Err(format!("No results found in {}", output_path.display()));
+    }
+    let start = probes.iter().map(|(time, _)| *time).min().unwrap();
+    let end = probes.iter().map(|(time, _)| *time).max().unwrap();
+    let latencies: Vec<f64> = probes.iter().filter_map(|(_, latency)| *latency).collect();
+    let sent = probes.len();
+    let lost = sent - latencies.len();
+    let outages: Vec<_> = runs.iter().flat_map(|run| outages(run)).collect();
+    let longest = outages
+        .iter()
+        .map(|outage| (outage.end - outage.start).unsigned_abs())
+        .max();
+    let datetime_fmt =
+        format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
+    let format_time = |time: OffsetDateTime| time.format(&datetime_fmt).unwrap_or_default();
+
+    let mut output = format!(
+        "{} {} to {} ({})\n{} {sent} sent, {} received, {:.3}% loss, {} outage(s){}\n",
+        bold("Period:"),
+        format_time(start),
+        format_time(end),
+        format_duration((end - start).unsigned_abs()),
+        bold("Probes:"),
+        latencies.len(),
+        lost as f64 * 100.0 / sent as f64,
+        outages.len(),
+        longest.map_or(String::new(), |longest| format!(
+            ", longest {}",
+            format_duration(longest)
+        )),
+    );
+    if !latencies.is_empty() {
+        output.push_str(&format!(
+            "{} {:.3}/{:.3}/{:.3} ms\n",
+            bold("Latency min/avg/max:"),
+            latencies.iter().copied().fold(f64::INFINITY, f64::min),
+            latencies.iter().sum::<f64>() / latencies.len() as f64,
+            latencies.iter().copied().fold(0.0, f64::max),
+        ));
+    }
+
+    // Latency timeline, one column per slice of the period
+    let columns = width.saturating_sub(AXIS_WIDTH).max(20);
+    let span = (end - start).as_seconds_f64().max(1.0);
+    let mut buckets = vec![Bucket::default(); columns];
+    for (time, latency) in &probes {
+        let column = ((*time - start).as_seconds_f64() / span * columns as f64) as usize;
+        let bucket = &mut buckets[column.min(columns - 1)];
+        match latency {
+            Some(latency) => {
+                bucket.total_latency += latency;
+                bucket.received += 1;
+            }
+            None => bucket.failed += 1,
+        }
+    }
+    let averages: Vec<Option<f64>> = buckets
+        .iter()
+        .map(|bucket| (bucket.received > 0).then(|| bucket.total_latency / bucket.received as f64))
+        .collect();
+    // Scaled between the lowest and highest average so changes stand out; any column with results
+    // gets at least the lowest level, leaving gaps in monitoring blank
+    let top = averages.iter().flatten().copied().fold(0.0, f64::max);
+    let bottom = averages.iter().flatten().copied().fold(top, f64::min);
+    let levels = TIMELINE_HEIGHT * 8 - 1;
+    let (axis, tick) = if ascii_only() {
+        ('|', '+')
+    } else {
+        ('│', '┤')
+    };
+    output.push_str(&format!(
+        "\n{}\n",
+        bold(&format!(
+            "Average latency (ms), each column {}",
+            format_duration(Duration::from_secs_f64(span / columns as f64))
+        ))
+    ));
+    for row in (0..TIMELINE_HEIGHT).rev() {
+        let label = if row == TIMELINE_HEIGHT - 1 {
+            format!("{top:>8.1} {tick}")
+        } else if row == 0 {
+            format!("{bottom:>8.1} {tick}")
+        } else {
+            format!("{:>8} {axis}", "")
+        };
+        let cells: String = averages
+            .iter()
+            .map(|average| {
+                let level = match average {
+                    Some(average) if top > bottom => {
+                        1 + ((average - bottom) / (top - bottom) * levels as f64).round() as usize
+                    }
+                    Some(_) => 1,
+                    None => 0,
+                };
+                block(level.saturating_sub(row * 8).min(8))
+            })
+            .collect();
+        output.push_str(&format!("{label}{cells}\n"));
+    }
+    // Failed probes are marked under the column they fell in
+    let losses: String = buckets
+        .iter()
+        .map(|bucket| {
+            if bucket.failed == 0 {
+                ' '
+            } else if ascii_only() {
+                'x'
+            } else {
+                '×'
+            }
+        })
+        .collect();
+    output.push_str(&format!("{:>8} {axis}{}\n", "loss", failure(losses)));
+    let (first, last) = (format_time(start), format_time(end));
+    output.push_str(&format!(
+        "{:AXIS_WIDTH$}{first}{}{last}\n",
+        "",
+        " ".repeat(columns.saturating_sub(first.len() + last.len()))
+    ));
+
+    // Loss per day, by the date the results were recorded on
+    let mut days: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for (time, latency) in &probes {
+        let day = days.entry(time.date().to_string()).or_default();
+        day.0 += 1;
+        if latency.is_none() {
+            day.1 += 1;
+        }
+    }
+    let loss = |(sent, lost): (u64, u64)| lost as f64 * 100.0 / sent as f64;
+    let worst = days.values().copied().map(loss).fold(0.0, f64::max);
+    output.push_str(&format!("\n{}\n", bold("Loss per day")));
+    for (day, counts) in days {
+        let day_loss = loss(counts);
+        let bar = if worst > 0.0 {
+            let eighths = (day_loss / worst * (BAR_WIDTH * 8) as f64).round() as usize;
+            horizontal_bar(eighths)
+        } else {
+            String::new()
+        };
+        output.push_str(&format!(
+            "{day} {} {day_loss:>7.3}% ({}/{})\n",
+            failure(format!("{bar:BAR_WIDTH$}")),
+            counts.1,
+            counts.0
+        ));
+    }
+    output.pop();
+    Ok(output)
+}
+
+/// A character cell filled to `level` eighths from the bottom.
+fn block(level: usize) -> char {
+    if ascii_only() {
+        if level >= 4 {
+            '#'
+        } else {
+            ' '
+        }
+    } else {
+        BLOCKS[level]
+    }
+}
+
+/// A bar `eighths` eighths of a character cell long.
+fn horizontal_bar(eighths: usize) -> String {
+    if ascii_only() {
+        return "#".repeat((eighths + 4) / 8);
+    }
+    let mut bar = "█".repeat(eighths / 8);
+    if !eighths.is_multiple_of(8) {
+        bar.push(['▏', '▎', '▍', '▌', '▋', '▊', '▉'][eighths % 8 - 1]);
+    }
+    bar
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Remove the colors from `text`.
+    fn plain(text: &str) -> String {
+        text.split('\x1b')
+            .enumerate()
+            .map(|(i, part)| {
+                if i == 0 {
+                    part
+                } else {
+                    part.split_once('m').unwrap().1
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bars_are_drawn_in_eighths_of_a_cell() {
+        for (eighths, bar) in [
+            (0, ""),
+            (1, "▏"),
+            (4, "▌"),
+            (8, "█"),
+            (9, "█▏"),
+            (15, "█▉"),
+            (16, "██"),
+        ] {
+            assert_eq!(horizontal_bar(eighths), bar, "{eighths}");
+        }
+        assert_eq!(
+            (0..=8).map(block).collect::<String>(),
+            String::from_iter(BLOCKS)
+        );
+    }
+
+    #[test]
+    fn post_mortems_cover_the_runs_outages_and_days() {
+        let dir = tempfile::tempdir().unwrap();
+        // Two runs over midnight, the second starting during an outage
+        std::fs::write(
+            dir.path().join("result_01-31-2024@23-59-56_3f9a1c07.csv"),
+            "Timestamp,Latency(ms)\n\
+             2024-01-31 23:59:56.0 +00:00:00,10.000\n\
+             2024-01-31 23:59:57.0 +00:00:00,30.000\n\
+             2024-01-31 23:59:58.0 +00:00:00,\n\
+             2024-01-31 23:59:59.0 +00:00:00,20.000\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("result_02-01-2024@00-00-00_8b2e6d10.csv"),
+            "Timestamp,Latency(ms)\n\
+             2024-02-01 00:00:00.0 +00:00:00,\n\
+             2024-02-01 00:00:01.0 +00:00:00,\n\
+             2024-02-01 00:00:03.0 +00:00:00,20.000\n\
+             2024-02-01 00:00:04.0 +00:00:00,40.000\n",
+        )
+        .unwrap();
+        let output = plain(&run(dir.path(), 30).unwrap());
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "Period: 2024-01-31 23:59:56 to 2024-02-01 00:00:04 (8s)",
+                "Probes: 8 sent, 5 received, 37.500% loss, 2 outage(s), longest 3s",
+                "Latency min/avg/max: 10.000/24.000/40.000 ms",
+            ]
+        );
+        // A header, the timeline's rows, failed probes and the time axis
+        let timeline = &lines[4..4 + 1 + TIMELINE_HEIGHT + 2];
+        assert!(timeline[0].starts_with("Average latency (ms), each column "));
+        assert!(timeline[1].starts_with("    40.0 ┤"));
+        assert!(timeline[TIMELINE_HEIGHT].starts_with("    10.0 ┤"));
+        let losses = timeline[TIMELINE_HEIGHT + 1];
+        assert!(losses.starts_with("    loss │"));
+        assert_eq!(losses.matches('×').count(), 3);
+        assert_eq!(lines[lines.len() - 3], "Loss per day");
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                format!(
+                    "2024-01-31 {}  25.000% (1/4)",
+                    "█".repeat(20) + &" ".repeat(20)
+                ),
+                format!("2024-02-01 {}  50.000% (2/4)", "█".repeat(40)),
+            ]
+        );
+        assert!(run(tempfile::tempdir().unwrap().path(), 30).is_err());
+    }
+}
@@ -0,0 +1,101 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::metrics::Snapshot;
+use std::io;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task;
+
+// How long a client gets to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness and readiness of the monitor, published by the ping loop.
+#[derive(Clone, Copy)]
+pub struct Health {
+    /// When the ping loop last completed a tick
+    pub last_tick: Instant,
+    /// How long without a tick before the loop is considered stuck
+    pub stale_after: Duration,
+    /// Whether the probe is configured and has produced its first result
+    pub ready: bool,
+}
+
+impl Health {
+    pub fn alive(&self) -> bool {
+        self.last_tick.elapsed() <= self.stale_after
+    }
+}
+
+/// Serve the HTTP API on `listener` until the process exits:
+/// - `/healthz`: 200 while the ping loop keeps ticking, 503 once it is stuck
+/// - `/readyz`: 200 once the first probe result has been obtained, 503 before that
+/// - `/metrics`: the metrics of the last probe in the Prometheus text format
+pub async fn serve(
+    listener: TcpListener,
+    health: watch::Receiver<Health>,
+    metrics: watch::Receiver<Option<Snapshot>>,
+) {
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let health = *health.borrow();
+        let metrics = metrics.borrow().clone();
+        task::spawn(async move {
+            let _ = tokio::time::timeout(REQUEST_TIMEOUT, handle(stream, health, metrics)).await;
+        });
+    }
+}
+
+async fn handle(stream: TcpStream, health: Health, metrics: Option<Snapshot>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut request_line = String::new();
+    stream.read_line(&mut request_line).await?;
+    let mut parts = request_line.split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let mut content_type = "text/plain";
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") if health.alive() => ("200 OK", "ok\n".to_string()),
+        ("GET" | "HEAD", "/healthz") => {
+            ("503 Service Unavailable", "ping loop stalled\n".to_string())
+        }
+        ("GET" | "HEAD", "/readyz") if health.ready => ("200 OK", "ready\n".to_string()),
+        ("GET" | "HEAD", "/readyz") => ("503 Service Unavailable", "not ready\n".to_string()),
+        ("GET" | "HEAD", "/metrics") => match metrics {
+            Some(metrics) => {
+                content_type = "text/plain; version=0.0.4";
+                ("200 OK", metrics.render())
+            }
+            None => ("503 Service Unavailable", "no results yet\n".to_string()),
+        },
+        ("GET" | "HEAD", _) => ("404 Not Found", "not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "method not allowed\n".to_string()),
+    };
+    let mut response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    if method != "HEAD" {
+        response.push_str(&body);
+    }
+    stream.get_mut().write_all(response.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
@@ -0,0 +1,107 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::net::IpAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Team Cymru's IP to ASN whois service
+const WHOIS_SERVER: (&str, u16) = ("whois.cymru.com", 43);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The network an address is announced from.
+#[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
+pub struct Network {
+    /// Autonomous system number
+    pub asn: u32,
+    /// Name of the autonomous system, e.g. "CLOUDFLARENET, US"
+    pub name: String,
+    /// BGP prefix the address is announced in
+    pub prefix: String,
+    /// Country code the prefix is registered in
+    pub country: String,
+}
+
+impl Network {
+    /// Describe the network in one line, e.g. `AS13335 CLOUDFLARENET, US (AU)`.
+    pub fn describe(&self) -> String {
+        format!("AS{} {} ({})", self.asn, self.name, self.country)
+    }
+}
+
+/// Find the network `ip_addr` belongs to with Team Cymru's whois service. This sends the address
+/// to a third party, so it's only done when asked for.
+pub async fn lookup(ip_addr: IpAddr) -> Result<Network, String> {
+    let response = tokio::time::timeout(LOOKUP_TIMEOUT, async {
+        let mut stream = TcpStream::connect(WHOIS_SERVER).await?;
+        stream
+            .write_all(format!(" -v {ip_addr}\r\n").as_bytes())
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    })
+    .await
+    .map_err(|_| "lookup timed out".to_string())?
+    .map_err(|e| format!("could not query {}: {e}", WHOIS_SERVER.0))?;
+    parse(&response)
+}
+
+/// Parse a verbose answer of the whois service:
+/// ```text
+/// AS      | IP               | BGP Prefix          | CC | Registry | Allocated  | AS Name
+/// 13335   | 1.1.1.1          | 1.1.1.0/24          | AU | apnic    | 2011-08-11 | CLOUDFLARENET, US
+/// ```
+fn parse(response: &str) -> Result<Network, String> {
+    let line = response
+        .lines()
+        .find(|line| !line.starts_with("AS ") && line.contains('|'))
+        .ok_or("unexpected answer from the whois service")?;
+    let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+    let [asn, _, prefix, country, _, _, name] = fields[..] else {
+        return Err(format!("unexpected answer from the whois service: {line}"));
+    };
+    let asn = asn
+        .parse()
+        .map_err(|_| "the address isn't announced by any network".to_string())?;
+    Ok(Network {
+        asn,
+        name: name.to_string(),
+        prefix: prefix.to_string(),
+        country: country.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn whois_answers_are_parsed() {
+        let answer = "AS      | IP               | BGP Prefix          | CC | Registry | Allocated  | AS Name\n\
+            13335   | 1.1.1.1          | 1.1.1.0/24          | AU | apnic    | 2011-08-11 | CLOUDFLARENET, US\n";
+        let network = parse(answer).unwrap();
+        assert_eq!(network.describe(), "AS13335 CLOUDFLARENET, US (AU)");
+        assert_eq!(network.prefix, "1.1.1.0/24");
+        let private = "AS      | IP               | BGP Prefix          | CC | Registry | Allocated  | AS Name\n\
+            NA      | 192.168.1.1      | NA                  |    | other    |            | NA\n";
+        assert!(parse(private).is_err());
+    }
+}
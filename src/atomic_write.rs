@@ -0,0 +1,52 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::OsString;
+use std::io;
+use std::path::Path;
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Write `contents` to `path` atomically. The data is written to a temporary file in the same
+/// directory, synced, and then renamed over `path`, so a crash mid-write never leaves a truncated
+/// file behind.
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(path.file_name().unwrap_or_default());
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = async {
+        let mut tmp_file = File::create(&tmp_path).await?;
+        tmp_file.write_all(contents).await?;
+        tmp_file.sync_all().await?;
+        drop(tmp_file);
+        fs::rename(&tmp_path, path).await
+    }
+    .await;
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path).await;
+        return result;
+    }
+    // Persist the rename itself; directories can't be opened as files on Windows
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent).await?.sync_all().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,207 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::clock::SystemClock;
+use crate::engine::{new_run_id, Engine, EngineConfig, FlushPolicy};
+use crate::probe::ProbeKind;
+use crate::settings::ProbeSettings;
+use crate::sink::SinkKind;
+use crate::theme::bold;
+use crate::writer::OverflowPolicy;
+#[cfg(feature = "alloc-counting")]
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::path::PathBuf;
+#[cfg(feature = "alloc-counting")]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+// Fastest supported rate, so the probe timeout can stay at least 1ms and below the delay
+pub const MAX_RATE: f64 = 500.0;
+
+#[cfg(feature = "alloc-counting")]
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "alloc-counting")]
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// The system allocator, counting allocations so the benchmark can report them. Only installed
+/// with the `alloc-counting` feature.
+#[cfg(feature = "alloc-counting")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-counting")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+/// Return the number of allocations and bytes allocated so far, if they're counted.
+fn allocation_counts() -> Option<(u64, u64)> {
+    #[cfg(feature = "alloc-counting")]
+    return Some((
+        ALLOCATIONS.load(Ordering::Relaxed),
+        ALLOCATED_BYTES.load(Ordering::Relaxed),
+    ));
+    #[cfg(not(feature = "alloc-counting"))]
+    None
+}
+
+/// Parse a probe rate such as `10/s`, `600/m`, or `10`, in pings per second.
+pub fn parse_rate(rate: &str) -> Result<f64, String> {
+    let (value, per_secs) = if let Some(value) = rate.strip_suffix("/s") {
+        (value, 1.0)
+    } else if let Some(value) = rate.strip_suffix("/m") {
+        (value, 60.0)
+    } else {
+        (rate, 1.0)
+    };
+    match value.parse::<f64>() {
+        Ok(value) if value > 0.0 && value / per_secs <= MAX_RATE => Ok(value / per_secs),
+        _ => Err(format!(
+            "invalid rate \"{rate}\" (expected e.g. 10/s, at most {MAX_RATE}/s)"
+        )),
+    }
+}
+
+/// Run the probe, writer, and stats pipeline against `addr` as fast as `rate` (pings per second)
+/// for `duration`, writing to a temporary directory, and return a report of the throughput and
+/// allocations.
+pub async fn run(addr: String, duration: Duration, rate: f64) -> Result<String, String> {
+    let output_path = std::env::temp_dir().join(format!("num-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&output_path)
+        .map_err(|e| format!("Could not create {}: {e}", output_path.display()))?;
+    let report = run_in(addr, duration, rate, output_path.clone()).await;
+    let _ = std::fs::remove_dir_all(&output_path);
+    report
+}
+
+async fn run_in(
+    addr: String,
+    duration: Duration,
+    rate: f64,
+    output_path: PathBuf,
+) -> Result<String, String> {
+    let delay = Duration::from_secs_f64(1.0 / rate);
+    let timeout = (delay.as_millis() as u64 / 2).clamp(1, 1000);
+    let mut engine = Engine::new(EngineConfig {
+        probe: ProbeSettings {
+            addr,
+            kind: ProbeKind::Icmp,
+            timeout,
+            delay,
+            interval_jitter: 0.0,
+            align: false,
+            num_bytes: 4,
+            ttl: 128,
+            down_after: 1,
+            down_on_no_route: false,
+            retries: 0,
+            cert_warn_days: 14,
+            max_offset: 1000,
+            portal_check: None,
+            alerts: Vec::new(),
+            alert_templates: Vec::new(),
+            alert_rate_limits: Vec::new(),
+            alert_dedup: Duration::ZERO,
+            window_alerts: Vec::new(),
+            isp_change_alert: None,
+            internet_alert: None,
+        },
+        sync_writes: false,
+        encrypt: None,
+        sign: None,
+        flush_policy: FlushPolicy::EveryRow,
+        write_overflow: OverflowPolicy::Block,
+        max_write_failures: 20,
+        output_path: Some(output_path.clone()),
+        wifi: false,
+        nic_counters: false,
+        columns: None,
+        delimiter: b',',
+        sinks: vec![SinkKind::Csv],
+        resume: false,
+        run_id: new_run_id(),
+        probe_name: String::new(),
+        asn_lookup: false,
+        clock: Arc::new(SystemClock),
+    })
+    .await?;
+    let mut interval = tokio::time::interval(delay);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let start_counts = allocation_counts();
+    let start = Instant::now();
+    let mut slowest = Duration::ZERO;
+    while start.elapsed() < duration {
+        let tick = interval.tick().await;
+        let iteration = Instant::now();
+        let _ = engine.ping(tick.into_std()).await;
+        let _ = engine.stats();
+        engine.take_outputs();
+        slowest = slowest.max(iteration.elapsed());
+    }
+    engine.flush().await;
+    let elapsed = start.elapsed();
+    let end_counts = allocation_counts();
+    let stats = engine.stats();
+    let written: u64 = std::fs::read_dir(&output_path)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum();
+    let pings = stats.sent.max(1) as f64;
+    let allocations = match start_counts.zip(end_counts) {
+        Some(((start_allocations, start_bytes), (end_allocations, end_bytes))) => format!(
+            "{:.1} allocations, {:.0} bytes per ping",
+            (end_allocations - start_allocations) as f64 / pings,
+            (end_bytes - start_bytes) as f64 / pings,
+        ),
+        None => "not counted (build with --features alloc-counting)".to_string(),
+    };
+    Ok(format!(
+        "{} {} sent, {} received in {:.1}s ({:.1}/s achieved, {rate:.1}/s requested)\n\
+         {} {:.3}ms slowest iteration (including the round trip)\n\
+         {} {allocations}\n\
+         {} {written} bytes ({:.1} per ping)",
+        bold("Pings:"),
+        stats.sent,
+        stats.received,
+        elapsed.as_secs_f64(),
+        stats.sent as f64 / elapsed.as_secs_f64(),
+        bold("Loop:"),
+        slowest.as_secs_f64() * 1000.0,
+        bold("Allocations:"),
+        bold("Written:"),
+        written as f64 / pings,
+    ))
+}
@@ -0,0 +1,370 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::report::parse_timestamp;
+use time::{OffsetDateTime, UtcOffset};
+
+/// Tag marking the start of the file as CBOR (RFC 8949 3.4.6), which also makes for a magic number
+const SELF_DESCRIBED: u64 = 55799;
+/// Tag of a decimal fraction, `[exponent, mantissa]` (RFC 8949 3.4.4)
+const DECIMAL_FRACTION: u64 = 4;
+/// Value of `format` in the header of result files
+pub const FORMAT: &str = "num-results";
+
+/// A decoded CBOR data item. Floats and byte strings aren't written, so they aren't read either.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Item {
+    Integer(i128),
+    Text(String),
+    Array(Vec<Item>),
+    Map(Vec<(Item, Item)>),
+    Tag(u64, Box<Item>),
+    Bool(bool),
+    Null,
+}
+
+impl Item {
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Item::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    /// The value of `key` in a map.
+    pub fn get(&self, key: &str) -> Option<&Item> {
+        match self {
+            Item::Map(entries) => entries
+                .iter()
+                .find(|(k, _)| k.as_text() == Some(key))
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
+fn head(major: u8, value: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    match value {
+        0..=23 => out.push(major | value as u8),
+        24..=0xff => out.extend([major | 24, value as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend((value as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend((value as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend(value.to_be_bytes());
+        }
+    }
+}
+
+fn integer(value: i64, out: &mut Vec<u8>) {
+    if value >= 0 {
+        head(0, value as u64, out);
+    } else {
+        head(1, !value as u64, out);
+    }
+}
+
+fn text(value: &str, out: &mut Vec<u8>) {
+    head(3, value.len() as u64, out);
+    out.extend(value.as_bytes());
+}
+
+/// Encode the header of a result file: the run metadata and the names of the columns of the
+/// records that follow.
+pub fn header(metadata: &[(&str, String)], columns: &[&str]) -> Vec<u8> {
+    let mut out = Vec::new();
+    head(6, SELF_DESCRIBED, &mut out);
+    head(5, 4, &mut out);
+    text("format", &mut out);
+    text(FORMAT, &mut out);
+    text("version", &mut out);
+    integer(1, &mut out);
+    text("metadata", &mut out);
+    head(5, metadata.len() as u64, &mut out);
+    for (key, value) in metadata {
+        text(key, &mut out);
+        text(value, &mut out);
+    }
+    text("columns", &mut out);
+    head(4, columns.len() as u64, &mut out);
+    for column in columns {
+        text(column, &mut out);
+    }
+    out
+}
+
+/// Encode a record as an array of its values, in the order of the columns in the header.
+pub fn record<'a>(values: impl ExactSizeIterator<Item = &'a str>) -> Vec<u8> {
+    let mut out = Vec::new();
+    head(4, values.len() as u64, &mut out);
+    for value in values {
+        value_item(value, &mut out);
+    }
+    out
+}
+
+/// Encode a value of a result column in as few bytes as it takes to give it back exactly:
+/// timestamps as `[unix time, nanoseconds, UTC offset (s)]`, `ok` and `failed` as booleans,
+/// integers as such, decimals as decimal fractions, empty values as null, and anything else as text.
+fn value_item(value: &str, out: &mut Vec<u8>) {
+    let start = out.len();
+    if value.is_empty() {
+        out.push(0xf6);
+        return;
+    }
+    match value {
+        "ok" => out.push(0xf5),
+        "failed" => out.push(0xf4),
+        _ => {
+            if let Some(timestamp) = parse_timestamp(value) {
+                head(4, 3, out);
+                integer(timestamp.unix_timestamp(), out);
+                integer(timestamp.nanosecond().into(), out);
+                integer(timestamp.offset().whole_seconds().into(), out);
+            } else if let Ok(number) = value.parse::<i64>() {
+                integer(number, out);
+            } else if let Some((whole, fraction)) = value.split_once('.') {
+                match format!("{whole}{fraction}").parse::<i64>() {
+                    Ok(mantissa) if fraction.bytes().all(|b| b.is_ascii_digit()) => {
+                        head(6, DECIMAL_FRACTION, out);
+                        head(4, 2, out);
+                        integer(-(fraction.len() as i64), out);
+                        integer(mantissa, out);
+                    }
+                    _ => text(value, out),
+                }
+            } else {
+                text(value, out);
+            }
+        }
+    }
+    // Forms that don't give the value back exactly, e.g. 007, -0.0 or timestamps in another
+    // format, are kept as text
+    let decoded = decode(&out[start..])
+        .ok()
+        .and_then(|(item, _)| value_text(&item));
+    if decoded.as_deref() != Some(value) {
+        out.truncate(start);
+        text(value, out);
+    }
+}
+
+/// The value of a result column encoded as `item` by `record`.
+pub fn value_text(item: &Item) -> Option<String> {
+    Some(match item {
+        Item::Null => String::new(),
+        Item::Bool(true) => "ok".to_string(),
+        Item::Bool(false) => "failed".to_string(),
+        Item::Text(text) => text.clone(),
+        Item::Integer(number) => number.to_string(),
+        Item::Array(parts) => match parts.as_slice() {
+            [Item::Integer(secs), Item::Integer(nanos), Item::Integer(offset)] => {
+                let offset = UtcOffset::from_whole_seconds(i32::try_from(*offset).ok()?).ok()?;
+                OffsetDateTime::from_unix_timestamp_nanos(secs * 1_000_000_000 + nanos)
+                    .ok()?
+                    .to_offset(offset)
+                    .to_string()
+            }
+            _ => return None,
+        },
+        Item::Tag(DECIMAL_FRACTION, fraction) => match fraction.as_ref() {
+            Item::Array(parts) => match parts.as_slice() {
+                [Item::Integer(exponent), Item::Integer(mantissa)] if *exponent < 0 => {
+                    let places = exponent.unsigned_abs() as usize;
+                    let digits =
+                        format!("{:0>width$}", mantissa.unsigned_abs(), width = places + 1);
+                    let (whole, fraction) = digits.split_at(digits.len() - places);
+                    let sign = if *mantissa < 0 { "-" } else { "" };
+                    format!("{sign}{whole}.{fraction}")
+                }
+                _ => return None,
+            },
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+/// Decode the data item at the start of `bytes`, returning it and the number of bytes it took.
+pub fn decode(bytes: &[u8]) -> Result<(Item, usize), String> {
+    let truncated = || "truncated record".to_string();
+    let initial = *bytes.first().ok_or_else(truncated)?;
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    let (argument, mut at) = match info {
+        0..=23 => (info as u64, 1),
+        24..=27 => {
+            let len = 1 << (info - 24);
+            let field = bytes.get(1..1 + len).ok_or_else(truncated)?;
+            (field.iter().fold(0, |n, b| n << 8 | *b as u64), 1 + len)
+        }
+        _ => return Err(format!("unsupported CBOR item 0x{initial:02x}")),
+    };
+    let item = match major {
+        0 => Item::Integer(argument.into()),
+        1 => Item::Integer(-1 - i128::from(argument)),
+        3 => {
+            let len = usize::try_from(argument).map_err(|_| truncated())?;
+            let end = at.checked_add(len).ok_or_else(truncated)?;
+            let text = bytes.get(at..end).ok_or_else(truncated)?;
+            at += len;
+            Item::Text(String::from_utf8(text.to_vec()).map_err(|e| e.to_string())?)
+        }
+        4 => {
+            let mut items = Vec::new();
+            for _ in 0..argument {
+                let (item, len) = decode(&bytes[at..])?;
+                items.push(item);
+                at += len;
+            }
+            Item::Array(items)
+        }
+        5 => {
+            let mut entries = Vec::new();
+            for _ in 0..argument {
+                let (key, len) = decode(&bytes[at..])?;
+                at += len;
+                let (value, len) = decode(&bytes[at..])?;
+                at += len;
+                entries.push((key, value));
+            }
+            Item::Map(entries)
+        }
+        6 => {
+            let (item, len) = decode(&bytes[at..])?;
+            at += len;
+            Item::Tag(argument, Box::new(item))
+        }
+        7 => match argument {
+            20 => Item::Bool(false),
+            21 => Item::Bool(true),
+            22 => Item::Null,
+            _ => return Err(format!("unsupported CBOR item 0x{initial:02x}")),
+        },
+        _ => return Err(format!("unsupported CBOR item 0x{initial:02x}")),
+    };
+    Ok((item, at))
+}
+
+/// A result file read back: the run metadata, column names and the values of each record.
+pub struct Results {
+    pub metadata: Vec<(String, String)>,
+    pub columns: Vec<String>,
+    pub records: Vec<Vec<String>>,
+    /// Whether the file ends in a partly written record, as after a crash
+    pub truncated: bool,
+}
+
+/// Read a result file written by the CBOR sink.
+pub fn read(bytes: &[u8]) -> Result<Results, String> {
+    let (header, mut at) = decode(bytes)?;
+    let header = match header {
+        Item::Tag(SELF_DESCRIBED, header)
+            if header.get("format").and_then(Item::as_text) == Some(FORMAT) =>
+        {
+            header
+        }
+        _ => return Err("not a num result file".to_string()),
+    };
+    let texts = |item: Option<&Item>| match item {
+        Some(Item::Array(items)) => items
+            .iter()
+            .map(|item| item.as_text().map(str::to_string))
+            .collect(),
+        _ => None,
+    };
+    let columns: Vec<String> =
+        texts(header.get("columns")).ok_or("missing columns in the header")?;
+    let metadata = match header.get("metadata") {
+        Some(Item::Map(entries)) => entries
+            .iter()
+            .filter_map(|(key, value)| {
+                Some((key.as_text()?.to_string(), value.as_text()?.to_string()))
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    let mut records = Vec::new();
+    let mut truncated = false;
+    while at < bytes.len() {
+        let Ok((item, len)) = decode(&bytes[at..]) else {
+            truncated = true;
+            break;
+        };
+        at += len;
+        match item {
+            // A header written again after the file was recreated
+            Item::Tag(SELF_DESCRIBED, _) => continue,
+            Item::Array(values) => records.push(
+                values
+                    .iter()
+                    .map(|value| value_text(value).ok_or("unreadable value"))
+                    .collect::<Result<_, _>>()?,
+            ),
+            _ => return Err("unreadable record".to_string()),
+        }
+    }
+    Ok(Results {
+        metadata,
+        columns,
+        records,
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_compact_and_read_back_exactly() {
+        let values = [
+            "2024-03-10 1:59:59.123456789 -05:00:00",
+            "12.345",
+            "ok",
+            "",
+            "-0.500",
+            "42",
+            "007",
+            "-0.0",
+            "timeout",
+        ];
+        let mut file = header(&[("run_id", "3f9a1c07".to_string())], &["timestamp", "rtt"]);
+        let record = record(values.iter().copied());
+        // The same CSV row takes 77 bytes
+        assert_eq!(record.len(), 48);
+        file.extend(&record);
+        file.extend(&record[..10]);
+        let results = read(&file).unwrap();
+        assert_eq!(
+            results.metadata,
+            [("run_id".to_string(), "3f9a1c07".to_string())]
+        );
+        assert_eq!(results.columns, ["timestamp", "rtt"]);
+        assert_eq!(results.records, [values.map(str::to_string)]);
+        assert!(results.truncated);
+        assert!(read(b"Timestamp,Latency(ms)\n").is_err());
+        // A corrupt length reaching past the end of memory
+        assert!(decode(&[0x7b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
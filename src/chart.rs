@@ -0,0 +1,335 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::report::{outages, read_results};
+use plotters::coord::ranged1d::Ranged;
+use plotters::coord::types::RangedCoordf64;
+use plotters::coord::Shift;
+use plotters::prelude::*;
+use plotters::style::register_font;
+use std::path::Path;
+use time::format_description;
+use time::OffsetDateTime;
+
+// Fonts tried, in order, for the chart's labels
+const FONT_PATHS: &[&str] = &[
+    "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/TTF/DejaVuSans.ttf",
+    "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+    "/usr/share/fonts/truetype/liberation/LiberationSans-Regular.ttf",
+    "/usr/share/fonts/liberation-sans/LiberationSans-Regular.ttf",
+    "/System/Library/Fonts/Supplemental/Arial.ttf",
+    "/Library/Fonts/Arial.ttf",
+    "C:\\Windows\\Fonts\\arial.ttf",
+];
+
+const SIZE: (u32, u32) = (1200, 500);
+
+// Number of labels on each axis
+const X_LABELS: usize = 8;
+const Y_LABELS: usize = 10;
+
+/// A label left for the SVG itself to draw, at a pixel position and anchored at its `start`,
+/// `middle` or `end`, when there is no font to lay it out with.
+struct SvgText {
+    x: i32,
+    y: i32,
+    anchor: &'static str,
+    size: u32,
+    text: String,
+}
+
+/// Render the latency of every probe in `output_path` over time to `chart_path`, an SVG or PNG
+/// file depending on its extension, with outages shaded.
+pub fn render(output_path: &Path, chart_path: &Path) -> Result<(), String> {
+    let mut runs = Vec::new();
+    for file in read_results(output_path)? {
+        runs.push(file.probes());
+    }
+    if runs.iter().all(Vec::is_empty) {
+        return Err(format!("No results found in {}", output_path.display()));
+    }
+    let font = load_font();
+    let failed = |e: String| format!("Could not draw {}: {e}", chart_path.display());
+    match chart_path
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some("svg") if font.is_ok() => draw(
+            SVGBackend::new(chart_path, SIZE).into_drawing_area(),
+            &runs,
+            true,
+        )
+        .map(drop),
+        // The viewer lays out the labels instead
+        Some("svg") => {
+            let mut svg = String::new();
+            let texts = draw(
+                SVGBackend::with_string(&mut svg, SIZE).into_drawing_area(),
+                &runs,
+                false,
+            )
+            .map_err(failed)?;
+            let texts: String = texts
+                .iter()
+                .map(|text| {
+                    format!(
+                        "<text x=\"{}\" y=\"{}\" text-anchor=\"{}\" font-family=\"sans-serif\" \
+                         font-size=\"{}\">{}</text>\n",
+                        text.x, text.y, text.anchor, text.size, text.text
+                    )
+                })
+                .collect();
+            svg = svg.replace("</svg>", &format!("{texts}</svg>"));
+            std::fs::write(chart_path, svg).map_err(|e| e.to_string())
+        }
+        Some("png") => {
+            font.map_err(|e| format!("{e} (or draw an .svg chart, which doesn't need one)"))?;
+            draw(
+                BitMapBackend::new(chart_path, SIZE).into_drawing_area(),
+                &runs,
+                true,
+            )
+            .map(drop)
+        }
+        _ => {
+            return Err(format!(
+                "Unsupported chart format {} (expected .svg or .png)",
+                chart_path.display()
+            ))
+        }
+    }
+    .map_err(failed)
+}
+
+/// Register a system font for the labels, as none is bundled. Only the first call looks for one.
+fn load_font() -> Result<(), String> {
+    static LOADED: std::sync::OnceLock<Result<(), String>> = std::sync::OnceLock::new();
+    LOADED.get_or_init(register_system_font).clone()
+}
+
+fn register_system_font() -> Result<(), String> {
+    let font = FONT_PATHS
+        .iter()
+        .find_map(|path| std::fs::read(path).ok())
+        .ok_or("Charts need a TrueType font such as DejaVu Sans to be installed")?;
+    // Fonts are registered for the rest of the program
+    register_font("sans-serif", FontStyle::Normal, Vec::leak(font))
+        .map_err(|_| "Could not load the font for the chart".to_string())
+}
+
+/// Draw the latency of each run of `runs` as a line (broken at failed probes), over red shading
+/// for each outage. Without `labels`, space is left for the labels and they're returned instead
+/// of drawn.
+fn draw<DB: DrawingBackend>(
+    root: DrawingArea<DB, Shift>,
+    runs: &[Vec<(OffsetDateTime, Option<f64>)>],
+    labels: bool,
+) -> Result<Vec<SvgText>, String> {
+    let error = |e: DrawingAreaErrorKind<DB::ErrorType>| e.to_string();
+    let probes = runs.iter().flatten();
+    let start = probes.clone().map(|(time, _)| *time).min().unwrap();
+    let end = probes.clone().map(|(time, _)| *time).max().unwrap();
+    let max_latency = probes
+        .filter_map(|(_, latency)| *latency)
+        .fold(0.0, f64::max);
+    // Plot seconds since the first probe, labelled in the timezone it was recorded in
+    let x = |time: OffsetDateTime| (time - start).as_seconds_f64();
+    let x_end = x(end).max(1.0);
+    let y_end = if max_latency > 0.0 {
+        max_latency * 1.1
+    } else {
+        1.0
+    };
+    let label_format = if x_end > 2.0 * 86400.0 {
+        "[year]-[month]-[day] [hour]:[minute]"
+    } else {
+        "[month]-[day] [hour]:[minute]:[second]"
+    };
+    let label_format = format_description::parse(label_format).unwrap();
+    let label = |seconds: &f64| {
+        (start + time::Duration::seconds_f64(*seconds))
+            .format(&label_format)
+            .unwrap_or_default()
+    };
+
+    let caption = format!("Latency from {} to {}", label(&0.0), label(&x_end));
+
+    root.fill(&WHITE).map_err(error)?;
+    let mut builder = ChartBuilder::on(&root);
+    builder.margin(15);
+    if labels {
+        builder
+            .caption(&caption, ("sans-serif", 22))
+            .x_label_area_size(40)
+            .y_label_area_size(60);
+    } else {
+        // The same room as the caption and label areas take
+        builder.margin_top(50).margin_bottom(55).margin_left(75);
+    }
+    let mut chart = builder
+        .build_cartesian_2d(0.0..x_end, 0.0..y_end)
+        .map_err(error)?;
+    chart
+        .configure_mesh()
+        .x_labels(X_LABELS)
+        .y_labels(Y_LABELS)
+        .x_label_formatter(&label)
+        .y_desc("Latency (ms)")
+        .light_line_style(WHITE)
+        .draw()
+        .map_err(error)?;
+    for run in runs {
+        let shading = outages(run).into_iter().map(|outage| {
+            Rectangle::new(
+                [(x(outage.start), 0.0), (x(outage.end), y_end)],
+                RED.mix(0.25).filled(),
+            )
+        });
+        chart.draw_series(shading).map_err(error)?;
+        for segment in run.split(|(_, latency)| latency.is_none()) {
+            let points = segment
+                .iter()
+                .filter_map(|&(time, latency)| Some((x(time), latency?)));
+            chart
+                .draw_series(LineSeries::new(points, BLUE.stroke_width(1)))
+                .map_err(error)?;
+        }
+    }
+    root.present().map_err(error)?;
+    if labels {
+        return Ok(Vec::new());
+    }
+    let (x_pixels, y_pixels) = chart.plotting_area().get_pixel_range();
+    let mut texts = vec![
+        SvgText {
+            x: SIZE.0 as i32 / 2,
+            y: 35,
+            anchor: "middle",
+            size: 22,
+            text: caption,
+        },
+        SvgText {
+            x: x_pixels.start - 8,
+            y: y_pixels.start - 8,
+            anchor: "end",
+            size: 12,
+            text: "ms".to_string(),
+        },
+    ];
+    for seconds in RangedCoordf64::from(0.0..x_end).key_points(X_LABELS) {
+        let (x, _) = chart.backend_coord(&(seconds, 0.0));
+        texts.push(SvgText {
+            x,
+            y: y_pixels.end + 20,
+            anchor: "middle",
+            size: 12,
+            text: label(&seconds),
+        });
+    }
+    for latency in RangedCoordf64::from(0.0..y_end).key_points(Y_LABELS) {
+        let (_, y) = chart.backend_coord(&(0.0, latency));
+        texts.push(SvgText {
+            x: x_pixels.start - 8,
+            y: y + 4,
+            anchor: "end",
+            size: 12,
+            text: format!("{latency}"),
+        });
+    }
+    Ok(texts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn write_results(dir: &Path) {
+        let mut contents = "Timestamp,Latency(ms)\n".to_string();
+        for second in 0..60 {
+            let latency = if (20..25).contains(&second) {
+                String::new()
+            } else {
+                format!("{}.000", 10 + second % 7)
+            };
+            contents.push_str(&format!(
+                "2024-01-01 00:00:{second:02}.0 +00:00:00,{latency}\n"
+            ));
+        }
+        std::fs::write(
+            dir.join("result_01-01-2024@00-00-00_3f9a1c07.csv"),
+            contents,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn charts_are_rendered_with_a_font_if_one_is_installed() {
+        let dir = tempfile::tempdir().unwrap();
+        write_results(dir.path());
+        let svg = dir.path().join("latency.svg");
+        render(dir.path(), &svg).unwrap();
+        let svg = std::fs::read_to_string(svg).unwrap();
+        assert!(svg.starts_with("<svg") && svg.trim_end().ends_with("</svg>"));
+        assert!(svg.contains("Latency from 01-01 00:00:00 to 01-01 00:00:59"));
+        // PNGs can't fall back to the viewer's fonts
+        let png = dir.path().join("latency.png");
+        match load_font() {
+            Ok(()) => {
+                render(dir.path(), &png).unwrap();
+                assert!(std::fs::read(png).unwrap().starts_with(b"\x89PNG"));
+            }
+            Err(_) => assert!(render(dir.path(), &png).is_err()),
+        }
+        assert!(render(dir.path(), &dir.path().join("latency.gif")).is_err());
+        assert!(render(&dir.path().join("missing"), &dir.path().join("latency.svg")).is_err());
+    }
+
+    #[test]
+    fn charts_without_a_font_leave_the_labels_to_the_svg() {
+        let start = datetime!(2024-01-01 0:00 UTC);
+        let runs = vec![(0..60)
+            .map(|second| {
+                let latency = (!(20..25).contains(&second)).then_some(10.0 + second as f64);
+                (start + time::Duration::seconds(second), latency)
+            })
+            .collect()];
+        let mut svg = String::new();
+        let texts = draw(
+            SVGBackend::with_string(&mut svg, SIZE).into_drawing_area(),
+            &runs,
+            false,
+        )
+        .unwrap();
+        assert!(!svg.contains("<text"));
+        assert_eq!(
+            texts[0].text,
+            "Latency from 01-01 00:00:00 to 01-01 00:00:59"
+        );
+        let labels: Vec<&str> = texts.iter().map(|text| text.text.as_str()).collect();
+        assert_eq!(labels[1], "ms");
+        assert_eq!(labels[2..4], ["01-01 00:00:00", "01-01 00:00:10"]);
+        assert_eq!(labels[labels.len() - 2..], ["60", "70"]);
+        assert!(texts
+            .iter()
+            .any(|text| text.anchor == "end" && text.text == "0"));
+        assert!(texts.iter().all(
+            |text| (0..SIZE.0 as i32).contains(&text.x) && (0..SIZE.1 as i32).contains(&text.y)
+        ));
+    }
+}
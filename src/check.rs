@@ -0,0 +1,229 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::clock::SystemClock;
+use crate::engine::{new_run_id, Engine, EngineConfig, FlushPolicy};
+use crate::settings::Settings;
+use crate::writer::OverflowPolicy;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+
+/// Time between the probes of a check.
+const DELAY: Duration = Duration::from_secs(1);
+
+/// Round trip time and loss at which a check reaches a status, given as `RTA,PL%` like
+/// `check_ping`, e.g. `200,20%`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Thresholds {
+    /// Average round trip time (ms)
+    pub rta: f64,
+    /// Share of probes lost (%)
+    pub loss: f64,
+}
+
+impl FromStr for Thresholds {
+    type Err = String;
+
+    fn from_str(thresholds: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid thresholds \"{thresholds}\" (expected RTA,PL%, e.g. 200,20%)");
+        let (rta, loss) = thresholds.split_once(',').ok_or_else(invalid)?;
+        let rta: f64 = rta.parse().map_err(|_| invalid())?;
+        let loss: f64 = loss
+            .strip_suffix('%')
+            .unwrap_or(loss)
+            .parse()
+            .map_err(|_| invalid())?;
+        if !(rta >= 0.0 && rta.is_finite() && (0.0..=100.0).contains(&loss)) {
+            return Err(invalid());
+        }
+        Ok(Thresholds { rta, loss })
+    }
+}
+
+/// Outcome of a check, with the exit codes of Nagios plugins.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Status::Ok => "OK",
+            Status::Warning => "WARNING",
+            Status::Critical => "CRITICAL",
+            Status::Unknown => "UNKNOWN",
+        }
+    }
+}
+
+/// Results of the probes sent by a check.
+pub struct Check {
+    pub target: String,
+    pub sent: u64,
+    pub received: u64,
+    pub avg_rtt: Option<Duration>,
+}
+
+impl Check {
+    fn loss(&self) -> f64 {
+        100.0 * (self.sent - self.received) as f64 / self.sent.max(1) as f64
+    }
+
+    fn rta_ms(&self) -> Option<f64> {
+        self.avg_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0)
+    }
+
+    /// The worst status whose thresholds are reached. Losing every probe is always critical.
+    pub fn status(&self, warning: Thresholds, critical: Thresholds) -> Status {
+        let reached = |thresholds: Thresholds| {
+            self.loss() >= thresholds.loss || self.rta_ms().is_some_and(|rta| rta >= thresholds.rta)
+        };
+        if self.received == 0 || reached(critical) {
+            Status::Critical
+        } else if reached(warning) {
+            Status::Warning
+        } else {
+            Status::Ok
+        }
+    }
+
+    /// A summary of the results, e.g. `1.1.1.1: 5/5 replies, rta 12.345ms, lost 0%`.
+    pub fn summary(&self) -> String {
+        let rta = self
+            .rta_ms()
+            .map_or(String::new(), |rta| format!(", rta {rta:.3}ms"));
+        format!(
+            "{}: {}/{} replies{rta}, lost {:.0}%",
+            self.target,
+            self.received,
+            self.sent,
+            self.loss()
+        )
+    }
+
+    /// The output line of a Nagios plugin, with the round trip time and loss as performance data.
+    /// Without replies, the round trip time is `U`, for undetermined.
+    pub fn nagios(&self, warning: Thresholds, critical: Thresholds) -> String {
+        let rta = self
+            .rta_ms()
+            .map_or("U".to_string(), |rta| format!("{rta:.3}ms"));
+        format!(
+            "NUM {} - {} | rta={rta};{:.3};{:.3};0; pl={:.0}%;{:.0};{:.0};0;100",
+            self.status(warning, critical).name(),
+            self.summary(),
+            warning.rta,
+            critical.rta,
+            self.loss(),
+            warning.loss,
+            critical.loss,
+        )
+    }
+}
+
+/// Send `count` probes to `target` (an address or URI such as `tcp://example.com:443`), one a
+/// second, waiting up to `timeout` (ms) for each reply.
+pub async fn run(target: &str, count: u32, timeout: u64) -> Result<Check, String> {
+    let probe = Settings {
+        address: Some(target.to_string()),
+        timeout: Some(timeout),
+        delay: Some(DELAY.as_secs_f64()),
+        fast: Some(true),
+        ..Settings::default()
+    }
+    .probe_settings()?;
+    let mut engine = Engine::new(EngineConfig {
+        probe,
+        sync_writes: false,
+        encrypt: None,
+        sign: None,
+        flush_policy: FlushPolicy::EveryRow,
+        write_overflow: OverflowPolicy::Block,
+        max_write_failures: 20,
+        output_path: None,
+        wifi: false,
+        nic_counters: false,
+        columns: None,
+        delimiter: b',',
+        sinks: Vec::new(),
+        resume: false,
+        run_id: new_run_id(),
+        probe_name: String::new(),
+        asn_lookup: false,
+        clock: Arc::new(SystemClock),
+    })
+    .await?;
+    let mut interval = tokio::time::interval(DELAY);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    for _ in 0..count {
+        let tick = interval.tick().await;
+        let _ = engine.ping(tick.into_std()).await;
+        engine.take_outputs();
+    }
+    let stats = engine.stats();
+    Ok(Check {
+        target: target.to_string(),
+        sent: stats.sent,
+        received: stats.received,
+        avg_rtt: (stats.received > 0).then(|| stats.total_rtt / stats.received as u32),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plugin_output_has_status_and_perfdata() {
+        let warning: Thresholds = "200,20%".parse().unwrap();
+        let critical: Thresholds = "500,60".parse().unwrap();
+        assert_eq!(
+            critical,
+            Thresholds {
+                rta: 500.0,
+                loss: 60.0
+            }
+        );
+        assert!("200".parse::<Thresholds>().is_err());
+        assert!("200,120%".parse::<Thresholds>().is_err());
+
+        let check = |received: u64, rtt: u64| Check {
+            target: "1.1.1.1".to_string(),
+            sent: 5,
+            received,
+            avg_rtt: (received > 0).then(|| Duration::from_millis(rtt)),
+        };
+        assert_eq!(
+            check(5, 12).nagios(warning, critical),
+            "NUM OK - 1.1.1.1: 5/5 replies, rta 12.000ms, lost 0% \
+             | rta=12.000ms;200.000;500.000;0; pl=0%;20;60;0;100"
+        );
+        assert_eq!(check(4, 12).status(warning, critical), Status::Warning);
+        assert_eq!(check(5, 600).status(warning, critical), Status::Critical);
+        assert_eq!(
+            check(0, 0).nagios(warning, critical),
+            "NUM CRITICAL - 1.1.1.1: 0/5 replies, lost 100% \
+             | rta=U;200.000;500.000;0; pl=100%;20;60;0;100"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::local_now;
+use std::time::Instant;
+use time::OffsetDateTime;
+
+/// Source of wall clock and monotonic time for the engine, so outage and scheduling logic can be
+/// driven by a mock clock in tests.
+pub trait Clock: Send + Sync {
+    /// Return the current wall clock time in the local UTC offset.
+    fn now(&self) -> OffsetDateTime;
+
+    /// Return the current monotonic time.
+    fn instant(&self) -> Instant;
+}
+
+/// The real system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        local_now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock driven by tokio's (pausable) time, so tests can advance it with `tokio::time::advance`
+/// alongside interval ticks. The wall clock can additionally be jumped to simulate NTP steps or a
+/// suspended host.
+#[cfg(test)]
+pub struct MockClock {
+    wall_start: OffsetDateTime,
+    monotonic_start: tokio::time::Instant,
+    wall_offset: std::sync::Mutex<time::Duration>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(wall_start: OffsetDateTime) -> Self {
+        MockClock {
+            wall_start,
+            monotonic_start: tokio::time::Instant::now(),
+            wall_offset: std::sync::Mutex::new(time::Duration::ZERO),
+        }
+    }
+
+    /// Move the wall clock by `offset` without affecting monotonic time.
+    pub fn jump(&self, offset: time::Duration) {
+        *self.wall_offset.lock().unwrap() += offset;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> OffsetDateTime {
+        self.wall_start + self.monotonic_start.elapsed() + *self.wall_offset.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use time::macros::datetime;
+
+    #[tokio::test(start_paused = true)]
+    async fn mock_clock_follows_paused_time() {
+        let clock = MockClock::new(datetime!(2024-01-01 00:00 UTC));
+        let start = clock.instant();
+        tokio::time::advance(Duration::from_secs(90)).await;
+        assert_eq!(clock.instant() - start, Duration::from_secs(90));
+        assert_eq!(clock.now(), datetime!(2024-01-01 00:01:30 UTC));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mock_clock_jump_only_moves_wall_clock() {
+        let clock = MockClock::new(datetime!(2024-01-01 00:00 UTC));
+        let start = clock.instant();
+        clock.jump(time::Duration::hours(-1));
+        assert_eq!(clock.instant(), start);
+        assert_eq!(clock.now(), datetime!(2023-12-31 23:00 UTC));
+    }
+}
@@ -0,0 +1,240 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::log_file::format_row;
+use crate::probe::ProbeKind;
+use std::str::FromStr;
+
+/// A field that can be recorded in the result CSV.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Column {
+    Timestamp,
+    /// ID of the run that recorded the row
+    Run,
+    /// Name of the machine that recorded the row
+    ProbeName,
+    Target,
+    Address,
+    Rtt,
+    Status,
+    Error,
+    Seq,
+    /// Times the probe was sent again after timing out
+    Retries,
+    /// TTL (or hop limit) of the reply, for ICMP probes
+    Ttl,
+    Connect,
+    Handshake,
+    CertExpiry,
+    Offset,
+    Signal,
+    TxBitrate,
+    RxErrors,
+    TxErrors,
+    RxDropped,
+    TxDropped,
+}
+
+const COLUMNS: [Column; 21] = [
+    Column::Timestamp,
+    Column::Run,
+    Column::ProbeName,
+    Column::Target,
+    Column::Address,
+    Column::Rtt,
+    Column::Status,
+    Column::Error,
+    Column::Seq,
+    Column::Retries,
+    Column::Ttl,
+    Column::Connect,
+    Column::Handshake,
+    Column::CertExpiry,
+    Column::Offset,
+    Column::Signal,
+    Column::TxBitrate,
+    Column::RxErrors,
+    Column::TxErrors,
+    Column::RxDropped,
+    Column::TxDropped,
+];
+
+/// Columns whose values are measured by the probe itself rather than the engine.
+pub const PROBE_COLUMNS: [Column; 5] = [
+    Column::Ttl,
+    Column::Connect,
+    Column::Handshake,
+    Column::CertExpiry,
+    Column::Offset,
+];
+
+impl Column {
+    /// Look up a column by its name in `--columns`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        COLUMNS.into_iter().find(|column| column.name() == name)
+    }
+
+    /// Look up a column by its header in the result files.
+    pub fn from_header(header: &str) -> Option<Self> {
+        COLUMNS.into_iter().find(|column| column.header() == header)
+    }
+
+    /// Name of the column in `--columns`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Column::Timestamp => "timestamp",
+            Column::Run => "run_id",
+            Column::ProbeName => "probe_name",
+            Column::Target => "target",
+            Column::Address => "address",
+            Column::Rtt => "rtt",
+            Column::Status => "status",
+            Column::Error => "error",
+            Column::Seq => "seq",
+            Column::Retries => "retries",
+            Column::Ttl => "ttl",
+            Column::Connect => "connect",
+            Column::Handshake => "handshake",
+            Column::CertExpiry => "cert_expiry",
+            Column::Offset => "offset",
+            Column::Signal => "signal",
+            Column::TxBitrate => "tx_bitrate",
+            Column::RxErrors => "rx_errors",
+            Column::TxErrors => "tx_errors",
+            Column::RxDropped => "rx_dropped",
+            Column::TxDropped => "tx_dropped",
+        }
+    }
+
+    /// Heading of the column in the result CSV.
+    pub fn header(&self) -> &'static str {
+        match self {
+            Column::Timestamp => "Timestamp",
+            Column::Run => "Run",
+            Column::ProbeName => "Probe",
+            Column::Target => "Target",
+            Column::Address => "Address",
+            Column::Rtt => "Latency(ms)",
+            Column::Status => "Status",
+            Column::Error => "Error",
+            Column::Seq => "Seq",
+            Column::Retries => "Retries",
+            Column::Ttl => "TTL",
+            Column::Connect => "Connect(ms)",
+            Column::Handshake => "Handshake(ms)",
+            Column::CertExpiry => "CertExpiry(days)",
+            Column::Offset => "Offset(ms)",
+            Column::Signal => "Signal(dBm)",
+            Column::TxBitrate => "TxBitrate(Mbps)",
+            Column::RxErrors => "RxErrors",
+            Column::TxErrors => "TxErrors",
+            Column::RxDropped => "RxDropped",
+            Column::TxDropped => "TxDropped",
+        }
+    }
+
+    /// Return whether the column holds numbers (or nothing), rather than text that may look like one.
+    pub fn is_numeric(&self) -> bool {
+        !matches!(
+            self,
+            Column::Timestamp
+                | Column::Run
+                | Column::ProbeName
+                | Column::Target
+                | Column::Address
+                | Column::Status
+                | Column::Error
+        )
+    }
+
+    pub fn is_wifi(&self) -> bool {
+        matches!(self, Column::Signal | Column::TxBitrate)
+    }
+
+    pub fn is_nic_counter(&self) -> bool {
+        matches!(
+            self,
+            Column::RxErrors | Column::TxErrors | Column::RxDropped | Column::TxDropped
+        )
+    }
+}
+
+/// The fields recorded in the result CSV, in order.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Columns(pub Vec<Column>);
+
+impl Columns {
+    /// The columns recorded when `--columns` isn't given: the outcome of each probe, the extra
+    /// measurements of its kind, and the Wi-Fi and interface counter columns if enabled.
+    pub fn default_for(kind: ProbeKind, wifi: bool, nic_counters: bool) -> Self {
+        let mut columns = vec![
+            Column::Timestamp,
+            Column::Rtt,
+            Column::Status,
+            Column::Error,
+        ];
+        columns.extend_from_slice(kind.columns());
+        if wifi {
+            columns.extend([Column::Signal, Column::TxBitrate]);
+        }
+        if nic_counters {
+            columns.extend([
+                Column::RxErrors,
+                Column::TxErrors,
+                Column::RxDropped,
+                Column::TxDropped,
+            ]);
+        }
+        Columns(columns)
+    }
+
+    /// The header row of the result CSV.
+    pub fn header(&self, delimiter: u8) -> String {
+        format_row(self.0.iter().map(Column::header), delimiter)
+    }
+
+    pub fn has_wifi(&self) -> bool {
+        self.0.iter().any(Column::is_wifi)
+    }
+
+    pub fn has_nic_counters(&self) -> bool {
+        self.0.iter().any(Column::is_nic_counter)
+    }
+}
+
+impl FromStr for Columns {
+    type Err = String;
+
+    /// Parse a comma-separated list of column names such as `timestamp,rtt,error`.
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let mut columns = Vec::new();
+        for name in list.split(',').map(str::trim) {
+            let Some(column) = Column::from_name(name) else {
+                let names: Vec<&str> = COLUMNS.iter().map(Column::name).collect();
+                return Err(format!(
+                    "unknown column \"{name}\" (expected any of {})",
+                    names.join(", ")
+                ));
+            };
+            if columns.contains(&column) {
+                return Err(format!("column \"{name}\" is listed twice"));
+            }
+            columns.push(column);
+        }
+        Ok(Columns(columns))
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::tui::format_duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Whether the internet as a whole is down, judged from the states of every monitored target, so
+/// that an ISP outage is reported once rather than once per target.
+pub struct Composite {
+    /// Target that is the LAN gateway rather than an external one
+    gateway: Option<String>,
+    /// Whether each monitored target is DOWN
+    down: BTreeMap<String, bool>,
+    /// When the internet went down, if it is
+    down_since: Option<Instant>,
+}
+
+/// A change of the composite state.
+#[derive(Debug, PartialEq)]
+pub enum Change {
+    /// Every external target is DOWN while the gateway (if known) still answers
+    Down {
+        targets: usize,
+        gateway: Option<String>,
+    },
+    /// An external target answers again
+    Up { after: Duration },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Down {
+                targets,
+                gateway: Some(gateway),
+            } => write!(
+                f,
+                "all {targets} external targets are DOWN while the gateway {gateway} answers"
+            ),
+            Change::Down { targets, .. } => write!(f, "all {targets} targets are DOWN"),
+            Change::Up { after } => write!(f, "internet back after {}", format_duration(*after)),
+        }
+    }
+}
+
+impl Composite {
+    pub fn new(gateway: Option<String>) -> Self {
+        Composite {
+            gateway,
+            down: BTreeMap::new(),
+            down_since: None,
+        }
+    }
+
+    /// Record whether `target` is DOWN, returning the change of the composite state if it causes
+    /// one.
+    pub fn update(&mut self, target: &str, down: bool, now: Instant) -> Option<Change> {
+        self.down.insert(target.to_string(), down);
+        self.evaluate(now)
+    }
+
+    /// Stop counting a target that is no longer monitored.
+    pub fn remove(&mut self, target: &str, now: Instant) -> Option<Change> {
+        self.down.remove(target);
+        self.evaluate(now)
+    }
+
+    /// The internet goes down once at least two external targets are all DOWN while the gateway
+    /// answers, and stays down (even if the gateway fails too) until one of them answers again.
+    fn evaluate(&mut self, now: Instant) -> Option<Change> {
+        let external: Vec<bool> = self
+            .down
+            .iter()
+            .filter(|(target, _)| self.gateway.as_ref() != Some(*target))
+            .map(|(_, &down)| down)
+            .collect();
+        let all_down = external.len() >= 2 && external.iter().all(|&down| down);
+        let gateway_down = self
+            .gateway
+            .as_ref()
+            .is_some_and(|gateway| self.down.get(gateway) == Some(&true));
+        match self.down_since {
+            None if all_down && !gateway_down => {
+                self.down_since = Some(now);
+                Some(Change::Down {
+                    targets: external.len(),
+                    gateway: self
+                        .gateway
+                        .clone()
+                        .filter(|gateway| self.down.contains_key(gateway)),
+                })
+            }
+            Some(since) if !all_down => {
+                self.down_since = None;
+                Some(Change::Up { after: now - since })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internet_is_down_only_when_every_external_target_is() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut composite = Composite::new(Some("192.168.1.1".to_string()));
+        for target in ["192.168.1.1", "1.1.1.1", "8.8.8.8"] {
+            assert_eq!(composite.update(target, false, at(0)), None);
+        }
+        assert_eq!(composite.update("1.1.1.1", true, at(10)), None);
+        // The gateway failing too is a LAN problem
+        assert_eq!(composite.update("192.168.1.1", true, at(20)), None);
+        assert_eq!(composite.update("8.8.8.8", true, at(20)), None);
+        assert_eq!(
+            composite.update("192.168.1.1", false, at(30)),
+            Some(Change::Down {
+                targets: 2,
+                gateway: Some("192.168.1.1".to_string()),
+            })
+        );
+        assert_eq!(composite.update("192.168.1.1", true, at(40)), None);
+        assert_eq!(
+            composite.update("8.8.8.8", false, at(90)),
+            Some(Change::Up {
+                after: Duration::from_secs(60)
+            })
+        );
+    }
+}
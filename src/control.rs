@@ -0,0 +1,200 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(unix)]
+use std::time::Duration;
+#[cfg(unix)]
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+#[cfg(unix)]
+use tokio::task;
+
+/// Name of the control socket in the output directory.
+#[cfg(unix)]
+pub const CONTROL_SOCKET_NAME: &str = "control.sock";
+
+// Longest request line accepted, far longer than any target
+#[cfg(unix)]
+const MAX_REQUEST_LEN: u64 = 1024;
+
+// Pause after failing to accept a connection (e.g. out of file descriptors) before trying again
+#[cfg(unix)]
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// A request to the running monitor from `num ctl`.
+pub enum Request {
+    Status,
+    Stats,
+    Pause,
+    Resume,
+    Reload,
+    AddTarget(String),
+    RemoveTarget(String),
+}
+
+impl Request {
+    /// Parse a request line such as `pause` or `add-target 10.0.0.5`.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let argument = words.next();
+        match (command, argument, words.next()) {
+            ("status", None, None) => Ok(Request::Status),
+            ("stats", None, None) => Ok(Request::Stats),
+            ("pause", None, None) => Ok(Request::Pause),
+            ("resume", None, None) => Ok(Request::Resume),
+            ("reload", None, None) => Ok(Request::Reload),
+            ("add-target", Some(target), None) => Ok(Request::AddTarget(target.to_string())),
+            ("remove-target", Some(target), None) => Ok(Request::RemoveTarget(target.to_string())),
+            _ => Err(format!("unknown request \"{}\"", line.trim())),
+        }
+    }
+}
+
+/// A request waiting for the ping loop, which sends back the reply (or an error) when done.
+pub type Pending = (Request, oneshot::Sender<Result<String, String>>);
+
+/// Listen on the control socket at `path`, replacing a stale one left behind by a monitor that
+/// didn't exit cleanly. Each connection sends one request line and gets one reply, prefixed with
+/// `ok` or `error`. Only the user running the monitor may connect.
+#[cfg(unix)]
+pub fn listen(path: &Path, requests: mpsc::UnboundedSender<Pending>) -> io::Result<()> {
+    if path.exists() && std::os::unix::net::UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    task::spawn(async move {
+        loop {
+            let Ok((stream, _)) = listener.accept().await else {
+                tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                continue;
+            };
+            let requests = requests.clone();
+            task::spawn(async move {
+                let _ = handle(stream, requests).await;
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn handle(stream: UnixStream, requests: mpsc::UnboundedSender<Pending>) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let mut line = String::new();
+    (&mut stream)
+        .take(MAX_REQUEST_LEN)
+        .read_line(&mut line)
+        .await?;
+    let request = if line.len() as u64 >= MAX_REQUEST_LEN && !line.ends_with('\n') {
+        Err(format!("request longer than {MAX_REQUEST_LEN} bytes"))
+    } else {
+        Request::parse(&line)
+    };
+    let reply = match request {
+        Ok(request) => {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            let _ = requests.send((request, reply_tx));
+            reply_rx
+                .await
+                .unwrap_or(Err("the monitor is shutting down".to_string()))
+        }
+        Err(e) => Err(e),
+    };
+    let reply = match reply {
+        Ok(reply) => format!("ok {reply}\n"),
+        Err(e) => format!("error {e}\n"),
+    };
+    stream.get_mut().write_all(reply.as_bytes()).await?;
+    stream.get_mut().shutdown().await
+}
+
+/// Send a request line to the monitor listening at `path` and return its reply, or the error it
+/// reported.
+#[cfg(unix)]
+pub async fn send(path: &Path, request: &str) -> Result<String, String> {
+    let reply = async {
+        let mut stream = BufReader::new(UnixStream::connect(path).await?);
+        stream
+            .get_mut()
+            .write_all(format!("{request}\n").as_bytes())
+            .await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        io::Result::Ok(reply)
+    }
+    .await
+    .map_err(|e| format!("Could not reach the monitor at {}: {e}", path.display()))?;
+    match reply.trim_end().split_once(' ') {
+        Some(("ok", reply)) => Ok(reply.to_string()),
+        Some(("error", e)) => Err(e.to_string()),
+        _ if reply.trim_end() == "ok" => Ok(String::new()),
+        _ => Err(format!("Unexpected reply from the monitor: {reply}")),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn requests_reach_the_ping_loop_and_replies_come_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CONTROL_SOCKET_NAME);
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel::<Pending>();
+        listen(&path, request_tx).unwrap();
+        task::spawn(async move {
+            while let Some((request, reply)) = request_rx.recv().await {
+                let _ = reply.send(match request {
+                    Request::AddTarget(target) => Ok(format!("monitoring {target}")),
+                    Request::RemoveTarget(target) => Err(format!("{target} is not added")),
+                    Request::Pause => Ok("paused".to_string()),
+                    _ => Err("not handled".to_string()),
+                });
+            }
+        });
+        assert_eq!(
+            send(&path, "add-target 10.0.0.5").await,
+            Ok("monitoring 10.0.0.5".to_string())
+        );
+        assert_eq!(
+            send(&path, "remove-target 10.0.0.6").await,
+            Err("10.0.0.6 is not added".to_string())
+        );
+        assert_eq!(send(&path, "pause").await, Ok("paused".to_string()));
+        assert!(send(&path, "pause now").await.is_err());
+        assert!(send(&path, "reboot").await.is_err());
+        assert_eq!(
+            send(&path, &format!("add-target {}", "a".repeat(2000))).await,
+            Err("request longer than 1024 bytes".to_string())
+        );
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,365 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::cbor;
+use crate::columns::{Column, Columns};
+use crate::log_file::format_row;
+use crate::report::{self, parse_timestamp, read_results};
+use crate::sink::{csv_header, json_header, json_record};
+use crate::tui::format_duration;
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, Worksheet, XlsxError};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+// Rows per data sheet after the header, as Excel can't open larger sheets
+const MAX_SHEET_ROWS: usize = 1_048_575;
+
+/// Convert every result file in `output_path` into an XLSX workbook at `destination`, with a
+/// summary sheet (uptime and outages) followed by the raw results.
+pub fn to_xlsx(output_path: &Path, destination: &Path) -> Result<String, String> {
+    let files = read_results(output_path)?;
+    // Runs with different --columns are merged under the union of their columns
+    let mut headers: Vec<String> = Vec::new();
+    for file in &files {
+        for header in &file.headers {
+            if !headers.contains(header) {
+                headers.push(header.clone());
+            }
+        }
+    }
+    let mut sent = 0u64;
+    let mut received = 0u64;
+    let mut outages = Vec::new();
+    let mut rows = Vec::new();
+    for file in &files {
+        let probes = file.probes();
+        sent += probes.len() as u64;
+        received += probes
+            .iter()
+            .filter(|(_, latency)| latency.is_some())
+            .count() as u64;
+        outages.extend(report::outages(&probes));
+        let timestamp_column = file.column("Timestamp").unwrap();
+        for row in &file.rows {
+            let Some(timestamp) = row.get(timestamp_column).and_then(parse_timestamp) else {
+                continue;
+            };
+            let values: Vec<&str> = headers
+                .iter()
+                .map(|header| {
+                    file.column(header)
+                        .and_then(|column| row.get(column))
+                        .unwrap_or_default()
+                })
+                .collect();
+            rows.push((timestamp, values));
+        }
+    }
+    if rows.is_empty() {
+        return Err(format!("No results found in {}", output_path.display()));
+    }
+
+    let xlsx_error = |e: XlsxError| format!("Could not write {}: {e}", destination.display());
+    let mut workbook = Workbook::new();
+    let bold = Format::new().set_bold();
+    let datetime = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss.000");
+    let summary = workbook.add_worksheet();
+    summary.set_name("Summary").map_err(xlsx_error)?;
+    let uptime = received as f64 * 100.0 / sent as f64;
+    let downtime: Duration = outages
+        .iter()
+        .map(|outage| (outage.end - outage.start).unsigned_abs())
+        .sum();
+    summary
+        .write_string_with_format(0, 0, "First probe", &bold)
+        .and_then(|sheet| write_timestamp(sheet, 0, 1, rows[0].0, &datetime))
+        .and_then(|sheet| sheet.write_string_with_format(1, 0, "Last probe", &bold))
+        .and_then(|sheet| write_timestamp(sheet, 1, 1, rows[rows.len() - 1].0, &datetime))
+        .and_then(|sheet| sheet.write_string_with_format(2, 0, "Probes", &bold))
+        .and_then(|sheet| sheet.write_number(2, 1, sent as f64))
+        .and_then(|sheet| sheet.write_string_with_format(3, 0, "Successful", &bold))
+        .and_then(|sheet| sheet.write_number(3, 1, received as f64))
+        .and_then(|sheet| sheet.write_string_with_format(4, 0, "Uptime (%)", &bold))
+        .and_then(|sheet| sheet.write_number(4, 1, (uptime * 1000.0).round() / 1000.0))
+        .and_then(|sheet| sheet.write_string_with_format(5, 0, "Outages", &bold))
+        .and_then(|sheet| sheet.write_number(5, 1, outages.len() as f64))
+        .and_then(|sheet| sheet.write_string_with_format(6, 0, "Downtime", &bold))
+        .and_then(|sheet| sheet.write_string(6, 1, format_duration(downtime)))
+        .map_err(xlsx_error)?;
+    for (column, header) in [
+        "Outage start",
+        "Outage end",
+        "Failed probes",
+        "Duration (s)",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        summary
+            .write_string_with_format(8, column as u16, header, &bold)
+            .map_err(xlsx_error)?;
+    }
+    for (i, outage) in outages.iter().enumerate() {
+        let row = 9 + i as u32;
+        write_timestamp(summary, row, 0, outage.start, &datetime)
+            .and_then(|sheet| write_timestamp(sheet, row, 1, outage.end, &datetime))
+            .and_then(|sheet| sheet.write_number(row, 2, outage.failures as f64))
+            .and_then(|sheet| {
+                sheet.write_number(row, 3, (outage.end - outage.start).as_seconds_f64())
+            })
+            .map_err(xlsx_error)?;
+    }
+    summary.autofit();
+
+    let data_sheets = rows.len().div_ceil(MAX_SHEET_ROWS);
+    for (sheet_index, chunk) in rows.chunks(MAX_SHEET_ROWS).enumerate() {
+        let sheet = workbook.add_worksheet();
+        sheet
+            .set_name(if data_sheets == 1 {
+                "Results".to_string()
+            } else {
+                format!("Results {}", sheet_index + 1)
+            })
+            .and_then(|sheet| sheet.set_freeze_panes(1, 0))
+            .map_err(xlsx_error)?;
+        for (column, header) in headers.iter().enumerate() {
+            sheet
+                .write_string_with_format(0, column as u16, header, &bold)
+                .map_err(xlsx_error)?;
+        }
+        for (i, (timestamp, values)) in chunk.iter().enumerate() {
+            let row = 1 + i as u32;
+            for (column, value) in values.iter().enumerate() {
+                let column = column as u16;
+                let written = if headers[column as usize] == "Timestamp" {
+                    write_timestamp(sheet, row, column, *timestamp, &datetime)
+                } else if let Ok(number) = value.parse::<f64>() {
+                    sheet.write_number(row, column, number)
+                } else if value.is_empty() {
+                    continue;
+                } else {
+                    sheet.write_string(row, column, *value)
+                };
+                written.map_err(xlsx_error)?;
+            }
+        }
+        sheet.autofit();
+    }
+    workbook.save(destination).map_err(xlsx_error)?;
+    Ok(format!(
+        "Wrote {} results and {} outage(s) to {}",
+        rows.len(),
+        outages.len(),
+        destination.display()
+    ))
+}
+
+/// Write `timestamp` as an Excel date and time, in the timezone it was recorded in.
+fn write_timestamp<'a>(
+    sheet: &'a mut Worksheet,
+    row: u32,
+    column: u16,
+    timestamp: OffsetDateTime,
+    format: &Format,
+) -> Result<&'a mut Worksheet, XlsxError> {
+    let datetime = ExcelDateTime::from_ymd(
+        timestamp.year() as u16,
+        timestamp.month().into(),
+        timestamp.day(),
+    )?
+    .and_hms(
+        timestamp.hour().into(),
+        timestamp.minute(),
+        timestamp.second() as f64 + timestamp.nanosecond() as f64 / 1e9,
+    )?;
+    sheet.write_datetime_with_format(row, column, datetime, format)
+}
+
+/// Convert every CBOR result file in `output_path` into a CSV (`json` false) or JSON lines file
+/// next to it, named the same but for the extension, as the CSV and JSON sinks would have written.
+/// Existing files aren't overwritten.
+pub fn from_cbor(output_path: &Path, json: bool) -> Result<String, String> {
+    let entries = std::fs::read_dir(output_path)
+        .map_err(|e| format!("Could not read {}: {e}", output_path.display()))?;
+    let mut paths: Vec<_> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("result_") && name.ends_with(".cbor")
+        })
+        .collect();
+    if paths.is_empty() {
+        return Err(format!(
+            "No CBOR result files found in {}",
+            output_path.display()
+        ));
+    }
+    paths.sort();
+    let mut summary = Vec::new();
+    for path in paths {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        // Runs that also wrote CSV or NDJSON already have one
+        let destination = path.with_extension(if json { "jsonl" } else { "csv" });
+        if destination.exists() {
+            summary.push(format!(
+                "Skipped {name}, as {} already exists",
+                destination.display()
+            ));
+            continue;
+        }
+        let bytes = std::fs::read(&path).map_err(|e| format!("Could not read {name}: {e}"))?;
+        let results = cbor::read(&bytes).map_err(|e| format!("Could not read {name}: {e}"))?;
+        let columns = results
+            .columns
+            .iter()
+            .map(|column| {
+                Column::from_name(column).ok_or(format!(
+                    "Could not read {name}: unknown column \"{column}\""
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let metadata: Vec<(&str, String)> = results
+            .metadata
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.clone()))
+            .collect();
+        let mut contents = if json {
+            json_header(&metadata)
+        } else {
+            csv_header(&metadata, &Columns(columns.clone()), b',')
+        };
+        contents.push('\n');
+        for record in &results.records {
+            let row = if json {
+                json_record(
+                    columns
+                        .iter()
+                        .copied()
+                        .zip(record.iter().map(String::as_str)),
+                )
+            } else {
+                format_row(record, b',')
+            };
+            contents.push_str(&row);
+            contents.push('\n');
+        }
+        std::fs::File::options()
+            .create_new(true)
+            .write(true)
+            .open(&destination)
+            .and_then(|mut file| file.write_all(contents.as_bytes()))
+            .map_err(|e| format!("Could not write {}: {e}", destination.display()))?;
+        summary.push(format!(
+            "Wrote {} results from {name} to {}{}",
+            results.records.len(),
+            destination.display(),
+            if results.truncated {
+                " (the last one was only partly written)"
+            } else {
+                ""
+            }
+        ));
+    }
+    Ok(summary.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Contents of `name` in the workbook at `path`.
+    fn workbook_part(path: &Path, name: &str) -> String {
+        let mut workbook = zip::ZipArchive::new(std::fs::File::open(path).unwrap()).unwrap();
+        std::io::read_to_string(workbook.by_name(name).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn workbooks_have_a_summary_and_the_results_of_every_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let runs = [
+            (
+                "result_01-31-2024@23-59-58_3f9a1c07.csv",
+                "# target: 192.0.2.1\nTimestamp,Latency(ms)\n\
+                 2024-01-31 23:59:58.0 +00:00:00,12.000\n\
+                 2024-01-31 23:59:59.0 +00:00:00,\n\
+                 2024-02-01 00:00:00.0 +00:00:00,14.000\n",
+            ),
+            (
+                "result_02-01-2024@00-00-05_8b2e6d10.csv",
+                "Timestamp,Latency(ms),Status\n\
+                 2024-02-01 00:00:05.0 +00:00:00,,failed\n\
+                 2024-02-01 00:00:06.0 +00:00:00,,failed\n",
+            ),
+        ];
+        for (name, contents) in runs {
+            std::fs::write(dir.path().join(name), contents).unwrap();
+        }
+        let destination = dir.path().join("results.xlsx");
+        assert_eq!(
+            to_xlsx(dir.path(), &destination).unwrap(),
+            format!(
+                "Wrote 5 results and 2 outage(s) to {}",
+                destination.display()
+            )
+        );
+        let workbook = workbook_part(&destination, "xl/workbook.xml");
+        assert!(workbook.contains("name=\"Summary\""));
+        assert!(workbook.contains("name=\"Results\""));
+        assert_eq!(workbook.matches("<sheet ").count(), 2);
+        // The summary has 7 figures and a header over the outages
+        let summary = workbook_part(&destination, "xl/worksheets/sheet1.xml");
+        assert_eq!(summary.matches("<row ").count(), 7 + 1 + 2);
+        // The runs' columns are merged under one header
+        let results = workbook_part(&destination, "xl/worksheets/sheet2.xml");
+        assert_eq!(results.matches("<row ").count(), 1 + 5);
+        let strings = workbook_part(&destination, "xl/sharedStrings.xml");
+        assert!(strings.contains("Status") && strings.contains("failed"));
+        assert!(to_xlsx(tempfile::tempdir().unwrap().path(), &destination).is_err());
+    }
+
+    #[test]
+    fn cbor_results_already_converted_are_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cbor = cbor::header(&[], &["timestamp", "rtt"]);
+        cbor.extend(cbor::record(
+            ["2024-01-31 23:59:59.5 +00:00:00", "12.345"].into_iter(),
+        ));
+        for run in [
+            "01-31-2024@23-59-59_3f9a1c07",
+            "02-01-2024@00-00-00_8b2e6d10",
+        ] {
+            std::fs::write(dir.path().join(format!("result_{run}.cbor")), &cbor).unwrap();
+        }
+        let written = dir.path().join("result_01-31-2024@23-59-59_3f9a1c07.csv");
+        std::fs::write(&written, "Timestamp,Latency(ms)\n").unwrap();
+        let summary = from_cbor(dir.path(), false).unwrap();
+        let lines: Vec<&str> = summary.lines().collect();
+        assert!(lines[0].starts_with("Skipped result_01-31-2024@23-59-59_3f9a1c07.cbor"));
+        assert!(lines[1].starts_with("Wrote 1 results from result_02-01-2024@00-00-00_8b2e6d10"));
+        assert_eq!(
+            std::fs::read_to_string(&written).unwrap(),
+            "Timestamp,Latency(ms)\n"
+        );
+        let converted = dir.path().join("result_02-01-2024@00-00-00_8b2e6d10.csv");
+        assert!(std::fs::read_to_string(converted)
+            .unwrap()
+            .ends_with("2024-01-31 23:59:59.5 +00:00:00,12.345\n"));
+    }
+}
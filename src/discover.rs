@@ -0,0 +1,222 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::path::{checksum, recv_from, sockaddr};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+
+/// A host that answered the discovery ping.
+pub struct Responder {
+    pub address: IpAddr,
+    /// Round trip time of its first reply
+    pub rtt: Duration,
+}
+
+/// Ping an IPv4 broadcast address or an IPv6 multicast group such as `ff02::1` once and collect
+/// every host that answers within `wait`, sorted by address. IPv6 multicast is sent on
+/// `interface`, which link-local groups require.
+pub async fn run(
+    address: IpAddr,
+    interface: Option<&str>,
+    wait: Duration,
+) -> Result<Vec<Responder>, String> {
+    let scope_id = match (address, interface) {
+        (IpAddr::V6(group), Some(interface)) if group.is_multicast() => interface_index(interface)
+            .map_err(|e| format!("Could not find interface {interface}: {e}"))?,
+        (IpAddr::V6(group), None) if group.is_multicast() => {
+            return Err(format!(
+                "Pinging {group} needs the interface to send on (pass --interface)"
+            ))
+        }
+        (IpAddr::V6(_), _) => {
+            return Err(format!(
+                "{address} is not a multicast address (expected e.g. ff02::1)"
+            ))
+        }
+        (IpAddr::V4(_), _) => 0,
+    };
+    let socket = open(address).map_err(|e| format!("Could not open ICMP socket: {e}"))?;
+    let identifier = std::process::id() as u16;
+    let destination = match address {
+        IpAddr::V4(_) => SocketAddr::new(address, 0),
+        IpAddr::V6(group) => SocketAddr::V6(SocketAddrV6::new(group, 0, 0, scope_id)),
+    };
+    let sent = Instant::now();
+    send(socket.get_ref(), destination, identifier)
+        .map_err(|e| format!("Could not ping {address}: {e}"))?;
+
+    let mut responders = BTreeMap::new();
+    let mut buf = [0u8; 1500];
+    let _ = tokio::time::timeout(wait, async {
+        loop {
+            let Ok(mut guard) = socket.readable().await else {
+                return;
+            };
+            let Ok(received) = guard.try_io(|socket| recv_from(socket.get_ref(), &mut buf)) else {
+                continue;
+            };
+            let Ok((len, from)) = received else {
+                continue;
+            };
+            if is_reply(&buf[..len], address.is_ipv4(), identifier) {
+                // Hosts may answer more than once, e.g. on several interfaces
+                responders.entry(from).or_insert_with(|| sent.elapsed());
+            }
+        }
+    })
+    .await;
+    Ok(responders
+        .into_iter()
+        .map(|(address, rtt)| Responder { address, rtt })
+        .collect())
+}
+
+/// Open a non-blocking raw ICMP socket allowed to send to broadcast addresses.
+fn open(address: IpAddr) -> io::Result<AsyncFd<OwnedFd>> {
+    let (domain, protocol) = match address {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+    };
+    // SAFETY: plain socket calls; the descriptor is owned by the returned OwnedFd
+    let socket = unsafe {
+        let fd = libc::socket(
+            domain,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            protocol,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+        let enable: libc::c_int = 1;
+        if address.is_ipv4()
+            && libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_BROADCAST,
+                &enable as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as u32,
+            ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        socket
+    };
+    AsyncFd::new(socket)
+}
+
+fn send(socket: &OwnedFd, destination: SocketAddr, identifier: u16) -> io::Result<()> {
+    let echo_type = if destination.is_ipv4() { 8 } else { 128 };
+    let mut packet = vec![echo_type, 0, 0, 0];
+    packet.extend_from_slice(&identifier.to_be_bytes());
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.extend_from_slice(&[0; 8]);
+    // The kernel fills in the checksum for ICMPv6
+    if destination.is_ipv4() {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    let (addr, addr_len) = sockaddr(destination);
+    // SAFETY: the buffer and address are valid for the given lengths
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            packet.as_ptr() as *const libc::c_void,
+            packet.len(),
+            0,
+            &addr as *const _ as *const libc::sockaddr,
+            addr_len,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Check whether a received packet is an echo reply to our discovery ping. Raw ICMPv4 sockets
+/// include the IP header, raw ICMPv6 sockets don't.
+fn is_reply(packet: &[u8], ipv4: bool, identifier: u16) -> bool {
+    let icmp = if ipv4 {
+        let header_len = usize::from(packet.first().copied().unwrap_or_default() & 0x0f) * 4;
+        packet.get(header_len..).unwrap_or_default()
+    } else {
+        packet
+    };
+    let reply_type = if ipv4 { 0 } else { 129 };
+    icmp.len() >= 8 && icmp[0] == reply_type && icmp[4..6] == identifier.to_be_bytes()
+}
+
+fn interface_index(name: &str) -> io::Result<u32> {
+    let c_name = CString::new(name)?;
+    // SAFETY: the name is a valid NUL-terminated string for the duration of the call
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(index)
+}
+
+/// List the responders as a table of addresses and round trip times.
+pub fn render(address: IpAddr, responders: &[Responder]) -> String {
+    let hosts = match responders.len() {
+        1 => "1 host".to_string(),
+        count => format!("{count} hosts"),
+    };
+    let mut table = format!("{hosts} answered a ping to {address}");
+    let width = responders
+        .iter()
+        .map(|responder| responder.address.to_string().len())
+        .max()
+        .unwrap_or_default();
+    for responder in responders {
+        table.push_str(&format!(
+            "\n  {:<width$}  {:.1} ms",
+            responder.address.to_string(),
+            responder.rtt.as_secs_f64() * 1000.0
+        ));
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn only_echo_replies_to_our_ping_count() {
+        let mut reply = vec![0x45, 0, 0, 28, 0, 0, 0, 0, 64, 1, 0, 0];
+        reply.extend_from_slice(&Ipv4Addr::new(192, 168, 1, 7).octets());
+        reply.extend_from_slice(&Ipv4Addr::new(192, 168, 1, 2).octets());
+        reply.extend_from_slice(&[0, 0, 0, 0, 0x12, 0x34, 0, 0]);
+        assert!(is_reply(&reply, true, 0x1234));
+        assert!(!is_reply(&reply, true, 0x4321));
+        // Our own request, looped back to the socket
+        reply[20] = 8;
+        assert!(!is_reply(&reply, true, 0x1234));
+        assert!(is_reply(&[129, 0, 0, 0, 0x12, 0x34, 0, 0], false, 0x1234));
+        assert!(!is_reply(&[129, 0, 0, 0], false, 0x1234));
+    }
+}
@@ -0,0 +1,132 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use age::stream::StreamWriter;
+use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Key files are encrypted to, given to `--encrypt` as `age:<recipient>`, e.g.
+/// `age:age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p`.
+#[derive(Clone)]
+pub struct Recipient(age::x25519::Recipient);
+
+impl FromStr for Recipient {
+    type Err = String;
+
+    fn from_str(recipient: &str) -> Result<Self, Self::Err> {
+        let key = recipient.strip_prefix("age:").ok_or(format!(
+            "invalid recipient \"{recipient}\" (expected age:<recipient>)"
+        ))?;
+        key.parse()
+            .map(Recipient)
+            .map_err(|e| format!("invalid age recipient \"{key}\" ({e})"))
+    }
+}
+
+/// Ciphertext written by the stream, kept until it's taken to be written to the file.
+#[derive(Clone, Default)]
+struct Ciphertext(Arc<Mutex<Vec<u8>>>);
+
+impl Ciphertext {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for Ciphertext {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An age file being encrypted in memory, so the ciphertext can be written with the file's own
+/// buffering and retries. Plaintext only comes out encrypted in chunks of 64 KiB, the last one
+/// once the stream is finished.
+pub struct Encryption {
+    stream: Option<StreamWriter<Ciphertext>>,
+    ciphertext: Ciphertext,
+}
+
+impl Encryption {
+    /// Start an age file encrypted to `recipient`.
+    pub fn new(recipient: &Recipient) -> io::Result<Self> {
+        let encryptor =
+            age::Encryptor::with_recipients(std::iter::once(&recipient.0 as &dyn age::Recipient))
+                .map_err(io::Error::other)?;
+        let ciphertext = Ciphertext::default();
+        let stream = encryptor.wrap_output(ciphertext.clone())?;
+        Ok(Encryption {
+            stream: Some(stream),
+            ciphertext,
+        })
+    }
+
+    /// Encrypt `plaintext`, returning the ciphertext completed so far (starting with the age
+    /// header).
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+        let stream = self
+            .stream
+            .as_mut()
+            .ok_or_else(|| io::Error::other("the encrypted file was already finished"))?;
+        stream.write_all(plaintext)?;
+        Ok(self.ciphertext.take())
+    }
+
+    /// End the file, returning the rest of the ciphertext. Nothing can be encrypted after this.
+    pub fn finish(&mut self) -> io::Result<Vec<u8>> {
+        if let Some(stream) = self.stream.take() {
+            stream.finish()?;
+        }
+        Ok(self.ciphertext.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_files_decrypt_with_the_identity() {
+        let identity = age::x25519::Identity::generate();
+        let recipient: Recipient = format!("age:{}", identity.to_public()).parse().unwrap();
+        assert!("age1nope".parse::<Recipient>().is_err());
+        assert!(identity
+            .to_public()
+            .to_string()
+            .parse::<Recipient>()
+            .is_err());
+        let mut encryption = Encryption::new(&recipient).unwrap();
+        let mut file = encryption.encrypt(b"Timestamp,Latency(ms)\n").unwrap();
+        let rows = "2024-01-01 0:00:00.0 +00:00:00,1.500\n".repeat(3000);
+        file.extend(encryption.encrypt(rows.as_bytes()).unwrap());
+        // Only whole chunks are written before the end
+        assert!(file.len() > 64 * 1024 && file.len() < rows.len());
+        file.extend(encryption.finish().unwrap());
+        assert!(encryption.encrypt(b"late").is_err());
+        let plaintext = age::decrypt(&identity, &file).unwrap();
+        assert_eq!(
+            plaintext,
+            format!("Timestamp,Latency(ms)\n{rows}").into_bytes()
+        );
+    }
+}
@@ -16,205 +16,3431 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::net::IpAddr;
-use std::path::PathBuf;
-use std::time::Duration;
-use surge_ping::{
-    Client, Config, IcmpPacket, PingIdentifier, PingSequence, Pinger, SurgeError, ICMP,
+use crate::alert::{
+    AlertLimiter, AlertRule, AlertTemplate, Channel, Notification, RateLimit, WindowRule,
+};
+use crate::asn::{self, Network};
+use crate::atomic_write::write_atomic;
+use crate::clock::Clock;
+use crate::columns::{Column, Columns};
+use crate::composite::Change;
+use crate::encrypt::Recipient;
+use crate::health::{
+    Checkpoint, CheckpointTransition, StateFile, STATE_FILE_INTERVAL, STATE_FILE_NAME,
 };
+use crate::host;
+use crate::lifetime::{self, Lifetime};
+use crate::log_file::{format_row, LogFile};
+use crate::pipeline::{self, Output, Probed, ProberCommand, ProberSettings};
+use crate::portal::PortalCheck;
+use crate::probe::{self, socket_error_help, Probe, ProbeKind};
+use crate::public_ip::PublicAddress;
+use crate::settings::ProbeSettings;
+use crate::sign::Key;
+use crate::sink::{self, OutputSink, ProbeRecord, SinkKind};
+use crate::snapshot::{ConfigSnapshot, ResolvedAddress, SNAPSHOT_VERSION};
+use crate::summary::Summary;
+use crate::throughput::Throughput;
+use crate::tui::format_duration;
+use crate::window::ResultWindow;
+use crate::writer::{OverflowPolicy, QueuedSink, QUEUE_CAPACITY};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+use surge_ping::SurgeError;
 use time::format_description::OwnedFormatItem;
-use time::{format_description, OffsetDateTime};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use time::{format_description, OffsetDateTime, UtcOffset};
 use tokio::net;
+use tokio::sync::mpsc;
+
+/// Determine and cache the local UTC offset, returning false (and falling back to UTC) if it cannot
+/// be determined. The offset can only be queried soundly while the process is single-threaded, so
+/// this must be called before any threads are spawned. Note that the cached offset does not follow
+/// DST changes during a run.
+pub fn init_local_offset() -> bool {
+    let offset = UtcOffset::current_local_offset();
+    let _ = LOCAL_OFFSET.set(offset.unwrap_or(UtcOffset::UTC));
+    offset.is_ok()
+}
+
+/// Return the current time in the cached local UTC offset.
+pub fn local_now() -> OffsetDateTime {
+    OffsetDateTime::now_utc().to_offset(*LOCAL_OFFSET.get().unwrap_or(&UtcOffset::UTC))
+}
+
+/// Generate a short random ID for a run, e.g. `3f9a1c07`, that ties together the files, events and
+/// alerts it produces.
+pub fn new_run_id() -> String {
+    let hash = RandomState::new().hash_one((std::process::id(), SystemTime::now()));
+    format!("{:08x}", hash as u32)
+}
+
+/// Reachability of the target, derived from consecutive ping results.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    Unknown,
+    Up,
+    Down,
+    /// The target answers, but the portal check shows no genuine internet access
+    Captive,
+}
+
+impl LinkState {
+    /// Return the name of the state as shown to the user.
+    pub fn label(&self) -> &'static str {
+        match self {
+            LinkState::Unknown => "UNKNOWN",
+            LinkState::Up => "UP",
+            LinkState::Down => "DOWN",
+            LinkState::Captive => "CAPTIVE",
+        }
+    }
+
+    /// Parse a state name as returned by `label`, in any case.
+    pub fn from_label(label: &str) -> Option<Self> {
+        [
+            LinkState::Unknown,
+            LinkState::Up,
+            LinkState::Down,
+            LinkState::Captive,
+        ]
+        .into_iter()
+        .find(|state| state.label().eq_ignore_ascii_case(label))
+    }
+}
+
+/// A change of the target's `LinkState`.
+#[derive(Clone, Copy)]
+pub struct Transition {
+    pub from: LinkState,
+    pub to: LinkState,
+    /// Time the change was detected
+    pub at: OffsetDateTime,
+}
+
+/// A notable occurrence recorded in the events log.
+#[derive(Clone)]
+pub struct Event {
+    pub time: OffsetDateTime,
+    pub name: String,
+    pub details: String,
+}
+
+/// How late ticks fired compared with their schedule, excluding monitoring gaps.
+#[derive(Clone, Default)]
+pub struct TickDrift {
+    pub ticks: u64,
+    pub total: Duration,
+    pub max: Duration,
+    /// Ticks at least `DRIFT_THRESHOLD` late
+    pub late: u64,
+}
+
+impl TickDrift {
+    pub fn avg(&self) -> Duration {
+        self.total.div_f64(self.ticks.max(1) as f64)
+    }
+}
+
+/// Download speeds measured with `--throughput-url`.
+#[derive(Clone, Default)]
+pub struct ThroughputStats {
+    pub measurements: u64,
+    /// Sum of the measured speeds (Mbps)
+    pub total_mbps: f64,
+    pub min_mbps: Option<f64>,
+    pub max_mbps: Option<f64>,
+}
+
+impl ThroughputStats {
+    pub fn avg_mbps(&self) -> Option<f64> {
+        (self.measurements > 0).then(|| self.total_mbps / self.measurements as f64)
+    }
+}
+
+/// Statistics gathered by the engine since it started.
+#[derive(Clone)]
+pub struct Stats {
+    pub sent: u64,
+    pub received: u64,
+    pub min_rtt: Option<Duration>,
+    pub max_rtt: Option<Duration>,
+    /// Sum of the round trip times of all successful pings
+    pub total_rtt: Duration,
+    /// Sum of the squared round trip times of all successful pings (s²)
+    pub total_rtt_squared: f64,
+    pub last_success: Option<(OffsetDateTime, Duration)>,
+    pub last_failure: Option<OffsetDateTime>,
+    pub state: LinkState,
+    /// Monotonic time the current state was entered
+    pub state_since: Option<Instant>,
+    pub last_transition: Option<Transition>,
+    /// Number of times the target went down
+    pub outages: u64,
+    /// Number of replies that arrived after their probe timed out
+    pub late_replies: u64,
+    pub drift: TickDrift,
+    pub throughput: ThroughputStats,
+    /// How long the engine has been monitoring, measured with a monotonic clock
+    pub runtime: Duration,
+    /// Statistics of the target over every run, if kept
+    pub lifetime: Option<Lifetime>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            sent: 0,
+            received: 0,
+            min_rtt: None,
+            max_rtt: None,
+            total_rtt: Duration::ZERO,
+            total_rtt_squared: 0.0,
+            last_success: None,
+            last_failure: None,
+            state: LinkState::Unknown,
+            state_since: None,
+            last_transition: None,
+            outages: 0,
+            late_replies: 0,
+            drift: TickDrift::default(),
+            throughput: ThroughputStats::default(),
+            runtime: Duration::ZERO,
+            lifetime: None,
+        }
+    }
+
+    /// Return the mean round trip time of successful pings.
+    pub fn avg_rtt(&self) -> Option<Duration> {
+        (self.received > 0).then(|| self.total_rtt.div_f64(self.received as f64))
+    }
+
+    /// Return the mean deviation of the round trip times of successful pings, as reported by
+    /// `ping`.
+    pub fn mdev_rtt(&self) -> Option<Duration> {
+        let avg = self.avg_rtt()?.as_secs_f64();
+        let variance = self.total_rtt_squared / self.received as f64 - avg * avg;
+        Some(Duration::from_secs_f64(variance.max(0.0).sqrt()))
+    }
+
+    /// Return the percentage of sent pings that went unanswered.
+    pub fn loss_percent(&self) -> f64 {
+        (self.sent - self.received) as f64 * 100.0 / self.sent.max(1) as f64
+    }
+}
+
+// Local UTC offset, determined once at startup while the process is still single-threaded
+static LOCAL_OFFSET: OnceLock<UtcOffset> = OnceLock::new();
+
+// How often hostname targets are resolved again to follow address changes
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+
+// Minimum discrepancy between the wall and monotonic clocks that is logged as a clock jump
+const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+// Lateness of a tick that counts as drift, showing an overloaded host or a blocked ping loop
+const DRIFT_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// When result rows buffered in memory are written to disk.
+#[derive(Clone, Copy)]
+pub enum FlushPolicy {
+    /// Write every row as soon as it is recorded
+    EveryRow,
+    /// Write buffered rows once this much time has passed since the last write
+    Interval(Duration),
+    /// Write buffered rows once this many have accumulated
+    Rows(usize),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = String;
+
+    /// Parse a policy such as `60s`, `500ms`, `5m`, or `100rows`.
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid flush policy \"{policy}\" (expected e.g. 60s or 100rows)");
+        if let Some(rows) = policy.strip_suffix("rows") {
+            return match rows.parse::<usize>() {
+                Ok(rows) if rows > 0 => Ok(FlushPolicy::Rows(rows)),
+                _ => Err(invalid()),
+            };
+        }
+        parse_duration(policy)
+            .map(FlushPolicy::Interval)
+            .ok_or_else(invalid)
+    }
+}
+
+/// Find the local address traffic to `ip_addr` is sent from, by letting the OS route a UDP socket
+/// (which sends nothing).
+fn source_address(ip_addr: IpAddr) -> Option<IpAddr> {
+    let unspecified: IpAddr = match ip_addr {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = std::net::UdpSocket::bind((unspecified, 0)).ok()?;
+    socket.connect((ip_addr, 9)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Find the network interface traffic to `ip_addr` leaves through, where supported.
+fn egress_interface(ip_addr: IpAddr) -> Option<String> {
+    #[cfg(target_os = "linux")]
+    return crate::interface::egress_interface(ip_addr).ok();
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = ip_addr;
+        None
+    }
+}
+
+/// List the addresses assigned to `interface`, where supported.
+fn local_addresses(interface: &str) -> Vec<IpAddr> {
+    #[cfg(target_os = "linux")]
+    return crate::interface::addresses(interface).unwrap_or_default();
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = interface;
+        Vec::new()
+    }
+}
+
+/// Describe how the addresses of an interface changed from `old` to `new`, e.g.
+/// `added 192.0.2.3, removed 192.0.2.2`.
+fn address_changes(old: &[IpAddr], new: &[IpAddr]) -> String {
+    let list = |addresses: Vec<&IpAddr>| {
+        addresses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    let added: Vec<&IpAddr> = new.iter().filter(|addr| !old.contains(addr)).collect();
+    let removed: Vec<&IpAddr> = old.iter().filter(|addr| !new.contains(addr)).collect();
+    let mut changes = Vec::new();
+    if !added.is_empty() {
+        changes.push(format!("added {}", list(added)));
+    }
+    if !removed.is_empty() {
+        changes.push(format!("removed {}", list(removed)));
+    }
+    changes.join(", ")
+}
+
+/// How long results must be kept for alert templates (1h) and the given window rules.
+fn window_horizon(window_alerts: &[WindowRule]) -> Duration {
+    window_alerts
+        .iter()
+        .map(|rule| rule.window)
+        .fold(Duration::from_secs(3600), Duration::max)
+}
+
+/// Parse a duration such as `500ms`, `60s`, `5m`, or `1h`.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let (value, unit_secs) = if let Some(value) = duration.strip_suffix("ms") {
+        (value, 0.001)
+    } else if let Some(value) = duration.strip_suffix('s') {
+        (value, 1.0)
+    } else if let Some(value) = duration.strip_suffix('m') {
+        (value, 60.0)
+    } else if let Some(value) = duration.strip_suffix('h') {
+        (value, 3600.0)
+    } else {
+        return None;
+    };
+    match value.parse::<f64>() {
+        Ok(value) if value.is_finite() && value >= 0.0 => {
+            Some(Duration::from_secs_f64(value * unit_secs))
+        }
+        _ => None,
+    }
+}
+
+/// The address a network lookup was made for and its result.
+pub type NetworkLookup = (IpAddr, Result<Network, String>);
+
+/// User-chosen settings used to construct an Engine.
+pub struct EngineConfig {
+    pub probe: ProbeSettings,
+    /// Whether to fsync log files after every write
+    pub sync_writes: bool,
+    /// Key to encrypt the result and event files to, if any
+    pub encrypt: Option<Recipient>,
+    /// Key to sign the result CSVs with, if any
+    pub sign: Option<Key>,
+    pub flush_policy: FlushPolicy,
+    /// What to do with results when the writer task falls behind
+    pub write_overflow: OverflowPolicy,
+    /// Consecutive failed writes after which the engine gives up
+    pub max_write_failures: u32,
+    /// Directory for the result/event CSVs and snapshots, or None to keep nothing on disk
+    pub output_path: Option<PathBuf>,
+    /// Whether to add Wi-Fi signal columns to the result CSV (Linux only)
+    pub wifi: bool,
+    /// Whether to add interface error counter columns to the result CSV (Linux only)
+    pub nic_counters: bool,
+    /// Columns of the result CSV, or None for the defaults of the probe type
+    pub columns: Option<Columns>,
+    /// Field separator of the result and event files
+    pub delimiter: u8,
+    /// Where probe results are recorded in the output directory
+    pub sinks: Vec<SinkKind>,
+    /// Whether to carry on from the statistics checkpointed in the output directory's state file
+    pub resume: bool,
+    /// ID of the run, from `new_run_id`
+    pub run_id: String,
+    /// Name of the machine running the probes, recorded with results and alerts
+    pub probe_name: String,
+    /// Whether to look up the network (ASN) of the target's address
+    pub asn_lookup: bool,
+    pub clock: Arc<dyn Clock>,
+}
 
 pub struct Engine {
+    target: String,
     ip_addr: IpAddr,
+    kind: ProbeKind,
     ttl: u32,
     data: Vec<u8>,
     timeout: Duration,
-    ping_handler: Pinger,
+    delay: Duration,
+    interval_jitter: f64,
+    align: bool,
+    /// Requests to the prober stage, which owns the probe
+    prober: mpsc::UnboundedSender<ProberCommand>,
+    /// Results from the prober stage
+    probed: mpsc::UnboundedReceiver<Probed>,
+    resolved_at: Instant,
+    /// Every address probed so far, for the config snapshot
+    address_history: Vec<ResolvedAddress>,
+    run_id: String,
+    probe_name: String,
+    asn_lookup: bool,
+    /// Network the target's address belongs to, if looked up
+    network: Option<Network>,
+    /// Results of the network lookups made when the address changes, which run in tasks of their
+    /// own, and their receiving end until it's taken
+    network_lookups: mpsc::UnboundedSender<NetworkLookup>,
+    network_lookups_rx: Option<mpsc::UnboundedReceiver<NetworkLookup>>,
     start_time: OffsetDateTime,
-    last_successful_latency: Option<Duration>,
-    last_successful_time: Option<OffsetDateTime>,
-    last_failed_time: Option<OffsetDateTime>,
-    output_path: PathBuf,
+    start_instant: Instant,
+    /// Runtime of the previous runs resumed from
+    resumed_runtime: Duration,
+    /// Lifetime statistics of the target from earlier runs, or None if they aren't kept
+    lifetime_base: Option<Lifetime>,
+    /// Start of this run's share of the lifetime statistics (reset by a change of target)
+    lifetime_since: Instant,
+    /// Time without pings (e.g. a suspended host), which isn't counted as monitored
+    unmonitored: Duration,
+    /// When probing was paused with `num ctl pause`, if it is
+    paused_since: Option<Instant>,
+    /// Whether the last tick was late, so a run of late ticks is only logged once
+    drifting: bool,
+    /// Public address found by the last successful poll
+    public_ip: Option<IpAddr>,
+    /// Whether the last poll for the public address failed
+    public_ip_failing: bool,
+    /// Network the public address is announced from, if it was looked up
+    public_network: Option<Network>,
+    isp_change_alert: Option<Channel>,
+    internet_alert: Option<Channel>,
+    /// Whether the internet going down was alerted, so its return is alerted too
+    internet_alerted: bool,
+    /// Total and longest length of the outages that ended during this run
+    run_downtime: Duration,
+    run_worst_outage: Duration,
+    stats: Stats,
+    consecutive_failures: u32,
+    first_failure_instant: Option<Instant>,
+    down_after: u32,
+    down_on_no_route: bool,
+    retries: u32,
+    cert_warn_days: u32,
+    /// Whether a cert_expiring event was logged for the current certificate
+    cert_warned: bool,
+    max_offset: Duration,
+    /// Whether the clock offset is currently beyond `max_offset`
+    offset_warned: bool,
+    portal_check: Option<PortalCheck>,
+    /// Whether a portal check is running, so only one is out at a time
+    portal_checking: bool,
+    /// Whether the last portal check found no genuine internet access
+    portal_failed: bool,
+    /// Results of the portal checks, which run in tasks of their own, and their receiving end
+    /// until it's taken
+    portal_results: mpsc::UnboundedSender<Result<(), String>>,
+    portal_results_rx: Option<mpsc::UnboundedReceiver<Result<(), String>>>,
+    alerts: Vec<AlertRule>,
+    /// Which alert rules have fired during the current outage
+    alerts_fired: Vec<bool>,
+    /// Which alert rules were held back by the limiter during the current outage
+    alerts_suppressed: Vec<bool>,
+    alert_limiter: AlertLimiter,
+    window_alerts: Vec<WindowRule>,
+    /// Which window rules are currently over their threshold (and were alerted)
+    window_breached: Vec<bool>,
+    /// Which window rules were held back by the limiter during the current breach
+    window_suppressed: Vec<bool>,
+    alert_templates: Vec<AlertTemplate>,
+    /// Results of the last hour (or longest alert window), for alert templates and window rules
+    recent: ResultWindow,
+    sync_writes: bool,
+    encrypt: Option<Recipient>,
+    sign: Option<Key>,
+    flush_policy: FlushPolicy,
+    write_overflow: OverflowPolicy,
+    max_write_failures: u32,
+    output_path: Option<PathBuf>,
+    columns: Columns,
+    /// Whether the columns were chosen by the user rather than derived from the probe type
+    custom_columns: bool,
+    delimiter: u8,
+    /// Network interface the probes leave through, if known
+    interface: Option<String>,
+    /// Addresses assigned to that interface
+    local_addresses: Vec<IpAddr>,
+    /// Interface and error counters seen at the previous ping
+    last_nic_counters: Option<(String, [u64; 4])>,
     file_date_fmt: OwnedFormatItem,
-    result_file_handle: Option<File>,
+    sink_kinds: Vec<SinkKind>,
+    /// Destinations of probe results, empty if nothing is kept on disk
+    sinks: Vec<Box<dyn OutputSink>>,
+    events_log: Option<LogFile>,
+    wall_clock_reference: Option<(OffsetDateTime, Instant)>,
+    state_file_written: Option<Instant>,
+    /// Whether the storage problem, if any, was already published
+    storage_degraded: bool,
+    /// What happened since the consumers last collected it with `take_outputs`
+    outputs: Vec<Output>,
+    clock: Arc<dyn Clock>,
 }
 
 impl Engine {
-    /// Create a new Engine struct and initialize config and result files.
-    pub async fn new(
-        addr: String,
-        ttl_i: u32,
-        timeout: u64,
-        num_bytes: u8,
-        delay: u64,
-        path: PathBuf,
-    ) -> Self {
-        unsafe {
-            time::util::local_offset::set_soundness(time::util::local_offset::Soundness::Unsound)
-        }
-        let ip_addr = Engine::process_ip(addr).await;
-        let config = match ip_addr {
-            IpAddr::V4(_) => Config::builder().kind(ICMP::V4).ttl(ttl_i).build(),
-            IpAddr::V6(_) => Config::builder().kind(ICMP::V6).ttl(ttl_i).build(),
-        };
-        let client = Client::new(&config).unwrap();
-        let mut pinger = client.pinger(ip_addr, PingIdentifier(1)).await;
-        pinger.timeout(Duration::from_millis(timeout));
+    /// Create a new Engine struct and initialize config and result files. Returns an error if the
+    /// target cannot be resolved, no probe socket can be created for it, or the output files
+    /// can't be created.
+    pub async fn new(engine_config: EngineConfig) -> Result<Self, String> {
+        let addrs = Engine::resolve(engine_config.probe.addr.clone())
+            .await
+            .map_err(|e| format!("Could not resolve {}: {e}", engine_config.probe.addr))?;
+        let (ip_addr, backend) = Engine::connect(
+            &addrs,
+            None,
+            &engine_config.probe.addr,
+            engine_config.probe.kind,
+            engine_config.probe.ttl,
+            Duration::from_millis(engine_config.probe.timeout),
+        )
+        .await
+        .map_err(|e| socket_error_help(&e))?;
+        Engine::with_backend(engine_config, ip_addr, backend).await
+    }
+
+    /// Create an Engine sending probes to `ip_addr` through `backend`.
+    async fn with_backend(
+        engine_config: EngineConfig,
+        ip_addr: IpAddr,
+        backend: Box<dyn Probe>,
+    ) -> Result<Self, String> {
+        let probe = engine_config.probe;
+        let timeout = Duration::from_millis(probe.timeout);
+        let (prober, probed) = pipeline::spawn_prober(
+            backend,
+            ProberSettings {
+                num_bytes: probe.num_bytes.into(),
+                timeout,
+                delay: probe.delay,
+                retries: probe.retries,
+            },
+            engine_config.clock.clone(),
+        );
+        let (portal_results, portal_results_rx) = mpsc::unbounded_channel();
+        let (network_lookups, network_lookups_rx) = mpsc::unbounded_channel();
+        let interface = egress_interface(ip_addr);
         let mut result_engine = Engine {
+            target: probe.addr,
             ip_addr,
-            data: vec![0; num_bytes.into()],
-            timeout: Duration::from_millis(timeout),
-            ping_handler: pinger,
-            ttl: ttl_i,
-            start_time: OffsetDateTime::now_local().expect("TZ data not found for this system"),
-            output_path: path,
-            last_successful_latency: None,
-            last_failed_time: None,
-            last_successful_time: None,
+            data: vec![0; probe.num_bytes.into()],
+            timeout,
+            delay: probe.delay,
+            interval_jitter: probe.interval_jitter,
+            align: probe.align,
+            prober,
+            probed,
+            resolved_at: engine_config.clock.instant(),
+            address_history: Vec::new(),
+            kind: probe.kind,
+            ttl: probe.ttl,
+            run_id: engine_config.run_id,
+            probe_name: engine_config.probe_name,
+            asn_lookup: engine_config.asn_lookup,
+            network: None,
+            network_lookups,
+            network_lookups_rx: Some(network_lookups_rx),
+            start_time: engine_config.clock.now(),
+            start_instant: engine_config.clock.instant(),
+            resumed_runtime: Duration::ZERO,
+            lifetime_base: None,
+            lifetime_since: engine_config.clock.instant(),
+            unmonitored: Duration::ZERO,
+            paused_since: None,
+            drifting: false,
+            public_ip: None,
+            public_ip_failing: false,
+            public_network: None,
+            isp_change_alert: probe.isp_change_alert,
+            internet_alert: probe.internet_alert,
+            internet_alerted: false,
+            run_downtime: Duration::ZERO,
+            run_worst_outage: Duration::ZERO,
+            stats: Stats::new(),
+            output_path: engine_config.output_path,
+            consecutive_failures: 0,
+            first_failure_instant: None,
+            down_after: probe.down_after,
+            down_on_no_route: probe.down_on_no_route,
+            retries: probe.retries,
+            cert_warn_days: probe.cert_warn_days,
+            cert_warned: false,
+            max_offset: Duration::from_millis(probe.max_offset),
+            offset_warned: false,
+            portal_check: probe.portal_check,
+            portal_checking: false,
+            portal_failed: false,
+            portal_results,
+            portal_results_rx: Some(portal_results_rx),
+            alerts_fired: vec![false; probe.alerts.len()],
+            alerts_suppressed: vec![false; probe.alerts.len()],
+            alert_limiter: AlertLimiter::new(probe.alert_rate_limits, probe.alert_dedup),
+            alerts: probe.alerts,
+            alert_templates: probe.alert_templates,
+            recent: ResultWindow::new(window_horizon(&probe.window_alerts)),
+            window_breached: vec![false; probe.window_alerts.len()],
+            window_suppressed: vec![false; probe.window_alerts.len()],
+            window_alerts: probe.window_alerts,
+            sync_writes: engine_config.sync_writes,
+            encrypt: engine_config.encrypt,
+            sign: engine_config.sign,
+            flush_policy: engine_config.flush_policy,
+            write_overflow: engine_config.write_overflow,
+            max_write_failures: engine_config.max_write_failures,
             file_date_fmt: format_description::parse_owned::<1>(
                 "[month]-[day]-[year]@[hour]-[minute]-[second]",
             )
             .unwrap(),
-            result_file_handle: None,
+            sink_kinds: engine_config.sinks,
+            sinks: Vec::new(),
+            events_log: None,
+            wall_clock_reference: None,
+            state_file_written: None,
+            storage_degraded: false,
+            outputs: Vec::new(),
+            custom_columns: engine_config.columns.is_some(),
+            delimiter: engine_config.delimiter,
+            columns: engine_config.columns.unwrap_or_else(|| {
+                let mut columns = Columns::default_for(
+                    probe.kind,
+                    engine_config.wifi,
+                    engine_config.nic_counters,
+                );
+                if probe.retries > 0 {
+                    columns.0.push(Column::Retries);
+                }
+                columns
+            }),
+            last_nic_counters: None,
+            local_addresses: interface.as_deref().map_or(Vec::new(), local_addresses),
+            interface,
+            clock: engine_config.clock,
         };
-        unsafe {
-            time::util::local_offset::set_soundness(time::util::local_offset::Soundness::Sound)
+        result_engine.record_address(result_engine.start_time);
+        if result_engine.output_path.is_some() {
+            let events_header = format_row(
+                ["Timestamp", "Event", "Details", "Run"],
+                result_engine.delimiter,
+            );
+            result_engine.events_log = Some(
+                result_engine
+                    .init_log_file("events", &events_header)
+                    .await?,
+            );
+        }
+        // Before the result files are created, so their metadata can name the network
+        result_engine.lookup_network(result_engine.start_time).await;
+        if result_engine.output_path.is_some() {
+            result_engine
+                .create_config()
+                .await
+                .map_err(|e| format!("Could not write {e}"))?;
+            result_engine.sinks = result_engine.init_sinks().await?;
+            result_engine.load_lifetime().await;
+            if engine_config.resume {
+                result_engine.resume().await;
+            }
         }
-        result_engine.create_config(delay).await;
-        result_engine.result_file_handle = Some(result_engine.init_csv().await);
-        std::mem::forget(client); // Client's socket needs to survive to ping, so it cannot be dropped
-        result_engine
+        Ok(result_engine)
     }
 
-    /// Transmit a ping and log relevant information. Returns sent time and ping information.
-    pub async fn ping(&mut self) -> (OffsetDateTime, Result<(IcmpPacket, Duration), SurgeError>) {
-        unsafe {
-            time::util::local_offset::set_soundness(time::util::local_offset::Soundness::Unsound)
+    /// Apply reloaded probe settings without restarting, keeping statistics and log files. The
+    /// probe is rebuilt if the target, probe type, or TTL changed, the changes are logged as a
+    /// `config_reloaded` event, and the config snapshot is rewritten. Returns an error (leaving the
+    /// current settings in place) if a new target cannot be resolved.
+    pub async fn reconfigure(&mut self, probe: &ProbeSettings) -> Result<(), String> {
+        // Some probe types write extra result columns, so the CSV would no longer match its header
+        if !self.custom_columns && probe.kind.columns() != self.kind.columns() {
+            return Err(format!(
+                "The probe type cannot be changed from {} to {} by a reload",
+                self.kind.name(),
+                probe.kind.name()
+            ));
+        }
+        let mut changes = Vec::new();
+        let timeout = Duration::from_millis(probe.timeout);
+        let mut new_backend = None;
+        if probe.addr != self.target {
+            let addrs = Engine::resolve(probe.addr.clone())
+                .await
+                .map_err(|e| format!("Could not resolve {}: {e}", probe.addr))?;
+            new_backend = Some(
+                Engine::connect(&addrs, None, &probe.addr, probe.kind, probe.ttl, timeout)
+                    .await
+                    .map_err(|e| socket_error_help(&e))?,
+            );
+            changes.push(format!("target {} -> {}", self.target, probe.addr));
+        } else if probe.kind != self.kind || probe.ttl != self.ttl {
+            new_backend = Some(
+                Engine::connect(
+                    &[self.ip_addr],
+                    None,
+                    &probe.addr,
+                    probe.kind,
+                    probe.ttl,
+                    timeout,
+                )
+                .await
+                .map_err(|e| socket_error_help(&e))?,
+            );
+        }
+        if timeout != self.timeout {
+            changes.push(format!(
+                "timeout {}ms -> {}ms",
+                self.timeout.as_millis(),
+                probe.timeout
+            ));
+        }
+        if probe.delay != self.delay {
+            changes.push(format!(
+                "delay {}s -> {}s",
+                self.delay.as_secs_f64(),
+                probe.delay.as_secs_f64()
+            ));
+        }
+        if probe.interval_jitter != self.interval_jitter {
+            changes.push(format!(
+                "interval-jitter {}% -> {}%",
+                self.interval_jitter * 100.0,
+                probe.interval_jitter * 100.0
+            ));
+        }
+        if probe.align != self.align {
+            changes.push(format!("align {} -> {}", self.align, probe.align));
+        }
+        if usize::from(probe.num_bytes) != self.data.len() {
+            changes.push(format!(
+                "num-bytes {} -> {}",
+                self.data.len(),
+                probe.num_bytes
+            ));
+        }
+        if probe.kind != self.kind {
+            changes.push(format!(
+                "probe {} -> {}",
+                self.kind.name(),
+                probe.kind.name()
+            ));
+        }
+        if probe.ttl != self.ttl {
+            changes.push(format!("ttl {} -> {}", self.ttl, probe.ttl));
+        }
+        if probe.down_after != self.down_after {
+            changes.push(format!(
+                "down-after {} -> {}",
+                self.down_after, probe.down_after
+            ));
+        }
+        if probe.down_on_no_route != self.down_on_no_route {
+            changes.push(format!(
+                "down-on-no-route {} -> {}",
+                self.down_on_no_route, probe.down_on_no_route
+            ));
+        }
+        if probe.retries != self.retries {
+            changes.push(format!("retries {} -> {}", self.retries, probe.retries));
+        }
+        if probe.cert_warn_days != self.cert_warn_days {
+            changes.push(format!(
+                "cert-warn-days {} -> {}",
+                self.cert_warn_days, probe.cert_warn_days
+            ));
+            // Warn again under the new threshold
+            self.cert_warned = false;
+        }
+        if Duration::from_millis(probe.max_offset) != self.max_offset {
+            changes.push(format!(
+                "max-offset {}ms -> {}ms",
+                self.max_offset.as_millis(),
+                probe.max_offset
+            ));
+            self.offset_warned = false;
         }
-        let curr_time = OffsetDateTime::now_local()
-            .expect("TZ data not found for this system or running in multithreaded context");
-        unsafe {
-            time::util::local_offset::set_soundness(time::util::local_offset::Soundness::Sound)
+        if probe.portal_check != self.portal_check {
+            let url = |check: &Option<PortalCheck>| {
+                check
+                    .as_ref()
+                    .map_or("none".to_string(), |check| check.url().to_string())
+            };
+            changes.push(format!(
+                "portal-check {} -> {}",
+                url(&self.portal_check),
+                url(&probe.portal_check)
+            ));
+        }
+        if probe.alerts != self.alerts {
+            let rules = |alerts: &[AlertRule]| {
+                if alerts.is_empty() {
+                    return "none".to_string();
+                }
+                alerts
+                    .iter()
+                    .map(AlertRule::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            changes.push(format!(
+                "alert {} -> {}",
+                rules(&self.alerts),
+                rules(&probe.alerts)
+            ));
+            // Rules that are kept don't alert twice for the same outage
+            self.alerts_fired = probe
+                .alerts
+                .iter()
+                .map(|rule| {
+                    self.alerts
+                        .iter()
+                        .zip(&self.alerts_fired)
+                        .any(|(old, &fired)| fired && old == rule)
+                })
+                .collect();
+            self.alerts_suppressed = vec![false; probe.alerts.len()];
+            self.alerts = probe.alerts.clone();
+        }
+        if probe.alert_rate_limits != self.alert_limiter.limits()
+            || probe.alert_dedup != self.alert_limiter.dedup()
+        {
+            let limits = |limits: &[RateLimit]| {
+                if limits.is_empty() {
+                    return "none".to_string();
+                }
+                limits
+                    .iter()
+                    .map(RateLimit::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            changes.push(format!(
+                "alert-rate-limit {} -> {}; alert-dedup {}s -> {}s",
+                limits(self.alert_limiter.limits()),
+                limits(&probe.alert_rate_limits),
+                self.alert_limiter.dedup().as_secs_f64(),
+                probe.alert_dedup.as_secs_f64()
+            ));
+            self.alert_limiter
+                .set_limits(probe.alert_rate_limits.clone(), probe.alert_dedup);
+        }
+        if probe.alert_templates != self.alert_templates {
+            changes.push("alert-template changed".to_string());
+            self.alert_templates = probe.alert_templates.clone();
+        }
+        if probe.window_alerts != self.window_alerts {
+            let rules = |rules: &[WindowRule]| {
+                if rules.is_empty() {
+                    return "none".to_string();
+                }
+                rules
+                    .iter()
+                    .map(WindowRule::to_string)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            };
+            changes.push(format!(
+                "window-alert {} -> {}",
+                rules(&self.window_alerts),
+                rules(&probe.window_alerts)
+            ));
+            // Rules that are kept stay breached, so they don't alert again
+            self.window_breached = probe
+                .window_alerts
+                .iter()
+                .map(|rule| {
+                    self.window_alerts
+                        .iter()
+                        .zip(&self.window_breached)
+                        .any(|(old, &breached)| breached && old == rule)
+                })
+                .collect();
+            self.window_suppressed = vec![false; probe.window_alerts.len()];
+            self.window_alerts = probe.window_alerts.clone();
+            self.recent.set_horizon(window_horizon(&self.window_alerts));
+        }
+        if probe.isp_change_alert != self.isp_change_alert {
+            let channel = |channel: &Option<Channel>| {
+                channel
+                    .as_ref()
+                    .map_or("none".to_string(), Channel::to_string)
+            };
+            changes.push(format!(
+                "isp-change-alert {} -> {}",
+                channel(&self.isp_change_alert),
+                channel(&probe.isp_change_alert)
+            ));
+            self.isp_change_alert = probe.isp_change_alert.clone();
+        }
+        if probe.internet_alert != self.internet_alert {
+            let channel = |channel: &Option<Channel>| {
+                channel
+                    .as_ref()
+                    .map_or("none".to_string(), Channel::to_string)
+            };
+            changes.push(format!(
+                "internet-alert {} -> {}",
+                channel(&self.internet_alert),
+                channel(&probe.internet_alert)
+            ));
+            self.internet_alert = probe.internet_alert.clone();
+        }
+        if let Some((ip_addr, backend)) = new_backend {
+            self.ip_addr = ip_addr;
+            let _ = self.prober.send(ProberCommand::Replace(backend));
+        }
+        let target_changed = probe.addr != self.target;
+        if target_changed {
+            // Reachability of the old target says nothing about the new one
+            self.reset_state(self.clock.instant());
+            self.save_lifetime().await;
+        }
+        self.target = probe.addr.clone();
+        if target_changed {
+            self.lifetime_since = self.clock.instant();
+            self.unmonitored = Duration::ZERO;
+            self.run_downtime = Duration::ZERO;
+            self.run_worst_outage = Duration::ZERO;
+            self.load_lifetime().await;
+            self.start_network_lookup();
+        }
+        self.record_address(self.clock.now());
+        self.resolved_at = self.clock.instant();
+        self.timeout = timeout;
+        self.delay = probe.delay;
+        self.interval_jitter = probe.interval_jitter;
+        self.align = probe.align;
+        self.data = vec![0; probe.num_bytes.into()];
+        self.kind = probe.kind;
+        self.ttl = probe.ttl;
+        self.down_after = probe.down_after;
+        self.down_on_no_route = probe.down_on_no_route;
+        self.retries = probe.retries;
+        self.cert_warn_days = probe.cert_warn_days;
+        self.max_offset = Duration::from_millis(probe.max_offset);
+        if probe.portal_check != self.portal_check {
+            self.portal_failed = false;
         }
-        let output = self.ping_handler.ping(PingSequence(0), &self.data).await;
-        self.write_csv(curr_time, &output).await;
-        if let Ok((_, rtt)) = &output {
-            self.last_successful_latency = Some(*rtt);
-            self.last_successful_time = Some(curr_time);
+        self.portal_check = probe.portal_check.clone();
+        self.configure_prober();
+        let details = if changes.is_empty() {
+            "no changes".to_string()
         } else {
-            self.last_failed_time = Some(curr_time);
+            changes.join("; ")
+        };
+        self.log_event(self.clock.now(), "config_reloaded", &details)
+            .await;
+        if !changes.is_empty() {
+            self.rewrite_config(self.clock.now()).await;
+            // Files recreated after rotation describe the current settings
+            let metadata = self.result_metadata();
+            for sink in &mut self.sinks {
+                sink.set_metadata(&metadata);
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass the settings for sending probes on to the prober stage.
+    fn configure_prober(&self) {
+        let _ = self.prober.send(ProberCommand::Configure(ProberSettings {
+            num_bytes: self.data.len(),
+            timeout: self.timeout,
+            delay: self.delay,
+            retries: self.retries,
+        }));
+    }
+
+    /// Send a probe scheduled for `scheduled` and record its result, for when nothing else has to
+    /// happen while it is out. Returns sent time and ping information.
+    pub async fn ping(
+        &mut self,
+        scheduled: Instant,
+    ) -> (OffsetDateTime, Result<Duration, SurgeError>) {
+        self.start_probe(scheduled).await;
+        let probed = self
+            .next_probed()
+            .await
+            .expect("the prober stage only stops with the engine");
+        self.record_probe(probed).await
+    }
+
+    /// Check the timeline and the target's address for the probe scheduled for `scheduled`, then
+    /// hand it to the prober stage. Its result comes back from `next_probed`.
+    pub async fn start_probe(&mut self, scheduled: Instant) {
+        let now = self.clock.now();
+        let now_instant = self.clock.instant();
+        self.check_timeline(now, now_instant, scheduled).await;
+        if self.target.parse::<IpAddr>().is_err()
+            && now_instant - self.resolved_at >= RESOLVE_INTERVAL
+        {
+            self.refresh_address(now).await;
+        }
+        self.refresh_interface(now).await;
+        let _ = self.prober.send(ProberCommand::Probe(scheduled));
+    }
+
+    /// Wait for the result of the next probe handed to the prober stage.
+    pub async fn next_probed(&mut self) -> Option<Probed> {
+        self.probed.recv().await
+    }
+
+    /// Log the result of a probe and update the statistics and state with it. Returns sent time
+    /// and ping information.
+    pub async fn record_probe(
+        &mut self,
+        probed: Probed,
+    ) -> (OffsetDateTime, Result<Duration, SurgeError>) {
+        let curr_time = probed.sent_at;
+        let sent_instant = probed.sent_instant;
+        self.check_cert_expiry(curr_time, probed.cert_not_after)
+            .await;
+        self.check_clock_offset(curr_time, probed.clock_offset)
+            .await;
+        self.check_late_replies(curr_time, &probed.late_replies)
+            .await;
+        self.write_record(&probed).await;
+        let output = probed.output;
+        self.check_storage();
+        self.stats.sent += 1;
+        if let Ok(rtt) = &output {
+            let rtt = *rtt;
+            self.stats.received += 1;
+            self.stats.min_rtt = Some(self.stats.min_rtt.map_or(rtt, |min| min.min(rtt)));
+            self.stats.max_rtt = Some(self.stats.max_rtt.map_or(rtt, |max| max.max(rtt)));
+            self.stats.total_rtt += rtt;
+            self.stats.total_rtt_squared += rtt.as_secs_f64().powi(2);
+            self.stats.last_success = Some((curr_time, rtt));
+        } else {
+            self.stats.last_failure = Some(curr_time);
+        }
+        if output.is_ok() {
+            self.start_portal_check();
+        }
+        let captive = self.portal_check.is_some() && self.portal_failed;
+        let previous_state = self.stats.state;
+        self.recent
+            .push(sent_instant, output.as_ref().ok().copied());
+        let streak = (self.first_failure_instant, self.consecutive_failures);
+        let definitive = self.is_definitive(&output);
+        self.update_state(output.is_ok(), definitive, captive, curr_time, sent_instant);
+        self.check_alerts(streak, curr_time, sent_instant).await;
+        self.check_window_alerts(curr_time, sent_instant).await;
+        if self.stats.state != previous_state {
+            // Make sure the rows leading up to a state change are on disk
+            self.flush().await;
+        }
+        if self.stats.state != previous_state
+            || self
+                .state_file_written
+                .is_none_or(|written| sent_instant - written >= STATE_FILE_INTERVAL)
+        {
+            self.write_state_file().await;
+            self.state_file_written = Some(sent_instant);
         }
         (curr_time, output)
     }
 
-    /// Convert a String representation of an IP address or hostname (with/without port number)
-    /// to an IpAddr. Panics if invalid address/port number is passed in.
-    async fn process_ip(addr: String) -> IpAddr {
-        let possible_addr = addr.parse::<IpAddr>();
-        if possible_addr.is_err() {
-            return if addr.contains(':') {
-                net::lookup_host(addr)
-                    .await
-                    .expect("Address/Port unreachable")
-                    .next()
-                    .unwrap()
-                    .ip()
+    /// Log a `cert_expiring` event when the certificate presented to a TLS probe gets within
+    /// `cert_warn_days` of expiring. The warning is given once, and again after a renewal pushes the
+    /// expiry back past the threshold and it gets close once more.
+    async fn check_cert_expiry(&mut self, now: OffsetDateTime, not_after: Option<OffsetDateTime>) {
+        let Some(not_after) = not_after else {
+            return;
+        };
+        let days_left = (not_after - now).whole_days();
+        let expiring = days_left < i64::from(self.cert_warn_days);
+        if expiring && !self.cert_warned {
+            let details = if days_left < 0 {
+                format!(
+                    "certificate for {} expired on {}",
+                    self.target,
+                    not_after.date()
+                )
             } else {
-                net::lookup_host([addr, ":80".to_string()].concat())
-                    .await
-                    .expect("Address/Port unreachable")
-                    .next()
-                    .unwrap()
-                    .ip()
+                format!(
+                    "certificate for {} expires in {days_left} days on {}",
+                    self.target,
+                    not_after.date()
+                )
             };
+            self.log_event(now, "cert_expiring", &details).await;
         }
-        possible_addr.unwrap()
+        self.cert_warned = expiring;
     }
 
-    /// Creates a JSON file reflecting current application configuration in a user-configurable directory.
-    async fn create_config(&self, delay: u64) {
-        let js_string = format!("{{\"address\": \"{}\",\"num_bytes\": {},\"timeout\": \"{}ms\",\"ttl\": {},\"delay\": \"{}s\"}}",
-            self.ip_addr,
-            self.data.len(),
-            self.timeout.as_millis(),
-            self.ttl,
-            delay
+    /// Log a `clock_offset` event when an NTP probe finds the local clock off by more than
+    /// `max_offset`. The warning is given once until the offset is back within the limit.
+    async fn check_clock_offset(&mut self, now: OffsetDateTime, offset: Option<f64>) {
+        let Some(offset) = offset else {
+            return;
+        };
+        let exceeded = offset.abs() > self.max_offset.as_secs_f64();
+        if exceeded && !self.offset_warned {
+            let details = format!(
+                "local clock is {:.3}s {} {}",
+                offset.abs(),
+                if offset > 0.0 { "behind" } else { "ahead of" },
+                self.target
+            );
+            self.log_event(now, "clock_offset", &details).await;
+        }
+        self.offset_warned = exceeded;
+    }
+
+    /// Log a `late_reply` event for each reply that arrived after its probe timed out. The probe
+    /// still counts as lost, but chronic late replies point at bufferbloat rather than loss.
+    async fn check_late_replies(&mut self, now: OffsetDateTime, late_replies: &[(u16, Duration)]) {
+        for &(seq, rtt) in late_replies {
+            self.stats.late_replies += 1;
+            let details = format!(
+                "late reply ({} ms) to probe {seq} from {}",
+                rtt.as_millis(),
+                self.target
+            );
+            self.log_event(now, "late_reply", &details).await;
+        }
+    }
+
+    /// Record the outcome of a throughput measurement in the statistics and as a `throughput` (or
+    /// `throughput_failed`) event.
+    pub async fn record_throughput(&mut self, result: Result<Throughput, String>) {
+        let now = self.clock.now();
+        match result {
+            Ok(throughput) => {
+                let mbps = throughput.mbps();
+                let stats = &mut self.stats.throughput;
+                stats.measurements += 1;
+                stats.total_mbps += mbps;
+                stats.min_mbps = Some(stats.min_mbps.map_or(mbps, |min| min.min(mbps)));
+                stats.max_mbps = Some(stats.max_mbps.map_or(mbps, |max| max.max(mbps)));
+                let details = format!(
+                    "{mbps:.3} Mbps ({} bytes in {:.3}s)",
+                    throughput.bytes,
+                    throughput.elapsed.as_secs_f64()
+                );
+                self.log_event(now, "throughput", &details).await;
+            }
+            Err(e) => self.log_event(now, "throughput_failed", &e).await,
+        }
+    }
+
+    /// Record the public address found by a poll, logging it the first time as a `public_ip`
+    /// event and whenever it changes as a `public_ip_changed` event. Only the first of a run of
+    /// failed polls is logged. A change to an address in another prefix or AS is also logged as
+    /// an `isp_changed` event.
+    pub async fn record_public_ip(&mut self, result: Result<PublicAddress, String>) {
+        let now = self.clock.now();
+        match (result, self.public_ip) {
+            (Ok(answer), None) => {
+                let address = answer.address;
+                self.log_event(now, "public_ip", &format!("public address is {address}"))
+                    .await;
+                self.public_ip = Some(address);
+                self.public_network = self.public_network_of(now, answer).await;
+            }
+            (Ok(answer), Some(previous)) if answer.address != previous => {
+                let address = answer.address;
+                let details = format!("public address changed from {previous} to {address}");
+                self.log_event(now, "public_ip_changed", &details).await;
+                self.public_ip = Some(address);
+                let network = self.public_network_of(now, answer).await;
+                if let (Some(old), Some(new)) = (&self.public_network, &network) {
+                    if old.asn != new.asn || old.prefix != new.prefix {
+                        self.record_isp_change(now, old.clone(), new.clone()).await;
+                    }
+                }
+                self.public_network = network;
+            }
+            (Ok(_), Some(_)) => {}
+            (Err(e), _) => {
+                if !self.public_ip_failing {
+                    self.log_event(now, "public_ip_failed", &e).await;
+                }
+                self.public_ip_failing = true;
+                return;
+            }
+        }
+        self.public_ip_failing = false;
+    }
+
+    /// The network a polled public address was found in, logging a failed lookup as an
+    /// `asn_lookup_failed` event.
+    async fn public_network_of(
+        &mut self,
+        now: OffsetDateTime,
+        answer: PublicAddress,
+    ) -> Option<Network> {
+        match answer.network? {
+            Ok(network) => Some(network),
+            Err(e) => {
+                let details = format!("{}: {e}", answer.address);
+                self.log_event(now, "asn_lookup_failed", &details).await;
+                None
+            }
+        }
+    }
+
+    /// Log the public address moving from network `old` to `new` as an `isp_changed` event, and
+    /// alert on the `--isp-change-alert` channel if there is one.
+    async fn record_isp_change(&mut self, now: OffsetDateTime, old: Network, new: Network) {
+        let details = format!(
+            "public address moved from {} ({}) to {} ({})",
+            old.prefix,
+            old.describe(),
+            new.prefix,
+            new.describe()
         );
-        let mut config_file = File::create(self.output_path.join(format!(
-            "config_{}.json",
-            self.start_time.format(&self.file_date_fmt).unwrap()
-        )))
+        self.log_event(now, "isp_changed", &details).await;
+        let Some(channel) = self.isp_change_alert.clone() else {
+            return;
+        };
+        let subject = format!("ISP changed: now {}", new.describe());
+        let key = format!("isp-change-alert {}", new.prefix);
+        if let Err(reason) = self
+            .alert_limiter
+            .allow(self.clock.instant(), channel.name(), &key)
+        {
+            self.log_event(
+                now,
+                "alert_suppressed",
+                &format!("isp-change-alert: {subject} ({reason})"),
+            )
+            .await;
+            return;
+        }
+        let body = format!("The {details}");
+        self.queue_alert("isp-change-alert", channel, subject, body, now)
+            .await;
+    }
+
+    /// Log a change of the state of the internet as a whole, judged from every monitored target,
+    /// as an `internet_down` or `internet_up` event, and alert on the `--internet-alert` channel
+    /// if there is one.
+    pub async fn record_internet_state(&mut self, change: &Change) {
+        let now = self.clock.now();
+        let down = matches!(change, Change::Down { .. });
+        let details = change.to_string();
+        let name = if down { "internet_down" } else { "internet_up" };
+        self.log_event(now, name, &details).await;
+        let Some(channel) = self.internet_alert.clone() else {
+            return;
+        };
+        let subject = format!("Internet is {}", if down { "DOWN" } else { "UP" });
+        let key = format!("internet-alert {down}");
+        if down {
+            if let Err(reason) =
+                self.alert_limiter
+                    .allow(self.clock.instant(), channel.name(), &key)
+            {
+                self.log_event(
+                    now,
+                    "alert_suppressed",
+                    &format!("internet-alert: {subject} ({reason})"),
+                )
+                .await;
+                return;
+            }
+            self.internet_alerted = true;
+        } else {
+            // Only a channel told about the outage hears that it ended, and always does
+            if !std::mem::replace(&mut self.internet_alerted, false) {
+                return;
+            }
+            self.alert_limiter
+                .record(self.clock.instant(), channel.name(), &key);
+        }
+        let mut body = details;
+        body[..1].make_ascii_uppercase();
+        self.queue_alert("internet-alert", channel, subject, body, now)
+            .await;
+    }
+
+    /// Start a portal check in a task of its own, unless one is already running, so a slow portal
+    /// doesn't hold up the probes. Its result comes back through `take_portal_results`.
+    fn start_portal_check(&mut self) {
+        let Some(portal_check) = self.portal_check.clone().filter(|_| !self.portal_checking) else {
+            return;
+        };
+        self.portal_checking = true;
+        let (results, timeout) = (self.portal_results.clone(), self.timeout);
+        tokio::spawn(async move {
+            let _ = results.send(portal_check.run(timeout).await);
+        });
+    }
+
+    /// Take the receiving end of the portal check results, each to be handed back to
+    /// `record_portal_result`. It can only be taken once.
+    pub fn take_portal_results(&mut self) -> mpsc::UnboundedReceiver<Result<(), String>> {
+        self.portal_results_rx
+            .take()
+            .expect("the portal check results were already taken")
+    }
+
+    /// Record the result of a portal check. The target becomes CAPTIVE (or UP again) right away
+    /// if it's answering, otherwise on its next successful probe. The reason is logged as a
+    /// `portal_check_failed` event when the check starts failing.
+    pub async fn record_portal_result(&mut self, result: Result<(), String>) {
+        self.portal_checking = false;
+        // Left over from a check that has since been turned off
+        if self.portal_check.is_none() {
+            return;
+        }
+        let now = self.clock.now();
+        if let Err(reason) = &result {
+            if !self.portal_failed {
+                self.log_event(now, "portal_check_failed", reason).await;
+            }
+        }
+        self.portal_failed = result.is_err();
+        let previous_state = self.stats.state;
+        if self.consecutive_failures == 0
+            && matches!(previous_state, LinkState::Up | LinkState::Captive)
+        {
+            let instant = self.clock.instant();
+            self.update_state(true, false, self.portal_failed, now, instant);
+        }
+        if self.stats.state != previous_state {
+            self.flush().await;
+            self.write_state_file().await;
+            self.state_file_written = Some(self.clock.instant());
+        }
+    }
+
+    /// Queue a notification for every alert rule whose threshold the current outage has reached,
+    /// and a recovery notice on the channels that were alerted once the target answers again.
+    /// `streak` is the start and length of the run of failures leading up to this ping.
+    async fn check_alerts(
+        &mut self,
+        streak: (Option<Instant>, u32),
+        now: OffsetDateTime,
+        sent_instant: Instant,
+    ) {
+        let recovered = self.first_failure_instant.is_none();
+        let (first_failure, failures) = if recovered {
+            streak
+        } else {
+            (self.first_failure_instant, self.consecutive_failures)
+        };
+        let Some(first_failure) = first_failure else {
+            return;
+        };
+        if recovered {
+            self.alerts_suppressed.fill(false);
+        }
+        let down_for = sent_instant - first_failure;
+        let due: Vec<usize> = (0..self.alerts.len())
+            .filter(|&index| {
+                if recovered {
+                    self.alerts_fired[index]
+                } else {
+                    !self.alerts_fired[index] && self.alerts[index].is_due(failures, down_for)
+                }
+            })
+            .collect();
+        if due.is_empty() {
+            return;
+        }
+        let time_fmt =
+            format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
+        let since = (now - down_for).format(&time_fmt).unwrap();
+        let variables = [
+            ("target", self.target.clone()),
+            ("address", self.ip_addr.to_string()),
+            ("state", if recovered { "up" } else { "down" }.to_string()),
+            ("failures", failures.to_string()),
+            ("outage_duration", format_duration(down_for)),
+            ("since", since.clone()),
+            (
+                "loss_pct_1h",
+                format!(
+                    "{:.1}",
+                    self.recent
+                        .loss_percent(Duration::from_secs(3600), sent_instant)
+                        .unwrap_or_default()
+                ),
+            ),
+            ("time", now.format(&time_fmt).unwrap()),
+            ("run_id", self.run_id.clone()),
+            ("probe_name", self.probe_name.clone()),
+        ];
+        let (subject, default_body) = if recovered {
+            (
+                format!("{} is back up", self.target),
+                format!(
+                    "{} answered again after {} of failures",
+                    self.target,
+                    format_duration(down_for)
+                ),
+            )
+        } else {
+            (
+                format!("{} is down", self.target),
+                format!(
+                    "{} has failed {failures} probe(s) in a row since {since}",
+                    self.target
+                ),
+            )
+        };
+        for index in due {
+            let rule = self.alerts[index].clone();
+            let key = format!("{rule} {subject}");
+            if recovered {
+                // A channel told about an outage always hears that it ended
+                self.alert_limiter
+                    .record(sent_instant, rule.channel.name(), &key);
+            } else if let Err(reason) =
+                self.alert_limiter
+                    .allow(sent_instant, rule.channel.name(), &key)
+            {
+                // Retried on later pings, but only logged once per outage
+                if !std::mem::replace(&mut self.alerts_suppressed[index], true) {
+                    self.log_event(
+                        now,
+                        "alert_suppressed",
+                        &format!("{rule}: {subject} ({reason})"),
+                    )
+                    .await;
+                }
+                continue;
+            }
+            self.alerts_fired[index] = !recovered;
+            let body = self
+                .alert_templates
+                .iter()
+                .rfind(|template| template.channel == rule.channel.name())
+                .map_or(default_body.clone(), |template| template.render(&variables));
+            self.queue_alert(&rule.to_string(), rule.channel, subject.clone(), body, now)
+                .await;
+        }
+    }
+
+    /// Queue a notice for every window rule whose statistic crossed its threshold, and another
+    /// once it is back within the threshold.
+    async fn check_window_alerts(&mut self, now: OffsetDateTime, sent_instant: Instant) {
+        for index in 0..self.window_alerts.len() {
+            let rule = self.window_alerts[index].clone();
+            // Keep the current state until the window can be evaluated
+            let Some(value) = rule.evaluate(&self.recent, sent_instant) else {
+                continue;
+            };
+            let breached = value > rule.threshold;
+            if breached == self.window_breached[index] {
+                continue;
+            }
+            let key = format!("{rule} {breached}");
+            let (subject, body) = if breached {
+                (
+                    format!("{}: {}", self.target, rule.describe()),
+                    format!(
+                        "{} for {} is {}",
+                        rule.describe(),
+                        self.target,
+                        rule.format_value(value)
+                    ),
+                )
+            } else {
+                self.window_suppressed[index] = false;
+                (
+                    format!("{}: no longer {}", self.target, rule.describe()),
+                    format!(
+                        "No longer {} for {} ({})",
+                        rule.describe(),
+                        self.target,
+                        rule.format_value(value)
+                    ),
+                )
+            };
+            if !breached {
+                // A channel told about a breach always hears that it ended
+                self.alert_limiter
+                    .record(sent_instant, rule.channel.name(), &key);
+            } else if let Err(reason) =
+                self.alert_limiter
+                    .allow(sent_instant, rule.channel.name(), &key)
+            {
+                if !std::mem::replace(&mut self.window_suppressed[index], true) {
+                    self.log_event(
+                        now,
+                        "alert_suppressed",
+                        &format!("{rule}: {subject} ({reason})"),
+                    )
+                    .await;
+                }
+                continue;
+            }
+            self.window_breached[index] = breached;
+            self.queue_alert(&rule.to_string(), rule.channel, subject, body, now)
+                .await;
+        }
+    }
+
+    /// Log an alert from the rule described by `rule` as an `alert` event and queue it for
+    /// delivery.
+    async fn queue_alert(
+        &mut self,
+        rule: &str,
+        channel: Channel,
+        subject: String,
+        body: String,
+        now: OffsetDateTime,
+    ) {
+        self.log_event(now, "alert", &format!("{rule}: {subject}"))
+            .await;
+        self.outputs.push(Output::Notification(Notification {
+            channel,
+            subject,
+            body,
+            run_id: self.run_id.clone(),
+            probe_name: self.probe_name.clone(),
+        }));
+    }
+
+    /// Record a failed alert delivery as an `alert_failed` event.
+    pub async fn record_alert_failure(&mut self, error: &str) {
+        self.log_event(self.clock.now(), "alert_failed", error)
+            .await;
+    }
+
+    /// Advance the UP/DOWN state machine. The target goes DOWN after `down_after` consecutive
+    /// failures (or on a `definitive` one), with the outage considered to have started at the first
+    /// failure of the streak, and comes back UP (or CAPTIVE, if the portal check failed) on the first
+    /// success.
+    fn update_state(
+        &mut self,
+        success: bool,
+        definitive: bool,
+        captive: bool,
+        sent_time: OffsetDateTime,
+        sent_instant: Instant,
+    ) {
+        let (state, since) = if success {
+            self.consecutive_failures = 0;
+            self.first_failure_instant = None;
+            let state = if captive {
+                LinkState::Captive
+            } else {
+                LinkState::Up
+            };
+            (state, sent_instant)
+        } else {
+            self.consecutive_failures += 1;
+            let first_failure = *self.first_failure_instant.get_or_insert(sent_instant);
+            if self.consecutive_failures < self.down_after && !definitive {
+                return;
+            }
+            (LinkState::Down, first_failure)
+        };
+        if self.stats.state != state {
+            self.end_outage(sent_instant);
+            if state == LinkState::Down {
+                self.stats.outages += 1;
+                self.outputs.push(Output::OutageStarted {
+                    at: sent_time - (sent_instant - since),
+                });
+            }
+            let transition = Transition {
+                from: self.stats.state,
+                to: state,
+                at: sent_time,
+            };
+            self.stats.last_transition = Some(transition);
+            self.outputs.push(Output::StateChanged(transition));
+            self.stats.state = state;
+            self.stats.state_since = Some(since);
+        }
+    }
+
+    /// Count the current outage, if the target is DOWN, as having ended at `at` in this run's
+    /// lifetime statistics, and publish its end.
+    fn end_outage(&mut self, at: Instant) {
+        if let (LinkState::Down, Some(since)) = (self.stats.state, self.stats.state_since) {
+            // A resumed outage only counts from when this run took over
+            self.run_downtime += at.saturating_duration_since(since.max(self.lifetime_since));
+            self.run_worst_outage = self
+                .run_worst_outage
+                .max(at.saturating_duration_since(since));
+            self.outputs.push(Output::OutageEnded {
+                at: self.clock.now() - self.clock.instant().saturating_duration_since(at),
+                duration: at.saturating_duration_since(since),
+            });
+        }
+    }
+
+    /// Forget the UP/DOWN state as of `at`, e.g. after a monitoring gap or a change of target.
+    fn reset_state(&mut self, at: Instant) {
+        self.end_outage(at);
+        self.stats.state = LinkState::Unknown;
+        self.stats.state_since = None;
+        self.consecutive_failures = 0;
+        self.first_failure_instant = None;
+        self.alerts_fired.fill(false);
+        self.alerts_suppressed.fill(false);
+    }
+
+    /// Resolve a String representation of an IP address or hostname (with/without port number) to
+    /// its IP addresses. Returns an error if the address cannot be resolved.
+    async fn resolve(addr: String) -> io::Result<Vec<IpAddr>> {
+        let (host, port) = probe::split_host_port(&addr, 80)?;
+        if let Ok(ip_addr) = host.parse::<IpAddr>() {
+            return Ok(vec![ip_addr]);
+        }
+        let addrs: Vec<IpAddr> = net::lookup_host((host, port))
+            .await?
+            .map(|socket_addr| socket_addr.ip())
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no addresses found",
+            ));
+        }
+        Ok(addrs)
+    }
+
+    /// Create a probe for the first usable address in `candidates`, trying addresses of the
+    /// same family as `current` first and falling back to the other family if its socket can't be
+    /// created (e.g. no IPv6 support).
+    async fn connect(
+        candidates: &[IpAddr],
+        current: Option<IpAddr>,
+        target: &str,
+        kind: ProbeKind,
+        ttl: u32,
+        timeout: Duration,
+    ) -> io::Result<(IpAddr, Box<dyn Probe>)> {
+        let mut ordered = candidates.to_vec();
+        if let Some(current) = current {
+            ordered.sort_by_key(|ip_addr| ip_addr.is_ipv4() != current.is_ipv4());
+        }
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+        for ip_addr in ordered {
+            match probe::create(kind, target, ip_addr, ttl, timeout).await {
+                Ok(backend) => return Ok((ip_addr, backend)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Look up which interface the probes leave through and log an `interface_changed` event
+    /// (rewriting the config snapshot) if it differs from before, e.g. after a failover from
+    /// Ethernet to LTE or a VPN coming up. Changes to the addresses of the same interface, e.g. a
+    /// DHCP renewal handing out a new lease, are logged as a `local_address_changed` event.
+    async fn refresh_interface(&mut self, now: OffsetDateTime) {
+        let interface = egress_interface(self.ip_addr);
+        let addresses = interface.as_deref().map_or(Vec::new(), local_addresses);
+        if interface == self.interface {
+            if addresses == self.local_addresses {
+                return;
+            }
+            let details = format!(
+                "{}: {}",
+                interface.as_deref().unwrap_or("none"),
+                address_changes(&self.local_addresses, &addresses)
+            );
+            self.local_addresses = addresses;
+            self.log_event(now, "local_address_changed", &details).await;
+            self.rewrite_config(now).await;
+            return;
+        }
+        let name = |interface: &Option<String>| interface.clone().unwrap_or("none".to_string());
+        let details = format!("{} -> {}", name(&self.interface), name(&interface));
+        self.interface = interface;
+        self.local_addresses = addresses;
+        self.log_event(now, "interface_changed", &details).await;
+        self.rewrite_config(now).await;
+    }
+
+    /// Re-resolve a hostname target and move to a new address if the old one is no longer listed.
+    /// The probe is rebuilt while the sequence counter and statistics carry over.
+    async fn refresh_address(&mut self, now: OffsetDateTime) {
+        self.resolved_at = self.clock.instant();
+        let addrs = match Engine::resolve(self.target.clone()).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                self.log_event(now, "resolve_failed", &format!("{}: {e}", self.target))
+                    .await;
+                return;
+            }
+        };
+        if addrs.contains(&self.ip_addr) {
+            return;
+        }
+        match Engine::connect(
+            &addrs,
+            Some(self.ip_addr),
+            &self.target,
+            self.kind,
+            self.ttl,
+            self.timeout,
+        )
         .await
-        .expect("Error creating config file");
-        config_file
-            .write_all(js_string.as_ref())
+        {
+            Ok((ip_addr, backend)) => {
+                self.log_event(
+                    now,
+                    "address_changed",
+                    &format!("{} -> {ip_addr}", self.ip_addr),
+                )
+                .await;
+                self.ip_addr = ip_addr;
+                let _ = self.prober.send(ProberCommand::Replace(backend));
+                self.record_address(now);
+                self.start_network_lookup();
+                self.rewrite_config(now).await;
+            }
+            Err(e) => {
+                self.log_event(
+                    now,
+                    "address_change_failed",
+                    &format!("could not create probe socket: {e}"),
+                )
+                .await;
+            }
+        }
+    }
+
+    /// With `--asn-lookup`, look up the network the target's address belongs to. A failed lookup
+    /// is logged as an `asn_lookup_failed` event and leaves the network unknown.
+    async fn lookup_network(&mut self, now: OffsetDateTime) {
+        if !self.asn_lookup {
+            return;
+        }
+        self.network = match asn::lookup(self.ip_addr).await {
+            Ok(network) => Some(network),
+            Err(e) => {
+                self.log_event(now, "asn_lookup_failed", &format!("{}: {e}", self.ip_addr))
+                    .await;
+                None
+            }
+        };
+    }
+
+    /// With `--asn-lookup`, look up the network of the target's new address in a task of its own,
+    /// so a slow whois service doesn't hold up the probes. Its result comes back through
+    /// `take_network_lookups`.
+    fn start_network_lookup(&mut self) {
+        if !self.asn_lookup {
+            return;
+        }
+        let (lookups, ip_addr) = (self.network_lookups.clone(), self.ip_addr);
+        tokio::spawn(async move {
+            let _ = lookups.send((ip_addr, asn::lookup(ip_addr).await));
+        });
+    }
+
+    /// Take the receiving end of the network lookup results, each to be handed back to
+    /// `record_network`. It can only be taken once.
+    pub fn take_network_lookups(&mut self) -> mpsc::UnboundedReceiver<NetworkLookup> {
+        self.network_lookups_rx
+            .take()
+            .expect("the network lookups were already taken")
+    }
+
+    /// Record the result of a network lookup and update the config snapshot with it. A failed
+    /// lookup is logged as an `asn_lookup_failed` event and leaves the network unknown. Results
+    /// for an address the target has since moved away from are dropped.
+    pub async fn record_network(&mut self, (ip_addr, result): NetworkLookup) {
+        if ip_addr != self.ip_addr {
+            return;
+        }
+        let now = self.clock.now();
+        self.network = match result {
+            Ok(network) => Some(network),
+            Err(e) => {
+                self.log_event(now, "asn_lookup_failed", &format!("{ip_addr}: {e}"))
+                    .await;
+                None
+            }
+        };
+        self.rewrite_config(now).await;
+    }
+
+    /// The network the target's address belongs to, if it was looked up.
+    pub fn network(&self) -> Option<&Network> {
+        self.network.as_ref()
+    }
+
+    /// Add the current target and address to the address history if either changed.
+    fn record_address(&mut self, now: OffsetDateTime) {
+        if self
+            .address_history
+            .last()
+            .is_none_or(|last| last.target != self.target || last.address != self.ip_addr)
+        {
+            self.address_history.push(ResolvedAddress {
+                target: self.target.clone(),
+                address: self.ip_addr,
+                since: now.to_string(),
+            });
+        }
+    }
+
+    /// Creates a JSON file reflecting current application configuration in a user-configurable directory.
+    async fn create_config(&self) -> io::Result<()> {
+        let Some(output_path) = &self.output_path else {
+            return Ok(());
+        };
+        let js_string = ConfigSnapshot {
+            version: SNAPSHOT_VERSION,
+            target: self.target.clone(),
+            address: self.ip_addr,
+            run_id: self.run_id.clone(),
+            started: self.start_time.to_string(),
+            source_address: source_address(self.ip_addr),
+            address_history: self.address_history.clone(),
+            boot_id: host::boot_id(),
+            host_booted: host::boot_time(self.start_time).map(|booted| booted.to_string()),
+            network: self.network.clone(),
+            num_bytes: self.data.len(),
+            timeout_ms: self.timeout.as_millis() as u64,
+            probe: self.kind,
+            interface: self.interface.clone(),
+            ttl: self.ttl,
+            delay_s: self.delay.as_secs_f64(),
+        }
+        .to_json();
+        let config_path = output_path.join(format!("{}.json", self.file_stem("config")));
+        write_atomic(&config_path, js_string.as_ref())
             .await
-            .expect("Error writing to config file");
-        config_file.flush().await.unwrap();
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", config_path.display())))
     }
 
-    /// Creates a CSV file for the app logs with a header.
-    async fn init_csv(&self) -> File {
-        let csv_path = self.output_path.join(format!(
-            "result_{}.csv",
-            self.start_time.format(&self.file_date_fmt).unwrap()
+    /// Rewrite the config snapshot after the settings changed during the run. A failed write is
+    /// logged as a `config_write_failed` event rather than stopping the monitor.
+    async fn rewrite_config(&mut self, now: OffsetDateTime) {
+        if let Err(e) = self.create_config().await {
+            self.log_event(now, "config_write_failed", &e.to_string())
+                .await;
+        }
+    }
+
+    /// Record the current state and statistics in the output directory for `num healthcheck` and
+    /// `--resume`, and update the lifetime statistics.
+    pub async fn write_state_file(&self) {
+        let Some(output_path) = &self.output_path else {
+            return;
+        };
+        self.save_lifetime().await;
+        let state_file = StateFile::new(
+            &self.target,
+            self.stats.state,
+            &self.run_id,
+            self.delay.max(STATE_FILE_INTERVAL) + self.timeout,
+            self.checkpoint(),
+        );
+        // Failing to write it only makes health checks fail, which is the right outcome
+        let _ = write_atomic(
+            &output_path.join(STATE_FILE_NAME),
+            state_file.to_json().as_ref(),
+        )
+        .await;
+    }
+
+    /// Load the lifetime statistics of the target from earlier runs. If they can't be read, they
+    /// aren't kept during this run (so the file isn't overwritten) and a `lifetime_failed` event is
+    /// logged.
+    async fn load_lifetime(&mut self) {
+        match lifetime::read(self.output_path.as_ref().unwrap()) {
+            Ok(mut lifetimes) => {
+                self.lifetime_base = Some(lifetimes.remove(&self.target).unwrap_or_default());
+            }
+            Err(e) => {
+                self.lifetime_base = None;
+                self.log_event(self.clock.now(), "lifetime_failed", &e)
+                    .await;
+            }
+        }
+    }
+
+    /// Return the lifetime statistics of the target including this run so far, if kept.
+    fn lifetime(&self) -> Option<Lifetime> {
+        let base = self.lifetime_base?;
+        let now_instant = self.clock.instant();
+        let (mut downtime, mut worst_outage) = (self.run_downtime, self.run_worst_outage);
+        if let (LinkState::Down, Some(since)) = (self.stats.state, self.stats.state_since) {
+            downtime += now_instant.saturating_duration_since(since.max(self.lifetime_since));
+            worst_outage = worst_outage.max(now_instant.saturating_duration_since(since));
+        }
+        let monitored = (now_instant - self.lifetime_since).saturating_sub(self.unmonitored);
+        Some(Lifetime {
+            monitored_s: base.monitored_s + monitored.as_secs_f64(),
+            downtime_s: base.downtime_s + downtime.as_secs_f64(),
+            worst_outage_s: base.worst_outage_s.max(worst_outage.as_secs_f64()),
+            runs: base.runs + 1,
+        })
+    }
+
+    /// Write the lifetime statistics of the target to the output directory, if kept.
+    async fn save_lifetime(&self) {
+        let (Some(output_path), Some(lifetime)) = (&self.output_path, self.lifetime()) else {
+            return;
+        };
+        // Like the state file, this is best effort
+        let _ = lifetime::update(output_path, &self.target, lifetime).await;
+    }
+
+    /// Capture the statistics for the state file.
+    fn checkpoint(&self) -> Checkpoint {
+        let now = self.clock.now();
+        let now_instant = self.clock.instant();
+        let stats = self.stats();
+        Checkpoint {
+            sent: stats.sent,
+            received: stats.received,
+            rtt_min_ms: stats.min_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            rtt_max_ms: stats.max_rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+            rtt_total_ms: stats.total_rtt.as_secs_f64() * 1000.0,
+            rtt_total_squared: stats.total_rtt_squared,
+            outages: stats.outages,
+            runtime_s: stats.runtime.as_secs_f64(),
+            state_since: stats
+                .state_since
+                .map(|since| (now - (now_instant - since)).unix_timestamp()),
+            last_transition: stats
+                .last_transition
+                .map(|transition| CheckpointTransition {
+                    from: transition.from.label().to_lowercase(),
+                    to: transition.to.label().to_lowercase(),
+                    at: transition.at.unix_timestamp(),
+                }),
+        }
+    }
+
+    /// Carry on from the statistics checkpointed in the state file by a previous run for the same
+    /// target. The UP/DOWN state is only restored if the checkpoint is fresh, as the target wasn't
+    /// watched in between; otherwise it starts out unknown as usual. Failures are logged as a
+    /// `resume_failed` event and leave the statistics empty.
+    async fn resume(&mut self) {
+        let now = self.clock.now();
+        let result = match StateFile::read(self.output_path.as_ref().unwrap()) {
+            Ok(state_file) if state_file.target != self.target => Err(format!(
+                "the state file is for {}, not {}",
+                state_file.target, self.target
+            )),
+            Ok(StateFile {
+                checkpoint: None, ..
+            }) => Err("the state file has no checkpoint".to_string()),
+            Ok(state_file) => Ok(state_file),
+            Err(e) => Err(e),
+        };
+        let state_file = match result {
+            Ok(state_file) => state_file,
+            Err(e) => {
+                self.log_event(now, "resume_failed", &e).await;
+                return;
+            }
+        };
+        // A gap caused by the monitoring host itself rebooting isn't a network outage
+        if let (Some(previous), Some(current)) = (&state_file.boot_id, host::boot_id()) {
+            if *previous != current {
+                let since = Duration::from_secs(
+                    now.unix_timestamp()
+                        .saturating_sub(state_file.updated as i64)
+                        .max(0) as u64,
+                );
+                let details = format!(
+                    "the monitoring host rebooted since the previous run, last seen {} ago",
+                    format_duration(since)
+                );
+                self.log_event(now, "host_rebooted", &details).await;
+            }
+        }
+        let checkpoint = state_file.checkpoint.unwrap();
+        let now_instant = self.clock.instant();
+        // Instant of a past UNIX time, if it's within the monotonic clock's range
+        let instant_at = |at: i64| {
+            now_instant.checked_sub(Duration::from_secs(
+                now.unix_timestamp().saturating_sub(at).max(0) as u64,
+            ))
+        };
+        let from_ms = |ms: f64| Duration::from_secs_f64(ms.max(0.0) / 1000.0);
+        self.stats.sent = checkpoint.sent;
+        self.stats.received = checkpoint.received;
+        self.stats.min_rtt = checkpoint.rtt_min_ms.map(from_ms);
+        self.stats.max_rtt = checkpoint.rtt_max_ms.map(from_ms);
+        self.stats.total_rtt = from_ms(checkpoint.rtt_total_ms);
+        self.stats.total_rtt_squared = checkpoint.rtt_total_squared;
+        self.stats.outages = checkpoint.outages;
+        self.stats.last_transition = checkpoint.last_transition.and_then(|transition| {
+            Some(Transition {
+                from: LinkState::from_label(&transition.from)?,
+                to: LinkState::from_label(&transition.to)?,
+                at: OffsetDateTime::from_unix_timestamp(transition.at)
+                    .ok()?
+                    .to_offset(now.offset()),
+            })
+        });
+        self.resumed_runtime = Duration::from_secs_f64(checkpoint.runtime_s.max(0.0));
+        let state = LinkState::from_label(&state_file.state).unwrap_or(LinkState::Unknown);
+        let state_since = checkpoint.state_since.and_then(instant_at);
+        let fresh = now.unix_timestamp() <= state_file.expires as i64;
+        let restored = match state_since {
+            Some(since) if fresh && state != LinkState::Unknown => {
+                self.stats.state = state;
+                self.stats.state_since = Some(since);
+                if state == LinkState::Down {
+                    self.consecutive_failures = self.down_after;
+                }
+                format!(
+                    ", {} for {}",
+                    state.label(),
+                    format_duration(now_instant - since)
+                )
+            }
+            _ => String::new(),
+        };
+        self.log_event(
+            now,
+            "resumed",
+            &format!(
+                "{} sent, {} received over {}{restored}",
+                checkpoint.sent,
+                checkpoint.received,
+                format_duration(self.resumed_runtime)
+            ),
+        )
+        .await;
+    }
+
+    /// Detect discontinuities in the monitoring timeline since the previous ping. A tick that fired
+    /// at least one delay later than scheduled (e.g. a paused VM) or a forward wall clock jump of at
+    /// least one delay (e.g. a suspended host, during which the monotonic clock stops) is logged as a
+    /// monitoring gap, and the UP/DOWN state is reset so the gap isn't counted as downtime. Smaller
+    /// wall clock discrepancies (e.g. NTP steps) are logged as clock jumps, and smaller lateness is
+    /// counted as tick drift, logged at the start of each run of late ticks. Ticks missed during a
+    /// gap and sent right after it (with `--missed-ticks burst`) are late too, but only the first
+    /// of them is counted.
+    async fn check_timeline(
+        &mut self,
+        now: OffsetDateTime,
+        now_instant: Instant,
+        scheduled: Instant,
+    ) {
+        let lateness = now_instant.saturating_duration_since(scheduled);
+        let caught_up = self
+            .wall_clock_reference
+            .is_some_and(|(_, previous)| now_instant - previous < self.delay);
+        let mut gap = None;
+        if lateness < self.delay {
+            self.record_drift(now, lateness).await;
+        } else if !caught_up {
+            gap = Some(lateness);
+        }
+        if let Some((reference_time, reference_instant)) = self.wall_clock_reference {
+            let expected = reference_time + (now_instant - reference_instant);
+            let jump = (now - expected).as_seconds_f64();
+            if jump >= self.delay.as_secs_f64() {
+                gap = Some(gap.unwrap_or_default() + Duration::from_secs_f64(jump));
+            } else if jump.abs() >= CLOCK_JUMP_THRESHOLD.as_secs_f64() {
+                self.log_event(
+                    now,
+                    "clock_jump",
+                    &format!("wall clock moved {jump:+.3}s relative to monotonic time"),
+                )
+                .await;
+            }
+        }
+        self.wall_clock_reference = Some((now, now_instant));
+        if let Some(gap) = gap {
+            self.log_event(
+                now,
+                "monitoring_gap",
+                &format!(
+                    "no pings for {:.3}s before this time (host suspended or paused)",
+                    gap.as_secs_f64() + self.delay.as_secs_f64()
+                ),
+            )
+            .await;
+            self.unmonitored += gap;
+            // The last ping before the gap is as far as the state is known
+            self.reset_state(now_instant.checked_sub(gap).unwrap_or(now_instant));
+        }
+    }
+
+    /// Count how late the current tick fired.
+    async fn record_drift(&mut self, now: OffsetDateTime, lateness: Duration) {
+        let drift = &mut self.stats.drift;
+        drift.ticks += 1;
+        drift.total += lateness;
+        drift.max = drift.max.max(lateness);
+        if lateness < DRIFT_THRESHOLD {
+            self.drifting = false;
+            return;
+        }
+        drift.late += 1;
+        if !self.drifting {
+            self.drifting = true;
+            self.log_event(
+                now,
+                "tick_drift",
+                &format!(
+                    "probe sent {} ms behind schedule (host overloaded or ping loop blocked)",
+                    lateness.as_millis()
+                ),
+            )
+            .await;
+        }
+    }
+
+    /// Name of the run's output file of the given kind, without the extension, e.g.
+    /// `result_05-31-2023@17-10-38_3f9a1c07`.
+    fn file_stem(&self, prefix: &str) -> String {
+        format!(
+            "{prefix}_{}_{}",
+            self.start_time.format(&self.file_date_fmt).unwrap(),
+            self.run_id
+        )
+    }
+
+    /// Creates a CSV file named `<prefix>_<start time>_<run ID>.csv` (or `.tsv` if tab-separated,
+    /// with `.age` added if encrypted) in the output directory with a header.
+    async fn init_log_file(&self, prefix: &str, header: &str) -> Result<LogFile, String> {
+        let csv_path = self.output_path.as_ref().unwrap().join(format!(
+            "{}.{}{}",
+            self.file_stem(prefix),
+            if self.delimiter == b'\t' {
+                "tsv"
+            } else {
+                "csv"
+            },
+            if self.encrypt.is_some() { ".age" } else { "" }
         ));
-        let mut new_csv = File::options()
-            .create_new(true)
-            .write(true)
-            .open(&csv_path)
+        LogFile::create(
+            csv_path.clone(),
+            header,
+            self.sync_writes,
+            self.encrypt.as_ref(),
+        )
+        .await
+        .map_err(|e| format!("Could not create {}: {e}", csv_path.display()))
+    }
+
+    /// Create the result sinks, `result_<start time>_<run ID>` with the extension of their format,
+    /// in the output directory.
+    async fn init_sinks(&self) -> Result<Vec<Box<dyn OutputSink>>, String> {
+        let path_stem = self
+            .output_path
+            .as_ref()
+            .unwrap()
+            .join(self.file_stem("result"));
+        let metadata = self.result_metadata();
+        let mut sinks: Vec<Box<dyn OutputSink>> = Vec::new();
+        for &kind in &self.sink_kinds {
+            let sink = sink::create(
+                kind,
+                path_stem.clone(),
+                &metadata,
+                &self.columns,
+                self.delimiter,
+                self.sync_writes,
+                self.encrypt.as_ref(),
+                self.sign.as_ref(),
+                self.flush_policy,
+            )
             .await
-            .expect("Error creating CSV");
-        new_csv
-            .write_all("Timestamp,Latency(ms)\n".as_ref())
+            .map_err(|e| format!("Could not create result file {}: {e}", path_stem.display()))?;
+            // Written from a task of its own so a stalled disk can't hold up the ping loop
+            sinks.push(Box::new(QueuedSink::spawn(
+                sink,
+                self.write_overflow,
+                QUEUE_CAPACITY,
+            )));
+        }
+        Ok(sinks)
+    }
+
+    /// The run's settings, written at the top of the result files.
+    fn result_metadata(&self) -> Vec<(&'static str, String)> {
+        let mut metadata = vec![
+            ("num", env!("CARGO_PKG_VERSION").to_string()),
+            ("hostname", host::hostname().unwrap_or_default()),
+            ("probe_name", self.probe_name.clone()),
+            ("target", self.target.clone()),
+            ("address", self.ip_addr.to_string()),
+            ("probe", self.kind.name().to_string()),
+            ("delay_s", self.delay.as_secs_f64().to_string()),
+            ("timeout_ms", self.timeout.as_millis().to_string()),
+            ("num_bytes", self.data.len().to_string()),
+            ("ttl", self.ttl.to_string()),
+            ("started", self.start_time.to_string()),
+            ("run_id", self.run_id.clone()),
+        ];
+        if let Some(interface) = &self.interface {
+            metadata.push(("interface", interface.clone()));
+        }
+        if let Some(network) = &self.network {
+            metadata.push(("network", network.describe()));
+        }
+        metadata
+    }
+
+    /// Hands a record with the chosen columns to every sink.
+    async fn write_record(&mut self, probed: &Probed) {
+        let timestamp = probed.sent_at;
+        let result = &probed.output;
+        if self.sinks.is_empty() {
+            return;
+        }
+        let [signal, tx_bitrate] = if self.columns.has_wifi() {
+            self.wifi_values().await
+        } else {
+            Default::default()
+        };
+        let [rx_errors, tx_errors, rx_dropped, tx_dropped] = if self.columns.has_nic_counters() {
+            self.nic_counter_values()
+        } else {
+            Default::default()
+        };
+        let fields = self
+            .columns
+            .0
+            .iter()
+            .map(|&column| {
+                let value = match column {
+                    Column::Timestamp => timestamp.to_string(),
+                    Column::Run => self.run_id.clone(),
+                    Column::ProbeName => self.probe_name.clone(),
+                    Column::Target => self.target.clone(),
+                    Column::Address => self.ip_addr.to_string(),
+                    Column::Rtt => result
+                        .as_ref()
+                        .map(|rtt| format!("{:.3}", rtt.as_secs_f64() * 1000.0))
+                        .unwrap_or_default(),
+                    Column::Status => if result.is_ok() { "ok" } else { "failed" }.to_string(),
+                    Column::Error => result
+                        .as_ref()
+                        .err()
+                        .map(|e| crate::probe::failure_reason(e).to_string())
+                        .unwrap_or_default(),
+                    Column::Seq => probed.seq.to_string(),
+                    Column::Retries => probed.retries.to_string(),
+                    Column::Signal => signal.clone(),
+                    Column::TxBitrate => tx_bitrate.clone(),
+                    Column::RxErrors => rx_errors.clone(),
+                    Column::TxErrors => tx_errors.clone(),
+                    Column::RxDropped => rx_dropped.clone(),
+                    Column::TxDropped => tx_dropped.clone(),
+                    column => probed
+                        .values
+                        .iter()
+                        .find(|(measured, _)| *measured == column)
+                        .map(|(_, value)| value.clone())
+                        .unwrap_or_default(),
+                };
+                (column, value)
+            })
+            .collect();
+        let record = ProbeRecord { fields };
+        for sink in &mut self.sinks {
+            sink.write(&record).await;
+        }
+    }
+
+    /// Sample the radio conditions of the interface the target is reached through, as the signal
+    /// and bitrate columns that are left empty if it isn't a wireless interface. The netlink
+    /// requests block, so they're made off the runtime's threads.
+    async fn wifi_values(&self) -> [String; 2] {
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = self.interface.clone() {
+            let sample = tokio::task::spawn_blocking(move || crate::wifi::sample(&interface))
+                .await
+                .ok()
+                .flatten();
+            let Some(sample) = sample else {
+                return Default::default();
+            };
+            return [
+                sample
+                    .signal_dbm
+                    .map(|signal| signal.to_string())
+                    .unwrap_or_default(),
+                sample
+                    .tx_bitrate_mbps
+                    .map(|rate| format!("{rate:.1}"))
+                    .unwrap_or_default(),
+            ];
+        }
+        Default::default()
+    }
+
+    /// Sample the error and drop counters of the interface the target is reached through, as the
+    /// four counter columns with the increase since the previous ping. They are left empty for the
+    /// first sample and whenever the interface changes.
+    fn nic_counter_values(&mut self) -> [String; 4] {
+        #[cfg(target_os = "linux")]
+        {
+            let sample = crate::interface::egress_interface(self.ip_addr)
+                .and_then(|interface| {
+                    crate::interface::error_counters(&interface)
+                        .map(|counters| (interface, counters))
+                })
+                .ok();
+            let previous = std::mem::replace(&mut self.last_nic_counters, sample);
+            if let (Some((interface, counters)), Some((previous_interface, previous_counters))) =
+                (&self.last_nic_counters, previous)
+            {
+                if *interface == previous_interface {
+                    // Counters go back to zero if the driver is reloaded
+                    return std::array::from_fn(|i| {
+                        counters[i].saturating_sub(previous_counters[i]).to_string()
+                    });
+                }
+            }
+        }
+        Default::default()
+    }
+
+    /// Write any buffered rows to the result sinks and event CSV. Failed writes stay buffered and
+    /// are retried later.
+    pub async fn flush(&mut self) {
+        for sink in &mut self.sinks {
+            sink.flush().await;
+        }
+        if let Some(events_log) = &mut self.events_log {
+            events_log.flush().await;
+        }
+    }
+
+    /// Write any buffered rows as the run ends.
+    pub async fn close(&mut self) {
+        for sink in &mut self.sinks {
+            sink.close().await;
+        }
+        if let Some(events_log) = &mut self.events_log {
+            events_log.close().await;
+        }
+    }
+
+    /// Reopen the result and event files at their original paths so external log rotation takes
+    /// effect. Buffered rows are written to the old files first.
+    pub async fn reopen_logs(&mut self) {
+        let mut errors = Vec::new();
+        for sink in &mut self.sinks {
+            if let Err(e) = sink.reopen().await {
+                errors.push(e);
+            }
+        }
+        if let Some(events_log) = &mut self.events_log {
+            if let Err(e) = events_log.reopen().await {
+                errors.push(format!("{}: {e}", events_log.path().display()));
+            }
+        }
+        if errors.is_empty() {
+            self.log_event(self.clock.now(), "logs_reopened", "").await;
+        } else {
+            self.log_event(self.clock.now(), "logs_reopen_failed", &errors.join("; "))
+                .await;
+        }
+    }
+
+    /// Appends an event (clock jumps, monitoring gaps, etc.) to the events CSV, and queues it to
+    /// be collected with `take_outputs`.
+    pub async fn log_event(&mut self, timestamp: OffsetDateTime, event: &str, details: &str) {
+        // Keep each event on a single line
+        let details = details.replace('\n', " ");
+        if let Some(events_log) = self.events_log.as_mut() {
+            events_log.push(format_row(
+                [
+                    timestamp.to_string().as_str(),
+                    event,
+                    &details,
+                    &self.run_id,
+                ],
+                self.delimiter,
+            ));
+            events_log.flush().await;
+        }
+        self.outputs.push(Output::Event(Event {
+            time: timestamp,
+            name: event.to_string(),
+            details,
+        }));
+    }
+
+    /// Return what happened since the last call (events, notifications to deliver, state changes
+    /// and outages, storage problems), for the pipeline's consumers.
+    pub fn take_outputs(&mut self) -> Vec<Output> {
+        std::mem::take(&mut self.outputs)
+    }
+
+    /// Describe the storage problem if writes to the output directory are currently failing.
+    pub fn get_storage_error(&self) -> Option<String> {
+        self.sinks.iter().find_map(|sink| sink.error()).or_else(|| {
+            let log = self.events_log.as_ref()?;
+            log.last_error().map(|e| {
+                format!(
+                    "{}: {e} ({} rows buffered)",
+                    log.path().display(),
+                    log.pending_rows()
+                )
+            })
+        })
+    }
+
+    /// Publish the storage problem when writes start failing, and once they recover, be ready to
+    /// publish the next one.
+    fn check_storage(&mut self) {
+        match self.get_storage_error() {
+            Some(error) if !self.storage_degraded => {
+                self.storage_degraded = true;
+                self.outputs.push(Output::StorageDegraded(error));
+            }
+            Some(_) => {}
+            None => self.storage_degraded = false,
+        }
+    }
+
+    /// Check whether writes have failed too many times in a row to keep going.
+    pub fn storage_failed(&self) -> bool {
+        self.sinks
+            .iter()
+            .map(|sink| sink.consecutive_failures())
+            .chain(self.events_log.iter().map(LogFile::consecutive_failures))
+            .any(|failures| failures >= self.max_write_failures)
+    }
+
+    /// Stop probing until `resume_probing` is called. Ticks should be skipped in the meantime.
+    pub async fn pause_probing(&mut self) {
+        if self.paused_since.is_none() {
+            self.paused_since = Some(self.clock.instant());
+            self.log_event(
+                self.clock.now(),
+                "probing_paused",
+                "probing paused with num ctl",
+            )
+            .await;
+        }
+    }
+
+    /// Start probing again after `pause_probing`. The pause is recorded like a monitoring gap: it
+    /// isn't counted as monitored time, and the UP/DOWN state is reset as of when it began.
+    pub async fn resume_probing(&mut self) {
+        let Some(since) = self.paused_since.take() else {
+            return;
+        };
+        let paused = self.clock.instant().saturating_duration_since(since);
+        self.unmonitored += paused;
+        self.reset_state(since);
+        self.log_event(
+            self.clock.now(),
+            "probing_resumed",
+            &format!("probing resumed after {}", format_duration(paused)),
+        )
+        .await;
+    }
+
+    /// Return whether probing is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused_since.is_some()
+    }
+
+    /// Return a snapshot of the statistics gathered so far.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            runtime: self.resumed_runtime + (self.clock.instant() - self.start_instant),
+            lifetime: self.lifetime(),
+            ..self.stats.clone()
+        }
+    }
+
+    /// Summarize the run as it ends, for the reason given.
+    pub fn summary(&self, exit_reason: &str) -> Summary {
+        Summary::new(
+            &self.target,
+            self.ip_addr,
+            &self.run_id,
+            self.start_time,
+            self.clock.now(),
+            &self.stats(),
+            exit_reason,
+        )
+    }
+
+    /// Write the summary of the run to `summary_<start time>_<run ID>.json` in the output directory.
+    pub async fn write_summary(&self, summary: &Summary) -> Result<(), String> {
+        let Some(output_path) = &self.output_path else {
+            return Ok(());
+        };
+        let path = output_path.join(format!("{}.json", self.file_stem("summary")));
+        write_atomic(&path, summary.to_json().as_ref())
             .await
-            .expect("Error writing header to CSV");
-        new_csv.flush().await.unwrap();
-        File::options().append(true).open(&csv_path).await.unwrap()
+            .map_err(|e| format!("Could not write {}: {e}", path.display()))
     }
 
-    /// Appends log data to a pre-created CSV.
-    async fn write_csv(
-        &mut self,
-        timestamp: OffsetDateTime,
-        result: &Result<(IcmpPacket, Duration), SurgeError>,
-    ) {
-        let rtt: String = match result {
-            Ok((_, rtt)) => rtt.as_millis().to_string(),
-            Err(_) => "failed".to_string(),
+    /// Return whether a failed probe leaves no doubt that the target is down: with
+    /// `--down-on-no-route`, when the host had no route to it. Unlike a timeout, which may just be
+    /// a lost packet, that holds until the routing table (or neighbor cache) changes.
+    fn is_definitive(&self, output: &Result<Duration, SurgeError>) -> bool {
+        self.down_on_no_route
+            && output
+                .as_ref()
+                .is_err_and(|e| crate::probe::failure_reason(e) == "unreachable")
+    }
+
+    /// Return the internal IpAddr used for pinging.
+    pub fn get_processed_ip(&self) -> IpAddr {
+        self.ip_addr
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::probe::ScriptedProbe;
+    use std::path::Path;
+    use time::macros::datetime;
+
+    const DELAY: Duration = Duration::from_secs(10);
+
+    fn ms(millis: u64) -> Option<Duration> {
+        Some(Duration::from_millis(millis))
+    }
+
+    async fn scripted_engine(
+        output_path: &Path,
+        down_after: u32,
+        replies: Vec<Option<Duration>>,
+    ) -> (Engine, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(datetime!(2024-01-01 00:00 UTC)));
+        let engine_config = test_config(output_path, down_after, clock.clone());
+        let backend = Box::new(ScriptedProbe::new(replies));
+        let engine = Engine::with_backend(engine_config, "192.0.2.1".parse().unwrap(), backend)
+            .await
+            .unwrap();
+        (engine, clock)
+    }
+
+    fn test_config(output_path: &Path, down_after: u32, clock: Arc<MockClock>) -> EngineConfig {
+        EngineConfig {
+            probe: ProbeSettings {
+                addr: "192.0.2.1".to_string(),
+                kind: ProbeKind::Icmp,
+                timeout: 1000,
+                delay: DELAY,
+                interval_jitter: 0.0,
+                align: false,
+                num_bytes: 4,
+                ttl: 64,
+                down_after,
+                down_on_no_route: false,
+                retries: 0,
+                cert_warn_days: 14,
+                max_offset: 1000,
+                portal_check: None,
+                alerts: Vec::new(),
+                alert_templates: Vec::new(),
+                alert_rate_limits: Vec::new(),
+                alert_dedup: Duration::ZERO,
+                window_alerts: Vec::new(),
+                isp_change_alert: None,
+                internet_alert: None,
+            },
+            sync_writes: false,
+            encrypt: None,
+            sign: None,
+            flush_policy: FlushPolicy::EveryRow,
+            write_overflow: OverflowPolicy::Block,
+            max_write_failures: 20,
+            output_path: Some(output_path.to_path_buf()),
+            wifi: false,
+            nic_counters: false,
+            columns: None,
+            delimiter: b',',
+            sinks: vec![SinkKind::Csv],
+            resume: false,
+            run_id: "test".to_string(),
+            probe_name: "probe-1".to_string(),
+            asn_lookup: false,
+            clock,
+        }
+    }
+
+    /// Names of the events among the engine's outputs.
+    fn event_names(engine: &mut Engine) -> Vec<String> {
+        engine
+            .take_outputs()
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Event(event) => Some(event.name),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Make the config snapshot unwritable by putting a directory in its place.
+    fn block_config(engine: &Engine, dir: &Path) {
+        let config_path = dir.join(format!("{}.json", engine.file_stem("config")));
+        std::fs::remove_file(&config_path).unwrap();
+        std::fs::create_dir_all(config_path.join("blocked")).unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_that_cannot_rewrite_the_config_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![]).await;
+        block_config(&engine, dir.path());
+        let mut probe = test_config(dir.path(), 1, clock).probe;
+        probe.delay = DELAY * 2;
+        engine.reconfigure(&probe).await.unwrap();
+        assert_eq!(
+            event_names(&mut engine),
+            ["config_reloaded", "config_write_failed"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn interface_changes_that_cannot_rewrite_the_config_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![]).await;
+        block_config(&engine, dir.path());
+        engine.interface = Some("gone0".to_string());
+        engine.refresh_interface(clock.now()).await;
+        assert_eq!(
+            event_names(&mut engine),
+            ["interface_changed", "config_write_failed"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn output_files_that_cannot_be_created_fail_the_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        // A file where the output directory should be
+        let output_path = dir.path().join("not_a_directory");
+        std::fs::write(&output_path, "").unwrap();
+        let clock = Arc::new(MockClock::new(datetime!(2024-01-01 00:00 UTC)));
+        let backend = Box::new(ScriptedProbe::new(vec![]));
+        let result = Engine::with_backend(
+            test_config(&output_path, 1, clock),
+            "192.0.2.1".parse().unwrap(),
+            backend,
+        )
+        .await;
+        let Err(e) = result else {
+            panic!("the engine was created");
+        };
+        assert!(e.starts_with("Could not create"), "{e}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn result_files_that_cannot_be_created_fail_the_engine() {
+        let dir = tempfile::tempdir().unwrap();
+        let (engine, _) = scripted_engine(dir.path(), 1, vec![]).await;
+        let result_name = format!("{}.csv", engine.file_stem("result"));
+        drop(engine);
+        // The same run again, with a directory where its result file should be
+        let blocked = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(blocked.path().join(result_name).join("blocked")).unwrap();
+        let clock = Arc::new(MockClock::new(datetime!(2024-01-01 00:00 UTC)));
+        let backend = Box::new(ScriptedProbe::new(vec![]));
+        let result = Engine::with_backend(
+            test_config(blocked.path(), 1, clock),
+            "192.0.2.1".parse().unwrap(),
+            backend,
+        )
+        .await;
+        let Err(e) = result else {
+            panic!("the engine was created");
         };
-        self.result_file_handle
-            .as_mut()
+        assert!(e.starts_with("Could not create result file"), "{e}");
+    }
+
+    /// Send the next scripted ping on schedule, then wait out the rest of the delay.
+    async fn ping_on_schedule(engine: &mut Engine, clock: &MockClock) -> Stats {
+        let scheduled = clock.instant();
+        let _ = engine.ping(scheduled).await;
+        tokio::time::advance(DELAY - (clock.instant() - scheduled)).await;
+        engine.stats()
+    }
+
+    /// Take the notifications among the engine's outputs.
+    fn take_notifications(engine: &mut Engine) -> Vec<Notification> {
+        engine
+            .take_outputs()
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Notification(notification) => Some(notification),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Read the rows (after the comments and header) of the CSV starting with `prefix`.
+    fn read_rows(output_path: &Path, prefix: &str) -> Vec<Vec<String>> {
+        let entry = std::fs::read_dir(output_path)
             .unwrap()
-            .write_all(format!("{},{}\n", timestamp, rtt).as_ref())
-            .await
-            .expect("Failed to write to CSV");
-        self.result_file_handle
-            .as_mut()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+            .unwrap();
+        std::fs::read_to_string(entry.path())
+            .unwrap()
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .skip(1)
+            .map(|line| line.split(',').map(str::to_string).collect())
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_csv_records_latency_and_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(12), None, ms(3)]).await;
+        for _ in 0..3 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+        engine.close().await;
+        let rows = read_rows(dir.path(), "result_");
+        let outcomes: Vec<&[String]> = rows.iter().map(|row| &row[1..4]).collect();
+        assert_eq!(
+            outcomes,
+            [
+                ["12.000", "ok", ""],
+                ["", "failed", "timeout"],
+                ["3.000", "ok", ""]
+            ]
+        );
+        assert!(rows[1][0].starts_with("2024-01-01 0:00:10"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_csv_records_only_the_chosen_columns() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(12), None]).await;
+        engine.columns = "seq,target,error,rtt".parse().unwrap();
+        for _ in 0..2 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+        engine.close().await;
+        assert_eq!(
+            read_rows(dir.path(), "result_"),
+            [
+                ["0", "192.0.2.1", "", "12.000"],
+                ["1", "192.0.2.1", "timeout", ""]
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_are_retried_within_the_delay() {
+        let dir = tempfile::tempdir().unwrap();
+        let replies = vec![None, ms(5), None, None, None, None, None];
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, replies).await;
+        engine.columns = "rtt,status,retries".parse().unwrap();
+        // The 10s delay leaves room for 9 attempts of 1s, but only 3 retries are allowed
+        engine.retries = 3;
+        engine.configure_prober();
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert_eq!((stats.sent, stats.received), (1, 1));
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert_eq!((stats.sent, stats.received), (2, 1));
+        engine.close().await;
+        assert_eq!(
+            read_rows(dir.path(), "result_"),
+            [["5.000", "ok", "1"], ["", "failed", "3"]]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn results_csv_starts_with_run_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(1)]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        let entry = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("result_"))
+            .unwrap();
+        let contents = std::fs::read_to_string(entry.path()).unwrap();
+        let metadata: Vec<&str> = contents
+            .lines()
+            .take_while(|line| line.starts_with('#'))
+            .collect();
+        assert_eq!(metadata[0], format!("# num: {}", env!("CARGO_PKG_VERSION")));
+        assert!(metadata.contains(&"# target: 192.0.2.1"));
+        assert!(metadata.contains(&"# delay_s: 10"));
+        assert!(metadata.contains(&"# timeout_ms: 1000"));
+        assert!(metadata.contains(&"# probe_name: probe-1"));
+        assert_eq!(
+            contents.lines().nth(metadata.len()),
+            Some("Timestamp,Latency(ms),Status,Error")
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn run_id_is_in_file_names_and_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(1)]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        engine.log_event(clock.now(), "test", "details").await;
+        engine.flush().await;
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
             .unwrap()
-            .flush()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| !name.ends_with(".json") || name.starts_with("config_"))
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "config_01-01-2024@00-00-00_test.json",
+                "events_01-01-2024@00-00-00_test.csv",
+                "result_01-01-2024@00-00-00_test.csv"
+            ]
+        );
+        assert_eq!(
+            read_rows(dir.path(), "events_"),
+            [[clock.now().to_string().as_str(), "test", "details", "test"]]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_ticks_are_counted_as_drift_and_logged_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(10); 4]).await;
+        for lateness in [0, 300, 400, 20] {
+            let scheduled = clock.instant();
+            tokio::time::advance(Duration::from_millis(lateness)).await;
+            let _ = engine.ping(scheduled).await;
+            tokio::time::advance(DELAY - (clock.instant() - scheduled)).await;
+        }
+        let drift = engine.stats().drift;
+        assert_eq!((drift.ticks, drift.late), (4, 2));
+        assert_eq!(drift.max, Duration::from_millis(400));
+        assert_eq!(drift.avg(), Duration::from_millis(180));
+        let events = engine
+            .take_outputs()
+            .into_iter()
+            .filter(|output| matches!(output, Output::Event(event) if event.name == "tick_drift"))
+            .count();
+        assert_eq!(events, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn portal_check_results_make_an_answering_target_captive() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 2, vec![ms(10), None]).await;
+        engine.portal_check = Some(PortalCheck::parse("http://192.0.2.1/generate_204").unwrap());
+        ping_on_schedule(&mut engine, &clock).await;
+        assert!(engine.portal_checking);
+        assert!(engine.stats().state == LinkState::Up);
+        let blocked = || Err("http://192.0.2.1/generate_204 answered \"302 Found\"".to_string());
+        engine.record_portal_result(blocked()).await;
+        engine.record_portal_result(blocked()).await;
+        assert!(engine.stats().state == LinkState::Captive);
+        engine.record_portal_result(Ok(())).await;
+        assert!(engine.stats().state == LinkState::Up);
+        // Only applied while the target answers
+        ping_on_schedule(&mut engine, &clock).await;
+        engine.record_portal_result(blocked()).await;
+        assert!(engine.stats().state == LinkState::Up);
+        let failures = engine
+            .take_outputs()
+            .into_iter()
+            .filter(|output| {
+                matches!(output, Output::Event(event) if event.name == "portal_check_failed")
+            })
+            .count();
+        assert_eq!(failures, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_lookups_for_a_previous_address_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, _) = scripted_engine(dir.path(), 1, vec![]).await;
+        let network = Network {
+            asn: 13335,
+            name: "CLOUDFLARENET, US".to_string(),
+            prefix: "1.1.1.0/24".to_string(),
+            country: "AU".to_string(),
+        };
+        let previous: IpAddr = "192.0.2.2".parse().unwrap();
+        let current = engine.ip_addr;
+        assert_ne!(previous, current);
+        engine.record_network((previous, Ok(network.clone()))).await;
+        assert_eq!(engine.network(), None);
+        engine.record_network((current, Ok(network.clone()))).await;
+        assert_eq!(engine.network(), Some(&network));
+        engine
+            .record_network((current, Err("lookup timed out".to_string())))
+            .await;
+        assert_eq!(engine.network(), None);
+        let failures = engine
+            .take_outputs()
+            .into_iter()
+            .filter(|output| {
+                matches!(output, Output::Event(event) if event.name == "asn_lookup_failed")
+            })
+            .count();
+        assert_eq!(failures, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn network_lookups_that_cannot_rewrite_the_config_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, _) = scripted_engine(dir.path(), 1, vec![]).await;
+        block_config(&engine, dir.path());
+        let lookup = Err("lookup timed out".to_string());
+        engine.record_network((engine.ip_addr, lookup)).await;
+        assert_eq!(
+            event_names(&mut engine),
+            ["asn_lookup_failed", "config_write_failed"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn public_address_changes_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, _) = scripted_engine(dir.path(), 1, vec![]).await;
+        let first: IpAddr = "203.0.113.7".parse().unwrap();
+        let second: IpAddr = "198.51.100.20".parse().unwrap();
+        for result in [
+            Ok(first),
+            Ok(first),
+            Err("timed out".to_string()),
+            Err("timed out".to_string()),
+            Ok(second),
+        ] {
+            let answer = result.map(|address| PublicAddress {
+                address,
+                network: None,
+            });
+            engine.record_public_ip(answer).await;
+        }
+        let events: Vec<(String, String)> = engine
+            .take_outputs()
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Event(event) => Some((event.name, event.details)),
+                _ => None,
+            })
+            .filter(|(name, _)| name.starts_with("public_ip"))
+            .collect();
+        let event = |name: &str, details: &str| (name.to_string(), details.to_string());
+        assert_eq!(
+            events,
+            [
+                event("public_ip", "public address is 203.0.113.7"),
+                event("public_ip_failed", "timed out"),
+                event(
+                    "public_ip_changed",
+                    "public address changed from 203.0.113.7 to 198.51.100.20"
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moving_to_another_network_is_logged_and_alerted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, _) = scripted_engine(dir.path(), 1, vec![]).await;
+        engine.isp_change_alert = Some(Channel::Desktop);
+        let network = |asn: u32, prefix: &str| Network {
+            asn,
+            name: format!("ISP{asn}, US"),
+            prefix: prefix.to_string(),
+            country: "US".to_string(),
+        };
+        for (address, network) in [
+            ("203.0.113.7", Some(Ok(network(64500, "203.0.113.0/24")))),
+            ("203.0.113.9", Some(Ok(network(64500, "203.0.113.0/24")))),
+            ("198.51.100.20", Some(Ok(network(64501, "198.51.100.0/24")))),
+        ] {
+            let answer = PublicAddress {
+                address: address.parse().unwrap(),
+                network,
+            };
+            engine.record_public_ip(Ok(answer)).await;
+        }
+        let outputs = engine.take_outputs();
+        let changes: Vec<String> = outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::Event(event) if event.name == "isp_changed" => Some(event.details.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            changes,
+            [
+                "public address moved from 203.0.113.0/24 (AS64500 ISP64500, US (US)) to \
+              198.51.100.0/24 (AS64501 ISP64501, US (US))"
+            ]
+        );
+        let subjects: Vec<&str> = outputs
+            .iter()
+            .filter_map(|output| match output {
+                Output::Notification(notification) => Some(notification.subject.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(subjects, ["ISP changed: now AS64501 ISP64501, US (US)"]);
+    }
+
+    #[test]
+    fn local_address_changes_list_what_was_added_and_removed() {
+        let addresses = |list: &[&str]| -> Vec<IpAddr> {
+            list.iter().map(|addr| addr.parse().unwrap()).collect()
+        };
+        assert_eq!(
+            address_changes(
+                &addresses(&["192.0.2.2", "fe80::1"]),
+                &addresses(&["192.0.2.3", "fe80::1"])
+            ),
+            "added 192.0.2.3, removed 192.0.2.2"
+        );
+        assert_eq!(
+            address_changes(&[], &addresses(&["10.8.0.2"])),
+            "added 10.8.0.2"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn late_replies_are_logged_but_still_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 1, vec![ms(10), ms(1500), ms(10)]).await;
+        let mut stats = None;
+        for _ in 0..3 {
+            stats = Some(ping_on_schedule(&mut engine, &clock).await);
+        }
+        let stats = stats.unwrap();
+        assert_eq!((stats.received, stats.late_replies), (2, 1));
+        let events: Vec<String> = engine
+            .take_outputs()
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Event(event) if event.name == "late_reply" => Some(event.details),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events, ["late reply (1500 ms) to probe 1 from 192.0.2.1"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn summary_reports_rtt_deviation_and_outages() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 1, vec![ms(10), None, ms(20), None, None]).await;
+        for _ in 0..5 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+        let summary = engine.summary("interrupted");
+        assert_eq!((summary.transmitted, summary.received), (5, 2));
+        assert_eq!(summary.outages, 2);
+        assert_eq!(summary.rtt_avg_ms, Some(15.0));
+        assert!((summary.rtt_mdev_ms.unwrap() - 5.0).abs() < 1e-6);
+        engine.write_summary(&summary).await.unwrap();
+        let entry = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("summary_"))
+            .unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(entry.path()).unwrap()).unwrap();
+        assert_eq!(written["exit_reason"], "interrupted");
+        assert_eq!(written["outages"], 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_is_in_the_summary_and_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(10)]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        let download = |bytes, secs| {
+            Ok(Throughput {
+                bytes,
+                elapsed: Duration::from_secs(secs),
+            })
+        };
+        engine.record_throughput(download(5_000_000, 1)).await;
+        engine.record_throughput(Err("timed out".to_string())).await;
+        engine.record_throughput(download(10_000_000, 1)).await;
+        let summary = engine.summary("interrupted");
+        assert_eq!(summary.throughput_min_mbps, Some(40.0));
+        assert_eq!(summary.throughput_avg_mbps, Some(60.0));
+        assert_eq!(summary.throughput_max_mbps, Some(80.0));
+        assert!(summary
+            .to_string()
+            .contains("throughput min/avg/max = 40.000/60.000/80.000 Mbps"));
+        engine.flush().await;
+        let sla = crate::report::Sla {
+            availability: None,
+            p95: None,
+        };
+        let report = crate::report::run(dir.path(), &sla).unwrap();
+        assert!(report.contains("Mbps"));
+        assert!(report.lines().nth(1).unwrap().contains("     60.0 "));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_carries_on_from_the_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(10), None, ms(20)]).await;
+        for _ in 0..3 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+
+        // Restarted 20s after the target came back up
+        let restart_dir = tempfile::tempdir().unwrap();
+        std::fs::copy(
+            dir.path().join(STATE_FILE_NAME),
+            restart_dir.path().join(STATE_FILE_NAME),
+        )
+        .unwrap();
+        let (mut engine, clock) = scripted_engine(restart_dir.path(), 1, vec![ms(30)]).await;
+        clock.jump(time::Duration::seconds(40));
+        engine.resume().await;
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert_eq!((stats.sent, stats.received, stats.outages), (4, 3, 1));
+        assert_eq!((stats.min_rtt, stats.max_rtt), (ms(10), ms(30)));
+        assert!(stats.state == LinkState::Up);
+        assert_eq!(
+            stats.state_since,
+            Some(clock.instant() - DELAY - Duration::from_secs(20))
+        );
+        assert!(stats.last_transition.unwrap().from == LinkState::Down);
+        assert!(stats.runtime >= DELAY * 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resume_notices_a_reboot_of_the_host() {
+        // Only platforms with a boot ID can tell
+        if host::boot_id().is_none() {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(10)]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        let mut state_file = StateFile::read(dir.path()).unwrap();
+        state_file.boot_id = Some("previous-boot".to_string());
+        let restart_dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            restart_dir.path().join(STATE_FILE_NAME),
+            state_file.to_json(),
+        )
+        .unwrap();
+
+        let (mut engine, _) = scripted_engine(restart_dir.path(), 1, vec![]).await;
+        engine.resume().await;
+        let events: Vec<String> = engine
+            .take_outputs()
+            .into_iter()
+            .filter_map(|output| match output {
+                Output::Event(event) => Some(event.name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events, ["host_rebooted", "resumed"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lifetime_statistics_add_up_across_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 1, vec![ms(5), None, None, ms(5)]).await;
+        for _ in 0..4 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+        engine.write_state_file().await;
+
+        let next_dir = tempfile::tempdir().unwrap();
+        std::fs::copy(
+            dir.path().join(lifetime::LIFETIME_FILE_NAME),
+            next_dir.path().join(lifetime::LIFETIME_FILE_NAME),
+        )
+        .unwrap();
+        let (mut engine, clock) = scripted_engine(next_dir.path(), 1, vec![ms(5)]).await;
+        let lifetime = ping_on_schedule(&mut engine, &clock)
             .await
+            .lifetime
             .unwrap();
+        assert_eq!(lifetime.runs, 2);
+        assert_eq!(lifetime.monitored_s, 50.0);
+        assert_eq!((lifetime.downtime_s, lifetime.worst_outage_s), (20.0, 20.0));
+        assert_eq!(lifetime.uptime_percent(), Some(60.0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn definitive_failures_skip_the_down_after_threshold() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 3, vec![ms(5)]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        let timeout = Err(SurgeError::Timeout {
+            seq: surge_ping::PingSequence(1),
+        });
+        let no_route = Err(SurgeError::IOError(std::io::Error::from(
+            std::io::ErrorKind::HostUnreachable,
+        )));
+        assert!(
+            !engine.is_definitive(&no_route),
+            "only with --down-on-no-route"
+        );
+        engine.down_on_no_route = true;
+        let definitive = engine.is_definitive(&timeout);
+        engine.update_state(false, definitive, false, clock.now(), clock.instant());
+        assert!(
+            engine.stats.state == LinkState::Up,
+            "a timeout waits for down-after"
+        );
+        let definitive = engine.is_definitive(&no_route);
+        engine.update_state(false, definitive, false, clock.now(), clock.instant());
+        assert!(engine.stats.state == LinkState::Down);
     }
 
-    pub fn get_last_successful_latency(&self) -> Duration {
-        self.last_successful_latency.unwrap()
+    #[tokio::test(start_paused = true)]
+    async fn state_goes_down_after_consecutive_failures() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 2, vec![ms(5), None, None, ms(7)]).await;
+
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Up);
+        let first_failure = clock.instant();
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Up, "one failure is not an outage");
+
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Down);
+        // The outage is dated from the first failure of the streak
+        assert_eq!(stats.state_since, Some(first_failure));
+        let transition = stats.last_transition.unwrap();
+        assert!(transition.from == LinkState::Up && transition.to == LinkState::Down);
+
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Up);
+        let transition = stats.last_transition.unwrap();
+        assert!(transition.from == LinkState::Down && transition.to == LinkState::Up);
+        assert_eq!((stats.sent, stats.received), (4, 2));
+        assert_eq!(stats.min_rtt, ms(5));
+        assert_eq!(stats.max_rtt, ms(7));
+        assert_eq!(stats.avg_rtt(), ms(6));
+        assert_eq!(stats.runtime, DELAY * 4);
     }
 
-    pub fn get_possible_last_successful_time(&self) -> Option<OffsetDateTime> {
-        self.last_successful_time
+    #[tokio::test(start_paused = true)]
+    async fn alerts_escalate_during_an_outage_and_report_recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 1, vec![ms(5), None, None, None, None, ms(5)]).await;
+        engine.alerts = vec![
+            "desktop:1".parse().unwrap(),
+            "email:25s:a@example.com".parse().unwrap(),
+        ];
+        engine.alerts_fired = vec![false; 2];
+        let mut subjects = Vec::new();
+        for _ in 0..6 {
+            ping_on_schedule(&mut engine, &clock).await;
+            let notifications = take_notifications(&mut engine);
+            subjects.push(
+                notifications
+                    .iter()
+                    .map(|notification| {
+                        format!("{}: {}", notification.channel.name(), notification.subject)
+                    })
+                    .collect::<Vec<_>>(),
+            );
+        }
+        let down = "192.0.2.1 is down";
+        let up = "192.0.2.1 is back up";
+        assert_eq!(
+            subjects,
+            [
+                vec![],
+                vec![format!("desktop: {down}")],
+                vec![],
+                // The third failure is 20s after the first, the fourth 30s
+                vec![],
+                vec![format!("email: {down}")],
+                vec![format!("desktop: {up}"), format!("email: {up}")],
+            ]
+        );
     }
 
-    pub fn get_possible_last_failed_time(&self) -> Option<OffsetDateTime> {
-        self.last_failed_time
+    #[tokio::test(start_paused = true)]
+    async fn repeated_alerts_from_a_flapping_link_are_suppressed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 1, vec![ms(5), None, ms(5), None, None, ms(5)]).await;
+        engine.alerts = vec!["desktop:1".parse().unwrap()];
+        engine.alerts_fired = vec![false];
+        engine.alerts_suppressed = vec![false];
+        engine.alert_limiter = AlertLimiter::new(Vec::new(), Duration::from_secs(15 * 60));
+        let mut sent = Vec::new();
+        for _ in 0..6 {
+            ping_on_schedule(&mut engine, &clock).await;
+            sent.push(take_notifications(&mut engine).len());
+        }
+        // Neither the repeated down alert nor its recovery is sent
+        assert_eq!(sent, [0, 1, 1, 0, 0, 0]);
+        let events: Vec<String> = read_rows(dir.path(), "events_")
+            .into_iter()
+            .map(|row| row[1].clone())
+            .collect();
+        assert_eq!(events, ["alert", "alert", "alert_suppressed"]);
     }
 
-    /// Return the internal IpAddr used for pinging.
-    pub fn get_processed_ip(&self) -> IpAddr {
-        self.ip_addr
+    #[tokio::test(start_paused = true)]
+    async fn window_alerts_fire_on_breach_and_clear() {
+        let dir = tempfile::tempdir().unwrap();
+        let replies = vec![ms(5), ms(5), ms(5), None, None, ms(5), ms(5), ms(5), ms(5)];
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, replies).await;
+        engine.window_alerts = vec!["loss>20%/30s:desktop".parse().unwrap()];
+        engine.window_breached = vec![false];
+        engine.window_suppressed = vec![false];
+        let mut subjects = Vec::new();
+        for _ in 0..9 {
+            ping_on_schedule(&mut engine, &clock).await;
+            subjects.extend(
+                take_notifications(&mut engine)
+                    .into_iter()
+                    .map(|notification| notification.subject),
+            );
+        }
+        // Evaluated once 30s of results exist: 1 of 4 failed at 30s, none of 4 at 80s
+        assert_eq!(
+            subjects,
+            [
+                "192.0.2.1: loss over the last 30s above 20%",
+                "192.0.2.1: no longer loss over the last 30s above 20%",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wall_clock_changes_are_logged_as_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![None, ms(1), ms(1)]).await;
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Down);
+
+        // A small step (e.g. NTP) is only noted
+        clock.jump(time::Duration::seconds(5));
+        ping_on_schedule(&mut engine, &clock).await;
+        // A jump of at least one delay means the host was suspended, which isn't downtime
+        clock.jump(time::Duration::hours(1));
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Up);
+        assert!(stats.last_transition.unwrap().from == LinkState::Unknown);
+
+        let events: Vec<String> = read_rows(dir.path(), "events_")
+            .into_iter()
+            .map(|row| row[1].clone())
+            .collect();
+        assert_eq!(events, ["clock_jump", "monitoring_gap"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ticks_sent_in_a_burst_after_a_gap_log_it_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![ms(1); 5]).await;
+        ping_on_schedule(&mut engine, &clock).await;
+        // The loop was held up for three delays, and the ticks missed meanwhile follow at once
+        let first_missed = clock.instant();
+        tokio::time::advance(DELAY * 3).await;
+        for tick in 0..4 {
+            let _ = engine.ping(first_missed + DELAY * tick).await;
+        }
+        let events: Vec<String> = read_rows(dir.path(), "events_")
+            .into_iter()
+            .map(|row| row[1].clone())
+            .collect();
+        assert_eq!(events, ["monitoring_gap"]);
+        assert_eq!(engine.stats().drift.ticks, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn outages_are_published_as_they_start_and_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) =
+            scripted_engine(dir.path(), 2, vec![ms(1), None, None, ms(1)]).await;
+        for _ in 0..4 {
+            ping_on_schedule(&mut engine, &clock).await;
+        }
+        let outputs: Vec<String> = engine
+            .take_outputs()
+            .into_iter()
+            .map(|output| match output {
+                Output::StateChanged(transition) => {
+                    format!("{} -> {}", transition.from.label(), transition.to.label())
+                }
+                Output::OutageStarted { at } => format!("started {}", at.second()),
+                Output::OutageEnded { duration, .. } => format!("ended {}s", duration.as_secs()),
+                _ => "other".to_string(),
+            })
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                "UNKNOWN -> UP",
+                "started 10",
+                "UP -> DOWN",
+                "ended 20s",
+                "DOWN -> UP"
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_pause_is_not_counted_as_downtime() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut engine, clock) = scripted_engine(dir.path(), 1, vec![None, ms(1)]).await;
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.state == LinkState::Down);
+
+        engine.pause_probing().await;
+        assert!(engine.is_paused());
+        tokio::time::advance(Duration::from_secs(3600)).await;
+        engine.resume_probing().await;
+        let stats = ping_on_schedule(&mut engine, &clock).await;
+        assert!(stats.last_transition.unwrap().from == LinkState::Unknown);
+        assert_eq!(stats.outages, 1);
+        assert!(engine.unmonitored >= Duration::from_secs(3600));
+
+        let events: Vec<String> = read_rows(dir.path(), "events_")
+            .into_iter()
+            .map(|row| row[1].clone())
+            .collect();
+        assert_eq!(events, ["probing_paused", "probing_resumed"]);
     }
 }
@@ -0,0 +1,135 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::alert;
+use crate::pipeline::Output;
+use crate::tui::format_duration;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+
+/// Source (or tag, on macOS) the entries are logged under.
+const SOURCE: &str = "num";
+
+/// An entry of the Windows Application log or the macOS unified log.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    /// Event ID, for filters and triggers in Windows tooling
+    id: u16,
+    /// `ERROR`, `WARNING` or `INFORMATION`
+    level: &'static str,
+    description: String,
+}
+
+/// The entry to log for `output` of the monitor of `target`, if any. The event IDs are:
+/// 1 target DOWN, 2 target back UP, 3 internet DOWN, 4 internet back UP.
+fn entry(output: &Output, target: &str) -> Option<Entry> {
+    let (id, level, description) = match output {
+        Output::OutageStarted { .. } => (1, "ERROR", format!("{target} is DOWN")),
+        Output::OutageEnded { duration, .. } => (
+            2,
+            "INFORMATION",
+            format!("{target} is back UP after {}", format_duration(*duration)),
+        ),
+        Output::Event(event) if event.name == "internet_down" => {
+            (3, "ERROR", format!("Internet is DOWN: {}", event.details))
+        }
+        Output::Event(event) if event.name == "internet_up" => (
+            4,
+            "INFORMATION",
+            format!("Internet is UP: {}", event.details),
+        ),
+        _ => return None,
+    };
+    Some(Entry {
+        id,
+        level,
+        description,
+    })
+}
+
+/// Consumer writing the outages and recoveries of `target` to the Windows Application event log
+/// with `eventcreate`, or to the unified log with `logger` on macOS, reporting failed writes to
+/// `failures`.
+pub async fn record(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    while let Some(output) = outputs.recv().await {
+        let Some(entry) = entry(&output, &target) else {
+            continue;
+        };
+        let mut command = if cfg!(target_os = "macos") {
+            let priority = if entry.level == "ERROR" {
+                "user.err"
+            } else {
+                "user.notice"
+            };
+            let mut command = Command::new("logger");
+            command
+                .args(["-t", SOURCE, "-p", priority])
+                .arg(format!("{} (event {})", entry.description, entry.id));
+            command
+        } else {
+            let mut command = Command::new("eventcreate");
+            command
+                .args(["/L", "APPLICATION", "/SO", SOURCE, "/T", entry.level])
+                .args(["/ID", &entry.id.to_string(), "/D", &entry.description]);
+            command
+        };
+        let result = alert::run(&mut command, None).await;
+        if let Err(e) = result {
+            let _ = failures.send(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{local_now, Event};
+    use std::time::Duration;
+
+    #[test]
+    fn outages_and_recoveries_get_their_event_ids() {
+        let at = local_now();
+        let event = |name: &str| {
+            Output::Event(Event {
+                time: at,
+                name: name.to_string(),
+                details: "all 2 targets are DOWN".to_string(),
+            })
+        };
+        let logged =
+            |output: Output| entry(&output, "1.1.1.1").map(|entry| (entry.id, entry.level));
+        assert_eq!(logged(Output::OutageStarted { at }), Some((1, "ERROR")));
+        assert_eq!(
+            entry(
+                &Output::OutageEnded {
+                    at,
+                    duration: Duration::from_secs(272),
+                },
+                "1.1.1.1"
+            )
+            .map(|entry| entry.description),
+            Some("1.1.1.1 is back UP after 4m32s".to_string())
+        );
+        assert_eq!(logged(event("internet_down")), Some((3, "ERROR")));
+        assert_eq!(logged(event("internet_up")), Some((4, "INFORMATION")));
+        assert_eq!(logged(event("clock_jump")), None);
+    }
+}
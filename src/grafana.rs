@@ -0,0 +1,143 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use serde_json::{json, Value};
+
+/// Dashboard panels as title, panel type, unit and PromQL queries over the exported metrics
+/// (see `metrics::METRICS`).
+const PANELS: [(&str, &str, &str, &[&str]); 4] = [
+    ("State", "stat", "none", &["num_up{target=~\"$target\"}"]),
+    (
+        "Latency",
+        "timeseries",
+        "s",
+        &[
+            "num_rtt_seconds{target=~\"$target\"}",
+            "num_rtt_avg_seconds{target=~\"$target\"}",
+        ],
+    ),
+    (
+        "Packet loss",
+        "timeseries",
+        "percentunit",
+        &[
+            "1 - rate(num_probes_received_total{target=~\"$target\"}[$__rate_interval]) \
+           / rate(num_probes_sent_total{target=~\"$target\"}[$__rate_interval])",
+        ],
+    ),
+    (
+        "Outages",
+        "stat",
+        "none",
+        &["increase(num_outages_total{target=~\"$target\"}[$__range])"],
+    ),
+];
+
+/// A Grafana dashboard of the metrics `num` exports, to import with a Prometheus data source
+/// chosen at import time.
+pub fn dashboard() -> Value {
+    let datasource = json!({"type": "prometheus", "uid": "${DS_PROMETHEUS}"});
+    let panels: Vec<Value> = PANELS
+        .iter()
+        .enumerate()
+        .map(|(index, (title, kind, unit, queries))| {
+            let targets: Vec<Value> = queries
+                .iter()
+                .zip('A'..)
+                .map(|(query, ref_id)| {
+                    json!({
+                        "datasource": datasource,
+                        "expr": query,
+                        "legendFormat": "{{target}} ({{probe_name}})",
+                        "refId": ref_id.to_string(),
+                    })
+                })
+                .collect();
+            let mut panel = json!({
+                "id": index + 1,
+                "title": title,
+                "type": kind,
+                "datasource": datasource,
+                "gridPos": {"h": 8, "w": 12, "x": 12 * (index % 2), "y": 8 * (index / 2)},
+                "fieldConfig": {"defaults": {"unit": unit}, "overrides": []},
+                "targets": targets,
+            });
+            if *title == "State" {
+                panel["fieldConfig"]["defaults"]["mappings"] = json!([{
+                    "type": "value",
+                    "options": {
+                        "0": {"text": "DOWN", "color": "red"},
+                        "1": {"text": "UP", "color": "green"},
+                    },
+                }]);
+            }
+            panel
+        })
+        .collect();
+    json!({
+        "__inputs": [{
+            "name": "DS_PROMETHEUS",
+            "label": "Prometheus",
+            "type": "datasource",
+            "pluginId": "prometheus",
+            "pluginName": "Prometheus",
+        }],
+        "title": "num",
+        "uid": "num-uptime",
+        "tags": ["num"],
+        "timezone": "browser",
+        "schemaVersion": 39,
+        "time": {"from": "now-24h", "to": "now"},
+        "refresh": "1m",
+        "templating": {"list": [{
+            "name": "target",
+            "label": "Target",
+            "type": "query",
+            "datasource": datasource,
+            "query": "label_values(num_up, target)",
+            "refresh": 2,
+            "includeAll": true,
+            "multi": true,
+            "current": {"text": "All", "value": "$__all"},
+        }]},
+        "panels": panels,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::METRICS;
+
+    #[test]
+    fn panels_only_query_exported_metrics() {
+        let dashboard = dashboard();
+        for panel in dashboard["panels"].as_array().unwrap() {
+            for target in panel["targets"].as_array().unwrap() {
+                let expr = target["expr"].as_str().unwrap();
+                let names: Vec<&str> = expr
+                    .split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .filter(|word| word.starts_with("num_"))
+                    .collect();
+                assert!(!names.is_empty(), "{expr}");
+                for name in names {
+                    assert!(METRICS.iter().any(|(metric, ..)| *metric == name), "{name}");
+                }
+            }
+        }
+    }
+}
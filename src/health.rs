@@ -0,0 +1,153 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::LinkState;
+use crate::host;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Name of the state file the monitor keeps in its output directory.
+pub const STATE_FILE_NAME: &str = "state.json";
+
+// How often the state file is refreshed while the state is unchanged
+pub const STATE_FILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Current state of a running monitor, written to `state.json` in the output directory so that
+/// `num healthcheck` can report on it.
+#[derive(Serialize, Deserialize)]
+pub struct StateFile {
+    /// Process ID of the monitor
+    pub pid: u32,
+    pub target: String,
+    /// ID of the run, missing in files written by older versions
+    #[serde(default)]
+    pub run_id: String,
+    /// "unknown", "up", "down", or "captive"
+    pub state: String,
+    /// When the file was written (UNIX seconds)
+    pub updated: u64,
+    /// Time after which the file is stale if it hasn't been rewritten (UNIX seconds)
+    pub expires: u64,
+    /// Statistics for `--resume`, missing in files written by older versions
+    pub checkpoint: Option<Checkpoint>,
+    /// Boot ID of the host, to tell a reboot from a restart of the monitor when resuming
+    #[serde(default)]
+    pub boot_id: Option<String>,
+}
+
+/// Statistics of a monitor, saved so a restarted monitor can carry on from them with `--resume`.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub sent: u64,
+    pub received: u64,
+    pub rtt_min_ms: Option<f64>,
+    pub rtt_max_ms: Option<f64>,
+    /// Sum of the round trip times of all successful pings
+    pub rtt_total_ms: f64,
+    /// Sum of the squared round trip times of all successful pings (s²)
+    pub rtt_total_squared: f64,
+    pub outages: u64,
+    pub runtime_s: f64,
+    /// When the current state was entered (UNIX seconds)
+    pub state_since: Option<i64>,
+    pub last_transition: Option<CheckpointTransition>,
+}
+
+/// The last UP/DOWN transition of a `Checkpoint`, with states named as in `StateFile`.
+#[derive(Serialize, Deserialize)]
+pub struct CheckpointTransition {
+    pub from: String,
+    pub to: String,
+    /// UNIX seconds
+    pub at: i64,
+}
+
+impl StateFile {
+    /// Describe the monitor's state as of now, expecting the next update within `max_interval`.
+    pub fn new(
+        target: &str,
+        state: LinkState,
+        run_id: &str,
+        max_interval: Duration,
+        checkpoint: Checkpoint,
+    ) -> Self {
+        let updated = unix_now();
+        StateFile {
+            pid: std::process::id(),
+            target: target.to_string(),
+            run_id: run_id.to_string(),
+            state: state.label().to_lowercase(),
+            updated,
+            // Allow one missed update before calling the monitor stuck
+            expires: updated + 2 * max_interval.as_secs().max(1),
+            checkpoint: Some(checkpoint),
+            boot_id: host::boot_id(),
+        }
+    }
+
+    /// Read the state file in `output_path`.
+    pub fn read(output_path: &Path) -> Result<Self, String> {
+        let path = output_path.join(STATE_FILE_NAME);
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid state file {}: {e}", path.display()))
+    }
+
+    /// Return whether the monitor has rewritten the file as often as it promised.
+    pub fn is_fresh(&self) -> bool {
+        unix_now() <= self.expires
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Check the state file in `output_path`. The monitor is healthy if the file is fresh and the
+/// target isn't down or behind a captive portal; a stale file means the monitor has stopped or its ping loop is stuck.
+/// Returns a description of the state either way.
+pub fn check(output_path: &Path) -> Result<String, String> {
+    let state_file = StateFile::read(output_path)?;
+    let now = unix_now();
+    if now > state_file.expires {
+        return Err(format!(
+            "Monitor (pid {}) last reported {}s ago",
+            state_file.pid,
+            now.saturating_sub(state_file.updated)
+        ));
+    }
+    let description = format!(
+        "{} is {}",
+        state_file.target,
+        state_file.state.to_uppercase()
+    );
+    if state_file.state == "down" || state_file.state == "captive" {
+        Err(description)
+    } else {
+        Ok(description)
+    }
+}
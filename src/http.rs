@@ -0,0 +1,151 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::probe::split_host_port;
+use std::io;
+use std::sync::{Arc, OnceLock};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{crypto, ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// A connection to an HTTP server, encrypted or not.
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// An `http://` or `https://host[:port][/path]` URL, fetched with a minimal HTTP/1.1 client.
+#[derive(Clone, PartialEq)]
+pub struct HttpUrl {
+    url: String,
+    /// Host and port as given, for the Host header
+    authority: String,
+    host: String,
+    port: u16,
+    path: String,
+    tls: bool,
+}
+
+impl HttpUrl {
+    /// Parse an `http://` or `https://` URL.
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid URL \"{url}\" (expected http(s)://host/path)");
+        let (rest, tls) = match url.strip_prefix("https://") {
+            Some(rest) => (rest, true),
+            None => (url.strip_prefix("http://").ok_or_else(invalid)?, false),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let default_port = if tls { 443 } else { 80 };
+        let (host, port) = split_host_port(authority, default_port).map_err(|_| invalid())?;
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(HttpUrl {
+            url: url.to_string(),
+            authority: authority.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            tls,
+        })
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.tls
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.url
+    }
+
+    /// Send a GET request and read the status line, returning it along with the connection
+    /// positioned at the response headers.
+    pub async fn get(&self) -> io::Result<(String, BufReader<Box<dyn Connection>>)> {
+        self.request("GET", None).await
+    }
+
+    /// Send a POST request with `body` of type `content_type`, returning the status line.
+    pub async fn post(&self, content_type: &str, body: &[u8]) -> io::Result<String> {
+        let (status_line, _) = self.request("POST", Some((content_type, body))).await?;
+        Ok(status_line)
+    }
+
+    async fn request(
+        &self,
+        method: &str,
+        body: Option<(&str, &[u8])>,
+    ) -> io::Result<(String, BufReader<Box<dyn Connection>>)> {
+        let mut stream = self.connect().await?;
+        let mut request = format!(
+            "{method} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: num\r\nConnection: close\r\n",
+            self.path, self.authority
+        );
+        if let Some((content_type, body)) = body {
+            request.push_str(&format!(
+                "Content-Type: {content_type}\r\nContent-Length: {}\r\n",
+                body.len()
+            ));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+        if let Some((_, body)) = body {
+            stream.write_all(body).await?;
+        }
+        stream.flush().await?;
+        let mut stream = BufReader::new(stream);
+        let mut status_line = String::new();
+        stream.read_line(&mut status_line).await?;
+        Ok((status_line.trim().to_string(), stream))
+    }
+
+    async fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        if !self.tls {
+            return Ok(Box::new(stream));
+        }
+        let server_name = ServerName::try_from(self.host.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = tls_connector().connect(server_name, stream).await?;
+        Ok(Box::new(stream))
+    }
+}
+
+/// Connector verifying servers against the bundled Mozilla root certificates, built on first use.
+fn tls_connector() -> &'static TlsConnector {
+    static CONNECTOR: OnceLock<TlsConnector> = OnceLock::new();
+    CONNECTOR.get_or_init(|| {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    })
+}
+
+/// Return the status code of an HTTP status line such as `HTTP/1.1 204 No Content`.
+pub fn status_code(status_line: &str) -> &str {
+    status_line.split_whitespace().nth(1).unwrap_or("")
+}
@@ -0,0 +1,108 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+/// Error and drop counters of an interface, in the order they are logged.
+pub const ERROR_COUNTERS: [&str; 4] = ["rx_errors", "tx_errors", "rx_dropped", "tx_dropped"];
+
+/// Find the name of the network interface traffic to `target` leaves through, by asking the
+/// kernel to route a (never used) UDP socket and looking up the interface with the chosen source
+/// address.
+pub fn egress_interface(target: IpAddr) -> io::Result<String> {
+    let local: SocketAddr = match target {
+        IpAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+        IpAddr::V6(_) => ([0u16; 8], 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect((target, 9))?;
+    let source = socket.local_addr()?.ip();
+    interface_addresses()?
+        .into_iter()
+        .find(|(_, addr)| *addr == source)
+        .map(|(name, _)| name)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no interface has the source address {source}"),
+            )
+        })
+}
+
+/// List the IPv4 and IPv6 addresses assigned to `interface`, sorted.
+pub fn addresses(interface: &str) -> io::Result<Vec<IpAddr>> {
+    let mut addresses: Vec<IpAddr> = interface_addresses()?
+        .into_iter()
+        .filter(|(name, _)| name == interface)
+        .map(|(_, addr)| addr)
+        .collect();
+    addresses.sort();
+    Ok(addresses)
+}
+
+/// List every IPv4 and IPv6 address with the name of the interface it is assigned to.
+fn interface_addresses() -> io::Result<Vec<(String, IpAddr)>> {
+    let mut found = Vec::new();
+    // SAFETY: the list returned by getifaddrs is only read while alive and freed once
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut current = addrs;
+        while !current.is_null() {
+            let ifaddr = &*current;
+            current = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null() {
+                continue;
+            }
+            let addr = match i32::from((*ifaddr.ifa_addr).sa_family) {
+                libc::AF_INET => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    IpAddr::from(u32::from_be(addr.sin_addr.s_addr).to_be_bytes())
+                }
+                libc::AF_INET6 => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in6);
+                    IpAddr::from(addr.sin6_addr.s6_addr)
+                }
+                _ => continue,
+            };
+            let name = CStr::from_ptr(ifaddr.ifa_name)
+                .to_string_lossy()
+                .into_owned();
+            found.push((name, addr));
+        }
+        libc::freeifaddrs(addrs);
+    }
+    Ok(found)
+}
+
+/// Read the `ERROR_COUNTERS` of `interface` from sysfs.
+pub fn error_counters(interface: &str) -> io::Result<[u64; 4]> {
+    let mut counters = [0; 4];
+    for (counter, name) in counters.iter_mut().zip(ERROR_COUNTERS) {
+        *counter =
+            std::fs::read_to_string(format!("/sys/class/net/{interface}/statistics/{name}"))?
+                .trim()
+                .parse()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{name}: {e}")))?;
+    }
+    Ok(counters)
+}
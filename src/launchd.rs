@@ -0,0 +1,133 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::alert;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+pub const DEFAULT_LABEL: &str = "com.github.adih-20.num";
+
+/// A LaunchAgent running the monitor whenever the user is logged in.
+pub struct Agent {
+    pub label: String,
+    /// The `num` executable and its arguments
+    pub program: Vec<String>,
+    /// Directory relative paths in the arguments are resolved from
+    pub working_directory: PathBuf,
+    /// File the NDJSON logs and errors go to
+    pub log_path: PathBuf,
+}
+
+impl Agent {
+    pub fn new(label: &str, program: Vec<String>, working_directory: PathBuf, home: &Path) -> Self {
+        Agent {
+            label: label.to_string(),
+            program,
+            working_directory,
+            log_path: home.join("Library/Logs").join(format!("{label}.log")),
+        }
+    }
+
+    /// Render the agent as a property list, restarted by launchd if it exits.
+    pub fn plist(&self) -> String {
+        let arguments: String = self
+            .program
+            .iter()
+            .map(|arg| format!("        <string>{}</string>\n", escape(arg)))
+            .collect();
+        let log_path = escape(&self.log_path.to_string_lossy());
+        format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>WorkingDirectory</key>
+    <string>{}</string>
+    <key>RunAtLoad</key>
+    <true/>
+    <key>KeepAlive</key>
+    <true/>
+    <key>StandardOutPath</key>
+    <string>{log_path}</string>
+    <key>StandardErrorPath</key>
+    <string>{log_path}</string>
+</dict>
+</plist>
+"#,
+            escape(&self.label),
+            escape(&self.working_directory.to_string_lossy()),
+        )
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Write the agent to `~/Library/LaunchAgents`, replacing an earlier version, and load it with
+/// `launchctl` unless `load` is false. Returns the path of the plist.
+pub async fn install(agent: &Agent, home: &Path, load: bool) -> Result<PathBuf, String> {
+    let dir = home.join("Library/LaunchAgents");
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+    let path = dir.join(format!("{}.plist", agent.label));
+    if load && path.exists() {
+        // The old version keeps running until unloaded
+        let _ = alert::run(Command::new("launchctl").arg("unload").arg(&path), None).await;
+    }
+    tokio::fs::write(&path, agent.plist())
+        .await
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+    if load {
+        alert::run(
+            Command::new("launchctl").args(["load", "-w"]).arg(&path),
+            None,
+        )
+        .await
+        .map_err(|e| format!("Could not load {}: {e}", path.display()))?;
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plist_runs_the_monitor_with_its_arguments() {
+        let program = ["/usr/local/bin/num", "1.1.1.1", "-o", "a&b"].map(str::to_string);
+        let agent = Agent::new(
+            DEFAULT_LABEL,
+            program.to_vec(),
+            PathBuf::from("/Users/me"),
+            Path::new("/Users/me"),
+        );
+        let plist = agent.plist();
+        assert!(plist.contains("<string>a&amp;b</string>\n    </array>"));
+        assert!(
+            plist.contains("<string>/Users/me/Library/Logs/com.github.adih-20.num.log</string>")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::atomic_write::write_atomic;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+/// Name of the file in the output directory holding the lifetime statistics of every target.
+pub const LIFETIME_FILE_NAME: &str = "lifetime.json";
+
+/// Statistics of a target accumulated over every run that monitored it.
+#[derive(Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Lifetime {
+    pub monitored_s: f64,
+    /// Time spent DOWN
+    pub downtime_s: f64,
+    /// Length of the longest outage
+    pub worst_outage_s: f64,
+    pub runs: u64,
+}
+
+impl Lifetime {
+    /// Return the share of the monitored time the target wasn't down.
+    pub fn uptime_percent(&self) -> Option<f64> {
+        (self.monitored_s > 0.0)
+            .then(|| (1.0 - self.downtime_s / self.monitored_s).clamp(0.0, 1.0) * 100.0)
+    }
+
+    /// Describe the statistics, e.g. "99.812% over 40d03h10m in 12 run(s), worst outage 12m03s".
+    pub fn describe(&self) -> String {
+        let duration = |secs: f64| format_days(Duration::from_secs_f64(secs.max(0.0)));
+        format!(
+            "{} over {} in {} run(s), worst outage {}",
+            self.uptime_percent()
+                .map_or("-".to_string(), |uptime| format!("{uptime:.3}%")),
+            duration(self.monitored_s),
+            self.runs,
+            duration(self.worst_outage_s)
+        )
+    }
+}
+
+/// Format a possibly long duration, e.g. `40d03h10m`, falling back to the TUI format below a day.
+fn format_days(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 86400 {
+        format!(
+            "{}d{:02}h{:02}m",
+            secs / 86400,
+            secs / 3600 % 24,
+            secs / 60 % 60
+        )
+    } else {
+        crate::tui::format_duration(duration)
+    }
+}
+
+/// Read the lifetime statistics of every target from `output_path`, or none if there are none yet.
+pub fn read(output_path: &Path) -> Result<BTreeMap<String, Lifetime>, String> {
+    let path = output_path.join(LIFETIME_FILE_NAME);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid lifetime statistics {}: {e}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(format!("Could not read {}: {e}", path.display())),
+    }
+}
+
+/// Replace the lifetime statistics of `target` in `output_path`, keeping those of other targets.
+pub async fn update(output_path: &Path, target: &str, lifetime: Lifetime) -> Result<(), String> {
+    let mut lifetimes = read(output_path)?;
+    lifetimes.insert(target.to_string(), lifetime);
+    let path = output_path.join(LIFETIME_FILE_NAME);
+    write_atomic(&path, serde_json::to_string(&lifetimes).unwrap().as_ref())
+        .await
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))
+}
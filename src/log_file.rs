@@ -0,0 +1,514 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::encrypt::{Encryption, Recipient};
+use crate::sign::{Chain, Key};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+// Maximum number of rows held in memory while writes are failing; the oldest are dropped beyond this
+const MAX_PENDING_ROWS: usize = 10_000;
+
+// Bounds of the exponential backoff between write attempts after a failure
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// An append-only CSV log that buffers rows in memory until flushed. Write failures (disk full, NFS
+/// hiccups, etc.) keep the rows buffered and are retried with exponential backoff rather than
+/// crashing the monitor.
+pub struct LogFile {
+    path: PathBuf,
+    header: Vec<u8>,
+    file: File,
+    sync_writes: bool,
+    /// Whether the header and rows are lines of text, rather than binary records written as is
+    lines: bool,
+    pending: VecDeque<Vec<u8>>,
+    dropped_rows: u64,
+    last_flush: Instant,
+    consecutive_failures: u32,
+    next_retry: Option<Instant>,
+    last_error: Option<String>,
+    /// Key the file is encrypted to with `--encrypt`, to start another file with on rotation
+    recipient: Option<Recipient>,
+    encryption: Option<Encryption>,
+    /// Encrypted rows waiting to be written
+    ciphertext: Vec<u8>,
+    /// Length of the file as last written in full, to drop what a failed write left behind
+    written_len: u64,
+    /// Signatures appended after each write with `--sign`
+    chain: Option<Chain>,
+    /// Text written to the file that the next signature has to cover besides the rows
+    unsigned: Vec<u8>,
+    /// Bytes the next write gets out before failing, as when the disk fills up partway
+    #[cfg(test)]
+    fail_after: Option<usize>,
+}
+
+impl LogFile {
+    /// Create a new log at `path` with a header (the column names, possibly preceded by `#` comment
+    /// lines), encrypted to `recipient` if given. Fails if the file already exists.
+    pub async fn create(
+        path: PathBuf,
+        header: &str,
+        sync_writes: bool,
+        recipient: Option<&Recipient>,
+    ) -> io::Result<LogFile> {
+        let header = format!("{header}\n").into_bytes();
+        LogFile::create_with(path, header, sync_writes, true, recipient).await
+    }
+
+    /// Create a new log of binary records at `path`, starting with `header`, encrypted to
+    /// `recipient` if given. Fails if the file already exists.
+    pub async fn create_binary(
+        path: PathBuf,
+        header: Vec<u8>,
+        sync_writes: bool,
+        recipient: Option<&Recipient>,
+    ) -> io::Result<LogFile> {
+        LogFile::create_with(path, header, sync_writes, false, recipient).await
+    }
+
+    async fn create_with(
+        path: PathBuf,
+        header: Vec<u8>,
+        sync_writes: bool,
+        lines: bool,
+        recipient: Option<&Recipient>,
+    ) -> io::Result<LogFile> {
+        let mut new_file = File::options()
+            .create_new(true)
+            .write(true)
+            .open(&path)
+            .await?;
+        let mut encryption = recipient.map(Encryption::new).transpose()?;
+        let start = match &mut encryption {
+            Some(encryption) => encryption.encrypt(&header)?,
+            None => header.clone(),
+        };
+        new_file.write_all(&start).await?;
+        new_file.flush().await?;
+        let file = File::options().append(true).open(&path).await?;
+        Ok(LogFile {
+            path,
+            header,
+            file,
+            sync_writes,
+            lines,
+            pending: VecDeque::new(),
+            dropped_rows: 0,
+            last_flush: Instant::now(),
+            consecutive_failures: 0,
+            next_retry: None,
+            last_error: None,
+            recipient: recipient.cloned(),
+            encryption,
+            ciphertext: Vec::new(),
+            written_len: start.len() as u64,
+            chain: None,
+            unsigned: Vec::new(),
+            #[cfg(test)]
+            fail_after: None,
+        })
+    }
+
+    /// Sign the file with `key`, ending every write with a signature line covering everything
+    /// written before it. Only meant for logs of text, before any rows are written.
+    pub fn sign(&mut self, key: &Key) {
+        self.chain = Some(Chain::new(key));
+        self.unsigned = self.header.clone();
+    }
+
+    /// Change the header written if the file has to be recreated.
+    pub fn set_header(&mut self, header: impl Into<Vec<u8>>) {
+        self.header = header.into();
+        if self.lines {
+            self.header.push(b'\n');
+        }
+    }
+
+    /// Buffer a row (without trailing newline) or binary record to be written on the next flush.
+    pub fn push(&mut self, row: impl Into<Vec<u8>>) {
+        if self.pending.len() >= MAX_PENDING_ROWS {
+            self.pending.pop_front();
+            self.dropped_rows += 1;
+        }
+        self.pending.push_back(row.into());
+    }
+
+    /// Write all buffered rows to disk. While in backoff after a failure this is a no-op until the
+    /// next retry is due.
+    pub async fn flush(&mut self) {
+        if self.next_retry.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        self.write_out(false).await;
+    }
+
+    /// Write all buffered rows to disk as the run ends, also finishing the file if it's encrypted.
+    pub async fn close(&mut self) {
+        self.write_out(true).await;
+    }
+
+    async fn write_out(&mut self, finish: bool) {
+        let mut rows = Vec::new();
+        // Binary logs have no way to note the gap
+        if self.dropped_rows > 0 && self.lines {
+            rows.extend(
+                format!(
+                    "# {} rows dropped while storage was unavailable\n",
+                    self.dropped_rows
+                )
+                .bytes(),
+            );
+        }
+        for row in &self.pending {
+            rows.extend(row);
+            if self.lines {
+                rows.push(b'\n');
+            }
+        }
+        // The chain only moves on once the rows are written, so they're signed the same way when
+        // retried
+        let mut tag = None;
+        if let Some(chain) = self.chain.as_ref().filter(|_| !rows.is_empty()) {
+            let (signature, signed) = chain.sign(&[self.unsigned.as_slice(), &rows].concat());
+            rows.extend(signature.bytes());
+            tag = Some(signed);
+        }
+        if let Some(encryption) = &mut self.encryption {
+            // Rows are handed to the encryption at once, and what comes out kept until written
+            let encrypted = if rows.is_empty() {
+                Ok(Vec::new())
+            } else {
+                encryption.encrypt(&rows)
+            };
+            let encrypted = encrypted.and_then(|mut ciphertext| {
+                if finish {
+                    ciphertext.extend(encryption.finish()?);
+                }
+                Ok(ciphertext)
+            });
+            match encrypted {
+                Ok(ciphertext) => {
+                    self.ciphertext.extend(ciphertext);
+                    self.pending.clear();
+                    self.dropped_rows = 0;
+                    if let (Some(chain), Some(tag)) = (&mut self.chain, tag.take()) {
+                        chain.advance(tag);
+                        self.unsigned.clear();
+                    }
+                }
+                Err(e) => {
+                    self.record_failure(e);
+                    return;
+                }
+            }
+            rows = std::mem::take(&mut self.ciphertext);
+        }
+        if rows.is_empty() {
+            return;
+        }
+        match self.write(&rows).await {
+            Ok(()) => {
+                self.written_len += rows.len() as u64;
+                if self.encryption.is_none() {
+                    self.pending.clear();
+                    self.dropped_rows = 0;
+                    if let (Some(chain), Some(tag)) = (&mut self.chain, tag.take()) {
+                        chain.advance(tag);
+                        self.unsigned.clear();
+                    }
+                }
+                self.consecutive_failures = 0;
+                self.next_retry = None;
+                self.last_error = None;
+            }
+            Err(e) => {
+                // Writing part of the rows twice would duplicate them (or corrupt binary and
+                // encrypted files), so drop any part that made it before writing them again
+                let _ = self.file.set_len(self.written_len).await;
+                if self.encryption.is_some() {
+                    self.ciphertext = rows;
+                }
+                self.record_failure(e);
+            }
+        }
+        self.last_flush = Instant::now();
+    }
+
+    fn record_failure(&mut self, e: io::Error) {
+        let backoff = INITIAL_RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(self.consecutive_failures))
+            .min(MAX_RETRY_DELAY);
+        self.consecutive_failures += 1;
+        self.next_retry = Some(Instant::now() + backoff);
+        self.last_error = Some(e.to_string());
+    }
+
+    /// Flush buffered rows, then reopen the log at its path, recreating it with a header if it was
+    /// moved away (e.g. by logrotate). On failure the current handle is kept. An encrypted file
+    /// moved away is finished first, as another can't be appended to it.
+    pub async fn reopen(&mut self) -> io::Result<()> {
+        self.flush().await;
+        let mut file = File::options()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        let len = file.metadata().await?.len();
+        let mut unfinished = None;
+        if let Some(recipient) = self.recipient.clone() {
+            if len == 0 {
+                let mut encryption = Encryption::new(&recipient)?;
+                let start = encryption.encrypt(&self.header)?;
+                file.write_all(&start).await?;
+                file.flush().await?;
+                self.close().await;
+                // The rotated file's ending can't be written to the new one, so it's given up
+                if !std::mem::take(&mut self.ciphertext).is_empty() {
+                    unfinished = self.last_error.take();
+                    self.consecutive_failures = 0;
+                    self.next_retry = None;
+                }
+                self.encryption = Some(encryption);
+                self.written_len = start.len() as u64;
+                self.restart_signatures();
+            } else {
+                self.written_len = len;
+            }
+        } else if len == 0 {
+            file.write_all(&self.header).await?;
+            file.flush().await?;
+            self.written_len = self.header.len() as u64;
+            self.restart_signatures();
+        } else {
+            self.written_len = len;
+        }
+        self.file = file;
+        match unfinished {
+            Some(e) => Err(io::Error::other(format!(
+                "couldn't finish the rotated encrypted file, which is missing its last rows ({e})"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Start the signatures over for a new file beginning with the header.
+    fn restart_signatures(&mut self) {
+        if let Some(chain) = &mut self.chain {
+            chain.restart();
+            self.unsigned = self.header.clone();
+        }
+    }
+
+    async fn write(&mut self, rows: &[u8]) -> io::Result<()> {
+        #[cfg(test)]
+        if let Some(limit) = self.fail_after.take() {
+            self.file.write_all(&rows[..limit.min(rows.len())]).await?;
+            self.file.flush().await?;
+            return Err(io::Error::other("No space left on device"));
+        }
+        self.file.write_all(rows).await?;
+        self.file.flush().await?;
+        if self.sync_writes {
+            self.file.sync_data().await?;
+        }
+        Ok(())
+    }
+
+    /// Return the number of rows waiting to be written.
+    pub fn pending_rows(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Return the time since the last write attempt.
+    pub fn since_last_flush(&self) -> Duration {
+        self.last_flush.elapsed()
+    }
+
+    /// Return the number of write attempts that have failed in a row.
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Return the error from the most recent write attempt, if it failed.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Format one row of a delimited log, quoting any field that contains the delimiter, a quote or a
+/// line break.
+pub fn format_row<I, T>(fields: I, delimiter: u8) -> String
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(Vec::new());
+    // Writing to memory can't fail
+    writer.write_record(fields).unwrap();
+    let mut row = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    row.truncate(row.trim_end_matches(['\r', '\n']).len());
+    row
+}
+
+/// Parse a field delimiter: a single ASCII punctuation character or space, or `tab`. Quotes, `#`
+/// (which starts comment lines) and parentheses (which appear in column names) are not allowed.
+pub fn parse_delimiter(delimiter: &str) -> Result<u8, String> {
+    match delimiter {
+        "tab" | "\\t" | "\t" => Ok(b'\t'),
+        _ => match delimiter.as_bytes() {
+            [byte]
+                if (byte.is_ascii_punctuation() || *byte == b' ')
+                    && !matches!(byte, b'"' | b'#' | b'(' | b')') =>
+            {
+                Ok(*byte)
+            }
+            _ => Err(format!(
+                "invalid delimiter \"{delimiter}\" (expected a punctuation character, space or tab)"
+            )),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rows_written_partly_before_a_failure_are_written_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("result_test.csv");
+        let mut log = LogFile::create(path.clone(), "Seq,Status", false, None)
+            .await
+            .unwrap();
+        log.push("0,ok");
+        log.push("1,ok");
+        log.fail_after = Some(6);
+        log.flush().await;
+        assert_eq!(log.consecutive_failures(), 1);
+        assert_eq!(log.pending_rows(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "Seq,Status\n");
+
+        log.push("2,failed");
+        log.close().await;
+        assert_eq!(log.last_error(), None);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "Seq,Status\n0,ok\n1,ok\n2,failed\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn a_rotated_encrypted_file_left_unfinished_stays_out_of_the_new_one() {
+        let identity = age::x25519::Identity::generate();
+        let recipient: Recipient = format!("age:{}", identity.to_public()).parse().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("result_test.csv.age");
+        let mut log = LogFile::create(path.clone(), "Seq,Status", false, Some(&recipient))
+            .await
+            .unwrap();
+        log.push("0,ok");
+        std::fs::rename(&path, dir.path().join("result_test.csv.age.1")).unwrap();
+        log.fail_after = Some(0);
+        assert!(log.reopen().await.is_err());
+        assert_eq!(log.consecutive_failures(), 0);
+
+        log.push("1,ok");
+        log.close().await;
+        assert_eq!(log.last_error(), None);
+        let plaintext = age::decrypt(&identity, &std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(plaintext, b"Seq,Status\n1,ok\n");
+    }
+
+    #[test]
+    fn fields_with_delimiters_quotes_or_line_breaks_are_quoted() {
+        let cases = [
+            (vec!["ok", "12.345"], b',', "ok,12.345"),
+            (vec!["", ""], b',', ","),
+            (vec!["a,b", "c"], b',', "\"a,b\",c"),
+            (vec!["a,b", "c"], b';', "a,b;c"),
+            (vec!["a;b", "c"], b';', "\"a;b\";c"),
+            (vec!["say \"hi\"", "x"], b'\t', "\"say \"\"hi\"\"\"\tx"),
+            (vec!["two\nlines", "x"], b',', "\"two\nlines\",x"),
+            (vec!["carriage\rreturn"], b',', "\"carriage\rreturn\""),
+            (vec!["no route to host"], b' ', "\"no route to host\""),
+        ];
+        for (fields, delimiter, row) in cases {
+            assert_eq!(format_row(&fields, delimiter), row, "{fields:?}");
+            // Rows read back as the same fields
+            let mut reader = csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .has_headers(false)
+                .from_reader(row.as_bytes());
+            let read: Vec<String> = reader
+                .records()
+                .next()
+                .unwrap()
+                .unwrap()
+                .iter()
+                .map(str::to_string)
+                .collect();
+            assert_eq!(read, fields, "{row:?}");
+        }
+    }
+
+    #[test]
+    fn delimiters_are_punctuation_space_or_tab() {
+        let cases = [
+            (",", Ok(b',')),
+            (";", Ok(b';')),
+            ("|", Ok(b'|')),
+            (" ", Ok(b' ')),
+            ("tab", Ok(b'\t')),
+            ("\\t", Ok(b'\t')),
+            ("\t", Ok(b'\t')),
+            ("\"", Err(())),
+            ("#", Err(())),
+            ("(", Err(())),
+            (")", Err(())),
+            ("a", Err(())),
+            ("1", Err(())),
+            (",,", Err(())),
+            ("", Err(())),
+            ("é", Err(())),
+        ];
+        for (delimiter, parsed) in cases {
+            assert_eq!(
+                parse_delimiter(delimiter).map_err(|_| ()),
+                parsed,
+                "{delimiter:?}"
+            );
+        }
+        assert_eq!(
+            parse_delimiter("x").unwrap_err(),
+            "invalid delimiter \"x\" (expected a punctuation character, space or tab)"
+        );
+    }
+}
@@ -16,44 +16,473 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::engine::Engine;
-use clap::{arg, value_parser, ArgAction, Command};
-use crossterm::style::{Attribute, StyledContent, Stylize};
-use crossterm::{cursor, terminal, ExecutableCommand};
-use std::io::{stdout, Stdout, Write};
-use std::net::IpAddr;
+use crate::alert::{AlertRule, AlertTemplate, Channel, RateLimit, WindowRule};
+use crate::api::Health;
+use crate::asn::Network;
+use crate::clock::SystemClock;
+use crate::columns::Columns;
+use crate::composite::Composite;
+use crate::control::Request;
+#[cfg(unix)]
+use crate::control::CONTROL_SOCKET_NAME;
+use crate::engine::{
+    local_now, new_run_id, parse_duration, Engine, EngineConfig, FlushPolicy, LinkState,
+};
+use crate::http::HttpUrl;
+use crate::log_file::parse_delimiter;
+use crate::pipeline::{Fanout, Output, Schedule};
+use crate::probe::ProbeKind;
+use crate::public_ip::{PublicAddress, PublicIpSource};
+use crate::settings::{
+    added_target_settings, describe_sources, AutoAdjust, ConfigWatcher, RecordingSettings,
+    Settings, Source,
+};
+use crate::sink::SinkKind;
+use crate::targets::{ConfigBuilder, ExtraTargets};
+use crate::theme::{failure, success, Theme};
+use crate::tui::{format_duration, PingUpdate, TuiConfig};
+use crate::writer::OverflowPolicy;
+use clap::parser::ValueSource;
+use clap::{arg, value_parser, ArgAction, ArgMatches, Command, Id};
+use std::collections::HashSet;
+use std::io::{stdout, IsTerminal};
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
-use std::time::Duration;
-use surge_ping::{IcmpPacket, SurgeError};
-use time::format_description::FormatItem;
-use time::{format_description, OffsetDateTime};
+use std::str::FromStr;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc as async_mpsc, oneshot, watch};
+use tokio::time::MissedTickBehavior;
 use tokio::{signal, task};
+mod alert;
+mod analyze;
+mod api;
+mod asn;
+mod atomic_write;
+mod bench;
+mod cbor;
+mod chart;
+mod check;
+mod clock;
+mod columns;
+mod composite;
+mod control;
+mod convert;
+#[cfg(target_os = "linux")]
+mod discover;
+mod encrypt;
 mod engine;
+mod event_log;
+mod grafana;
+mod health;
+mod host;
+mod http;
+#[cfg(target_os = "linux")]
+mod interface;
+mod launchd;
+mod lifetime;
+mod log_file;
+mod metrics;
+mod ndjson;
+#[cfg(target_os = "linux")]
+mod neighbor;
+mod ntp;
+#[cfg(target_os = "linux")]
+mod path;
+mod pipeline;
+mod portal;
+mod probe;
+mod public_ip;
+mod report;
+mod settings;
+mod sign;
+mod sink;
+mod snapshot;
+mod snmp;
+mod summary;
+#[cfg(target_os = "linux")]
+mod systemd;
+mod targets;
+mod tcp;
+mod theme;
+mod throughput;
+mod tls;
+mod tui;
+mod verify;
+#[cfg(target_os = "linux")]
+mod wifi;
+mod window;
+mod writer;
+mod zabbix;
 
-// Format string for user-presented timestamp
-const DT_FMT: &str = "[month]/[day]/[year] [hour]:[minute]:[second]";
+// Minimum delay between pings, and the minimum once sub-second probing is acknowledged with --fast
+const MIN_DELAY_SECS: f64 = 5.0;
+const MIN_FAST_DELAY_SECS: f64 = 0.1;
+
+#[cfg(feature = "alloc-counting")]
+#[global_allocator]
+static ALLOCATOR: bench::CountingAllocator = bench::CountingAllocator;
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() {
+    // Must happen first, while the process is still single-threaded
+    let local_offset_known = engine::init_local_offset();
+
     // Set up argument parser
     let matches = Command::new("num (Network Uptime Monitor)")
         .version(env!("CARGO_PKG_VERSION"))
         .about("Monitors the uptime of a network connection and records data to a CSV.")
-        .arg(arg!(<ADDRESS> "Host to ping (required)").required(true))
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(Command::new("schema").about("Print the JSON Schema of config_*.json files"))
+        .subcommand(
+            Command::new("healthcheck")
+                .about("Exit with 0 if the monitor writing to a directory is running and the target is up, 1 otherwise")
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required)")
+                        .required(true)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("ctl")
+                .about("Control a running monitor through the control socket in its output directory (unix only)")
+                .subcommand_required(true)
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required unless set in the config file)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .subcommand(Command::new("status").about("Show the state of the target and whether probing is paused"))
+                .subcommand(Command::new("stats").about("Show the statistics gathered so far, like on exit"))
+                .subcommand(Command::new("pause").about("Stop probing until resumed; the time in between is recorded as a monitoring gap"))
+                .subcommand(Command::new("resume").about("Start probing again after pause"))
+                .subcommand(Command::new("reload").about("Re-read the config file given with --config and apply it"))
+                .subcommand(
+                    Command::new("add-target")
+                        .about("Start monitoring another target, with results in targets/<ADDRESS> under the output directory")
+                        .arg(arg!(<ADDRESS> "Host to monitor, optionally as a URI such as tls://example.com")),
+                )
+                .subcommand(
+                    Command::new("remove-target")
+                        .about("Stop monitoring a target added with add-target")
+                        .arg(arg!(<ADDRESS> "Host to stop monitoring")),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Stress the probe, writer, and stats pipeline and report throughput and allocations")
+                .arg(arg!([ADDRESS] "Host to ping (default=127.0.0.1)"))
+                .arg(
+                    arg!(--duration <DURATION> "How long to run, e.g. 60s or 5m (default=60s)")
+                        .required(false)
+                        .value_parser(|duration: &str| {
+                            parse_duration(duration)
+                                .ok_or(format!("invalid duration \"{duration}\" (expected e.g. 60s)"))
+                        }),
+                )
+                .arg(
+                    arg!(--rate <RATE> "Pings per second, e.g. 10/s (default=10/s, max=500/s)")
+                        .required(false)
+                        .value_parser(bench::parse_rate),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Probe a host a few times, one second apart, and exit with 0 (OK), 1 (WARNING) or 2 (CRITICAL) depending on latency and loss")
+                .arg(arg!(<ADDRESS> "Host to check, optionally as a URI such as tcp://example.com:443"))
+                .arg(
+                    arg!(--nagios "Print the result as a Nagios/Icinga plugin, with performance data, and exit with 3 (UNKNOWN) if the check can't run")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(-w --warning <THRESHOLDS> "Average round trip time (ms) and loss at which to warn, as RTA,PL% (default=200,20%)")
+                        .required(false)
+                        .value_parser(check::Thresholds::from_str),
+                )
+                .arg(
+                    arg!(-c --critical <THRESHOLDS> "Average round trip time (ms) and loss at which the check is critical, as RTA,PL% (default=500,60%)")
+                        .required(false)
+                        .value_parser(check::Thresholds::from_str),
+                )
+                .arg(
+                    arg!(-p --packets <COUNT> "Number of probes to send (default=5)")
+                        .required(false)
+                        .value_parser(value_parser!(u32).range(1..=100)),
+                )
+                .arg(
+                    arg!(-t --timeout <TIMEOUT> "Time to wait for each reply (ms) (default=500, max=999)")
+                        .required(false)
+                        .value_parser(value_parser!(u64).range(1..1000)),
+                ),
+        )
+        .subcommand(
+            Command::new("path")
+                .about("Continuously probe every hop to a host, like mtr (Linux only)")
+                .arg(arg!(<ADDRESS> "Host to trace"))
+                .arg(
+                    arg!(-o --output <PATH> "Directory to write per-hop results to as CSV")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-t --timeout <TIMEOUT> "Time to wait for replies to each round (ms) (default=500)")
+                        .required(false)
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(-d --delay <DELAY> "Time between rounds (s) (default=1)")
+                        .required(false)
+                        .value_parser(value_parser!(u64).range(1..)),
+                )
+                .arg(
+                    arg!(--"max-hops" <HOPS> "Highest TTL to probe (default=30)")
+                        .required(false)
+                        .value_parser(value_parser!(u8).range(1..)),
+                ),
+        )
+        .subcommand(
+            Command::new("discover")
+                .about("Ping a broadcast address or ff02::1 and list the hosts that answer, optionally adding them as targets of a running monitor (Linux only)")
+                .arg(
+                    arg!(<ADDRESS> "IPv4 broadcast address, e.g. 192.168.1.255, or IPv6 multicast group, e.g. ff02::1")
+                        .value_parser(value_parser!(IpAddr)),
+                )
+                .arg(
+                    arg!(-i --interface <NAME> "Interface to send IPv6 multicast on (required for IPv6)")
+                        .required(false),
+                )
+                .arg(
+                    arg!(-t --timeout <TIMEOUT> "Time to collect replies for (ms) (default=2000)")
+                        .required(false)
+                        .value_parser(value_parser!(u64)),
+                )
+                .arg(
+                    arg!(--add "Add every host that answered as a target of the monitor writing to the output directory")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor to add targets to")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("report")
+                .about("Report availability and p95 latency per month from the result CSVs, with compliance and error budget against an SLA")
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required unless set in the config file)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings, including sla-availability and sla-p95")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--"sla-availability" <PERCENT> "Monthly availability target, e.g. 99.9")
+                        .required(false)
+                        .value_parser(|percent: &str| match percent.parse::<f64>() {
+                            Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent),
+                            _ => Err(format!("invalid percentage \"{percent}\" (expected 0 to 100)")),
+                        }),
+                )
+                .arg(
+                    arg!(--"sla-p95" <MS> "Monthly 95th percentile latency target (ms)")
+                        .required(false)
+                        .value_parser(value_parser!(f64)),
+                )
+                .arg(
+                    arg!(--chart <FILE> "Also draw latency over time, with outages shaded, to an .svg or .png file")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("analyze")
+                .about("Print statistics, a latency timeline and loss per day from the result CSVs, for post-mortems in a terminal")
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required unless set in the config file)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--ascii "Only use plain ASCII characters in the charts")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Convert the result CSVs into a spreadsheet with a summary sheet (uptime and outages) and the raw results, or CBOR result files back into CSV or JSON lines files")
+                .arg(
+                    arg!(--to <FORMAT> "Format to convert to: xlsx, or csv or json for the result_*.cbor files")
+                        .required(true)
+                        .value_parser(["xlsx", "csv", "json"]),
+                )
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required unless set in the config file)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--file <FILE> "Spreadsheet to write (default=results.xlsx in the output directory)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
+        .subcommand(
+            Command::new("verify")
+                .about("Check the result CSVs for truncated lines, rows that don't match the header, timestamps going backwards and duplicate sequence numbers, and optionally their signatures")
+                .arg(
+                    arg!(-o --output <PATH> "Output directory of the monitor (required unless set in the config file)")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-c --config <FILE> "TOML file with the monitor's settings")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--repair "Remove damaged rows, keeping each original file with a .bak extension")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--signatures <KEYFILE> "Also check the signatures written with --sign, using the key in KEYFILE")
+                        .required(false)
+                        .value_parser(sign::Key::load),
+                ),
+        )
+        .subcommand(
+            Command::new("grafana-dashboard")
+                .about("Print a Grafana dashboard of the metrics served at /metrics (see --listen), ready to import")
+                .arg(
+                    arg!(--datasource <TYPE> "Type of data source the dashboard queries")
+                        .required(true)
+                        .value_parser(["prometheus"]),
+                ),
+        )
+        .subcommand(
+            Command::new("install-systemd")
+                .about("Write a systemd service running the monitor with the given arguments, and enable and start it (Linux only)")
+                .arg(
+                    arg!(--user "Install a user unit in ~/.config/systemd/user instead of a system one")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(--name <NAME> "Name of the service (default=num)")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"run-as" <USER> "Account the system service runs as, instead of root")
+                        .required(false)
+                        .conflicts_with("user"),
+                )
+                .arg(
+                    arg!(--"no-enable" "Only write the unit file")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(<ARGS> ... "Arguments of the monitor after --, e.g. -- 1.1.1.1 -o /var/lib/num")
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .subcommand(
+            Command::new("install-launchd")
+                .about("Write a LaunchAgent running the monitor with the given arguments whenever you're logged in, and load it (macOS only)")
+                .arg(
+                    arg!(--label <LABEL> "Label of the agent, also naming its plist and log file (default=com.github.adih-20.num)")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"no-load" "Only write the plist to ~/Library/LaunchAgents")
+                        .required(false)
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    arg!(<ARGS> ... "Arguments of the monitor after --, e.g. -- 1.1.1.1 -o ~/num-logs")
+                        .trailing_var_arg(true)
+                        .allow_hyphen_values(true),
+                ),
+        )
+        .arg(arg!([ADDRESS] "Host to ping (required unless set in the config file); - reads a list of targets from stdin"))
+        .arg(
+            arg!(--"targets-file" <FILE> "File listing more targets to monitor, one per line with an optional label, with results in targets/<LABEL or ADDRESS>; - reads stdin")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--gateway <TARGET> "Which of the monitored targets is the LAN gateway, so the internet only counts as down while it still answers (see --internet-alert)")
+                .required(false),
+        )
         .arg(
-            arg!(-o --output <PATH> "Output directory path (required)")
-                .required(true)
+            arg!(-o --output <PATH> "Output directory path (required unless set in the config file)")
+                .required(false)
                 .value_parser(value_parser!(PathBuf)),
         )
+        .arg(
+            arg!(-c --config <FILE> "TOML file with settings, reloaded on change or SIGHUP (command line and NUM_* environment variables take precedence)")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"no-watch" "Only reload the config file on SIGHUP or num ctl reload, not whenever it changes")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"dry-run" "Print the effective settings and where each came from, check them, and exit")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--probe <TYPE> "How to probe the target: icmp, neighbor for ARP (IPv4) / NDP (IPv6) on the local subnet, tcp to time the TCP connect to host:port, tls to also time the TLS handshake to host[:port], or ntp; can also be given as the target's scheme, e.g. tls://example.com (default=icmp)")
+                .required(false)
+                .value_parser(ProbeKind::from_str),
+        )
         .arg(
             arg!(-t --timeout <TIMEOUT> "Time to wait for host response (ms) (default=1000)")
                 .required(false)
                 .value_parser(value_parser!(u64)),
         )
         .arg(
-            arg!(-d --delay <DELAY> "Time to wait between pings (s) (default=120, min=5)")
+            arg!(-d --delay <DELAY> "Time to wait between pings (s) (default=120, min=5 or 0.1 with --fast)")
                 .required(false)
-                .value_parser(value_parser!(u64).range(5..)),
+                .value_parser(value_parser!(f64)),
+        )
+        .arg(
+            arg!(--fast "Allow sub-5s (and fractional) delays between pings")
+                .required(false)
+                .action(ArgAction::SetTrue),
         )
         .arg(
             arg!(-n --"num-bytes" <BYTES> "Number of bytes to send (default=4, max=24)") // due to ping_rs restrictions
@@ -65,242 +494,1784 @@ async fn main() {
                 .required(false)
                 .value_parser(value_parser!(u32).range(1..)),
         )
+        .arg(
+            arg!(--"auto-adjust" <SETTING> "When the timeout doesn't fit within the delay, lower it to half the delay (timeout) or raise the delay to twice the timeout (delay) instead of exiting")
+                .required(false)
+                .value_parser(AutoAdjust::from_str),
+        )
+        .arg(
+            arg!(--"missed-ticks" <BEHAVIOR> "What to do when pings fall behind schedule (default=skip)")
+                .required(false)
+                .value_parser(["skip", "delay", "burst"]),
+        )
+        .arg(
+            arg!(--"interval-jitter" <PERCENT> "Postpone each ping by a random part of the delay, up to this percentage, so monitors started together don't ping in lockstep (default=0%)")
+                .required(false)
+                .value_parser(|percent: &str| {
+                    settings::parse_percent(percent)
+                        .filter(|jitter| (0.0..=0.5).contains(jitter))
+                        .map(|_| percent.to_string())
+                        .ok_or(format!("invalid jitter \"{percent}\" (expected a percentage up to 50%, e.g. 10%)"))
+                }),
+        )
+        .arg(
+            arg!(--align "Ping at multiples of the delay on the clock (e.g. :00 and :30 of every minute for a 30s delay) instead of counting from the start, so results from different machines line up")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"down-after" <COUNT> "Consecutive failed pings before the target is considered down (default=1)")
+                .required(false)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--"down-on-no-route" "Consider the target down on the first probe that fails for lack of a route to it (or of a neighbor on the local network), while timeouts still need --down-after failures in a row")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--retries <COUNT> "Send a timed-out probe again up to this many times, as long as it fits before the next one is due, before counting it as failed (default=0)")
+                .required(false)
+                .value_parser(value_parser!(u32).range(..=10)),
+        )
+        .arg(
+            arg!(--"cert-warn-days" <DAYS> "With --probe tls, log a cert_expiring event once the certificate expires in fewer than this many days (default=14)")
+                .required(false)
+                .value_parser(value_parser!(u32)),
+        )
+        .arg(
+            arg!(--"max-offset" <MS> "With --probe ntp, log a clock_offset event once the local clock is off by more than this (ms) (default=1000)")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--"portal-check" <URL> "After each successful ping, fetch this URL and report CAPTIVE unless it answers 204, e.g. http://connectivitycheck.gstatic.com/generate_204")
+                .required(false),
+        )
+        .arg(
+            arg!(--alert <RULE> "Alert once an outage reaches a threshold, as CHANNEL:AFTER[:DESTINATION] with AFTER a number of failed pings or a duration, e.g. desktop:1, webhook:5m:https://example.com/hook or email:30m:ops@example.com (repeatable)")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(AlertRule::from_str),
+        )
+        .arg(
+            arg!(--"window-alert" <RULE> "Alert while a statistic over a rolling window exceeds a threshold, as METRIC>THRESHOLD/WINDOW:CHANNEL[:DESTINATION] with METRIC loss, avg or a percentile such as p95, e.g. loss>2%/15m:desktop or p95>100ms/1h:email:ops@example.com (repeatable)")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(WindowRule::from_str),
+        )
+        .arg(
+            arg!(--"alert-template" <TEMPLATE> "Message body for alerts on a channel, as CHANNEL=TEMPLATE with {{target}}, {{address}}, {{state}}, {{failures}}, {{outage_duration}}, {{since}}, {{loss_pct_1h}} or {{time}} placeholders (repeatable)")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(AlertTemplate::from_str),
+        )
+        .arg(
+            arg!(--"alert-rate-limit" <LIMIT> "Send at most COUNT alerts per PERIOD, on all channels together or on one, as [CHANNEL=]COUNT/PERIOD, e.g. 10/1h or email=2/1h (repeatable)")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(RateLimit::from_str),
+        )
+        .arg(
+            arg!(--"isp-change-alert" <CHANNEL> "Alert when the public address (see --public-ip and --asn-lookup) moves to another prefix or AS, as CHANNEL[:DESTINATION], e.g. desktop or email:ops@example.com")
+                .required(false)
+                .value_parser(Channel::from_str),
+        )
+        .arg(
+            arg!(--"internet-alert" <CHANNEL> "With several targets, alert once when every external target is DOWN (and the --gateway, if given, still answers) instead of per target, as CHANNEL[:DESTINATION], e.g. desktop or email:ops@example.com")
+                .required(false)
+                .value_parser(Channel::from_str),
+        )
+        .arg(
+            arg!(--"alert-dedup" <WINDOW> "Don't repeat an identical alert within this window, e.g. 15m (default=15m, 0s to disable)")
+                .required(false)
+                .value_parser(|window: &str| {
+                    parse_duration(window)
+                        .map(|_| window.to_string())
+                        .ok_or(format!("invalid window \"{window}\" (expected e.g. 15m)"))
+                }),
+        )
+        .arg(
+            arg!(--"throughput-url" <URL> "Periodically download this http(s):// URL (for up to 15s) and log the throughput as an event")
+                .required(false)
+                .value_parser(HttpUrl::parse),
+        )
+        .arg(
+            arg!(--"throughput-every" <INTERVAL> "How often to measure throughput, e.g. 6h (default=6h, min=1m)")
+                .required(false)
+                .value_parser(|interval: &str| match parse_duration(interval) {
+                    Some(interval) if interval >= Duration::from_secs(60) => Ok(interval),
+                    _ => Err(format!("invalid interval \"{interval}\" (expected e.g. 6h, at least 1m)")),
+                }),
+        )
+        .arg(
+            arg!(--"public-ip" <SOURCE> "Periodically ask this http(s):// URL (answering with the address as text, e.g. https://api.ipify.org) or STUN server (stun:host[:port]) for the public address and log it and its changes as events")
+                .required(false)
+                .value_parser(PublicIpSource::parse),
+        )
+        .arg(
+            arg!(--"public-ip-every" <INTERVAL> "How often to check the public address, e.g. 10m (default=10m, min=1m)")
+                .required(false)
+                .value_parser(|interval: &str| match parse_duration(interval) {
+                    Some(interval) if interval >= Duration::from_secs(60) => Ok(interval),
+                    _ => Err(format!("invalid interval \"{interval}\" (expected e.g. 10m, at least 1m)")),
+                }),
+        )
+        .arg(
+            arg!(--wifi "Log the Wi-Fi signal strength and bitrate with each result when the target is reached over a wireless interface (Linux only)")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--textfile <FILE> "Rewrite this .prom file with the metrics (as served at /metrics) after every probe, for node_exporter's textfile collector")
+                .required(false)
+                .value_parser(|path: &str| {
+                    if path.ends_with(".prom") {
+                        Ok(PathBuf::from(path))
+                    } else {
+                        Err(format!("invalid textfile \"{path}\" (node_exporter only reads .prom files)"))
+                    }
+                }),
+        )
+        .arg(
+            arg!(--openmetrics <FILE> "Periodically rewrite this file with an OpenMetrics snapshot of the metrics (as served at /metrics), e.g. for scraping from a file")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"openmetrics-every" <INTERVAL> "How often to rewrite the OpenMetrics snapshot, e.g. 30s (default=15s, min=1s)")
+                .required(false)
+                .value_parser(|interval: &str| match parse_duration(interval) {
+                    Some(interval) if interval >= Duration::from_secs(1) => Ok(interval),
+                    _ => Err(format!("invalid interval \"{interval}\" (expected e.g. 30s, at least 1s)")),
+                }),
+        )
+        .arg(
+            arg!(--zabbix <SERVER> "Push the target's values to this Zabbix server or proxy as trapper items, as HOST[:PORT] (default port=10051)")
+                .required(false)
+                .value_parser(zabbix::Sender::parse_server),
+        )
+        .arg(
+            arg!(--"zabbix-host" <HOST> "Zabbix host the items belong to (default=the probe name)")
+                .required(false),
+        )
+        .arg(
+            arg!(--"zabbix-key-prefix" <PREFIX> "Start of the Zabbix item keys, e.g. num for num.rtt[TARGET] (default=num)")
+                .required(false),
+        )
+        .arg(
+            arg!(--"zabbix-every" <INTERVAL> "How often to push values to Zabbix, e.g. 30s (default=60s, min=1s)")
+                .required(false)
+                .value_parser(|interval: &str| match parse_duration(interval) {
+                    Some(interval) if interval >= Duration::from_secs(1) => Ok(interval),
+                    _ => Err(format!("invalid interval \"{interval}\" (expected e.g. 30s, at least 1s)")),
+                }),
+        )
+        .arg(
+            arg!(--"event-log" "Write the target's outages and recoveries, and the internet going down and back up, to the Application event log on Windows or the unified log on macOS")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"snmp-trap" <MANAGER> "Send an SNMP trap to this manager when the target goes down and comes back up, and when the internet does, as HOST[:PORT] (default port=162)")
+                .required(false)
+                .value_parser(snmp::TrapConfig::parse_destination),
+        )
+        .arg(
+            arg!(--"snmp-community" <COMMUNITY> "Community of SNMPv2c traps (default=public)")
+                .required(false)
+                .conflicts_with("snmp-user"),
+        )
+        .arg(
+            arg!(--"snmp-oid" <OID> "Base OID of the traps and their variables (default=1.3.6.1.4.1.8072.9999.9999)")
+                .required(false)
+                .value_parser(snmp::Oid::from_str),
+        )
+        .arg(
+            arg!(--"snmp-user" <USER> "Send SNMPv3 traps as this user instead of SNMPv2c ones")
+                .required(false)
+                .requires("snmp-engine-id"),
+        )
+        .arg(
+            arg!(--"snmp-engine-id" <HEX> "Engine ID the SNMPv3 traps are sent from, which the manager's user must be created with, e.g. 80001f88046e756d")
+                .required(false)
+                .value_parser(snmp::parse_engine_id),
+        )
+        .arg(
+            arg!(--"snmp-auth" <AUTH> "Authenticate SNMPv3 traps, as PROTOCOL:PASSPHRASE with PROTOCOL sha or sha256 (traps aren't encrypted)")
+                .required(false)
+                .requires("snmp-user")
+                .value_parser(snmp::Auth::from_str),
+        )
+        .arg(
+            arg!(--"trace-path" "Also trace the path to the target like num path, and log the hop each outage's loss started at as an outage_attributed event (Linux only)")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"nic-counters" "Log how much the outgoing interface's error and drop counters grew with each result (Linux only)")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"asn-lookup" "Look up the network (ASN and country) of the target's address with Team Cymru's whois service, for the config JSON, result files and TUI, and of each new public address with --public-ip")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"probe-name" <NAME> "Name of this machine in the results, metadata and alerts, to tell apart data from several probe machines (default=the hostname)")
+                .required(false),
+        )
+        .arg(
+            arg!(--columns <COLUMNS> "Comma-separated fields to record in the result CSV, from timestamp, run_id, probe_name, target, address, rtt, status, error, seq, retries, ttl, connect, handshake, cert_expiry, offset, signal, tx_bitrate, rx_errors, tx_errors, rx_dropped and tx_dropped (default=timestamp,rtt,status,error and those of the probe type)")
+                .required(false)
+                .value_parser(Columns::from_str),
+        )
+        .arg(
+            arg!(--delimiter <DELIMITER> "Field separator of the result and event files: a punctuation character, space, or tab for tab-separated .tsv files (default=,)")
+                .required(false)
+                .value_parser(parse_delimiter),
+        )
+        .arg(
+            arg!(--sink <SINK> "Where to record probe results in the output directory, repeatable: csv (result_*.csv, default), json (one object per line in result_*.jsonl) or cbor (compact binary records in result_*.cbor, see num convert)")
+                .required(false)
+                .action(ArgAction::Append)
+                .value_parser(SinkKind::from_str),
+        )
+        .arg(
+            arg!(--resume "Carry on from the statistics and UP/DOWN state saved in the output directory by a previous run")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--sync "Flush results to disk (fsync) after every ping to survive power loss")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--encrypt <RECIPIENT> "Encrypt the result and event files to an age public key, as age:<RECIPIENT>, writing them with an added .age extension")
+                .required(false)
+                .value_parser(encrypt::Recipient::from_str),
+        )
+        .arg(
+            arg!(--sign <KEYFILE> "End every write to the result CSV with a signature chained from the ones before, an HMAC-SHA256 with the secret key in KEYFILE, for num verify --signatures")
+                .required(false)
+                .value_parser(sign::Key::load),
+        )
+        .arg(
+            arg!(--"flush-every" <POLICY> "Buffer results and write them in batches, e.g. 60s or 100rows (default=every ping)")
+                .required(false)
+                .value_parser(FlushPolicy::from_str),
+        )
+        .arg(
+            arg!(--"write-overflow" <POLICY> "What to do with results once writing falls 1024 behind: block, drop-oldest or spill (default=block)")
+                .required(false)
+                .value_parser(OverflowPolicy::from_str),
+        )
+        .arg(
+            arg!(--"max-write-failures" <COUNT> "Consecutive failed writes to the output directory before giving up (default=20)")
+                .required(false)
+                .value_parser(value_parser!(u32).range(1..)),
+        )
+        .arg(
+            arg!(--container "Run in a container: no TUI or colors, NDJSON logs on stdout, and CSVs only if an output path is given")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--listen <ADDR> "Serve the HTTP API (/healthz, /readyz, /metrics) on this address, e.g. 127.0.0.1:9090")
+                .required(false)
+                .value_parser(value_parser!(SocketAddr)),
+        )
         .arg(
             arg!(-q --quiet "Suppress output to stdout/stderr")
                 .required(false)
                 .action(ArgAction::SetFalse),
         )
+        .arg(
+            arg!(--color <WHEN> "Colorize output (default=auto, honors NO_COLOR)")
+                .required(false)
+                .value_parser(["auto", "always", "never"]),
+        )
+        .arg(
+            arg!(--theme <NAME> "Color theme (default=default)")
+                .required(false)
+                .value_parser(Theme::PRESETS),
+        )
+        .arg(
+            arg!(--colors <COLORS> "Override theme colors, e.g. success=cyan,failure=yellow,info=white")
+                .required(false),
+        )
+        .arg(
+            arg!(--ascii "Only use plain ASCII characters in the TUI")
+                .required(false)
+                .action(ArgAction::SetTrue),
+        )
         .get_matches();
 
+    match matches.subcommand() {
+        Some(("schema", _)) => {
+            println!("{}", snapshot::schema());
+            return;
+        }
+        Some(("healthcheck", healthcheck_matches)) => {
+            let output_path = healthcheck_matches.get_one::<PathBuf>("output").unwrap();
+            match health::check(output_path) {
+                Ok(status) => {
+                    println!("healthy: {status}");
+                    return;
+                }
+                Err(status) => {
+                    println!("unhealthy: {status}");
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some(("ctl", ctl_matches)) => {
+            theme::init_styling("auto");
+            let (output_path, _) = results_location(ctl_matches).await;
+            let Some((command, command_matches)) = ctl_matches.subcommand() else {
+                unreachable!("a ctl command is required");
+            };
+            // Only add-target and remove-target take an address
+            let address = command_matches.try_get_one::<String>("ADDRESS");
+            let request = match address.ok().flatten() {
+                Some(address) => format!("{command} {address}"),
+                None => command.to_string(),
+            };
+            #[cfg(unix)]
+            match control::send(&output_path.join(CONTROL_SOCKET_NAME), &request).await {
+                Ok(reply) => println!("{reply}"),
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            #[cfg(not(unix))]
+            {
+                let _ = (output_path, request);
+                eprintln!("{}", failure("num ctl is only supported on unix. Exiting"));
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(("bench", bench_matches)) => {
+            theme::init_styling("auto");
+            let addr = bench_matches
+                .get_one::<String>("ADDRESS")
+                .map_or("127.0.0.1", String::as_str);
+            let duration = bench_matches
+                .get_one::<Duration>("duration")
+                .copied()
+                .unwrap_or(Duration::from_secs(60));
+            let rate = bench_matches
+                .get_one::<f64>("rate")
+                .copied()
+                .unwrap_or(10.0);
+            match bench::run(addr.to_string(), duration, rate).await {
+                Ok(report) => println!("{report}"),
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("check", check_matches)) => {
+            let nagios = check_matches.get_flag("nagios");
+            theme::init_styling(if nagios { "never" } else { "auto" });
+            let target = check_matches.get_one::<String>("ADDRESS").unwrap();
+            let warning = check_matches
+                .get_one::<check::Thresholds>("warning")
+                .copied()
+                .unwrap_or(check::Thresholds {
+                    rta: 200.0,
+                    loss: 20.0,
+                });
+            let critical = check_matches
+                .get_one::<check::Thresholds>("critical")
+                .copied()
+                .unwrap_or(check::Thresholds {
+                    rta: 500.0,
+                    loss: 60.0,
+                });
+            let count = check_matches
+                .get_one::<u32>("packets")
+                .copied()
+                .unwrap_or(5);
+            let timeout = check_matches
+                .get_one::<u64>("timeout")
+                .copied()
+                .unwrap_or(500);
+            match check::run(target, count, timeout).await {
+                Ok(result) => {
+                    let status = result.status(warning, critical);
+                    if nagios {
+                        println!("{}", result.nagios(warning, critical));
+                    } else if status == check::Status::Ok {
+                        println!("{}", success(format!("OK - {}", result.summary())));
+                    } else {
+                        println!(
+                            "{}",
+                            failure(format!("{} - {}", status.name(), result.summary()))
+                        );
+                    }
+                    std::process::exit(status as i32);
+                }
+                Err(e) if nagios => {
+                    println!("NUM UNKNOWN - {e}");
+                    std::process::exit(check::Status::Unknown as i32);
+                }
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(check::Status::Unknown as i32);
+                }
+            }
+        }
+        Some(("report", report_matches)) => {
+            theme::init_styling("auto");
+            let (output_path, file_settings) = results_location(report_matches).await;
+            let sla = report::Sla {
+                availability: report_matches
+                    .get_one::<f64>("sla-availability")
+                    .copied()
+                    .or(file_settings.sla_availability),
+                p95: report_matches
+                    .get_one::<f64>("sla-p95")
+                    .copied()
+                    .or(file_settings.sla_p95),
+            };
+            match report::run(&output_path, &sla) {
+                Ok(report) => println!("{report}"),
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            if let Some(chart_path) = report_matches.get_one::<PathBuf>("chart") {
+                if let Err(e) = chart::render(&output_path, chart_path) {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+                println!("Chart written to {}", chart_path.display());
+            }
+            return;
+        }
+        Some(("analyze", analyze_matches)) => {
+            theme::init_styling("auto");
+            theme::set_ascii_only(analyze_matches.get_flag("ascii"));
+            let (output_path, _) = results_location(analyze_matches).await;
+            let width = crossterm::terminal::size().map_or(80, |(columns, _)| columns.into());
+            match analyze::run(&output_path, width) {
+                Ok(analysis) => println!("{analysis}"),
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("convert", convert_matches)) => {
+            theme::init_styling("auto");
+            let (output_path, _) = results_location(convert_matches).await;
+            let format = convert_matches.get_one::<String>("to").unwrap();
+            let converted = if format == "xlsx" {
+                let destination = convert_matches
+                    .get_one::<PathBuf>("file")
+                    .cloned()
+                    .unwrap_or_else(|| output_path.join("results.xlsx"));
+                convert::to_xlsx(&output_path, &destination)
+            } else if convert_matches.contains_id("file") {
+                Err("--file only applies to --to xlsx".to_string())
+            } else {
+                convert::from_cbor(&output_path, format == "json")
+            };
+            match converted {
+                Ok(summary) => println!("{summary}"),
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("grafana-dashboard", _)) => {
+            println!("{:#}", grafana::dashboard());
+            return;
+        }
+        Some(("install-systemd", systemd_matches)) => {
+            theme::init_styling("auto");
+            if !cfg!(target_os = "linux") {
+                eprintln!(
+                    "{}",
+                    failure("num install-systemd is only supported on Linux. Exiting")
+                );
+                std::process::exit(1);
+            }
+            #[cfg(target_os = "linux")]
+            {
+                let name = systemd_matches
+                    .get_one::<String>("name")
+                    .map_or("num", String::as_str);
+                let args: Vec<String> = systemd_matches
+                    .get_many::<String>("ARGS")
+                    .unwrap()
+                    .cloned()
+                    .collect();
+                let enable = !systemd_matches.get_flag("no-enable");
+                let result = match service_command(&args) {
+                    Ok((program, working_directory)) => {
+                        let unit = systemd::Unit {
+                            program,
+                            working_directory,
+                            user: systemd_matches.get_flag("user"),
+                            run_as: systemd_matches.get_one::<String>("run-as").cloned(),
+                        };
+                        systemd::install(&unit, name, enable).await
+                    }
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(path) => {
+                        println!("Wrote {}", path.display());
+                        if enable {
+                            println!("Enabled and started {name}.service");
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("{}", failure(format!("{e}. Exiting")));
+                        std::process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(("install-launchd", launchd_matches)) => {
+            theme::init_styling("auto");
+            if !cfg!(target_os = "macos") {
+                eprintln!(
+                    "{}",
+                    failure("num install-launchd is only supported on macOS. Exiting")
+                );
+                std::process::exit(1);
+            }
+            let Some(home) = std::env::var_os("HOME").map(PathBuf::from) else {
+                eprintln!("{}", failure("HOME is not set. Exiting"));
+                std::process::exit(1);
+            };
+            let label = launchd_matches
+                .get_one::<String>("label")
+                .map_or(launchd::DEFAULT_LABEL, String::as_str);
+            let args: Vec<String> = launchd_matches
+                .get_many::<String>("ARGS")
+                .unwrap()
+                .cloned()
+                .collect();
+            let load = !launchd_matches.get_flag("no-load");
+            let result = match service_command(&args) {
+                Ok((program, working_directory)) => {
+                    let agent = launchd::Agent::new(label, program, working_directory, &home);
+                    launchd::install(&agent, &home, load)
+                        .await
+                        .map(|path| (agent, path))
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((agent, path)) => {
+                    println!("Wrote {}", path.display());
+                    if load {
+                        println!(
+                            "Loaded {}, logging to {}",
+                            agent.label,
+                            agent.log_path.display()
+                        );
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("verify", verify_matches)) => {
+            theme::init_styling("auto");
+            let (output_path, _) = results_location(verify_matches).await;
+            let repair = verify_matches.get_flag("repair");
+            let key = verify_matches.get_one::<sign::Key>("signatures");
+            match verify::run(&output_path, repair, key).await {
+                Ok((report, damaged)) => {
+                    println!("{report}");
+                    if damaged {
+                        std::process::exit(1);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some(("discover", discover_matches)) => {
+            if !cfg!(target_os = "linux") {
+                eprintln!(
+                    "{}",
+                    failure("num discover is only supported on Linux. Exiting")
+                );
+                std::process::exit(1);
+            }
+            theme::init_styling("auto");
+            #[cfg(target_os = "linux")]
+            {
+                let address = *discover_matches.get_one::<IpAddr>("ADDRESS").unwrap();
+                let wait = Duration::from_millis(
+                    discover_matches
+                        .get_one::<u64>("timeout")
+                        .copied()
+                        .unwrap_or(2000),
+                );
+                let interface = discover_matches
+                    .get_one::<String>("interface")
+                    .map(String::as_str);
+                let responders = match discover::run(address, interface, wait).await {
+                    Ok(responders) => responders,
+                    Err(e) => {
+                        eprintln!("{}", failure(format!("{e}. Exiting")));
+                        std::process::exit(1);
+                    }
+                };
+                println!("{}", discover::render(address, &responders));
+                if discover_matches.get_flag("add") {
+                    let (output_path, _) = results_location(discover_matches).await;
+                    let socket_path = output_path.join(CONTROL_SOCKET_NAME);
+                    let mut failed = false;
+                    for responder in &responders {
+                        let request = format!("add-target {}", responder.address);
+                        match control::send(&socket_path, &request).await {
+                            Ok(reply) => println!("{reply}"),
+                            // Hosts that are already monitored don't stop the others being added
+                            Err(e) => {
+                                eprintln!("{}", failure(e));
+                                failed = true;
+                            }
+                        }
+                    }
+                    if failed {
+                        std::process::exit(1);
+                    }
+                }
+            }
+            return;
+        }
+        Some(("path", path_matches)) => {
+            if !cfg!(target_os = "linux") {
+                eprintln!(
+                    "{}",
+                    failure("num path is only supported on Linux. Exiting")
+                );
+                std::process::exit(1);
+            }
+            theme::init_styling("auto");
+            let timeout = Duration::from_millis(
+                path_matches
+                    .get_one::<u64>("timeout")
+                    .copied()
+                    .unwrap_or(500),
+            );
+            let delay =
+                Duration::from_secs(path_matches.get_one::<u64>("delay").copied().unwrap_or(1));
+            if timeout >= delay {
+                eprintln!(
+                    "{}",
+                    failure("Timeout must be shorter than the delay between rounds. Exiting")
+                );
+                std::process::exit(1);
+            }
+            #[cfg(target_os = "linux")]
+            {
+                let config = path::PathConfig {
+                    target: path_matches.get_one::<String>("ADDRESS").unwrap().clone(),
+                    delay,
+                    timeout,
+                    max_hops: path_matches
+                        .get_one::<u8>("max-hops")
+                        .copied()
+                        .unwrap_or(30),
+                    output_path: path_matches.get_one::<PathBuf>("output").cloned(),
+                };
+                if let Err(e) = path::run(config, shutdown_signal()).await {
+                    eprintln!("{}", failure(format!("{e}. Exiting")));
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        _ => {}
+    }
+
     // Extract values from parser
-    let addr = matches.get_one::<String>("ADDRESS").unwrap().to_string();
-    let output_path = matches.get_one::<PathBuf>("output").unwrap().to_path_buf();
-    let timeout = matches
-        .get_one::<u64>("timeout")
-        .unwrap_or(&1000)
-        .to_owned();
-    let delay = matches.get_one::<u64>("delay").unwrap_or(&120).to_owned();
-    let num_bytes = matches.get_one::<u8>("num-bytes").unwrap_or(&4).to_owned();
-    let ttl = matches.get_one::<u32>("ttl").unwrap_or(&128).to_owned();
-    let verbose_mode = matches.get_flag("quiet");
+    let throughput_url = matches.get_one::<HttpUrl>("throughput-url").cloned();
+    let throughput_every = matches
+        .get_one::<Duration>("throughput-every")
+        .copied()
+        .unwrap_or(Duration::from_secs(6 * 3600));
+    let public_ip_source = matches.get_one::<PublicIpSource>("public-ip").cloned();
+    let public_ip_every = matches
+        .get_one::<Duration>("public-ip-every")
+        .copied()
+        .unwrap_or(Duration::from_secs(600));
+    let event_log = matches.get_flag("event-log");
+    let trace_path = matches.get_flag("trace-path");
+    let watch_config = !matches.get_flag("no-watch");
+    let container_mode = matches.get_flag("container");
+    let verbose_mode = matches.get_flag("quiet") && !container_mode;
+    let color_choice = if container_mode {
+        "never"
+    } else {
+        matches
+            .get_one::<String>("color")
+            .map_or("auto", String::as_str)
+    };
+    theme::init_styling(color_choice);
+    theme::set_ascii_only(matches.get_flag("ascii"));
+    if !local_offset_known && verbose_mode {
+        eprintln!(
+            "{}",
+            failure("Local timezone could not be determined, timestamps will be in UTC")
+        );
+    }
+    // Redrawing the TUI only makes sense on an ANSI-capable terminal; otherwise print one line per
+    // ping
+    let interactive = stdout().is_terminal() && theme::terminal_supports_ansi();
 
-    if !output_path.exists() || !output_path.is_dir() {
+    // Settings come from the command line, then the environment, then the config file's section
+    // for the target, then the rest of the config file
+    let mut cli_settings = Settings::from_matches(&matches);
+    let env_settings = match Settings::from_env() {
+        Ok(env_settings) => env_settings,
+        Err(e) => {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
+    };
+    let config_path = matches.get_one::<PathBuf>("config").cloned();
+    let file_settings = match &config_path {
+        Some(path) => match Settings::load(path).await {
+            Ok(file_settings) => file_settings,
+            Err(e) => {
+                eprintln!("{}", failure(format!("{e}. Exiting")));
+                std::process::exit(1);
+            }
+        },
+        None => Settings::default(),
+    };
+    // `-` as the address reads a list of targets from stdin, like --targets-file -
+    let mut lists = Vec::new();
+    if cli_settings.address.as_deref() == Some("-") {
+        cli_settings.address = None;
+        lists.push(Path::new("-"));
+    }
+    lists.extend(
+        matches
+            .get_one::<PathBuf>("targets-file")
+            .map(PathBuf::as_path),
+    );
+    let mut listed_targets = Vec::new();
+    for path in lists {
+        match read_target_list(path).await {
+            Ok(targets) => listed_targets.extend(targets),
+            Err(e) => {
+                eprintln!("{}", failure(format!("{e}. Exiting")));
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut seen = HashSet::new();
+    listed_targets.retain(|listed| seen.insert(listed.target.clone()));
+    // Without an address, the first listed target becomes the main one
+    if [&cli_settings, &env_settings, &file_settings]
+        .iter()
+        .all(|settings| settings.address.is_none())
+        && !listed_targets.is_empty()
+    {
+        cli_settings.address = Some(listed_targets.remove(0).target);
+    }
+    let overrides = cli_settings.clone().or(env_settings.clone());
+    let address = overrides.address.clone().or(file_settings.address.clone());
+    listed_targets.retain(|listed| Some(&listed.target) != address.as_ref());
+    let target_settings = file_settings.target_section(address.as_deref());
+    let merged = overrides
+        .clone()
+        .or(target_settings.clone())
+        .or(file_settings.clone());
+    let (settings, adjustment) = merged.adjusted();
+    match settings.theme() {
+        Ok(theme) => theme::set_theme(theme),
+        Err(e) => {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
+    }
+    if let Some(adjustment) = &adjustment {
+        eprintln!("{}", failure(adjustment));
+    }
+    if matches.get_flag("dry-run") {
+        let adjusted = Settings {
+            timeout: settings
+                .timeout
+                .filter(|_| settings.timeout != merged.timeout),
+            delay: settings.delay.filter(|_| settings.delay != merged.delay),
+            ..Settings::default()
+        };
+        let mut sources = vec![
+            (Source::AutoAdjust, &adjusted),
+            (Source::CommandLine, &cli_settings),
+            (Source::Environment, &env_settings),
+        ];
+        if let Some(path) = &config_path {
+            if let Some(address) = &address {
+                sources.push((Source::TargetSection(path, address), &target_settings));
+            }
+            sources.push((Source::ConfigFile(path), &file_settings));
+        }
+        let defaults = Settings::defaults();
+        sources.push((Source::Default, &defaults));
+        println!("{}", describe_sources(&sources));
+        let command_line_only = describe_command_line_only(&matches);
+        if !command_line_only.is_empty() {
+            println!("\nOnly read from the command line:\n{command_line_only}");
+        }
+        let check = settings
+            .probe_settings()
+            .and_then(|_| settings.recording_settings())
+            .and_then(|_| {
+                if settings.output.is_none() && !container_mode {
+                    return Err("No output path given".to_string());
+                }
+                Ok(())
+            });
+        if let Err(e) = check {
+            eprintln!("{}", failure(e));
+            std::process::exit(1);
+        }
+        std::process::exit(0);
+    }
+    let probe = match settings.probe_settings() {
+        Ok(probe) => probe,
+        Err(e) => {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
+    };
+    let RecordingSettings {
+        missed_tick_behavior,
+        wifi,
+        nic_counters,
+        asn_lookup,
+        columns,
+        delimiter,
+        sinks,
+        resume,
+        sync_writes,
+        encrypt,
+        sign,
+        flush_policy,
+        write_overflow,
+        max_write_failures,
+    } = match settings.recording_settings() {
+        Ok(recording) => recording,
+        Err(e) => {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
+    };
+    for listed in &listed_targets {
+        if let Err(e) = added_target_settings(&overrides, &file_settings, &listed.target) {
+            eprintln!("{}", failure(format!("{}: {e}. Exiting", listed.target)));
+            std::process::exit(1);
+        }
+    }
+    let gateway = matches.get_one::<String>("gateway").cloned();
+    if let Some(gateway) = &gateway {
+        if Some(gateway) != address.as_ref()
+            && !listed_targets
+                .iter()
+                .any(|listed| &listed.target == gateway)
+        {
+            eprintln!(
+                "{}",
+                failure(format!(
+                    "Gateway {gateway} is not one of the monitored targets. Exiting"
+                ))
+            );
+            std::process::exit(1);
+        }
+    }
+    let main_target = address.clone().unwrap_or_default();
+    let interface_columns = columns
+        .as_ref()
+        .is_some_and(|columns| columns.has_wifi() || columns.has_nic_counters());
+    if event_log && !cfg!(any(windows, target_os = "macos")) {
         eprintln!(
             "{}",
-            format!("Output path {} is invalid. Exiting", output_path.display()).red()
+            failure("The event log is only supported on Windows and macOS. Exiting")
         );
         std::process::exit(1);
     }
-    let canonicalized_output_path = output_path.canonicalize().unwrap();
-
-    // Need to check as otherwise timer will de-sync
-    if timeout >= delay * 1000 {
+    if (wifi || nic_counters || interface_columns) && !cfg!(target_os = "linux") {
+        eprintln!(
+            "{}",
+            failure("Wi-Fi and interface counter logging are only supported on Linux. Exiting")
+        );
+        std::process::exit(1);
+    }
+    if trace_path && !cfg!(target_os = "linux") {
+        eprintln!(
+            "{}",
+            failure("Path tracing is only supported on Linux. Exiting")
+        );
+        std::process::exit(1);
+    }
+    let probe_name = settings
+        .probe_name
+        .clone()
+        .or_else(host::hostname)
+        .unwrap_or_default();
+    let output_path = settings.output;
+    if output_path.is_none() && !container_mode {
+        eprintln!(
+            "{}",
+            failure("No output path given (pass -o, set NUM_OUTPUT or set output in the config file). Exiting")
+        );
+        std::process::exit(1);
+    }
+    if resume && output_path.is_none() {
+        eprintln!(
+            "{}",
+            failure("--resume needs an output path to read the saved state from. Exiting")
+        );
+        std::process::exit(1);
+    }
+    if let Some(output_path) = output_path.as_ref().filter(|path| !path.is_dir()) {
         eprintln!(
             "{}",
-            "Delay must be greater than the timeout. Exiting"
-                .to_string()
-                .red()
+            failure(format!(
+                "Output path {} is invalid. Exiting",
+                output_path.display()
+            ))
         );
         std::process::exit(1);
     }
 
-    let app_task = task::spawn(async move {
-        let mut stdout = stdout();
+    let run_id = new_run_id();
+    // Targets added with `num ctl add-target` share the settings of the main one, apart from
+    // their own section of the config file
+    let settings_for = {
+        let overrides = overrides.clone();
+        let file_settings = file_settings.clone();
+        move |target: &str| added_target_settings(&overrides, &file_settings, target)
+    };
+    let config_for: ConfigBuilder = {
+        let run_id = run_id.clone();
+        let probe_name = probe_name.clone();
+        Box::new(move |probe, output_path, resume| EngineConfig {
+            probe,
+            sync_writes,
+            encrypt: encrypt.clone(),
+            sign: sign.clone(),
+            flush_policy,
+            write_overflow,
+            max_write_failures,
+            output_path,
+            wifi,
+            nic_counters,
+            columns: columns.clone(),
+            delimiter,
+            sinks: sinks.clone(),
+            resume,
+            run_id: run_id.clone(),
+            probe_name: probe_name.clone(),
+            asn_lookup,
+            clock: Arc::new(SystemClock),
+        })
+    };
+    let (update_tx, update_rx) = mpsc::channel();
+    let renderer_handle = if container_mode {
+        Some(ndjson::spawn(
+            probe.clone(),
+            run_id.clone(),
+            probe_name.clone(),
+            update_rx,
+        ))
+    } else if verbose_mode {
+        Some(tui::spawn(
+            TuiConfig {
+                probe: probe.clone(),
+                output_path: output_path.as_ref().unwrap().canonicalize().unwrap(),
+                run_id: run_id.clone(),
+                interactive,
+            },
+            update_rx,
+        ))
+    } else {
+        None
+    };
 
-        let mut interval = tokio::time::interval(Duration::from_secs(delay));
-        let mut engine = Engine::new(
-            addr.clone(),
-            ttl,
-            timeout,
-            num_bytes,
-            delay,
-            output_path.clone(),
-        )
-        .await;
-        let dt_fmt = format_description::parse(DT_FMT).unwrap();
-        if verbose_mode {
-            stdout.execute(cursor::Hide).unwrap();
-        }
-        let target_text = generate_target_text(&addr);
-        let path_text = generate_path_text(&canonicalized_output_path);
-        let delay_timeout_text = generate_delay_timeout_text(delay, timeout);
-        let bytes_ttl_text = generate_bytes_ttl_text(ttl, num_bytes);
-        loop {
-            // wait for timer
-            interval.tick().await;
-            let (time, result) = engine.ping().await;
-            if verbose_mode {
-                let last_ping_text: StyledContent<String> = generate_ping_text(
-                    num_bytes,
-                    ttl,
-                    &dt_fmt,
-                    time,
-                    result,
-                    engine.get_processed_ip(),
-                );
-                let last_successful_text: StyledContent<String> =
-                    generate_last_success_text(&mut engine, &dt_fmt);
-                let last_failed_text: StyledContent<String> =
-                    generate_last_failed_text(&engine, &dt_fmt);
-                stdout
-                    .execute(terminal::Clear(terminal::ClearType::FromCursorDown))
-                    .unwrap();
-                display_tui(
-                    &stdout,
-                    &last_successful_text,
-                    &last_failed_text,
-                    &last_ping_text,
-                    &target_text,
-                    &path_text,
-                    &delay_timeout_text,
-                    &bytes_ttl_text,
+    // SIGHUP reopens the output files (for logrotate) and reloads the config file, which is also
+    // reloaded whenever it changes. SIGUSR2 only reopens the output files.
+    let (control_tx, mut control_rx) = async_mpsc::unbounded_channel::<Control>();
+    let mut config_watcher = match config_path {
+        Some(path) => Some(ConfigWatcher::new(path, overrides, file_settings.clone()).await),
+        None => None,
+    };
+    #[cfg(unix)]
+    {
+        use signal::unix::SignalKind;
+        forward_signal(
+            SignalKind::hangup(),
+            &[Control::ReopenLogs, Control::ReloadConfig],
+            control_tx.clone(),
+        );
+        forward_signal(
+            SignalKind::user_defined2(),
+            &[Control::ReopenLogs],
+            control_tx,
+        );
+    }
+    #[cfg(not(unix))]
+    drop(control_tx);
+
+    let (health_tx, health_rx) = watch::channel(Health {
+        last_tick: Instant::now(),
+        stale_after: 3 * probe.delay,
+        ready: false,
+    });
+    let (metrics_tx, _) = watch::channel(None);
+    #[cfg(target_os = "linux")]
+    task::spawn(systemd::notify_health(health_tx.subscribe()));
+    if let Some(listen_addr) = matches.get_one::<SocketAddr>("listen") {
+        match TcpListener::bind(listen_addr).await {
+            Ok(listener) => {
+                task::spawn(api::serve(listener, health_rx, metrics_tx.subscribe()));
+            }
+            Err(e) => {
+                eprintln!(
+                    "{}",
+                    failure(format!("Could not listen on {listen_addr}: {e}. Exiting"))
                 );
-                stdout.flush().unwrap();
-                stdout.execute(cursor::MoveUp(10)).unwrap();
+                std::process::exit(1);
             }
         }
+    }
+
+    // Throughput is measured in its own task so the download doesn't hold up pings
+    let (throughput_tx, mut throughput_rx) = async_mpsc::unbounded_channel();
+    if let Some(url) = throughput_url {
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(throughput_every);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if throughput_tx.send(throughput::measure(&url).await).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // So is the public address, which comes from an outside service
+    let (public_ip_tx, mut public_ip_rx) = async_mpsc::unbounded_channel();
+    if let Some(source) = public_ip_source {
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(public_ip_every);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let mut last = None;
+            loop {
+                interval.tick().await;
+                let result = source.query().await.map(|address| PublicAddress {
+                    address,
+                    network: None,
+                });
+                let result = match result {
+                    // Only new addresses are looked up, as the lookup goes to a third party too
+                    Ok(mut answer) if asn_lookup && last != Some(answer.address) => {
+                        answer.network = Some(asn::lookup(answer.address).await);
+                        last = Some(answer.address);
+                        Ok(answer)
+                    }
+                    result => result,
+                };
+                if public_ip_tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    // The ping loop is split into stages connected by channels: the scheduler says when a probe
+    // is due, the processor (the engine) probes the target and keeps the statistics and result
+    // files, and its output is copied to consumers that render it, deliver alerts and report
+    // health, so none of them can hold up pings
+    let (schedule_tx, schedule_rx) = watch::channel(Schedule::of(&probe));
+    let (alert_tx, mut alert_rx) = async_mpsc::unbounded_channel::<String>();
+    let mut fanout = Fanout::new();
+    fanout.add(|outputs| pipeline::render(outputs, update_tx));
+    fanout.add(|outputs| pipeline::deliver_alerts(outputs, alert_tx));
+    fanout.add(|outputs| pipeline::publish_health(outputs, schedule_rx, health_tx));
+    {
+        let (target, probe_name) = (main_target.clone(), probe_name.clone());
+        fanout.add(|outputs| pipeline::publish_metrics(outputs, target, probe_name, metrics_tx));
+    }
+    let (textfile_tx, mut textfile_rx) = async_mpsc::unbounded_channel::<String>();
+    if let Some(path) = matches.get_one::<PathBuf>("textfile").cloned() {
+        let (target, probe_name) = (main_target.clone(), probe_name.clone());
+        fanout.add(|outputs| {
+            pipeline::write_textfile(outputs, target, probe_name, path, textfile_tx)
+        });
+    }
+    let (openmetrics_tx, mut openmetrics_rx) = async_mpsc::unbounded_channel::<String>();
+    if let Some(path) = matches.get_one::<PathBuf>("openmetrics").cloned() {
+        let (target, probe_name) = (main_target.clone(), probe_name.clone());
+        let period = matches
+            .get_one::<Duration>("openmetrics-every")
+            .copied()
+            .unwrap_or(Duration::from_secs(15));
+        fanout.add(|outputs| {
+            pipeline::write_openmetrics(outputs, target, probe_name, path, period, openmetrics_tx)
+        });
+    }
+    let (zabbix_tx, mut zabbix_rx) = async_mpsc::unbounded_channel::<String>();
+    if let Some(server) = matches.get_one::<String>("zabbix").cloned() {
+        let sender = zabbix::Sender {
+            server,
+            host: matches
+                .get_one::<String>("zabbix-host")
+                .cloned()
+                .unwrap_or_else(|| probe_name.clone()),
+            prefix: matches
+                .get_one::<String>("zabbix-key-prefix")
+                .cloned()
+                .unwrap_or_else(|| "num".to_string()),
+        };
+        let target = main_target.clone();
+        let period = matches
+            .get_one::<Duration>("zabbix-every")
+            .copied()
+            .unwrap_or(Duration::from_secs(60));
+        fanout.add(|outputs| zabbix::push(outputs, target, sender, period, zabbix_tx));
+    }
+    let (snmp_tx, mut snmp_rx) = async_mpsc::unbounded_channel::<String>();
+    if let Some(destination) = matches.get_one::<String>("snmp-trap").cloned() {
+        let security = match matches.get_one::<String>("snmp-user") {
+            Some(user) => snmp::Security::User {
+                name: user.clone(),
+                engine_id: matches
+                    .get_one::<Vec<u8>>("snmp-engine-id")
+                    .cloned()
+                    .unwrap(),
+                auth: matches.get_one::<snmp::Auth>("snmp-auth").cloned(),
+            },
+            None => snmp::Security::Community(
+                matches
+                    .get_one::<String>("snmp-community")
+                    .cloned()
+                    .unwrap_or_else(|| "public".to_string()),
+            ),
+        };
+        let config = snmp::TrapConfig {
+            destination,
+            security,
+            oid: matches
+                .get_one::<snmp::Oid>("snmp-oid")
+                .cloned()
+                .unwrap_or_else(|| snmp::DEFAULT_OID.parse().unwrap()),
+        };
+        let target = main_target.clone();
+        fanout.add(|outputs| snmp::send_traps(outputs, target, config, snmp_tx));
+    }
+    let (event_log_tx, mut event_log_rx) = async_mpsc::unbounded_channel::<String>();
+    if event_log {
+        let target = main_target.clone();
+        fanout.add(|outputs| event_log::record(outputs, target, event_log_tx));
+    }
+
+    // `num ctl` talks to the ping loop through a socket in the output directory
+    let (request_tx, mut request_rx) = async_mpsc::unbounded_channel::<control::Pending>();
+    #[cfg(unix)]
+    let control_socket = output_path
+        .as_ref()
+        .map(|path| path.join(CONTROL_SOCKET_NAME));
+    // Without the socket (e.g. the path is too long for one) monitoring goes on without `num ctl`
+    #[cfg(unix)]
+    let control_error = control_socket.as_ref().and_then(|path| {
+        control::listen(path, request_tx)
+            .err()
+            .map(|e| format!("Could not listen on {}: {e}", path.display()))
     });
-    // Below is invoked upon the user pressing Ctrl+C
-    signal::ctrl_c().await.expect("event listener failure");
-    // Move cursor down to prevent overwriting old TUI
-    if verbose_mode {
-        let mut exit_stdout = stdout();
-        exit_stdout.execute(cursor::MoveDown(10)).unwrap();
-        println!("{}", "\nExiting".blue().bold());
-        exit_stdout.execute(cursor::Show).unwrap();
-    } else {
-        println!(); // Move down one line
+    // Only removed on exit if it's ours
+    #[cfg(unix)]
+    let control_socket = control_socket.filter(|_| control_error.is_none());
+    #[cfg(not(unix))]
+    let control_error: Option<String> = {
+        drop(request_tx);
+        None
+    };
+
+    let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+    let mut app_task = task::spawn(async move {
+        let engine = Engine::new(config_for(probe, output_path.clone(), resume)).await;
+        let mut engine = match engine {
+            Ok(engine) => engine,
+            Err(e) => {
+                fanout.close().await;
+                return (None, Err(e));
+            }
+        };
+        if let Some(error) = config_watcher
+            .as_ref()
+            .filter(|_| watch_config)
+            .and_then(ConfigWatcher::watch_error)
+        {
+            engine
+                .log_event(local_now(), "config_watch_failed", error)
+                .await;
+        }
+        if let Some(error) = control_error {
+            engine
+                .log_event(local_now(), "control_socket_failed", &error)
+                .await;
+        }
+        // Traced from the address the engine probes, which it keeps even if the name moves
+        let (attribution_tx, mut attribution_rx) = async_mpsc::unbounded_channel::<String>();
+        let (path_tx, mut path_rx) = async_mpsc::unbounded_channel::<String>();
+        #[cfg(target_os = "linux")]
+        if trace_path {
+            match path::PathTracer::new(engine.get_processed_ip(), path::TRACE_MAX_HOPS) {
+                Ok(tracer) => fanout.add(|outputs| {
+                    path::attribute_outages(
+                        outputs,
+                        tracer,
+                        path::TRACE_DELAY,
+                        path::TRACE_TIMEOUT,
+                        attribution_tx,
+                        path_tx,
+                    )
+                }),
+                Err(e) => {
+                    let details = probe::socket_error_help(&e);
+                    engine
+                        .log_event(local_now(), "path_trace_failed", &details)
+                        .await;
+                }
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        drop((attribution_tx, path_tx));
+        // Every target reports going DOWN and back, to judge whether the whole internet is down
+        let (state_tx, mut state_rx) = async_mpsc::unbounded_channel();
+        let mut composite = Composite::new(gateway);
+        composite.update(&main_target, false, Instant::now());
+        let mut main_down = false;
+        let mut extra_targets = ExtraTargets::new(
+            Box::new(settings_for),
+            config_for,
+            output_path,
+            missed_tick_behavior,
+            state_tx,
+        );
+        for listed in listed_targets {
+            match extra_targets
+                .add(&listed.target, listed.label.as_deref())
+                .await
+            {
+                Ok(_) => {
+                    composite.update(&listed.target, false, Instant::now());
+                }
+                Err(e) => {
+                    let details = format!("{}: {e}", listed.target);
+                    engine
+                        .log_event(local_now(), "add_target_failed", &details)
+                        .await;
+                }
+            }
+        }
+        let (added_tx, mut added_rx) = async_mpsc::unbounded_channel();
+        let mut portal_results = engine.take_portal_results();
+        let mut network_lookups = engine.take_network_lookups();
+        // Started after the engine so slow startup isn't seen as a late tick
+        let mut ticks = pipeline::spawn_scheduler(schedule_tx.subscribe(), missed_tick_behavior);
+        let mut outcome = loop {
+            // hand each tick to the prober and wait for its results, or stop once shutdown is
+            // requested
+            let probed = tokio::select! {
+                Some(tick) = ticks.recv() => {
+                    if engine.is_paused() {
+                        continue;
+                    }
+                    if let Some(watcher) = config_watcher.as_mut().filter(|_| watch_config) {
+                        // Only when the file can't be watched
+                        if watcher.changed().await {
+                            let _ = reload_config(
+                                &mut engine,
+                                &mut extra_targets,
+                                watcher,
+                                &schedule_tx,
+                                &mut fanout,
+                            )
+                            .await;
+                        }
+                    }
+                    engine.start_probe(tick).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(probed) = engine.next_probed() => probed,
+                () = config_changed(&mut config_watcher), if watch_config => {
+                    if let Some(watcher) = &mut config_watcher {
+                        let _ = reload_config(&mut engine, &mut extra_targets, watcher, &schedule_tx, &mut fanout).await;
+                    }
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(control) = control_rx.recv() => {
+                    match (control, &mut config_watcher) {
+                        (Control::ReopenLogs, _) => {
+                            engine.reopen_logs().await;
+                            extra_targets.reopen_logs();
+                        }
+                        (Control::ReloadConfig, Some(watcher)) => {
+                            let _ = reload_config(&mut engine, &mut extra_targets, watcher, &schedule_tx, &mut fanout).await;
+                        }
+                        (Control::ReloadConfig, None) => {}
+                    }
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some((request, reply)) = request_rx.recv() => {
+                    let result = match request {
+                        Request::Status => Ok(status(&engine, &extra_targets)),
+                        Request::Stats => Ok(engine.summary("running").to_string()),
+                        Request::Pause => {
+                            engine.pause_probing().await;
+                            Ok("probing paused".to_string())
+                        }
+                        Request::Resume => {
+                            engine.resume_probing().await;
+                            Ok("probing resumed".to_string())
+                        }
+                        Request::Reload => match &mut config_watcher {
+                            Some(watcher) => reload_config(&mut engine, &mut extra_targets, watcher, &schedule_tx, &mut fanout)
+                                .await
+                                .map(|()| "config reloaded".to_string()),
+                            None => Err("The monitor wasn't started with --config".to_string()),
+                        },
+                        // Creating the target's engine (resolving its name, looking up its network)
+                        // and writing out a removed one's results can take seconds, so they don't
+                        // hold up probing and the reply is sent when they're done
+                        Request::AddTarget(target) => match extra_targets.start(&target, None) {
+                            Ok(starting) => {
+                                let added_tx = added_tx.clone();
+                                task::spawn(async move {
+                                    let started = starting.run().await;
+                                    let _ = added_tx.send((target, started, reply));
+                                });
+                                continue;
+                            }
+                            Err(e) => Err(e),
+                        },
+                        Request::RemoveTarget(target) => {
+                            extra_targets.remove(&target, reply);
+                            if let Some(change) = composite.remove(&target, Instant::now()) {
+                                engine.record_internet_state(&change).await;
+                            }
+                            publish(&mut engine, &mut fanout);
+                            continue;
+                        }
+                    };
+                    let _ = reply.send(result);
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some((target, started, reply)) = added_rx.recv() => {
+                    let result = extra_targets.finish(&target, started);
+                    if result.is_ok() {
+                        composite.update(&target, false, Instant::now());
+                    }
+                    let _ = reply.send(result);
+                    continue;
+                }
+                Some(result) = throughput_rx.recv() => {
+                    engine.record_throughput(result).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(result) = public_ip_rx.recv() => {
+                    engine.record_public_ip(result).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(result) = portal_results.recv() => {
+                    engine.record_portal_result(result).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(lookup) = network_lookups.recv() => {
+                    engine.record_network(lookup).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = alert_rx.recv() => {
+                    engine.record_alert_failure(&error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = openmetrics_rx.recv() => {
+                    engine.log_event(local_now(), "openmetrics_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = zabbix_rx.recv() => {
+                    engine.log_event(local_now(), "zabbix_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = textfile_rx.recv() => {
+                    engine.log_event(local_now(), "textfile_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = snmp_rx.recv() => {
+                    engine.log_event(local_now(), "snmp_trap_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = event_log_rx.recv() => {
+                    engine.log_event(local_now(), "event_log_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(report) = attribution_rx.recv() => {
+                    engine.log_event(local_now(), "outage_attributed", &report).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some(error) = path_rx.recv() => {
+                    engine.log_event(local_now(), "path_trace_failed", &error).await;
+                    publish(&mut engine, &mut fanout);
+                    continue;
+                }
+                Some((target, down)) = state_rx.recv() => {
+                    // Sent by the target before it was removed
+                    if !extra_targets.contains(&target) {
+                        continue;
+                    }
+                    if let Some(change) = composite.update(&target, down, Instant::now()) {
+                        engine.record_internet_state(&change).await;
+                        publish(&mut engine, &mut fanout);
+                    }
+                    continue;
+                }
+                _ = &mut shutdown_rx => break Ok(()),
+            };
+            let scheduled = probed.scheduled;
+            let (time, result) = engine.record_probe(probed).await;
+            let stats = engine.stats();
+            if main_down != (stats.state == LinkState::Down) {
+                main_down = !main_down;
+                if let Some(change) = composite.update(&main_target, main_down, Instant::now()) {
+                    engine.record_internet_state(&change).await;
+                }
+            }
+            publish(&mut engine, &mut fanout);
+            fanout.send(Output::ProbeCompleted(Box::new(PingUpdate {
+                time,
+                address: engine.get_processed_ip(),
+                rtt: result.ok(),
+                running_since: Instant::now()
+                    .checked_sub(stats.runtime)
+                    .unwrap_or_else(Instant::now),
+                stats,
+                next_ping: scheduled + schedule_tx.borrow().delay,
+                storage_error: engine.get_storage_error(),
+                network: engine.network().map(Network::describe),
+            })));
+            if engine.storage_failed() {
+                break Err(format!(
+                    "Giving up after {max_write_failures} failed writes: {}",
+                    engine.get_storage_error().unwrap_or_default()
+                ));
+            }
+        };
+        extra_targets.stop_all().await;
+        engine.close().await;
+        // Checkpoint the final statistics for a later --resume
+        engine.write_state_file().await;
+        let summary = engine.summary(match &outcome {
+            Ok(()) => "interrupted",
+            Err(e) => e,
+        });
+        if let Err(e) = engine.write_summary(&summary).await {
+            outcome = outcome.and(Err(e));
+        }
+        #[cfg(unix)]
+        if let Some(path) = control_socket {
+            let _ = std::fs::remove_file(path);
+        }
+        // Lets the consumers finish, after which the renderer restores the terminal
+        fanout.close().await;
+        (Some(summary), outcome)
+    });
+    // Run until the user presses Ctrl+C (or the process is terminated) or the ping loop gives up
+    let outcome = tokio::select! {
+        () = shutdown_signal() => {
+            // Stopping the ping loop flushes buffered results and closes the fanout, which tells
+            // the TUI to restore the terminal
+            let _ = shutdown_tx.send(());
+            (&mut app_task).await
+        }
+        outcome = &mut app_task => outcome,
+    };
+    match renderer_handle {
+        Some(handle) => handle.join().unwrap(),
+        None => println!(), // Move down one line
+    }
+    if let Ok((summary, outcome)) = outcome {
+        if let Some(summary) = summary {
+            println!("{summary}");
+        }
+        if let Err(e) = outcome {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
     }
-    app_task.abort();
 }
 
-/// Create stylized text representing the last time a ping failed. Red is used to indicate a failed
-/// ping and green represents no failed pings up to the current time.
-fn generate_last_failed_text(engine: &Engine, dt_fmt: &Vec<FormatItem>) -> StyledContent<String> {
-    if let Some(last_failed_time) = engine.get_possible_last_failed_time() {
-        last_failed_time.format(&dt_fmt).unwrap().red()
-    } else {
-        "N/A".to_string().green()
+/// Wait for Ctrl+C or, on unix, SIGTERM (sent by e.g. `docker stop` and systemd).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("event listener failure");
+        tokio::select! {
+            result = signal::ctrl_c() => result.expect("event listener failure"),
+            _ = terminate.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    signal::ctrl_c().await.expect("event listener failure");
 }
 
-/// Create stylized text representing the last time a ping succeeded (and the latency of that ping).
-/// Red indicates no successful pings up to the current time while green represents a successful ping.
-fn generate_last_success_text(
-    engine: &mut Engine,
-    dt_fmt: &Vec<FormatItem>,
-) -> StyledContent<String> {
-    if let Some(last_successful_time) = engine.get_possible_last_successful_time() {
-        format!(
-            "{} ({}ms)",
-            last_successful_time.format(&dt_fmt).unwrap(),
-            engine.get_last_successful_latency().as_millis()
-        )
-        .green()
-    } else {
-        "N/A".to_string().red()
+/// Requests delivered to the ping loop by signal handlers.
+#[derive(Clone, Copy)]
+enum Control {
+    ReloadConfig,
+    ReopenLogs,
+}
+
+/// Send `requests` to the ping loop every time the signal `kind` arrives.
+#[cfg(unix)]
+fn forward_signal(
+    kind: signal::unix::SignalKind,
+    requests: &'static [Control],
+    control_tx: async_mpsc::UnboundedSender<Control>,
+) {
+    let mut signals = signal::unix::signal(kind).expect("event listener failure");
+    task::spawn(async move {
+        while signals.recv().await.is_some() {
+            if requests
+                .iter()
+                .any(|&request| control_tx.send(request).is_err())
+            {
+                break;
+            }
+        }
+    });
+}
+
+/// Send what the engine produced since the last call to the consumers.
+fn publish(engine: &mut Engine, fanout: &mut Fanout) {
+    for output in engine.take_outputs() {
+        fanout.send(output);
     }
 }
 
-/// Create stylized text representing data about the last ping performed. The text is red if the ping
-/// failed, and green otherwise.
-fn generate_ping_text(
-    num_bytes: u8,
-    ttl: u32,
-    dt_fmt: &Vec<FormatItem>,
-    time: OffsetDateTime,
-    result: Result<(IcmpPacket, Duration), SurgeError>,
-    address: IpAddr,
-) -> StyledContent<String> {
-    if let Ok((_, rtt)) = result {
-        format!(
-            "[{}] Reply from {}: bytes={} time={}ms TTL={}",
-            time.format(&dt_fmt).unwrap(),
-            address,
-            num_bytes,
-            rtt.as_millis(),
-            ttl
-        )
-        .green()
+/// Describe the state of the monitor for `num ctl status`.
+fn status(engine: &Engine, extra_targets: &ExtraTargets) -> String {
+    let stats = engine.stats();
+    let summary = engine.summary("running");
+    let mut status = format!(
+        "{} ({}) is {}",
+        summary.target,
+        summary.address,
+        stats.state.label()
+    );
+    if let Some(since) = stats.state_since {
+        status += &format!(" for {}", format_duration(since.elapsed()));
+    }
+    status += if engine.is_paused() {
+        ", probing paused"
     } else {
-        format!("[{}] Ping failed.", time.format(&dt_fmt).unwrap()).red()
+        ", probing"
+    };
+    let added = extra_targets.names();
+    if !added.is_empty() {
+        status += &format!("\nadded targets: {}", added.join(", "));
     }
+    status
 }
 
-/// Generate stylized text representing the target of the ping calls
-fn generate_target_text(addr: &String) -> String {
-    format!("{}Target:{} {addr}\n", Attribute::Bold, Attribute::Reset)
+/// Re-read the config file and apply it to the running engine, restarting the ping schedule if the
+/// delay or jitter changed. Invalid settings are logged and the current ones are kept, and the error is
+/// returned.
+async fn reload_config(
+    engine: &mut Engine,
+    extra_targets: &mut ExtraTargets,
+    watcher: &mut ConfigWatcher,
+    schedule_tx: &watch::Sender<Schedule>,
+    fanout: &mut Fanout,
+) -> Result<(), String> {
+    let result = match watcher.load().await {
+        Ok((probe, warning)) => {
+            if let Some(warning) = warning {
+                engine
+                    .log_event(local_now(), "config_adjusted", &warning)
+                    .await;
+            }
+            engine.reconfigure(&probe).await.map(|()| probe)
+        }
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(probe) => {
+            let schedule = Schedule::of(&probe);
+            schedule_tx
+                .send_if_modified(|current| std::mem::replace(current, schedule) != schedule);
+            fanout.send(Output::ConfigReloaded(Box::new(probe)));
+            let settings = watcher.added_target_settings_builder();
+            extra_targets.reconfigure(settings);
+            Ok(())
+        }
+        Err(e) => {
+            engine
+                .log_event(local_now(), "config_reload_failed", &e)
+                .await;
+            Err(e)
+        }
+    }
 }
 
-/// Generate stylized text representing the output path of the logs/config files
-fn generate_path_text(output_path: &Path) -> String {
-    format!(
-        "{}Output path:{} {}\n",
-        Attribute::Bold,
-        Attribute::Reset,
-        output_path.display()
-    )
+/// Describe the options given on the command line that have no config file key or environment
+/// variable, one per line.
+fn describe_command_line_only(matches: &ArgMatches) -> String {
+    let keys: Vec<&str> = Settings::default()
+        .entries()
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let mut lines = Vec::new();
+    for id in matches.ids().map(Id::as_str) {
+        if keys.contains(&id)
+            || ["ADDRESS", "config", "dry-run"].contains(&id)
+            || matches.value_source(id) != Some(ValueSource::CommandLine)
+        {
+            continue;
+        }
+        let values: Vec<String> = matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string_lossy().into_owned())
+            // Flags have true or false as their value
+            .filter(|value| value != "true" && value != "false")
+            .collect();
+        lines.push(
+            format!("--{id} {}", values.join(" "))
+                .trim_end()
+                .to_string(),
+        );
+    }
+    lines.join("\n")
 }
 
-/// Generate stylized text representing the delay and timeout of the current run  
-fn generate_delay_timeout_text(delay: u64, timeout: u64) -> String {
-    format!(
-        "{}Delay:{} {delay}s, {}Timeout:{} {timeout}ms\n",
-        Attribute::Bold,
-        Attribute::Reset,
-        Attribute::Bold,
-        Attribute::Reset
-    )
+/// Wait until the config file changes, if there is one.
+async fn config_changed(watcher: &mut Option<ConfigWatcher>) {
+    match watcher {
+        Some(watcher) => watcher.next_change().await,
+        None => std::future::pending().await,
+    }
 }
 
-/// Generate stylized text representing the number of bytes and ttl of the current run
-fn generate_bytes_ttl_text(ttl: u32, num_bytes: u8) -> String {
-    format!(
-        "{}Num. Bytes:{} {num_bytes}, {}TTL:{} {ttl}\n",
-        Attribute::Bold,
-        Attribute::Reset,
-        Attribute::Bold,
-        Attribute::Reset
-    )
+/// Read a list of targets from a file, or from stdin if `path` is `-`.
+async fn read_target_list(path: &Path) -> Result<Vec<targets::ListedTarget>, String> {
+    let (name, contents) = if path == Path::new("-") {
+        // Nothing else runs yet, so blocking on stdin holds nothing up
+        let contents = std::io::read_to_string(std::io::stdin())
+            .map_err(|e| format!("Could not read targets from stdin: {e}"))?;
+        ("stdin".to_string(), contents)
+    } else {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+        (path.display().to_string(), contents)
+    };
+    let targets = targets::parse_list(&contents)
+        .map_err(|e| format!("Invalid list of targets in {name}: {e}"))?;
+    if targets.is_empty() {
+        return Err(format!("No targets in {name}"));
+    }
+    Ok(targets)
 }
 
-/// Display a simple TUI (Terminal User Interface) to the user with basic statistics of the app
-/// state.
-#[allow(clippy::too_many_arguments)] // This method helps code readability in main
-fn display_tui(
-    mut stdout: &Stdout,
-    last_successful_text: &StyledContent<String>,
-    last_failed_text: &StyledContent<String>,
-    last_ping_text: &StyledContent<String>,
-    target_text: &String,
-    path_text: &String,
-    delay_timeout_text: &String,
-    bytes_ttl_text: &String,
-) {
-    stdout.write_all(target_text.as_ref()).unwrap();
-    stdout.write_all(path_text.as_ref()).unwrap();
-    stdout.write_all(delay_timeout_text.as_ref()).unwrap();
-    stdout.write_all(bytes_ttl_text.as_ref()).unwrap();
-    writeln!(
-        stdout,
-        "\n{}Last successful ping:{} {last_successful_text}",
-        Attribute::Bold,
-        Attribute::Reset
-    )
-    .unwrap();
-    writeln!(
-        stdout,
-        "{}Last failed ping:{} {last_failed_text}",
-        Attribute::Bold,
-        Attribute::Reset
-    )
-    .unwrap();
-    writeln!(
-        stdout,
-        "\n{}Last Ping Status:{}",
-        Attribute::Bold,
-        Attribute::Reset
-    )
-    .unwrap();
-    writeln!(stdout, "{last_ping_text}").unwrap();
+/// The command line a service manager runs the monitor with: this executable and `args`, with
+/// `--container` added since there's no terminal for the TUI, along with the current directory
+/// that relative paths in `args` are resolved from.
+fn service_command(args: &[String]) -> Result<(Vec<String>, PathBuf), String> {
+    let exe =
+        std::env::current_exe().map_err(|e| format!("Could not find the num executable: {e}"))?;
+    let working_directory = std::env::current_dir()
+        .map_err(|e| format!("Could not read the current directory: {e}"))?;
+    let mut program = vec![exe.to_string_lossy().into_owned()];
+    if !args.iter().any(|arg| arg == "--container") {
+        program.push("--container".to_string());
+    }
+    program.extend(args.iter().cloned());
+    Ok((program, working_directory))
+}
+
+/// Find the output directory of a monitor for subcommands that read its results, from `-o`,
+/// `NUM_OUTPUT` or the `--config` file, along with the rest of the environment and file settings.
+/// Exits if none gives one.
+async fn results_location(matches: &ArgMatches) -> (PathBuf, Settings) {
+    let env_settings = match Settings::from_env() {
+        Ok(env_settings) => env_settings,
+        Err(e) => {
+            eprintln!("{}", failure(format!("{e}. Exiting")));
+            std::process::exit(1);
+        }
+    };
+    let file_settings = match matches.get_one::<PathBuf>("config") {
+        Some(path) => match Settings::load(path).await {
+            Ok(file_settings) => file_settings,
+            Err(e) => {
+                eprintln!("{}", failure(format!("{e}. Exiting")));
+                std::process::exit(1);
+            }
+        },
+        None => Settings::default(),
+    };
+    let settings = env_settings.or(file_settings);
+    let Some(output_path) = matches
+        .get_one::<PathBuf>("output")
+        .cloned()
+        .or(settings.output.clone())
+    else {
+        eprintln!(
+            "{}",
+            failure("No output path given (pass -o, set NUM_OUTPUT or set output in the config file). Exiting")
+        );
+        std::process::exit(1);
+    };
+    (output_path, settings)
 }
@@ -0,0 +1,173 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::engine::LinkState;
+use crate::tui::PingUpdate;
+use std::fmt::Write;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// Metrics exported for the main target, as name, type and help text.
+pub const METRICS: [(&str, &str, &str); 6] = [
+    ("num_up", "gauge", "Whether the target is UP (1) or not (0)"),
+    (
+        "num_rtt_seconds",
+        "gauge",
+        "Round trip time of the last probe, NaN if it failed",
+    ),
+    (
+        "num_rtt_avg_seconds",
+        "gauge",
+        "Mean round trip time since the monitor started",
+    ),
+    ("num_probes_sent_total", "counter", "Probes sent"),
+    ("num_probes_received_total", "counter", "Probes answered"),
+    ("num_outages_total", "counter", "Times the target went DOWN"),
+];
+
+/// The values of the metrics after a probe.
+#[derive(Clone)]
+pub struct Snapshot {
+    pub target: String,
+    pub probe_name: String,
+    pub state: LinkState,
+    pub last_rtt: Option<Duration>,
+    pub avg_rtt: Option<Duration>,
+    pub sent: u64,
+    pub received: u64,
+    pub outages: u64,
+    /// When the probe was sent
+    pub time: OffsetDateTime,
+}
+
+impl Snapshot {
+    pub fn new(target: &str, probe_name: &str, update: &PingUpdate) -> Self {
+        Snapshot {
+            target: target.to_string(),
+            probe_name: probe_name.to_string(),
+            state: update.stats.state,
+            last_rtt: update.rtt,
+            avg_rtt: update.stats.avg_rtt(),
+            sent: update.stats.sent,
+            received: update.stats.received,
+            outages: update.stats.outages,
+            time: update.time,
+        }
+    }
+
+    /// The values of `METRICS`, in order.
+    fn values(&self) -> [f64; 6] {
+        let seconds = |rtt: Option<Duration>| rtt.map_or(f64::NAN, |rtt| rtt.as_secs_f64());
+        [
+            f64::from(u8::from(self.state == LinkState::Up)),
+            seconds(self.last_rtt),
+            seconds(self.avg_rtt),
+            self.sent as f64,
+            self.received as f64,
+            self.outages as f64,
+        ]
+    }
+
+    /// Render the metrics in the Prometheus text format, labelled with the target and probe name.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        for ((name, kind, help), value) in METRICS.iter().zip(self.values()) {
+            let _ = write!(
+                text,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name}{{{}}} {}\n",
+                self.labels(),
+                format_value(value)
+            );
+        }
+        text
+    }
+
+    /// Render the metrics in the OpenMetrics text format, where counter families are named
+    /// without their `_total` suffix, samples carry the time of the probe, and the exposition
+    /// ends with `# EOF`.
+    pub fn render_openmetrics(&self) -> String {
+        let timestamp = self.time.unix_timestamp_nanos() as f64 / 1e9;
+        let mut text = String::new();
+        for ((name, kind, help), value) in METRICS.iter().zip(self.values()) {
+            let family = name.strip_suffix("_total").unwrap_or(name);
+            let _ = write!(
+                text,
+                "# HELP {family} {help}\n# TYPE {family} {kind}\n{name}{{{}}} {} {timestamp:.3}\n",
+                self.labels(),
+                format_value(value)
+            );
+        }
+        text.push_str("# EOF\n");
+        text
+    }
+
+    fn labels(&self) -> String {
+        format!(
+            "target=\"{}\",probe_name=\"{}\"",
+            escape(&self.target),
+            escape(&self.probe_name)
+        )
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_rendered_with_their_labels() {
+        let snapshot = Snapshot {
+            target: "1.1.1.1".to_string(),
+            probe_name: "office \"A\"".to_string(),
+            state: LinkState::Up,
+            last_rtt: None,
+            avg_rtt: Some(Duration::from_millis(12)),
+            sent: 10,
+            received: 9,
+            outages: 1,
+            time: OffsetDateTime::from_unix_timestamp(1_685_570_400).unwrap(),
+        };
+        let text = snapshot.render();
+        let labels = r#"{target="1.1.1.1",probe_name="office \"A\""}"#;
+        assert!(text.starts_with("# HELP num_up Whether the target is UP (1) or not (0)\n"));
+        assert!(text.contains(&format!("num_up{labels} 1\n")));
+        assert!(text.contains(&format!("num_rtt_seconds{labels} NaN\n")));
+        assert!(text.contains(&format!("num_rtt_avg_seconds{labels} 0.012\n")));
+        assert!(text.contains(&format!("num_probes_received_total{labels} 9\n")));
+        let text = snapshot.render_openmetrics();
+        assert!(text.contains(&format!(
+            "# TYPE num_probes_received counter\nnum_probes_received_total{labels} 9 1685570400.000\n"
+        )));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
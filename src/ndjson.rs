@@ -0,0 +1,102 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::local_now;
+use crate::settings::ProbeSettings;
+use crate::tui::TuiMessage;
+use serde_json::json;
+use std::io::{stdout, Write};
+use std::sync::mpsc::Receiver;
+use std::thread::{self, JoinHandle};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Spawn a writer that logs each ping result and event to stdout as one JSON object per line, for
+/// log collectors in containers. Like the TUI, it exits once every sender for `updates` has been
+/// dropped.
+pub fn spawn(
+    probe: ProbeSettings,
+    run_id: String,
+    probe_name: String,
+    updates: Receiver<TuiMessage>,
+) -> JoinHandle<()> {
+    thread::spawn(move || run(probe, &run_id, &probe_name, updates))
+}
+
+fn run(mut probe: ProbeSettings, run_id: &str, probe_name: &str, updates: Receiver<TuiMessage>) {
+    let mut stdout = stdout();
+    for message in updates {
+        let mut line = match message {
+            TuiMessage::Ping(update) => json!({
+                "time": format_time(update.time),
+                "target": probe.addr,
+                "run_id": run_id,
+                "address": update.address,
+                "rtt_ms": update.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                "state": update.stats.state.label().to_lowercase(),
+                "sent": update.stats.sent,
+                "received": update.stats.received,
+                "storage_error": update.storage_error,
+            }),
+            TuiMessage::StateChanged(transition) => json!({
+                "time": format_time(transition.at),
+                "target": probe.addr,
+                "run_id": run_id,
+                "state": transition.to.label().to_lowercase(),
+                "previous_state": transition.from.label().to_lowercase(),
+            }),
+            TuiMessage::OutageStarted { at } => json!({
+                "time": format_time(at),
+                "target": probe.addr,
+                "run_id": run_id,
+                "outage": "started",
+            }),
+            TuiMessage::OutageEnded { at, duration } => json!({
+                "time": format_time(at),
+                "target": probe.addr,
+                "run_id": run_id,
+                "outage": "ended",
+                "outage_duration_s": duration.as_secs_f64(),
+            }),
+            TuiMessage::StorageDegraded(error) => json!({
+                "time": format_time(local_now()),
+                "target": probe.addr,
+                "run_id": run_id,
+                "storage_error": error,
+            }),
+            TuiMessage::Event(event) => json!({
+                "time": format_time(event.time),
+                "target": probe.addr,
+                "run_id": run_id,
+                "event": event.name,
+                "details": event.details,
+            }),
+            TuiMessage::Reconfigured(new_probe) => {
+                probe = *new_probe;
+                continue;
+            }
+        };
+        line["probe_name"] = json!(probe_name);
+        // Nothing useful can be done if stdout is gone
+        let _ = writeln!(stdout, "{line}").and_then(|()| stdout.flush());
+    }
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
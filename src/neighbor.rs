@@ -0,0 +1,364 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+
+const ETH_P_ARP: u16 = 0x0806;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+const ICMPV6_NEIGHBOR_SOLICITATION: u8 = 135;
+const ICMPV6_NEIGHBOR_ADVERTISEMENT: u8 = 136;
+
+/// The local interface a same-subnet target is reachable on.
+struct Interface {
+    name: String,
+    index: u32,
+    mac: [u8; 6],
+    /// Our IPv4 address on the interface, used as the ARP sender address
+    ipv4: Option<Ipv4Addr>,
+}
+
+/// Checks that a target on the local subnet is present by sending an ARP request (IPv4) or a
+/// Neighbor Solicitation (IPv6). Hosts answer these even when their firewall drops ICMP echo.
+pub struct NeighborProbe {
+    socket: AsyncFd<OwnedFd>,
+    interface: Interface,
+    target: IpAddr,
+    timeout: Duration,
+}
+
+impl NeighborProbe {
+    /// Open a socket for probing `target`, which must be on the subnet of a local interface.
+    pub fn new(target: IpAddr, timeout: Duration) -> io::Result<Self> {
+        let interface = find_interface(target)?;
+        let socket = match target {
+            IpAddr::V4(_) => arp_socket(interface.index)?,
+            IpAddr::V6(_) => ndp_socket(interface.index)?,
+        };
+        Ok(NeighborProbe {
+            socket: AsyncFd::new(socket)?,
+            interface,
+            target,
+            timeout,
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send one solicitation and wait for the target's answer, returning the round trip time.
+    /// Fails with `ErrorKind::TimedOut` if no answer arrives in time.
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        // Discard answers to earlier probes that arrived after their timeout
+        let mut buf = [0u8; 1500];
+        while recv(self.socket.get_ref(), &mut buf).is_ok() {}
+        let sent = Instant::now();
+        match self.target {
+            IpAddr::V4(target) => self.send_arp_request(target)?,
+            IpAddr::V6(target) => self.send_neighbor_solicitation(target)?,
+        }
+        tokio::time::timeout(self.timeout, async {
+            loop {
+                let mut guard = self.socket.readable().await?;
+                let Ok(received) = guard.try_io(|socket| recv(socket.get_ref(), &mut buf)) else {
+                    continue;
+                };
+                if self.is_answer(&buf[..received?]) {
+                    return Ok(sent.elapsed());
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer from target"))?
+    }
+
+    fn send_arp_request(&self, target: Ipv4Addr) -> io::Result<()> {
+        let source = self.interface.ipv4.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("{} has no IPv4 address", self.interface.name),
+            )
+        })?;
+        let mut packet = Vec::with_capacity(28);
+        packet.extend_from_slice(&1u16.to_be_bytes()); // Ethernet
+        packet.extend_from_slice(&0x0800u16.to_be_bytes()); // IPv4
+        packet.extend_from_slice(&[6, 4]);
+        packet.extend_from_slice(&ARP_REQUEST.to_be_bytes());
+        packet.extend_from_slice(&self.interface.mac);
+        packet.extend_from_slice(&source.octets());
+        packet.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&target.octets());
+
+        // SAFETY: sockaddr_ll is plain data, so all zeroes is a valid value
+        let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_ARP.to_be();
+        addr.sll_ifindex = self.interface.index as i32;
+        addr.sll_halen = 6;
+        addr.sll_addr[..6].copy_from_slice(&[0xff; 6]);
+        send_to(
+            self.socket.get_ref(),
+            &packet,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>(),
+        )
+    }
+
+    fn send_neighbor_solicitation(&self, target: Ipv6Addr) -> io::Result<()> {
+        let mut packet = vec![ICMPV6_NEIGHBOR_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+        packet.extend_from_slice(&target.octets());
+        // Source link-layer address option, so the target can answer without its own lookup
+        packet.extend_from_slice(&[1, 1]);
+        packet.extend_from_slice(&self.interface.mac);
+
+        // Solicited-node multicast address ff02::1:ffXX:XXXX
+        let octets = target.octets();
+        let mut group = [0u8; 16];
+        group[..2].copy_from_slice(&[0xff, 0x02]);
+        group[11..13].copy_from_slice(&[0x01, 0xff]);
+        group[13..].copy_from_slice(&octets[13..]);
+        // SAFETY: sockaddr_in6 is plain data, so all zeroes is a valid value
+        let mut addr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+        addr.sin6_family = libc::AF_INET6 as u16;
+        addr.sin6_addr.s6_addr = group;
+        addr.sin6_scope_id = self.interface.index;
+        // The kernel fills in the ICMPv6 checksum for raw ICMPv6 sockets
+        send_to(
+            self.socket.get_ref(),
+            &packet,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_in6>(),
+        )
+    }
+
+    /// Check whether a received ARP packet or ICMPv6 message is the target's answer.
+    fn is_answer(&self, packet: &[u8]) -> bool {
+        match self.target {
+            IpAddr::V4(target) => {
+                packet.len() >= 28
+                    && packet[6..8] == ARP_REPLY.to_be_bytes()
+                    && packet[14..18] == target.octets()
+            }
+            IpAddr::V6(target) => {
+                packet.len() >= 24
+                    && packet[0] == ICMPV6_NEIGHBOR_ADVERTISEMENT
+                    && packet[8..24] == target.octets()
+            }
+        }
+    }
+}
+
+/// Open a non-blocking packet socket for ARP bound to interface `index`.
+fn arp_socket(index: u32) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket calls; the descriptor is owned by the returned OwnedFd
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_PACKET,
+            libc::SOCK_DGRAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            i32::from(ETH_P_ARP.to_be()),
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+        let mut addr: libc::sockaddr_ll = mem::zeroed();
+        addr.sll_family = libc::AF_PACKET as u16;
+        addr.sll_protocol = ETH_P_ARP.to_be();
+        addr.sll_ifindex = index as i32;
+        if libc::bind(
+            fd,
+            &addr as *const _ as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_ll>() as u32,
+        ) < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(socket)
+    }
+}
+
+/// Open a non-blocking raw ICMPv6 socket sending on interface `index` with the hop limit of 255
+/// that neighbor discovery requires.
+fn ndp_socket(index: u32) -> io::Result<OwnedFd> {
+    // SAFETY: plain socket calls; the descriptor is owned by the returned OwnedFd
+    unsafe {
+        let fd = libc::socket(
+            libc::AF_INET6,
+            libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+            libc::IPPROTO_ICMPV6,
+        );
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = OwnedFd::from_raw_fd(fd);
+        let hops: libc::c_int = 255;
+        for (option, value) in [
+            (libc::IPV6_MULTICAST_HOPS, hops),
+            (libc::IPV6_UNICAST_HOPS, hops),
+            (libc::IPV6_MULTICAST_IF, index as libc::c_int),
+        ] {
+            if libc::setsockopt(
+                fd,
+                libc::IPPROTO_IPV6,
+                option,
+                &value as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as u32,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(socket)
+    }
+}
+
+fn send_to(
+    socket: &OwnedFd,
+    packet: &[u8],
+    addr: *const libc::sockaddr,
+    addr_len: usize,
+) -> io::Result<()> {
+    // SAFETY: the buffer and address are valid for the given lengths
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            packet.as_ptr() as *const libc::c_void,
+            packet.len(),
+            0,
+            addr,
+            addr_len as u32,
+        )
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv(socket: &OwnedFd, buf: &mut [u8]) -> io::Result<usize> {
+    // SAFETY: the buffer is valid for writes of its length
+    let received = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(received as usize)
+}
+
+/// Find the non-loopback interface whose subnet contains `target`. Targets carry no scope ID, so
+/// an IPv6 link-local target is on the subnet of every interface with a link-local address and the
+/// first one listed is used.
+fn find_interface(target: IpAddr) -> io::Result<Interface> {
+    let mut found: Option<String> = None;
+    let mut ipv4 = None;
+    // SAFETY: the list returned by getifaddrs is only read while alive and freed once
+    unsafe {
+        let mut addrs: *mut libc::ifaddrs = std::ptr::null_mut();
+        if libc::getifaddrs(&mut addrs) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut current = addrs;
+        while !current.is_null() {
+            let ifaddr = &*current;
+            current = ifaddr.ifa_next;
+            if ifaddr.ifa_addr.is_null()
+                || ifaddr.ifa_netmask.is_null()
+                || ifaddr.ifa_flags & libc::IFF_LOOPBACK as u32 != 0
+            {
+                continue;
+            }
+            let name = CStr::from_ptr(ifaddr.ifa_name)
+                .to_string_lossy()
+                .into_owned();
+            match (target, i32::from((*ifaddr.ifa_addr).sa_family)) {
+                (IpAddr::V4(target), libc::AF_INET) => {
+                    let addr = &*(ifaddr.ifa_addr as *const libc::sockaddr_in);
+                    let mask = &*(ifaddr.ifa_netmask as *const libc::sockaddr_in);
+                    let (addr, mask) = (
+                        u32::from_be(addr.sin_addr.s_addr),
+                        u32::from_be(mask.sin_addr.s_addr),
+                    );
+                    if addr & mask == u32::from(target) & mask {
+                        ipv4 = Some(Ipv4Addr::from(addr));
+                        found = Some(name);
+                        break;
+                    }
+                }
+                (IpAddr::V6(target), libc::AF_INET6) => {
+                    let addr = (*(ifaddr.ifa_addr as *const libc::sockaddr_in6))
+                        .sin6_addr
+                        .s6_addr;
+                    let mask = (*(ifaddr.ifa_netmask as *const libc::sockaddr_in6))
+                        .sin6_addr
+                        .s6_addr;
+                    let target = target.octets();
+                    if (0..16).all(|i| addr[i] & mask[i] == target[i] & mask[i]) {
+                        found = Some(name);
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+        libc::freeifaddrs(addrs);
+    }
+    let name = found.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{target} is not on the subnet of any local interface"),
+        )
+    })?;
+    let c_name = std::ffi::CString::new(name.clone())?;
+    // SAFETY: the name is a valid NUL-terminated string for the duration of the call
+    let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if index == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mac = std::fs::read_to_string(format!("/sys/class/net/{name}/address"))?
+        .trim()
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .ok()
+        .and_then(|mac| <[u8; 6]>::try_from(mac).ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{name} has no Ethernet address"),
+            )
+        })?;
+    Ok(Interface {
+        name,
+        index,
+        mac,
+        ipv4,
+    })
+}
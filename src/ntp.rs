@@ -0,0 +1,125 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::probe::split_host_port;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+
+/// Port used when the target doesn't name one.
+const DEFAULT_PORT: u16 = 123;
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const PACKET_LEN: usize = 48;
+
+/// Queries an NTP server (SNTPv4) and measures the round trip time and the offset of the local
+/// clock from the server's.
+pub struct NtpProbe {
+    socket: UdpSocket,
+    timeout: Duration,
+    /// Server time minus local time (s) from the last successful query
+    last_offset: Option<f64>,
+}
+
+impl NtpProbe {
+    /// Create a probe for `target` (`host` or `host:port`, default port 123) at `ip_addr`.
+    pub async fn new(target: &str, ip_addr: IpAddr, timeout: Duration) -> io::Result<Self> {
+        let port = split_host_port(target, DEFAULT_PORT)?.1;
+        let local: SocketAddr = match ip_addr {
+            IpAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            IpAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(SocketAddr::new(ip_addr, port)).await?;
+        Ok(NtpProbe {
+            socket,
+            timeout,
+            last_offset: None,
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Clock offset measured by the last probe, if it succeeded.
+    pub fn last_offset(&self) -> Option<f64> {
+        self.last_offset
+    }
+
+    /// Query the server and return the round trip time, excluding the time the server spent
+    /// handling the request. Fails with `ErrorKind::TimedOut` if no valid reply arrives in time.
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        self.last_offset = None;
+        // Discard replies to earlier queries that arrived after their timeout
+        let mut reply = [0u8; PACKET_LEN];
+        while self.socket.try_recv(&mut reply).is_ok() {}
+
+        let mut request = [0u8; PACKET_LEN];
+        // Leap indicator 0, version 4, mode 3 (client)
+        request[0] = 0b00_100_011;
+        let originate = ntp_timestamp(SystemTime::now());
+        request[40..48].copy_from_slice(&originate.to_be_bytes());
+        self.socket.send(&request).await?;
+
+        let (reply_len, received) = tokio::time::timeout(self.timeout, async {
+            loop {
+                let len = self.socket.recv(&mut reply).await?;
+                let received = ntp_timestamp(SystemTime::now());
+                // Ignore anything that isn't the answer to this request
+                if len >= PACKET_LEN && reply[24..32] == originate.to_be_bytes() {
+                    return Ok::<_, io::Error>((len, received));
+                }
+            }
+        })
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "timed out"))??;
+        let reply = &reply[..reply_len];
+
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+        if reply[0] & 0b111 != 4 {
+            return Err(invalid("reply is not from an NTP server"));
+        }
+        if reply[0] >> 6 == 3 {
+            return Err(invalid("server clock is not synchronized"));
+        }
+        if reply[1] == 0 {
+            let code = String::from_utf8_lossy(&reply[12..16]).into_owned();
+            return Err(invalid(&format!("server sent kiss-o'-death {code}")));
+        }
+        let server_received = u64::from_be_bytes(reply[32..40].try_into().unwrap());
+        let server_sent = u64::from_be_bytes(reply[40..48].try_into().unwrap());
+        // Differences of NTP timestamps stay correct across era rollovers (2036)
+        let to_secs = |delta: u64| delta as i64 as f64 / 4_294_967_296.0;
+        let outbound = to_secs(server_received.wrapping_sub(originate));
+        let inbound = to_secs(server_sent.wrapping_sub(received));
+        let processing = to_secs(server_sent.wrapping_sub(server_received));
+        let round_trip = to_secs(received.wrapping_sub(originate)) - processing;
+        self.last_offset = Some((outbound + inbound) / 2.0);
+        Ok(Duration::from_secs_f64(round_trip.max(0.0)))
+    }
+}
+
+/// Convert a wall clock time to a 64-bit NTP timestamp (32.32 fixed point seconds since 1900).
+fn ntp_timestamp(time: SystemTime) -> u64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = (u64::from(since_epoch.subsec_nanos()) << 32) / 1_000_000_000;
+    (secs << 32) | fraction
+}
@@ -0,0 +1,800 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::local_now;
+use crate::log_file::LogFile;
+use crate::pipeline::Output;
+use crate::theme::{bold, failure};
+use crossterm::{cursor, terminal, ExecutableCommand};
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, stdout, IsTerminal};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use time::{format_description, OffsetDateTime};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+/// Number of past outages listed below the table.
+const MAX_OUTAGE_REPORTS: usize = 5;
+
+// Rounds of probes sent to every hop by a monitor tracing the path to its target, which are kept
+// well apart as they run all the time
+pub const TRACE_DELAY: Duration = Duration::from_secs(5);
+pub const TRACE_TIMEOUT: Duration = Duration::from_millis(500);
+pub const TRACE_MAX_HOPS: u8 = 30;
+
+/// Settings of a `num path` run.
+pub struct PathConfig {
+    pub target: String,
+    /// Time between rounds of probes
+    pub delay: Duration,
+    /// Time to wait for replies after sending a round
+    pub timeout: Duration,
+    pub max_hops: u8,
+    /// Directory for the per-hop CSV, or None to keep nothing on disk
+    pub output_path: Option<PathBuf>,
+}
+
+/// Loss and latency statistics of one hop along the path.
+#[derive(Default)]
+struct Hop {
+    /// Router that answered most recently
+    address: Option<IpAddr>,
+    sent: u64,
+    received: u64,
+    last_rtt: Option<Duration>,
+    min_rtt: Option<Duration>,
+    max_rtt: Option<Duration>,
+    total_rtt: Duration,
+}
+
+impl Hop {
+    fn record(&mut self, reply: Option<(IpAddr, Duration)>) {
+        self.sent += 1;
+        self.last_rtt = None;
+        if let Some((address, rtt)) = reply {
+            self.address = Some(address);
+            self.received += 1;
+            self.last_rtt = Some(rtt);
+            self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+            self.max_rtt = Some(self.max_rtt.map_or(rtt, |max| max.max(rtt)));
+            self.total_rtt += rtt;
+        }
+    }
+
+    fn loss_percent(&self) -> f64 {
+        (self.sent - self.received) as f64 * 100.0 / self.sent.max(1) as f64
+    }
+
+    fn avg_rtt(&self) -> Option<Duration> {
+        (self.received > 0).then(|| self.total_rtt.div_f64(self.received as f64))
+    }
+}
+
+/// Where along the path the loss and latency seen at the target first appear, as hop indexes.
+#[derive(Default)]
+struct Attribution {
+    loss: Option<usize>,
+    /// Hop adding the most latency, and how much
+    latency: Option<(usize, Duration)>,
+}
+
+/// Attribute the end-to-end loss and latency in `window` to the first hop where they appear and
+/// persist to the target. Loss that later hops don't share is only a router rate-limiting its
+/// replies, so it is ignored. `responsive` tells which hops have ever answered: silent hops (`* * *`
+/// in traceroute) can't be blamed or cleared.
+fn attribute(window: &[Hop], responsive: &[bool]) -> Attribution {
+    let mut attribution = Attribution::default();
+    let Some(end) = window.last().filter(|end| end.sent > 0) else {
+        return attribution;
+    };
+    let answering = |from: usize| (from..window.len()).filter(move |&index| responsive[index]);
+    let end_loss = end.loss_percent();
+    if end_loss > 0.0 {
+        attribution.loss = answering(0).find(|&index| {
+            answering(index).all(|later| window[later].loss_percent() >= end_loss / 2.0)
+        });
+    }
+    let mut previous = Duration::ZERO;
+    for index in answering(0) {
+        let Some(avg) = window[index].avg_rtt() else {
+            continue;
+        };
+        let added = avg.saturating_sub(previous);
+        // Only latency that every later hop also carries was added here
+        let persists = answering(index + 1)
+            .filter_map(|later| window[later].avg_rtt())
+            .all(|later| later + added / 2 >= avg);
+        if persists && attribution.latency.is_none_or(|(_, most)| added > most) {
+            attribution.latency = Some((index, added));
+        }
+        previous = avg;
+    }
+    attribution
+}
+
+/// A stretch of rounds in which the target stopped answering.
+struct Outage {
+    start: OffsetDateTime,
+    /// Statistics of each hop over the outage only
+    hops: Vec<Hop>,
+}
+
+impl Outage {
+    fn new(start: OffsetDateTime, path_len: u8) -> Self {
+        Outage {
+            start,
+            hops: (0..path_len).map(|_| Hop::default()).collect(),
+        }
+    }
+
+    fn record(&mut self, replies: &HashMap<u8, (IpAddr, Duration)>) {
+        for (index, hop) in self.hops.iter_mut().enumerate() {
+            hop.record(replies.get(&(index as u8 + 1)).copied());
+        }
+    }
+
+    /// The hop (numbered from 1) and router the outage's loss started at, judging by which of
+    /// `hops` have ever answered.
+    fn loss_hop(&self, hops: &[Hop]) -> Option<(usize, IpAddr)> {
+        let responsive: Vec<bool> = hops.iter().map(|hop| hop.received > 0).collect();
+        let attribution = attribute(&self.hops, &responsive[..self.hops.len()]);
+        attribution
+            .loss
+            .map(|index| (index + 1, hops[index].address.unwrap()))
+    }
+
+    /// Describe the outage, had it ended at `end`, and where its loss started.
+    fn describe(&self, end: OffsetDateTime, loss_hop: Option<(usize, IpAddr)>) -> String {
+        let duration = (end - self.start).as_seconds_f64().round();
+        match loss_hop {
+            Some((hop, address)) => {
+                format!(
+                    "Outage of {duration}s from {}: loss started at hop {hop} ({address})",
+                    self.start
+                )
+            }
+            None => format!(
+                "Outage of {duration}s from {}: loss not attributable",
+                self.start
+            ),
+        }
+    }
+}
+
+/// Sends rounds of probes to every hop to a target and keeps per-hop statistics.
+pub struct PathTracer {
+    socket: TracerouteSocket,
+    target: IpAddr,
+    identifier: u16,
+    sequence: u16,
+    hops: Vec<Hop>,
+    /// Hops beyond the target are never probed once it has answered
+    path_len: u8,
+}
+
+impl PathTracer {
+    pub fn new(target: IpAddr, max_hops: u8) -> io::Result<Self> {
+        Ok(PathTracer {
+            socket: TracerouteSocket::new(target)?,
+            target,
+            identifier: std::process::id() as u16,
+            sequence: 0,
+            hops: (0..max_hops).map(|_| Hop::default()).collect(),
+            path_len: max_hops,
+        })
+    }
+
+    /// Probe every hop once, waiting up to `timeout` for the replies, and record them. Returns the
+    /// replies by TTL.
+    async fn round(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<HashMap<u8, (IpAddr, Duration)>, String> {
+        let mut pending = HashMap::new();
+        for ttl in 1..=self.path_len {
+            self.sequence = self.sequence.wrapping_add(1);
+            // A probe that can't be sent (e.g. no route during an outage) counts as lost
+            if self
+                .socket
+                .send(self.identifier, self.sequence, ttl)
+                .is_ok()
+            {
+                pending.insert(self.sequence, (ttl, Instant::now()));
+            }
+        }
+        let mut replies = HashMap::new();
+        let deadline = tokio::time::Instant::now() + timeout;
+        while !pending.is_empty() {
+            let Ok(reply) =
+                tokio::time::timeout_at(deadline, self.socket.recv(self.identifier)).await
+            else {
+                break;
+            };
+            let reply = reply.map_err(|e| format!("Could not receive replies: {e}"))?;
+            if let Some((ttl, sent)) = pending.remove(&reply.sequence) {
+                replies.insert(ttl, (reply.from, sent.elapsed()));
+                if reply.reached && ttl < self.path_len {
+                    self.path_len = ttl;
+                }
+            }
+        }
+        for ttl in 1..=self.path_len {
+            self.hops[usize::from(ttl) - 1].record(replies.get(&ttl).copied());
+        }
+        Ok(replies)
+    }
+
+    fn target_answered(&self, replies: &HashMap<u8, (IpAddr, Duration)>) -> bool {
+        replies.values().any(|&(from, _)| from == self.target)
+    }
+
+    /// Statistics of the hops up to the target.
+    fn hops(&self) -> &[Hop] {
+        &self.hops[..usize::from(self.path_len)]
+    }
+}
+
+/// A reply to one of our probes: an echo reply from the target or an error from a router on the way.
+struct Reply {
+    sequence: u16,
+    from: IpAddr,
+    /// Whether the reply came from the target itself
+    reached: bool,
+}
+
+/// Continuously probe every hop to the target with increasing TTLs, like mtr, keeping per-hop loss
+/// and latency statistics until `shutdown` completes.
+pub async fn run(config: PathConfig, shutdown: impl Future<Output = ()>) -> Result<(), String> {
+    tokio::pin!(shutdown);
+    let target = tokio::net::lookup_host((config.target.as_str(), 0))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or(format!("Could not resolve {}", config.target))?
+        .ip();
+    let mut tracer = PathTracer::new(target, config.max_hops)
+        .map_err(|e| crate::probe::socket_error_help(&e))?;
+    let (mut log, mut outage_log) = match &config.output_path {
+        Some(output_path) => {
+            let date_fmt = format_description::parse_owned::<1>(
+                "[month]-[day]-[year]@[hour]-[minute]-[second]",
+            )
+            .unwrap();
+            let date = local_now().format(&date_fmt).unwrap();
+            let log = LogFile::create(
+                output_path.join(format!("path_{date}.csv")),
+                "Timestamp,Hop,Address,Latency(ms)",
+                false,
+                None,
+            )
+            .await
+            .map_err(|e| format!("Could not create CSV: {e}"))?;
+            let outage_log = LogFile::create(
+                output_path.join(format!("path_outages_{date}.csv")),
+                "Start,End,Rounds,LossHop,LossAddress",
+                false,
+                None,
+            )
+            .await
+            .map_err(|e| format!("Could not create CSV: {e}"))?;
+            (Some(log), Some(outage_log))
+        }
+        None => (None, None),
+    };
+    let interactive = stdout().is_terminal();
+    // Outages only count once the target has answered at least once
+    let mut target_seen = false;
+    let mut outage: Option<Outage> = None;
+    let mut outage_reports = Vec::new();
+    let mut interval = tokio::time::interval(config.delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = &mut shutdown => break,
+        }
+        let round_time = local_now();
+        let replies = tracer.round(config.timeout).await?;
+        let mut ended = None;
+        if tracer.target_answered(&replies) {
+            target_seen = true;
+            ended = outage.take();
+        } else if target_seen && outage.is_none() {
+            outage = Some(Outage::new(round_time, tracer.path_len));
+        }
+        if let Some(outage) = &mut outage {
+            outage.record(&replies);
+        }
+        if let Some(log) = &mut log {
+            for ttl in 1..=tracer.path_len {
+                log.push(match replies.get(&ttl) {
+                    Some((from, rtt)) => format!(
+                        "{round_time},{ttl},{from},{:.3}",
+                        rtt.as_secs_f64() * 1000.0
+                    ),
+                    None => format!("{round_time},{ttl},,failed"),
+                });
+            }
+            log.flush().await;
+        }
+        if let Some(outage) = ended {
+            let report = outage_report(outage, round_time, &tracer.hops, &mut outage_log).await;
+            outage_reports.push(report);
+        }
+        render(
+            &config.target,
+            target,
+            tracer.hops(),
+            &outage_reports,
+            interactive,
+        );
+    }
+    if let Some(outage) = outage.take() {
+        outage_report(outage, local_now(), &tracer.hops, &mut outage_log).await;
+    }
+    if let Some(log) = &mut log {
+        log.flush().await;
+    }
+    Ok(())
+}
+
+/// Describe an outage that ended at `end` and the hop its loss started at, recording it in the
+/// outage CSV if there is one.
+async fn outage_report(
+    outage: Outage,
+    end: OffsetDateTime,
+    hops: &[Hop],
+    outage_log: &mut Option<LogFile>,
+) -> String {
+    let loss_hop = outage.loss_hop(hops);
+    let rounds = outage.hops.last().map_or(0, |hop| hop.sent);
+    if let Some(outage_log) = outage_log {
+        outage_log.push(match loss_hop {
+            Some((hop, address)) => format!("{},{end},{rounds},{hop},{address}", outage.start),
+            None => format!("{},{end},{rounds},,", outage.start),
+        });
+        outage_log.flush().await;
+    }
+    outage.describe(end, loss_hop)
+}
+
+/// Consumer tracing the path to the monitored target every `delay` while it is monitored, and
+/// sending a description of where the loss of each of its outages started to `reports`. Only the
+/// first of a run of failed rounds is reported, to `failures`.
+pub async fn attribute_outages(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    mut tracer: PathTracer,
+    delay: Duration,
+    timeout: Duration,
+    reports: mpsc::UnboundedSender<String>,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    let mut interval = tokio::time::interval(delay);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut outage: Option<Outage> = None;
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            output = outputs.recv() => match output {
+                Some(Output::OutageStarted { at }) => {
+                    outage = Some(Outage::new(at, tracer.path_len));
+                }
+                Some(Output::OutageEnded { at, .. }) => {
+                    if let Some(outage) = outage.take() {
+                        let loss_hop = outage.loss_hop(&tracer.hops);
+                        let _ = reports.send(outage.describe(at, loss_hop));
+                    }
+                }
+                Some(_) => {}
+                None => break,
+            },
+            _ = interval.tick() => match tracer.round(timeout).await {
+                Ok(replies) => {
+                    failing = false;
+                    if let Some(outage) = &mut outage {
+                        outage.record(&replies);
+                    }
+                }
+                Err(e) => {
+                    if !std::mem::replace(&mut failing, true) {
+                        let _ = failures.send(e);
+                    }
+                }
+            },
+        }
+    }
+}
+
+/// Print the per-hop statistics as a table, redrawing the screen on a terminal.
+fn render(name: &str, target: IpAddr, hops: &[Hop], outage_reports: &[String], interactive: bool) {
+    let mut out = stdout();
+    if interactive {
+        let _ = out.execute(cursor::MoveTo(0, 0));
+        let _ = out.execute(terminal::Clear(terminal::ClearType::All));
+    }
+    let ms = |rtt: Option<Duration>| {
+        rtt.map_or("-".to_string(), |rtt| {
+            format!("{:.1}", rtt.as_secs_f64() * 1000.0)
+        })
+    };
+    println!("{}", bold(&format!("Path to {name} ({target})")));
+    println!(
+        "{}",
+        bold(&format!(
+            "{:>3}  {:<39} {:>6} {:>6} {:>7} {:>7} {:>7} {:>7}",
+            "Hop", "Address", "Loss%", "Sent", "Last", "Avg", "Best", "Worst"
+        ))
+    );
+    for (index, hop) in hops.iter().enumerate() {
+        let line = format!(
+            "{:>3}  {:<39} {:>5.1}% {:>6} {:>7} {:>7} {:>7} {:>7}",
+            index + 1,
+            hop.address
+                .map_or("???".to_string(), |address| address.to_string()),
+            hop.loss_percent(),
+            hop.sent,
+            ms(hop.last_rtt),
+            ms(hop.avg_rtt()),
+            ms(hop.min_rtt),
+            ms(hop.max_rtt)
+        );
+        if hop.received < hop.sent {
+            println!("{}", failure(line));
+        } else {
+            println!("{line}");
+        }
+    }
+    let responsive: Vec<bool> = hops.iter().map(|hop| hop.received > 0).collect();
+    let attribution = attribute(hops, &responsive);
+    let describe = |index: usize| format!("hop {} ({})", index + 1, hops[index].address.unwrap());
+    if let Some(index) = attribution.loss {
+        println!("{}", failure(format!("Loss starts at {}", describe(index))));
+    }
+    if let Some((index, added)) = attribution.latency {
+        println!(
+            "Most latency added at {} (+{})",
+            describe(index),
+            ms(Some(added))
+        );
+    }
+    for report in outage_reports.iter().rev().take(MAX_OUTAGE_REPORTS).rev() {
+        println!("{}", failure(report));
+    }
+    if !interactive {
+        println!();
+    }
+}
+
+/// A raw ICMP socket sending echo requests with a chosen TTL and receiving both echo replies and
+/// the time exceeded (or unreachable) errors routers send back.
+struct TracerouteSocket {
+    socket: AsyncFd<OwnedFd>,
+    target: IpAddr,
+}
+
+impl TracerouteSocket {
+    fn new(target: IpAddr) -> io::Result<Self> {
+        let (domain, protocol) = match target {
+            IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+            IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+        };
+        // SAFETY: plain socket call; the descriptor is owned by the returned OwnedFd
+        let socket = unsafe {
+            let fd = libc::socket(
+                domain,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                protocol,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            OwnedFd::from_raw_fd(fd)
+        };
+        Ok(TracerouteSocket {
+            socket: AsyncFd::new(socket)?,
+            target,
+        })
+    }
+
+    /// Send an echo request with the given TTL (hop limit).
+    fn send(&self, identifier: u16, sequence: u16, ttl: u8) -> io::Result<()> {
+        let fd = self.socket.get_ref().as_raw_fd();
+        let (level, option) = match self.target {
+            IpAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_TTL),
+            IpAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS),
+        };
+        let ttl = libc::c_int::from(ttl);
+        // SAFETY: the option value is a valid c_int
+        if unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                option,
+                &ttl as *const _ as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as u32,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        let echo_type = if self.target.is_ipv4() { 8 } else { 128 };
+        let mut packet = vec![echo_type, 0, 0, 0];
+        packet.extend_from_slice(&identifier.to_be_bytes());
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        // The kernel fills in the checksum for ICMPv6
+        if self.target.is_ipv4() {
+            let checksum = checksum(&packet);
+            packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+        }
+        let addr = SocketAddr::new(self.target, 0);
+        let (addr, addr_len) = sockaddr(addr);
+        // SAFETY: the buffer and address are valid for the given lengths
+        let sent = unsafe {
+            libc::sendto(
+                fd,
+                packet.as_ptr() as *const libc::c_void,
+                packet.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                addr_len,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait for the next reply to one of our probes, skipping unrelated ICMP traffic.
+    async fn recv(&self, identifier: u16) -> io::Result<Reply> {
+        let mut buf = [0u8; 1500];
+        loop {
+            let mut guard = self.socket.readable().await?;
+            let Ok(received) = guard.try_io(|socket| recv_from(socket.get_ref(), &mut buf)) else {
+                continue;
+            };
+            let (len, from) = received?;
+            let reply = match self.target {
+                IpAddr::V4(_) => parse_v4(&buf[..len], identifier),
+                IpAddr::V6(_) => parse_v6(&buf[..len], from, identifier),
+            };
+            if let Some(reply) = reply {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+/// Parse an IPv4 packet (raw ICMP sockets include the IP header) into a reply to our probes.
+fn parse_v4(packet: &[u8], identifier: u16) -> Option<Reply> {
+    let header_len = usize::from(packet.first()? & 0x0f) * 4;
+    let from = IpAddr::V4(Ipv4Addr::from(
+        <[u8; 4]>::try_from(packet.get(12..16)?).ok()?,
+    ));
+    let icmp = packet.get(header_len..)?;
+    let (echo, reached) = match icmp.first()? {
+        // Echo reply
+        0 => (icmp, true),
+        // Destination unreachable or time exceeded, quoting the IP header and start of our probe
+        3 | 11 => {
+            let quoted = icmp.get(8..)?;
+            let quoted_header_len = usize::from(quoted.first()? & 0x0f) * 4;
+            let echo = quoted.get(quoted_header_len..)?;
+            (echo, icmp[0] == 3)
+        }
+        _ => return None,
+    };
+    if icmp[0] != 0 && *echo.first()? != 8 {
+        return None;
+    }
+    parse_echo(echo, identifier, from, reached)
+}
+
+/// Parse an ICMPv6 message from `from` into a reply to our probes.
+fn parse_v6(icmp: &[u8], from: IpAddr, identifier: u16) -> Option<Reply> {
+    let (echo, reached) = match icmp.first()? {
+        // Echo reply
+        129 => (icmp, true),
+        // Destination unreachable or time exceeded, quoting the IPv6 header and start of our probe
+        1 | 3 => {
+            let echo = icmp.get(48..)?;
+            if *echo.first()? != 128 {
+                return None;
+            }
+            (echo, icmp[0] == 1)
+        }
+        _ => return None,
+    };
+    parse_echo(echo, identifier, from, reached)
+}
+
+fn parse_echo(echo: &[u8], identifier: u16, from: IpAddr, reached: bool) -> Option<Reply> {
+    if u16::from_be_bytes(echo.get(4..6)?.try_into().ok()?) != identifier {
+        return None;
+    }
+    Some(Reply {
+        sequence: u16::from_be_bytes(echo.get(6..8)?.try_into().ok()?),
+        from,
+        reached,
+    })
+}
+
+/// Internet checksum of an ICMPv4 message.
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub(crate) fn sockaddr(addr: SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: sockaddr_storage is plain data, so all zeroes is a valid value, and it is large
+    // enough for either address family
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+pub(crate) fn recv_from(socket: &OwnedFd, buf: &mut [u8]) -> io::Result<(usize, IpAddr)> {
+    // SAFETY: the buffer is valid for writes of its length and the address storage for its size
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let mut storage_len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let received = libc::recvfrom(
+            socket.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            buf.len(),
+            0,
+            &mut storage as *mut _ as *mut libc::sockaddr,
+            &mut storage_len,
+        );
+        if received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let from = if i32::from(storage.ss_family) == libc::AF_INET6 {
+            let sin6 = &*(&storage as *const _ as *const libc::sockaddr_in6);
+            IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr))
+        } else {
+            let sin = &*(&storage as *const _ as *const libc::sockaddr_in);
+            IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+        };
+        Ok((received as usize, from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    /// Statistics of hop `number` from its round trip times in each round (ms), None for a loss.
+    fn hop(number: u8, rounds: &[Option<u64>]) -> Hop {
+        let mut hop = Hop::default();
+        for rtt in rounds {
+            let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, number));
+            hop.record(rtt.map(|rtt| (address, Duration::from_millis(rtt))));
+        }
+        hop
+    }
+
+    /// Hops answering the given share of four rounds in 10 ms each, with hops answering none
+    /// counted as silent.
+    fn answering(shares: &[u64]) -> (Vec<Hop>, Vec<bool>) {
+        let hops: Vec<Hop> = shares
+            .iter()
+            .enumerate()
+            .map(|(index, &answered)| {
+                let rounds: Vec<_> = (0..4)
+                    .map(|round| (round < answered).then_some(10))
+                    .collect();
+                hop(index as u8 + 1, &rounds)
+            })
+            .collect();
+        let responsive = hops.iter().map(|hop| hop.received > 0).collect();
+        (hops, responsive)
+    }
+
+    #[test]
+    fn loss_is_attributed_to_the_first_hop_it_persists_from() {
+        let cases: [(&[u64], Option<usize>); 5] = [
+            // Loss from the third hop on
+            (&[4, 4, 2, 2, 2], Some(2)),
+            // The second hop only rate-limits its replies
+            (&[4, 1, 4, 2, 2], Some(3)),
+            // A silent hop can't be blamed
+            (&[4, 0, 2, 2], Some(2)),
+            // Nothing lost at the target
+            (&[4, 2, 4, 4], None),
+            // Everything lost from the first hop on
+            (&[0, 0, 0, 0], None),
+        ];
+        for (shares, expected) in cases {
+            let (hops, responsive) = answering(shares);
+            assert_eq!(attribute(&hops, &responsive).loss, expected, "{shares:?}");
+        }
+    }
+
+    #[test]
+    fn latency_is_attributed_to_the_hop_adding_the_most_that_persists() {
+        let cases = [
+            (&[1, 2, 40, 41][..], Some((2, 38))),
+            // A slow router that later hops don't share only answers slowly itself
+            (&[1, 90, 3, 4], Some((0, 1))),
+            (&[5, 5, 5], Some((0, 5))),
+        ];
+        for (rtts, expected) in cases {
+            let hops: Vec<Hop> = rtts
+                .iter()
+                .enumerate()
+                .map(|(index, &rtt)| hop(index as u8 + 1, &[Some(rtt)]))
+                .collect();
+            let latency = attribute(&hops, &vec![true; hops.len()])
+                .latency
+                .map(|(index, added)| (index, added.as_millis() as u64));
+            assert_eq!(latency, expected, "{rtts:?}");
+        }
+    }
+
+    #[test]
+    fn outages_name_the_hop_their_loss_started_at() {
+        // Every hop answered before the outage, then only the first two did
+        let hops: Vec<Hop> = (1..=4).map(|number| hop(number, &[Some(5)])).collect();
+        let mut outage = Outage::new(datetime!(2024-01-01 00:00 UTC), 4);
+        let rounds: [&[u8]; 3] = [&[1, 2], &[1, 2], &[1, 2, 3]];
+        for answered in rounds {
+            let replies = answered
+                .iter()
+                .map(|&ttl| {
+                    let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, ttl));
+                    (ttl, (address, Duration::from_millis(5)))
+                })
+                .collect();
+            outage.record(&replies);
+        }
+        let loss_hop = outage.loss_hop(&hops);
+        assert_eq!(loss_hop, Some((3, "10.0.0.3".parse().unwrap())));
+        assert_eq!(
+            outage.describe(datetime!(2024-01-01 00:01:30 UTC), loss_hop),
+            "Outage of 90s from 2024-01-01 0:00:00.0 +00:00:00: loss started at hop 3 (10.0.0.3)"
+        );
+    }
+}
@@ -0,0 +1,626 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::alert::{self, Notification};
+use crate::api::Health;
+use crate::atomic_write::write_atomic;
+use crate::clock::Clock;
+use crate::columns::{Column, PROBE_COLUMNS};
+use crate::engine::{Event, Transition};
+use crate::metrics::Snapshot;
+use crate::probe::{self, Probe};
+use crate::settings::ProbeSettings;
+use crate::tui::{PingUpdate, TuiMessage};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use surge_ping::{PingSequence, SurgeError};
+use time::OffsetDateTime;
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinHandle};
+use tokio::time::MissedTickBehavior;
+
+/// Something the processor stage produced, copied to every consumer. Consumers pick out the
+/// variants they need rather than reading the engine's state.
+#[derive(Clone)]
+pub enum Output {
+    /// State of the monitor after a ping
+    ProbeCompleted(Box<PingUpdate>),
+    StateChanged(Transition),
+    /// The target went DOWN, with the outage considered to have started at `at`
+    OutageStarted {
+        at: OffsetDateTime,
+    },
+    /// The target is no longer DOWN (or its state was lost, e.g. to a monitoring gap)
+    OutageEnded {
+        at: OffsetDateTime,
+        duration: Duration,
+    },
+    /// The probe settings were changed by a config reload
+    ConfigReloaded(Box<ProbeSettings>),
+    /// Writes to the output directory started failing
+    StorageDegraded(String),
+    Event(Event),
+    /// An alert to be delivered
+    Notification(Notification),
+}
+
+/// When probes are due.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Schedule {
+    pub delay: Duration,
+    /// Fraction of the delay by which each probe is randomly postponed
+    pub jitter: f64,
+    /// Whether probes are due at multiples of the delay on the wall clock
+    pub align: bool,
+}
+
+impl Schedule {
+    pub fn of(probe: &ProbeSettings) -> Self {
+        Schedule {
+            delay: probe.delay,
+            jitter: probe.interval_jitter,
+            align: probe.align,
+        }
+    }
+
+    /// Pick when the next probe after `now` is due on the monotonic clock: a delay later, or with
+    /// alignment, at the next multiple of the delay on the wall clock at least half a delay away.
+    /// `wall` is the wall clock time at `now`, which for a late tick is earlier than the current
+    /// time.
+    fn next_after(&self, now: tokio::time::Instant, wall: SystemTime) -> tokio::time::Instant {
+        if !self.align {
+            return now + self.delay;
+        }
+        let half = self.delay / 2;
+        now + half + until_aligned(self.delay, wall + half)
+    }
+
+    /// Pick how long to postpone the probe due at `tick`.
+    fn offset(&self, tick: Instant) -> Duration {
+        if self.jitter == 0.0 {
+            return Duration::ZERO;
+        }
+        let random = RandomState::new().hash_one(tick) as f64 / u64::MAX as f64;
+        self.delay.mul_f64(self.jitter * random)
+    }
+}
+
+/// Time from `now` until the next multiple of `delay` since the Unix epoch, so that e.g. a 30s
+/// delay is due at :00 and :30 of every minute.
+fn until_aligned(delay: Duration, now: SystemTime) -> Duration {
+    let since_epoch = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let delay_nanos = delay.as_nanos().max(1);
+    match since_epoch % delay_nanos {
+        0 => Duration::ZERO,
+        past => Duration::from_nanos((delay_nanos - past) as u64),
+    }
+}
+
+/// The wall clock time at the monotonic `instant`, which is in the past.
+fn wall_time_at(instant: tokio::time::Instant) -> SystemTime {
+    let now = SystemTime::now();
+    now.checked_sub(instant.elapsed()).unwrap_or(now)
+}
+
+/// Start the scheduler stage, which sends the instant each probe is due on the returned channel.
+/// Deadlines are tracked on the monotonic clock, so wall clock changes can't disturb the schedule,
+/// and the schedule restarts whenever a new one is published on `schedule`. Aligned schedules
+/// instead follow the wall clock, taking their next deadline from it after every tick. With
+/// jitter, each probe is postponed by a random part of it, so that monitors started together
+/// drift apart rather than probing in lockstep. Only one tick is buffered, so a slow processor
+/// makes ticks late (handled by `behavior`) rather than piling up.
+pub fn spawn_scheduler(
+    mut schedule: watch::Receiver<Schedule>,
+    behavior: MissedTickBehavior,
+) -> mpsc::Receiver<Instant> {
+    let (tick_tx, tick_rx) = mpsc::channel(1);
+    task::spawn(async move {
+        let mut current = *schedule.borrow_and_update();
+        let now = tokio::time::Instant::now();
+        let start = if current.align {
+            now + until_aligned(current.delay, SystemTime::now())
+        } else {
+            now
+        };
+        let mut interval = tokio::time::interval_at(start, current.delay);
+        interval.set_missed_tick_behavior(behavior);
+        loop {
+            tokio::select! {
+                tick = interval.tick() => {
+                    if current.align {
+                        interval.reset_at(current.next_after(tick, wall_time_at(tick)));
+                    }
+                    let due = tick + current.offset(tick.into_std());
+                    tokio::time::sleep_until(due).await;
+                    if tick_tx.send(due.into_std()).await.is_err() {
+                        break;
+                    }
+                }
+                changed = schedule.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    current = *schedule.borrow_and_update();
+                    let start =
+                        current.next_after(tokio::time::Instant::now(), SystemTime::now());
+                    interval = tokio::time::interval_at(start, current.delay);
+                    interval.set_missed_tick_behavior(behavior);
+                }
+            }
+        }
+    });
+    tick_rx
+}
+
+/// Result of the probe due at `scheduled`, sent by the prober stage to the processor.
+pub struct Probed {
+    pub scheduled: Instant,
+    /// When the probe was sent
+    pub sent_at: OffsetDateTime,
+    pub sent_instant: Instant,
+    /// Sequence number of the last attempt
+    pub seq: u16,
+    /// Times the probe was sent again after timing out
+    pub retries: u32,
+    pub output: Result<Duration, SurgeError>,
+    /// Values of the columns measured by the probe itself
+    pub values: Vec<(Column, String)>,
+    /// Expiry time of the certificate presented, for probes that check one
+    pub cert_not_after: Option<OffsetDateTime>,
+    /// Offset of the local clock behind the server's (s), for probes that measure it
+    pub clock_offset: Option<f64>,
+    /// Replies that arrived after their probe timed out, as the sequence number and round trip time
+    pub late_replies: Vec<(u16, Duration)>,
+}
+
+/// How the prober stage sends probes.
+#[derive(Clone)]
+pub struct ProberSettings {
+    pub num_bytes: usize,
+    pub timeout: Duration,
+    pub delay: Duration,
+    pub retries: u32,
+}
+
+/// Requests to the prober stage, handled in the order they were sent.
+pub enum ProberCommand {
+    /// Send the probe due at the given instant
+    Probe(Instant),
+    /// Send probes through a new backend, e.g. to a new address, from now on
+    Replace(Box<dyn Probe>),
+    Configure(ProberSettings),
+}
+
+/// Start the prober stage, which sends a probe through `backend` for each `ProberCommand::Probe`
+/// and sends back its result. Timeouts are sent again up to `retries` times as long as the
+/// attempt fits before the next probe is due. The sequence number carries over when the backend
+/// is replaced. Stops once the command channel is closed.
+pub fn spawn_prober(
+    mut backend: Box<dyn Probe>,
+    mut settings: ProberSettings,
+    clock: Arc<dyn Clock>,
+) -> (
+    mpsc::UnboundedSender<ProberCommand>,
+    mpsc::UnboundedReceiver<Probed>,
+) {
+    let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+    let (probed_tx, probed_rx) = mpsc::unbounded_channel();
+    task::spawn(async move {
+        backend.set_timeout(settings.timeout);
+        let mut sequence: u16 = 0;
+        let mut data = vec![0; settings.num_bytes];
+        while let Some(command) = command_rx.recv().await {
+            let scheduled = match command {
+                ProberCommand::Probe(scheduled) => scheduled,
+                ProberCommand::Replace(new_backend) => {
+                    backend = new_backend;
+                    backend.set_timeout(settings.timeout);
+                    continue;
+                }
+                ProberCommand::Configure(new_settings) => {
+                    settings = new_settings;
+                    backend.set_timeout(settings.timeout);
+                    data = vec![0; settings.num_bytes];
+                    continue;
+                }
+            };
+            let sent_at = clock.now();
+            let sent_instant = clock.instant();
+            let mut seq = sequence;
+            let mut output = backend.ping(PingSequence(seq), &data).await;
+            sequence = sequence.wrapping_add(1);
+            let mut retries = 0;
+            while retries < settings.retries
+                && output
+                    .as_ref()
+                    .is_err_and(|e| probe::failure_reason(e) == "timeout")
+                && clock.instant().saturating_duration_since(scheduled) + settings.timeout
+                    < settings.delay
+            {
+                retries += 1;
+                seq = sequence;
+                output = backend.ping(PingSequence(seq), &data).await;
+                sequence = sequence.wrapping_add(1);
+            }
+            let probed = Probed {
+                scheduled,
+                sent_at,
+                sent_instant,
+                seq,
+                retries,
+                output,
+                values: PROBE_COLUMNS
+                    .into_iter()
+                    .filter_map(|column| {
+                        let value = backend.result_value(column, sent_at)?;
+                        Some((column, value))
+                    })
+                    .collect(),
+                cert_not_after: backend.cert_not_after(),
+                clock_offset: backend.clock_offset(),
+                late_replies: backend.take_late_replies(),
+            };
+            if probed_tx.send(probed).is_err() {
+                break;
+            }
+        }
+    });
+    (command_tx, probed_rx)
+}
+
+/// Copies the processor's output to consumer tasks (renderer, alert delivery, health), each of
+/// which runs at its own pace.
+pub struct Fanout {
+    consumers: Vec<mpsc::UnboundedSender<Output>>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Fanout {
+    pub fn new() -> Self {
+        Fanout {
+            consumers: Vec::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Start a consumer task running `consume` on its own copy of every output. The receiver ends
+    /// once the fanout is closed.
+    pub fn add<F, Fut>(&mut self, consume: F)
+    where
+        F: FnOnce(mpsc::UnboundedReceiver<Output>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.consumers.push(tx);
+        self.tasks.push(task::spawn(consume(rx)));
+    }
+
+    /// Send `output` to every consumer, forgetting the ones that have stopped.
+    pub fn send(&mut self, output: Output) {
+        self.consumers
+            .retain(|consumer| consumer.send(output.clone()).is_ok());
+    }
+
+    /// Close every consumer's channel and wait for the consumers to finish what was sent.
+    pub async fn close(self) {
+        drop(self.consumers);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+/// Consumer forwarding pings and events to the TUI or NDJSON writer thread. Stops once the
+/// renderer is gone, which just means it is disabled.
+pub async fn render(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    updates: std_mpsc::Sender<TuiMessage>,
+) {
+    while let Some(output) = outputs.recv().await {
+        let message = match output {
+            Output::ProbeCompleted(update) => TuiMessage::Ping(update),
+            Output::StateChanged(transition) => TuiMessage::StateChanged(transition),
+            Output::OutageStarted { at } => TuiMessage::OutageStarted { at },
+            Output::OutageEnded { at, duration } => TuiMessage::OutageEnded { at, duration },
+            Output::StorageDegraded(error) => TuiMessage::StorageDegraded(error),
+            Output::Event(event) => TuiMessage::Event(event),
+            Output::ConfigReloaded(probe) => TuiMessage::Reconfigured(probe),
+            Output::Notification(_) => continue,
+        };
+        if updates.send(message).is_err() {
+            break;
+        }
+    }
+}
+
+/// Consumer delivering alerts, each in its own task so a slow webhook or mail relay doesn't hold
+/// up the others. Only failures are sent back, on `failures`.
+pub async fn deliver_alerts(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    while let Some(output) = outputs.recv().await {
+        if let Output::Notification(notification) = output {
+            let failures = failures.clone();
+            task::spawn(async move {
+                if let Err(e) = alert::deliver(&notification).await {
+                    let _ = failures.send(e);
+                }
+            });
+        }
+    }
+}
+
+/// Consumer publishing the metrics of `target` after every ping.
+pub async fn publish_metrics(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    probe_name: String,
+    metrics: watch::Sender<Option<Snapshot>>,
+) {
+    while let Some(output) = outputs.recv().await {
+        if let Output::ProbeCompleted(update) = output {
+            metrics.send_replace(Some(Snapshot::new(&target, &probe_name, &update)));
+        }
+    }
+}
+
+/// Consumer rewriting `path` with the metrics of `target` after every ping, for node_exporter's
+/// textfile collector. Only the first of a run of failed writes is reported to `failures`.
+pub async fn write_textfile(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    probe_name: String,
+    path: PathBuf,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    let mut failing = false;
+    while let Some(output) = outputs.recv().await {
+        let Output::ProbeCompleted(update) = output else {
+            continue;
+        };
+        let text = Snapshot::new(&target, &probe_name, &update).render();
+        write_metrics(&path, &text, &mut failing, &failures).await;
+    }
+}
+
+/// Consumer rewriting `path` with an OpenMetrics snapshot of the metrics of `target` every
+/// `period`, once there is a probe result. Only the first of a run of failed writes is reported
+/// to `failures`.
+pub async fn write_openmetrics(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    probe_name: String,
+    path: PathBuf,
+    period: Duration,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut latest = None;
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            output = outputs.recv() => match output {
+                Some(Output::ProbeCompleted(update)) => {
+                    latest = Some(Snapshot::new(&target, &probe_name, &update));
+                }
+                Some(_) => {}
+                None => break,
+            },
+            _ = interval.tick() => {
+                if let Some(snapshot) = &latest {
+                    let text = snapshot.render_openmetrics();
+                    write_metrics(&path, &text, &mut failing, &failures).await;
+                }
+            }
+        }
+    }
+}
+
+/// Atomically replace `path` with `text`, reporting a failure unless the last write failed too.
+async fn write_metrics(
+    path: &Path,
+    text: &str,
+    failing: &mut bool,
+    failures: &mpsc::UnboundedSender<String>,
+) {
+    match write_atomic(path, text.as_bytes()).await {
+        Ok(()) => *failing = false,
+        Err(e) => {
+            if !std::mem::replace(failing, true) {
+                let _ = failures.send(format!("Could not write {}: {e}", path.display()));
+            }
+        }
+    }
+}
+
+/// Consumer publishing the monitor's health after every ping. A tick always finishes within two
+/// periods since the timeout is below the delay.
+pub async fn publish_health(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    schedule: watch::Receiver<Schedule>,
+    health: watch::Sender<Health>,
+) {
+    while let Some(output) = outputs.recv().await {
+        if let Output::ProbeCompleted(_) = output {
+            health.send_replace(Health {
+                last_tick: Instant::now(),
+                stale_after: 3 * schedule.borrow().delay,
+                ready: true,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn every(secs: u64, jitter: f64) -> Schedule {
+        Schedule {
+            delay: Duration::from_secs(secs),
+            jitter,
+            align: false,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scheduler_restarts_when_the_delay_changes() {
+        let (schedule_tx, schedule_rx) = watch::channel(every(5, 0.0));
+        let mut ticks = spawn_scheduler(schedule_rx, MissedTickBehavior::Delay);
+        let first = ticks.recv().await.unwrap();
+        assert_eq!(ticks.recv().await.unwrap() - first, Duration::from_secs(5));
+        schedule_tx.send_replace(every(2, 0.0));
+        let changed = tokio::time::Instant::now().into_std();
+        assert_eq!(
+            ticks.recv().await.unwrap() - changed,
+            Duration::from_secs(2)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn jitter_postpones_ticks_within_its_share_of_the_delay() {
+        let (_schedule_tx, schedule_rx) = watch::channel(every(10, 0.1));
+        let mut ticks = spawn_scheduler(schedule_rx, MissedTickBehavior::Delay);
+        let start = tokio::time::Instant::now().into_std();
+        let mut offsets = Vec::new();
+        for n in 0..20 {
+            let tick = ticks.recv().await.unwrap();
+            let offset = tick - (start + Duration::from_secs(10 * n));
+            assert!(
+                offset <= Duration::from_secs(1),
+                "tick {n} is {offset:?} late"
+            );
+            offsets.push(offset);
+        }
+        offsets.dedup();
+        assert!(
+            offsets.len() > 1,
+            "every tick was postponed by the same time"
+        );
+    }
+
+    #[test]
+    fn aligned_probes_are_due_at_multiples_of_the_delay() {
+        let at = |secs: f64| UNIX_EPOCH + Duration::from_secs_f64(secs);
+        let delay = Duration::from_secs(30);
+        assert_eq!(until_aligned(delay, at(75.0)), Duration::from_secs(15));
+        assert_eq!(until_aligned(delay, at(90.0)), Duration::ZERO);
+        assert_eq!(
+            until_aligned(Duration::from_millis(500), at(1.2)),
+            Duration::from_millis(300)
+        );
+    }
+
+    #[test]
+    fn late_ticks_are_aligned_from_when_they_were_due() {
+        let schedule = Schedule {
+            delay: Duration::from_secs(30),
+            jitter: 0.0,
+            align: true,
+        };
+        let tick = tokio::time::Instant::now();
+        // The next probe after one due at :30 or :40 is at :00, counted from the tick
+        let due = UNIX_EPOCH + Duration::from_secs(30);
+        assert_eq!(
+            schedule.next_after(tick, due),
+            tick + Duration::from_secs(30)
+        );
+        assert_eq!(
+            schedule.next_after(tick, due + Duration::from_secs(10)),
+            tick + Duration::from_secs(20)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn prober_keeps_the_sequence_when_its_backend_is_replaced() {
+        use crate::clock::MockClock;
+        use crate::probe::ScriptedProbe;
+        let clock = Arc::new(MockClock::new(datetime!(2024-01-01 00:00 UTC)));
+        let settings = ProberSettings {
+            num_bytes: 4,
+            timeout: Duration::from_secs(1),
+            delay: Duration::from_secs(10),
+            retries: 0,
+        };
+        let backend = Box::new(ScriptedProbe::new([Some(Duration::from_millis(5))]));
+        let (commands, mut probed) = spawn_prober(backend, settings, clock);
+        let replacement = Box::new(ScriptedProbe::new([Some(Duration::from_millis(7))]));
+        let scheduled = Instant::now();
+        let _ = commands.send(ProberCommand::Probe(scheduled));
+        let _ = commands.send(ProberCommand::Replace(replacement));
+        let _ = commands.send(ProberCommand::Probe(scheduled));
+        let first = probed.recv().await.unwrap();
+        let second = probed.recv().await.unwrap();
+        assert_eq!(
+            (first.seq, first.output.unwrap()),
+            (0, Duration::from_millis(5))
+        );
+        assert_eq!(
+            (second.seq, second.output.unwrap()),
+            (1, Duration::from_millis(7))
+        );
+    }
+
+    #[tokio::test]
+    async fn every_consumer_sees_every_output() {
+        let (seen_tx, mut seen_rx) = mpsc::unbounded_channel();
+        let mut fanout = Fanout::new();
+        for consumer in 0..2 {
+            let seen_tx = seen_tx.clone();
+            fanout.add(|mut outputs| async move {
+                while let Some(Output::Event(event)) = outputs.recv().await {
+                    let _ = seen_tx.send((consumer, event.name));
+                }
+            });
+        }
+        drop(seen_tx);
+        for name in ["first", "second"] {
+            fanout.send(Output::Event(Event {
+                time: datetime!(2024-01-01 00:00 UTC),
+                name: name.to_string(),
+                details: String::new(),
+            }));
+        }
+        fanout.close().await;
+        let mut seen = Vec::new();
+        while let Some(entry) = seen_rx.recv().await {
+            seen.push(entry);
+        }
+        seen.sort();
+        assert_eq!(
+            seen,
+            [
+                (0, "first".to_string()),
+                (0, "second".to_string()),
+                (1, "first".to_string()),
+                (1, "second".to_string())
+            ]
+        );
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::http::{status_code, HttpUrl};
+use std::time::Duration;
+
+/// Checks for genuine internet access by fetching a URL that always answers `204 No Content`, such
+/// as `http://connectivitycheck.gstatic.com/generate_204`. Captive portals and DNS hijacking
+/// answer with a redirect or a login page instead. HTTPS isn't supported, as portals can only
+/// intercept plain HTTP.
+#[derive(Clone, PartialEq)]
+pub struct PortalCheck {
+    url: HttpUrl,
+}
+
+impl PortalCheck {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let url = HttpUrl::parse(url)?;
+        if url.is_https() {
+            return Err(format!(
+                "Portal check URL \"{}\" must use http://",
+                url.as_str()
+            ));
+        }
+        Ok(PortalCheck { url })
+    }
+
+    pub fn url(&self) -> &str {
+        self.url.as_str()
+    }
+
+    /// Fetch the URL, returning why the answer shows no genuine internet access, if it doesn't.
+    pub async fn run(&self, timeout: Duration) -> Result<(), String> {
+        let url = self.url.as_str();
+        let (status_line, _) = tokio::time::timeout(timeout, self.url.get())
+            .await
+            .map_err(|_| format!("{url} timed out"))?
+            .map_err(|e| format!("{url}: {e}"))?;
+        if status_code(&status_line) == "204" {
+            Ok(())
+        } else {
+            Err(format!("{url} answered \"{status_line}\" instead of 204"))
+        }
+    }
+}
@@ -0,0 +1,663 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::columns::Column;
+#[cfg(target_os = "linux")]
+use crate::neighbor::NeighborProbe;
+use crate::ntp::NtpProbe;
+use crate::tcp::TcpProbe;
+use crate::tls::TlsProbe;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use surge_ping::{Client, Config, IcmpPacket, PingIdentifier, PingSequence, SurgeError, ICMP};
+use time::OffsetDateTime;
+use tokio::sync::oneshot;
+
+/// How long after its timeout a reply is still waited for, to be reported as late
+const LATE_REPLY_WAIT: Duration = Duration::from_secs(10);
+
+/// How the target is probed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ProbeKind {
+    /// ICMP echo request
+    #[default]
+    Icmp,
+    /// ARP request (IPv4) or Neighbor Solicitation (IPv6), for targets on the local subnet
+    Neighbor,
+    /// TCP connection to a port
+    Tcp,
+    /// TCP connection and TLS handshake
+    Tls,
+    /// NTP query, also measuring the local clock's offset
+    Ntp,
+}
+
+impl ProbeKind {
+    fn probe_type(&self) -> &'static ProbeType {
+        PROBE_TYPES
+            .iter()
+            .find(|probe_type| probe_type.kind == *self)
+            .unwrap()
+    }
+
+    /// Name of the probe type, used with `--probe` and as its URI scheme.
+    pub fn name(&self) -> &'static str {
+        self.probe_type().scheme
+    }
+
+    /// Extra result CSV columns recorded by this kind of probe by default.
+    pub fn columns(&self) -> &'static [Column] {
+        self.probe_type().columns
+    }
+}
+
+impl FromStr for ProbeKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        PROBE_TYPES
+            .iter()
+            .find(|probe_type| probe_type.scheme == kind)
+            .map(|probe_type| probe_type.kind)
+            .ok_or_else(|| {
+                let names: Vec<&str> = PROBE_TYPES
+                    .iter()
+                    .map(|probe_type| probe_type.scheme)
+                    .collect();
+                format!(
+                    "unknown probe type \"{kind}\" (expected {})",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Parse a target given as a host, `host:port`, an IPv6 address (bare or as `[2001:db8::1]:443`),
+/// a URI such as `tls://example.com:443` naming the probe type, or a web URL such as
+/// `https://example.com:8443/status`. Returns the probe type named by the scheme, if any, and the
+/// host with its port, if given. Paths, queries and user names in URLs are dropped.
+pub fn parse_target(target: &str) -> Result<(Option<ProbeKind>, String), String> {
+    let invalid = |reason: &str| format!("invalid target \"{target}\" ({reason})");
+    let (kind, rest) = match target.split_once("://") {
+        // Web URLs only give the host, which is probed the configured way
+        Some(("http" | "https", rest)) => (None, rest),
+        Some((scheme, rest)) => (Some(scheme.parse::<ProbeKind>()?), rest),
+        None => (None, target),
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let authority = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    if authority.is_empty() {
+        return Err(invalid("no host given"));
+    }
+    if authority.parse::<std::net::Ipv6Addr>().is_ok() {
+        return Ok((kind, authority.to_string()));
+    }
+    if let Some(bracketed) = authority.strip_prefix('[') {
+        let (host, port) = bracketed
+            .split_once(']')
+            .ok_or_else(|| invalid("missing ] after the IPv6 address"))?;
+        if host.parse::<std::net::Ipv6Addr>().is_err() {
+            return Err(invalid(&format!("{host} is not an IPv6 address")));
+        }
+        return match port.strip_prefix(':') {
+            Some(port) if port.parse::<u16>().is_ok() => Ok((kind, authority.to_string())),
+            Some(port) => Err(invalid(&format!("bad port \"{port}\""))),
+            None if port.is_empty() => Ok((kind, host.to_string())),
+            None => Err(invalid("expected :port after the IPv6 address")),
+        };
+    }
+    match authority.split_once(':') {
+        Some((_, port)) if port.contains(':') => Err(invalid(
+            "IPv6 addresses with a port must be in brackets, e.g. [2001:db8::1]:443",
+        )),
+        Some(("", _)) => Err(invalid("no host given")),
+        Some((_, port)) if port.parse::<u16>().is_err() => {
+            Err(invalid(&format!("bad port \"{port}\"")))
+        }
+        _ => Ok((kind, authority.to_string())),
+    }
+}
+
+/// Future returned by `Probe` methods, boxed so that probes can be kept as trait objects.
+pub type ProbeFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Sends probes to the target and measures the round trip time.
+pub trait Probe: Send + Sync {
+    /// Send probe number `seq` carrying `data` and return the round trip time. Only ICMP probes
+    /// carry the payload.
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>>;
+
+    /// Change how long to wait for a reply.
+    fn set_timeout(&mut self, timeout: Duration);
+
+    /// Value of a probe-specific result column for the last probe, if this kind of probe measures
+    /// it. `now` is when the probe was sent.
+    fn result_value(&self, _column: Column, _now: OffsetDateTime) -> Option<String> {
+        None
+    }
+
+    /// Offset of the local clock behind the server's (s) measured by the last probe, for probes
+    /// that measure it.
+    fn clock_offset(&self) -> Option<f64> {
+        None
+    }
+
+    /// Expiry time of the certificate presented to the last probe, for probes that check one.
+    fn cert_not_after(&self) -> Option<OffsetDateTime> {
+        None
+    }
+
+    /// Replies that arrived after their probe timed out, since the last call, as the sequence
+    /// number and round trip time. Only ICMP probes keep listening past the timeout.
+    fn take_late_replies(&mut self) -> Vec<(u16, Duration)> {
+        Vec::new()
+    }
+}
+
+/// Where a new probe is sent.
+pub struct ProbeTarget {
+    /// The target as given, e.g. `example.com:443`
+    pub target: String,
+    pub ip_addr: IpAddr,
+    pub ttl: u32,
+    pub timeout: Duration,
+}
+
+type CreateProbe = fn(ProbeTarget) -> ProbeFuture<'static, io::Result<Box<dyn Probe>>>;
+
+/// A type of probe, registered in `PROBE_TYPES`.
+struct ProbeType {
+    kind: ProbeKind,
+    /// Name of the type, and scheme of targets given as URIs
+    scheme: &'static str,
+    /// Extra result CSV columns recorded by default
+    columns: &'static [Column],
+    create: CreateProbe,
+}
+
+/// Every type of probe. A new type only needs an entry here and a `Probe` implementation.
+const PROBE_TYPES: &[ProbeType] = &[
+    ProbeType {
+        kind: ProbeKind::Icmp,
+        scheme: "icmp",
+        columns: &[],
+        create: |target| {
+            Box::pin(async move {
+                let probe = IcmpProbe::new(target.ip_addr, target.ttl, target.timeout).await?;
+                Ok(Box::new(probe) as Box<dyn Probe>)
+            })
+        },
+    },
+    ProbeType {
+        kind: ProbeKind::Neighbor,
+        scheme: "neighbor",
+        columns: &[],
+        create: |target| {
+            Box::pin(async move {
+                #[cfg(target_os = "linux")]
+                return Ok(
+                    Box::new(NeighborProbe::new(target.ip_addr, target.timeout)?) as Box<dyn Probe>,
+                );
+                #[cfg(not(target_os = "linux"))]
+                Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "neighbor probes are only supported on Linux",
+                ))
+            })
+        },
+    },
+    ProbeType {
+        kind: ProbeKind::Tcp,
+        scheme: "tcp",
+        columns: &[],
+        create: |target| {
+            Box::pin(async move {
+                let probe = TcpProbe::new(&target.target, target.ip_addr, target.timeout)?;
+                Ok(Box::new(probe) as Box<dyn Probe>)
+            })
+        },
+    },
+    ProbeType {
+        kind: ProbeKind::Tls,
+        scheme: "tls",
+        columns: &[Column::Connect, Column::Handshake, Column::CertExpiry],
+        create: |target| {
+            Box::pin(async move {
+                let probe = TlsProbe::new(&target.target, target.ip_addr, target.timeout)?;
+                Ok(Box::new(probe) as Box<dyn Probe>)
+            })
+        },
+    },
+    ProbeType {
+        kind: ProbeKind::Ntp,
+        scheme: "ntp",
+        columns: &[Column::Offset],
+        create: |target| {
+            Box::pin(async move {
+                let probe = NtpProbe::new(&target.target, target.ip_addr, target.timeout).await?;
+                Ok(Box::new(probe) as Box<dyn Probe>)
+            })
+        },
+    },
+];
+
+/// Create a probe of the given kind for `target` at `ip_addr`. The TTL only applies to ICMP
+/// probes, as neighbor discovery never leaves the local link and TCP-based probes use the system's
+/// TCP settings.
+pub async fn create(
+    kind: ProbeKind,
+    target: &str,
+    ip_addr: IpAddr,
+    ttl: u32,
+    timeout: Duration,
+) -> io::Result<Box<dyn Probe>> {
+    (kind.probe_type().create)(ProbeTarget {
+        target: target.to_string(),
+        ip_addr,
+        ttl,
+        timeout,
+    })
+    .await
+}
+
+/// Pick the identifier of a new ICMP probe. Every probe in the process gets its own, counting up
+/// from one derived from the process ID, so that replies can't be credited to another target's
+/// probe or to another `num` process pinging the same host.
+fn next_identifier() -> PingIdentifier {
+    static NEXT: AtomicU16 = AtomicU16::new(0);
+    let base = (std::process::id() as u16).wrapping_mul(4099);
+    PingIdentifier(base.wrapping_add(NEXT.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// Whether a reply with `reply`'s identifier and sequence number answers the echo request sent
+/// with `ident` and `seq`. Without an identifier of its own, the kernel has already checked it.
+fn is_own_reply(
+    reply: (PingIdentifier, PingSequence),
+    ident: Option<PingIdentifier>,
+    seq: PingSequence,
+) -> bool {
+    reply.1 == seq && ident.is_none_or(|ident| ident == reply.0)
+}
+
+/// Sends ICMP echo requests. Each request is waited for in its own task, which keeps listening
+/// for `LATE_REPLY_WAIT` after the probe has timed out so that late replies can be reported.
+pub struct IcmpProbe {
+    // The client owns the task receiving replies for its socket, which is stopped once the last
+    // handle to the client is dropped
+    client: Client,
+    ip_addr: IpAddr,
+    ident: PingIdentifier,
+    timeout: Duration,
+    /// TTL (or hop limit) of the last reply
+    last_ttl: Option<u8>,
+    late_replies: Arc<Mutex<Vec<(u16, Duration)>>>,
+}
+
+impl IcmpProbe {
+    /// Create an ICMP echo probe for `ip_addr` on a new raw socket.
+    pub async fn new(ip_addr: IpAddr, ttl: u32, timeout: Duration) -> io::Result<Self> {
+        let config = match ip_addr {
+            IpAddr::V4(_) => Config::builder().kind(ICMP::V4).ttl(ttl).build(),
+            IpAddr::V6(_) => Config::builder().kind(ICMP::V6).ttl(ttl).build(),
+        };
+        Ok(IcmpProbe {
+            client: Client::new(&config)?,
+            ip_addr,
+            ident: next_identifier(),
+            timeout,
+            last_ttl: None,
+            late_replies: Arc::default(),
+        })
+    }
+}
+
+impl Probe for IcmpProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move {
+            let mut pinger = self.client.pinger(self.ip_addr, self.ident).await;
+            pinger.timeout(self.timeout + LATE_REPLY_WAIT);
+            // Unprivileged (datagram) sockets leave the identifier to the kernel, and the pinger
+            // then has none to check
+            let ident = pinger.ident;
+            let data = data.to_vec();
+            let late_replies = self.late_replies.clone();
+            let (reply_tx, reply_rx) = oneshot::channel();
+            tokio::spawn(async move {
+                // Once the probe has given up on the reply it can only be late
+                if let Err(Ok((_, rtt))) = reply_tx.send(pinger.ping(seq, &data).await) {
+                    late_replies.lock().unwrap().push((seq.0, rtt));
+                }
+            });
+            let reply = match tokio::time::timeout(self.timeout, reply_rx).await {
+                Ok(Ok(reply)) => reply,
+                Ok(Err(_)) => Err(SurgeError::ClientDestroyed),
+                Err(_) => Err(SurgeError::Timeout { seq }),
+            }
+            .and_then(|(packet, rtt)| {
+                let (reply_ident, reply_seq) = match &packet {
+                    IcmpPacket::V4(packet) => (packet.get_identifier(), packet.get_sequence()),
+                    IcmpPacket::V6(packet) => (packet.get_identifier(), packet.get_sequence()),
+                };
+                if !is_own_reply((reply_ident, reply_seq), ident, seq) {
+                    return Err(SurgeError::IOError(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "reply to another probe",
+                    )));
+                }
+                Ok((packet, rtt))
+            });
+            self.last_ttl = match &reply {
+                Ok((IcmpPacket::V4(packet), _)) => packet.get_ttl(),
+                Ok((IcmpPacket::V6(packet), _)) => Some(packet.get_max_hop_limit()),
+                Err(_) => None,
+            };
+            reply.map(|(_, rtt)| rtt)
+        })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn take_late_replies(&mut self) -> Vec<(u16, Duration)> {
+        std::mem::take(&mut self.late_replies.lock().unwrap())
+    }
+
+    fn result_value(&self, column: Column, _now: OffsetDateTime) -> Option<String> {
+        match column {
+            Column::Ttl => self.last_ttl.map(|ttl| ttl.to_string()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Probe for NeighborProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        _data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move {
+            NeighborProbe::ping(self)
+                .await
+                .map_err(|e| probe_error(e, seq))
+        })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        NeighborProbe::set_timeout(self, timeout);
+    }
+}
+
+impl Probe for TcpProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        _data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move { TcpProbe::ping(self).await.map_err(|e| probe_error(e, seq)) })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        TcpProbe::set_timeout(self, timeout);
+    }
+}
+
+impl Probe for TlsProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        _data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move { TlsProbe::ping(self).await.map_err(|e| probe_error(e, seq)) })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        TlsProbe::set_timeout(self, timeout);
+    }
+
+    fn result_value(&self, column: Column, now: OffsetDateTime) -> Option<String> {
+        match column {
+            // The connect time is kept for failed handshakes, pointing at the server
+            Column::Connect => self.last_timing().connect.map(ms),
+            Column::Handshake => self.last_timing().handshake.map(ms),
+            Column::CertExpiry => TlsProbe::cert_not_after(self)
+                .map(|not_after| format!("{:.1}", (not_after - now).as_seconds_f64() / 86400.0)),
+            _ => None,
+        }
+    }
+
+    fn cert_not_after(&self) -> Option<OffsetDateTime> {
+        TlsProbe::cert_not_after(self)
+    }
+}
+
+impl Probe for NtpProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        _data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move { NtpProbe::ping(self).await.map_err(|e| probe_error(e, seq)) })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        NtpProbe::set_timeout(self, timeout);
+    }
+
+    fn result_value(&self, column: Column, _now: OffsetDateTime) -> Option<String> {
+        match column {
+            Column::Offset => self
+                .last_offset()
+                .map(|offset| format!("{:.3}", offset * 1000.0)),
+            _ => None,
+        }
+    }
+
+    fn clock_offset(&self) -> Option<f64> {
+        self.last_offset()
+    }
+}
+
+/// Format a duration as milliseconds for a result column.
+fn ms(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64() * 1000.0)
+}
+
+/// Split a target such as `example.com`, `example.com:8443`, `192.0.2.1:443`, or `[2001:db8::1]:443`
+/// into its host and port, using `default_port` if none is given.
+pub fn split_host_port(target: &str, default_port: u16) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid target \"{target}\" (expected host or host:port)"),
+        )
+    };
+    if let Some(rest) = target.strip_prefix('[') {
+        let (host, port) = rest.split_once(']').ok_or_else(invalid)?;
+        return match port.strip_prefix(':') {
+            Some(port) => Ok((host, port.parse().map_err(|_| invalid())?)),
+            None if port.is_empty() => Ok((host, default_port)),
+            None => Err(invalid()),
+        };
+    }
+    match target.rsplit_once(':') {
+        // A bare IPv6 address has more than one colon and no port
+        Some((host, port)) if !host.contains(':') => {
+            Ok((host, port.parse().map_err(|_| invalid())?))
+        }
+        _ => Ok((target, default_port)),
+    }
+}
+
+/// Report timeouts of non-ICMP probes the same way as ICMP ones.
+fn probe_error(error: io::Error, seq: PingSequence) -> SurgeError {
+    match error.kind() {
+        io::ErrorKind::TimedOut => SurgeError::Timeout { seq },
+        _ => SurgeError::IOError(error),
+    }
+}
+
+/// Classify why a probe failed, for the `Error` column of the result CSV.
+pub fn failure_reason(error: &SurgeError) -> &'static str {
+    match error {
+        SurgeError::Timeout { .. } => "timeout",
+        SurgeError::IOError(e) => match e.kind() {
+            io::ErrorKind::NetworkUnreachable | io::ErrorKind::HostUnreachable => "unreachable",
+            io::ErrorKind::ConnectionRefused => "refused",
+            io::ErrorKind::InvalidData => "bad_reply",
+            _ => "send_error",
+        },
+        SurgeError::IncorrectBufferSize | SurgeError::MalformedPacket(_) => "bad_reply",
+        _ => "send_error",
+    }
+}
+
+/// Explain why a probe socket could not be created and how to fix it. Raw sockets need elevated
+/// privileges on most platforms, so permission errors get the exact remedy for this binary.
+pub fn socket_error_help(error: &io::Error) -> String {
+    if error.kind() != io::ErrorKind::PermissionDenied {
+        return format!("Could not create probe socket: {error}");
+    }
+    let exe = std::env::current_exe()
+        .map(|exe| exe.display().to_string())
+        .unwrap_or_else(|_| "num".to_string());
+    let mut help = format!("Not permitted to create a raw socket ({error}). ");
+    if cfg!(target_os = "linux") {
+        help.push_str(&format!(
+            "Grant the CAP_NET_RAW capability with `sudo setcap cap_net_raw+ep {exe}`, or run as \
+             root"
+        ));
+        // Reading the mode doesn't need privileges; SELinux can deny raw sockets even to root
+        if std::fs::read_to_string("/sys/fs/selinux/enforce").is_ok_and(|mode| mode.trim() == "1") {
+            help.push_str(
+                ". SELinux is enforcing and may also be denying the socket (check `ausearch -m \
+                 avc -ts recent`)",
+            );
+        }
+    } else if cfg!(windows) {
+        help.push_str(&format!(
+            "Run num from an elevated (Administrator) prompt, e.g. `runas /user:Administrator \
+             \"{exe}\"`"
+        ));
+    } else {
+        help.push_str(&format!("Run num as root, e.g. `sudo {exe}`"));
+    }
+    help
+}
+
+/// A probe that replays a scripted sequence of results instead of touching the network, taking
+/// (tokio) time to answer just like a real target. Once the script runs out every probe times out.
+#[cfg(test)]
+pub struct ScriptedProbe {
+    replies: std::collections::VecDeque<Option<Duration>>,
+    timeout: Duration,
+    late_replies: Vec<(u16, Duration)>,
+}
+
+#[cfg(test)]
+impl ScriptedProbe {
+    /// Create a probe answering with each round trip time in turn, or timing out for `None`.
+    /// Round trip times past the timeout time out and are then reported as late replies.
+    pub fn new(replies: impl IntoIterator<Item = Option<Duration>>) -> Self {
+        ScriptedProbe {
+            replies: replies.into_iter().collect(),
+            timeout: Duration::from_secs(1),
+            late_replies: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Probe for ScriptedProbe {
+    fn ping<'a>(
+        &'a mut self,
+        seq: PingSequence,
+        _data: &'a [u8],
+    ) -> ProbeFuture<'a, Result<Duration, SurgeError>> {
+        Box::pin(async move {
+            match self.replies.pop_front().flatten() {
+                Some(rtt) if rtt < self.timeout => {
+                    tokio::time::sleep(rtt).await;
+                    Ok(rtt)
+                }
+                reply => {
+                    tokio::time::sleep(self.timeout).await;
+                    if let Some(rtt) = reply {
+                        self.late_replies.push((seq.0, rtt));
+                    }
+                    Err(SurgeError::Timeout { seq })
+                }
+            }
+        })
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn take_late_replies(&mut self) -> Vec<(u16, Duration)> {
+        std::mem::take(&mut self.late_replies)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_checked_against_the_identifier_and_sequence() {
+        let (ident, seq) = (PingIdentifier(7), PingSequence(3));
+        for (reply, ident, accepted) in [
+            ((PingIdentifier(7), PingSequence(3)), Some(ident), true),
+            ((PingIdentifier(8), PingSequence(3)), Some(ident), false),
+            ((PingIdentifier(7), PingSequence(4)), Some(ident), false),
+            ((PingIdentifier(8), PingSequence(3)), None, true),
+            ((PingIdentifier(8), PingSequence(4)), None, false),
+        ] {
+            assert_eq!(is_own_reply(reply, ident, seq), accepted, "{reply:?}");
+        }
+    }
+
+    #[test]
+    fn every_probe_gets_its_own_identifier() {
+        let first = next_identifier();
+        let second = next_identifier();
+        assert_ne!(first, second);
+    }
+}
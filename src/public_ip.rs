@@ -0,0 +1,230 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asn::Network;
+use crate::http::{status_code, HttpUrl};
+use crate::probe::split_host_port;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+use tokio::net::{lookup_host, UdpSocket};
+
+// Queries that take longer than this fail, so a dead service doesn't hold up the next poll
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const STUN_DEFAULT_PORT: u16 = 3478;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+
+/// A public address found by a poll.
+pub struct PublicAddress {
+    pub address: IpAddr,
+    /// Network the address is announced from, looked up with `--asn-lookup` when it changes
+    pub network: Option<Result<Network, String>>,
+}
+
+/// A service telling the monitor its public (WAN) address.
+#[derive(Clone)]
+pub enum PublicIpSource {
+    /// An `http(s)://` URL answering with the address as plain text, e.g. `https://api.ipify.org`
+    Http(HttpUrl),
+    /// A STUN server given as `stun:host[:port]`, asked with a binding request
+    Stun(String),
+}
+
+impl PublicIpSource {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        match source.strip_prefix("stun:") {
+            Some(server) if !server.is_empty() => Ok(PublicIpSource::Stun(server.to_string())),
+            Some(_) => Err(format!(
+                "Invalid STUN server \"{source}\" (expected stun:host[:port])"
+            )),
+            None => HttpUrl::parse(source).map(PublicIpSource::Http),
+        }
+    }
+
+    /// Ask the service for the public address.
+    pub async fn query(&self) -> Result<IpAddr, String> {
+        let (name, result) = match self {
+            PublicIpSource::Http(url) => (url.as_str(), query_http(url).await),
+            PublicIpSource::Stun(server) => (server.as_str(), query_stun(server).await),
+        };
+        result.map_err(|e| format!("{name}: {e}"))
+    }
+}
+
+async fn query_http(url: &HttpUrl) -> Result<IpAddr, String> {
+    let body = tokio::time::timeout(QUERY_TIMEOUT, async {
+        let (status_line, mut stream) = url.get().await.map_err(|e| e.to_string())?;
+        if status_code(&status_line) != "200" {
+            return Err(format!("answered \"{status_line}\""));
+        }
+        let mut header = String::new();
+        while stream
+            .read_line(&mut header)
+            .await
+            .map_err(|e| e.to_string())?
+            > 2
+        {
+            header.clear();
+        }
+        let mut body = String::new();
+        stream
+            .take(4096)
+            .read_to_string(&mut body)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(body)
+    })
+    .await
+    .map_err(|_| "timed out".to_string())??;
+    // The address is the only thing on its line, even in a chunked response
+    body.lines()
+        .find_map(|line| line.trim().parse().ok())
+        .ok_or_else(|| "no address in the answer".to_string())
+}
+
+async fn query_stun(server: &str) -> Result<IpAddr, String> {
+    let (host, port) = split_host_port(server, STUN_DEFAULT_PORT).map_err(|e| e.to_string())?;
+    let address = lookup_host((host, port))
+        .await
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| "no addresses found".to_string())?;
+    let bind: (IpAddr, u16) = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+    let socket = UdpSocket::bind(bind).await.map_err(|e| e.to_string())?;
+    let transaction: [u8; 12] = {
+        let random = RandomState::new().hash_one(SystemTime::now()).to_be_bytes();
+        let mut transaction = [0; 12];
+        transaction[..8].copy_from_slice(&random);
+        transaction[8..].copy_from_slice(&std::process::id().to_be_bytes());
+        transaction
+    };
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(&transaction);
+    socket
+        .send_to(&request, address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut buf = [0u8; 1024];
+    tokio::time::timeout(QUERY_TIMEOUT, async {
+        loop {
+            let (len, from) = socket
+                .recv_from(&mut buf)
+                .await
+                .map_err(|e| e.to_string())?;
+            if from != address {
+                continue;
+            }
+            if let Some(mapped) = parse_binding_response(&buf[..len], &transaction) {
+                return Ok(mapped);
+            }
+        }
+    })
+    .await
+    .map_err(|_| "timed out".to_string())?
+}
+
+/// Find the address the server saw us at in a STUN binding response to `transaction`.
+fn parse_binding_response(response: &[u8], transaction: &[u8; 12]) -> Option<IpAddr> {
+    if response.len() < 20
+        || response[..2] != STUN_BINDING_RESPONSE.to_be_bytes()
+        || response[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || response[8..20] != transaction[..]
+    {
+        return None;
+    }
+    let mut mapped = None;
+    let mut attributes = response.get(20..)?;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + len)?;
+        // The XOR'd form is preferred, as NATs rewriting addresses in packets can't mangle it
+        match kind {
+            STUN_XOR_MAPPED_ADDRESS => {
+                let mut key = STUN_MAGIC_COOKIE.to_be_bytes().to_vec();
+                key.extend_from_slice(transaction);
+                return decode_address(value, &key);
+            }
+            STUN_MAPPED_ADDRESS => mapped = decode_address(value, &[0; 16]),
+            _ => {}
+        }
+        // Attributes are padded to a multiple of 4 bytes
+        attributes = attributes
+            .get(4 + len.div_ceil(4) * 4..)
+            .unwrap_or_default();
+    }
+    mapped
+}
+
+/// Decode the address of a (XOR-)MAPPED-ADDRESS attribute, XORing it with `key`.
+fn decode_address(value: &[u8], key: &[u8]) -> Option<IpAddr> {
+    let octets = |len: usize| -> Option<Vec<u8>> {
+        let address = value.get(4..4 + len)?;
+        Some(
+            address
+                .iter()
+                .zip(key)
+                .map(|(byte, key)| byte ^ key)
+                .collect(),
+        )
+    };
+    match value.get(1)? {
+        1 => Some(IpAddr::from(<[u8; 4]>::try_from(octets(4)?).ok()?)),
+        2 => Some(IpAddr::from(<[u8; 16]>::try_from(octets(16)?).ok()?)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stun_responses_give_the_mapped_address() {
+        let transaction = [7; 12];
+        let mut response = STUN_BINDING_RESPONSE.to_be_bytes().to_vec();
+        response.extend_from_slice(&12u16.to_be_bytes());
+        response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(&transaction);
+        // XOR-MAPPED-ADDRESS of 203.0.113.7:54321
+        response.extend_from_slice(&STUN_XOR_MAPPED_ADDRESS.to_be_bytes());
+        response.extend_from_slice(&8u16.to_be_bytes());
+        response.extend_from_slice(&[0, 1]);
+        response.extend_from_slice(&(54321 ^ 0x2112u16).to_be_bytes());
+        let address = u32::from(Ipv4Addr::new(203, 0, 113, 7)) ^ STUN_MAGIC_COOKIE;
+        response.extend_from_slice(&address.to_be_bytes());
+        assert_eq!(
+            parse_binding_response(&response, &transaction),
+            Some(IpAddr::from([203, 0, 113, 7]))
+        );
+        assert_eq!(parse_binding_response(&response, &[8; 12]), None);
+    }
+}
@@ -0,0 +1,593 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::lifetime;
+use crate::theme::{bold, failure};
+use std::collections::BTreeMap;
+use std::path::Path;
+use time::{Date, OffsetDateTime, PrimitiveDateTime, Time, UtcOffset};
+
+/// Service level to check a link against. Either target may be left out.
+pub struct Sla {
+    /// Minimum share of successful probes per month (%)
+    pub availability: Option<f64>,
+    /// Maximum 95th percentile latency per month (ms)
+    pub p95: Option<f64>,
+}
+
+/// Results of one calendar month.
+#[derive(Default)]
+struct Month {
+    sent: u64,
+    /// Latencies of successful probes (ms)
+    latencies: Vec<f64>,
+    /// Download speeds measured with `--throughput-url` (Mbps)
+    throughput: Vec<f64>,
+}
+
+impl Month {
+    fn availability(&self) -> f64 {
+        self.latencies.len() as f64 * 100.0 / self.sent.max(1) as f64
+    }
+
+    /// 95th percentile latency (nearest-rank method), if any probe succeeded.
+    fn p95(&mut self) -> Option<f64> {
+        if self.latencies.is_empty() {
+            return None;
+        }
+        self.latencies.sort_unstable_by(f64::total_cmp);
+        let rank = (0.95 * self.latencies.len() as f64).ceil() as usize;
+        Some(self.latencies[rank.clamp(1, self.latencies.len()) - 1])
+    }
+
+    /// Mean measured download speed (Mbps), if any was measured.
+    fn throughput(&self) -> Option<f64> {
+        (!self.throughput.is_empty())
+            .then(|| self.throughput.iter().sum::<f64>() / self.throughput.len() as f64)
+    }
+}
+
+/// The rows of one result file, with its column names and the run metadata at its top.
+pub struct ResultFile {
+    pub metadata: Vec<(String, String)>,
+    pub headers: Vec<String>,
+    pub rows: Vec<csv::StringRecord>,
+}
+
+impl ResultFile {
+    /// Value of a `# key: value` metadata line, if the file has it.
+    pub fn metadata(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Index of the column named `header`, if the file has it.
+    pub fn column(&self, header: &str) -> Option<usize> {
+        self.headers.iter().position(|name| name == header)
+    }
+
+    /// Time and latency (ms, or None if it failed) of each probe. Rows with an unreadable
+    /// timestamp are left out.
+    pub fn probes(&self) -> Vec<(OffsetDateTime, Option<f64>)> {
+        let timestamp = self.column("Timestamp").unwrap();
+        let latency = self.column("Latency(ms)").unwrap();
+        self.rows
+            .iter()
+            .filter_map(|row| {
+                Some((
+                    parse_timestamp(row.get(timestamp)?)?,
+                    row.get(latency).and_then(|latency| latency.parse().ok()),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// A run of consecutive failed probes.
+pub struct Outage {
+    pub start: OffsetDateTime,
+    /// Time of the first successful probe after the outage, or of its last failure if the results
+    /// end during it
+    pub end: OffsetDateTime,
+    pub failures: u64,
+}
+
+/// Find the outages in the probes of one run. They don't span runs, since nothing was monitored
+/// in between.
+pub fn outages(probes: &[(OffsetDateTime, Option<f64>)]) -> Vec<Outage> {
+    let mut outages = Vec::new();
+    let mut outage: Option<Outage> = None;
+    for &(timestamp, latency) in probes {
+        if latency.is_some() {
+            if let Some(mut ended) = outage.take() {
+                ended.end = timestamp;
+                outages.push(ended);
+            }
+        } else {
+            let outage = outage.get_or_insert(Outage {
+                start: timestamp,
+                end: timestamp,
+                failures: 0,
+            });
+            outage.end = timestamp;
+            outage.failures += 1;
+        }
+    }
+    outages.extend(outage);
+    outages
+}
+
+/// Read every result file in `output_path`, oldest first. Files without timestamp and latency
+/// columns are skipped.
+pub fn read_results(output_path: &Path) -> Result<Vec<ResultFile>, String> {
+    let entries = std::fs::read_dir(output_path)
+        .map_err(|e| format!("Could not read {}: {e}", output_path.display()))?;
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !(name.starts_with("result_") && (name.ends_with(".csv") || name.ends_with(".tsv"))) {
+            continue;
+        }
+        let contents = std::fs::read_to_string(entry.path())
+            .map_err(|e| format!("Could not read {name}: {e}"))?;
+        // Skip the run metadata comments
+        let Some(header) = contents.lines().find(|line| !line.starts_with('#')) else {
+            continue;
+        };
+        let delimiter = detect_delimiter(header);
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(delimiter)
+            .comment(Some(b'#'))
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+        let headers = reader
+            .headers()
+            .map_err(|e| format!("Could not read {name}: {e}"))?
+            .iter()
+            .map(str::to_string)
+            .collect();
+        let metadata = contents
+            .lines()
+            .map_while(|line| line.strip_prefix("# "))
+            .filter_map(|line| line.split_once(": "))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        let file = ResultFile {
+            metadata,
+            headers,
+            rows: reader.records().flatten().collect(),
+        };
+        if file.column("Timestamp").is_some() && file.column("Latency(ms)").is_some() {
+            files.push(file);
+        }
+    }
+    // File names start with the month rather than the year, so order by the first timestamp
+    files.sort_by_cached_key(|file| {
+        let timestamp = file.column("Timestamp").unwrap();
+        file.rows
+            .first()
+            .and_then(|row| row.get(timestamp))
+            .and_then(parse_timestamp)
+    });
+    Ok(files)
+}
+
+/// Read the download speeds (Mbps) of the `throughput` events in the events files in
+/// `output_path`, with the time each was measured. Encrypted files and unreadable rows are skipped.
+pub fn read_throughput(output_path: &Path) -> Vec<(OffsetDateTime, f64)> {
+    let Ok(entries) = std::fs::read_dir(output_path) else {
+        return Vec::new();
+    };
+    let mut measurements = Vec::new();
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !(name.starts_with("events_") && (name.ends_with(".csv") || name.ends_with(".tsv"))) {
+            continue;
+        }
+        let Ok(contents) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+        let Some(header) = contents.lines().next() else {
+            continue;
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(detect_delimiter(header))
+            .flexible(true)
+            .from_reader(contents.as_bytes());
+        // Details start with the speed, e.g. `48.213 Mbps (30000000 bytes in 4.978s)`
+        measurements.extend(reader.records().flatten().filter_map(|row| {
+            if row.get(1)? != "throughput" {
+                return None;
+            }
+            let (mbps, _) = row.get(2)?.split_once(" Mbps")?;
+            Some((parse_timestamp(row.get(0)?)?, mbps.parse().ok()?))
+        }));
+    }
+    measurements
+}
+
+/// Find the delimiter of a result file from its header line. Column names only contain letters,
+/// digits and parentheses, so the first other character is the delimiter.
+pub fn detect_delimiter(header: &str) -> u8 {
+    header
+        .bytes()
+        .find(|byte| !byte.is_ascii_alphanumeric() && !matches!(byte, b'(' | b')'))
+        .unwrap_or(b',')
+}
+
+/// Parse a timestamp as written to the result files, e.g. `2023-05-31 17:10:38.662942381 -05:00:00`.
+pub fn parse_timestamp(timestamp: &str) -> Option<OffsetDateTime> {
+    let mut parts = timestamp.split(' ');
+    let (date, time, offset) = (parts.next()?, parts.next()?, parts.next()?);
+    let mut date = date.splitn(3, '-').map(str::parse::<i32>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let date = Date::from_calendar_date(
+        year,
+        time::Month::try_from(u8::try_from(month).ok()?).ok()?,
+        u8::try_from(day).ok()?,
+    )
+    .ok()?;
+    let (hms, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let mut hms = hms.splitn(3, ':').map(str::parse::<u8>);
+    let (hour, minute, second) = (hms.next()?.ok()?, hms.next()?.ok()?, hms.next()?.ok()?);
+    let nanosecond = format!("{fraction:0<9}").get(..9)?.parse().ok()?;
+    let time = Time::from_hms_nano(hour, minute, second, nanosecond).ok()?;
+    let (sign, offset) = offset.split_at_checked(1)?;
+    let mut offset = offset.splitn(3, ':').map(str::parse::<i8>);
+    let (hours, minutes, seconds) = (
+        offset.next()?.ok()?,
+        offset.next()?.ok()?,
+        offset.next()?.ok()?,
+    );
+    let offset = match sign {
+        "+" => UtcOffset::from_hms(hours, minutes, seconds),
+        "-" => UtcOffset::from_hms(-hours, -minutes, -seconds),
+        _ => return None,
+    }
+    .ok()?;
+    Some(PrimitiveDateTime::new(date, time).assume_offset(offset))
+}
+
+/// Read every result file in `output_path` and report availability and latency per month, with
+/// compliance and error budget against `sla`.
+pub fn run(output_path: &Path, sla: &Sla) -> Result<String, String> {
+    let mut months: BTreeMap<String, Month> = BTreeMap::new();
+    let mut networks = BTreeMap::new();
+    for file in read_results(output_path)? {
+        if let (Some(target), Some(network)) = (file.metadata("target"), file.metadata("network")) {
+            networks.insert(target.to_string(), network.to_string());
+        }
+        let timestamp_column = file.column("Timestamp").unwrap();
+        let latency_column = file.column("Latency(ms)").unwrap();
+        for row in &file.rows {
+            let (Some(timestamp), Some(latency)) =
+                (row.get(timestamp_column), row.get(latency_column))
+            else {
+                continue;
+            };
+            // Timestamps start with the date, e.g. 2023-05-31
+            let Some(month) = timestamp.get(..7) else {
+                continue;
+            };
+            let month = months.entry(month.to_string()).or_default();
+            month.sent += 1;
+            if let Ok(latency) = latency.parse::<f64>() {
+                month.latencies.push(latency);
+            }
+        }
+    }
+    if months.is_empty() {
+        return Err(format!("No results found in {}", output_path.display()));
+    }
+    // Only months with results are reported, so measurements outside them are left out
+    for (measured, mbps) in read_throughput(output_path) {
+        let month = format!("{:04}-{:02}", measured.year(), u8::from(measured.month()));
+        if let Some(month) = months.get_mut(&month) {
+            month.throughput.push(mbps);
+        }
+    }
+    let mut report = bold(&format!(
+        "{:<8} {:>9} {:>13} {:>9} {:>9} {:>10} {:>12} {:>12}",
+        "Month", "Probes", "Availability", "p95(ms)", "Mbps", "SLA", "Budget used", "Budget left"
+    ));
+    for (name, month) in &mut months {
+        let availability = month.availability();
+        let p95 = month.p95();
+        let met = sla.availability.is_none_or(|target| availability >= target)
+            && sla
+                .p95
+                .is_none_or(|target| p95.is_some_and(|p95| p95 <= target));
+        // The error budget is the share of probes the availability target allows to fail
+        let budget_used = sla.availability.map(|target| {
+            let allowed = (100.0 - target) / 100.0 * month.sent as f64;
+            let failed = (month.sent - month.latencies.len() as u64) as f64;
+            if allowed > 0.0 {
+                failed * 100.0 / allowed
+            } else if failed > 0.0 {
+                f64::INFINITY
+            } else {
+                0.0
+            }
+        });
+        let percent =
+            |value: Option<f64>| value.map_or("-".to_string(), |value| format!("{value:.1}%"));
+        let row = format!(
+            "{name:<8} {:>9} {:>12.3}% {:>9} {:>9} {:>10} {:>12} {:>12}",
+            month.sent,
+            availability,
+            p95.map_or("-".to_string(), |p95| format!("{p95:.3}")),
+            month
+                .throughput()
+                .map_or("-".to_string(), |mbps| format!("{mbps:.1}")),
+            if sla.availability.is_none() && sla.p95.is_none() {
+                "-"
+            } else if met {
+                "met"
+            } else {
+                "missed"
+            },
+            percent(budget_used),
+            percent(budget_used.map(|used| (100.0 - used).max(0.0))),
+        );
+        report.push('\n');
+        report.push_str(&if met { row } else { failure(row).to_string() });
+    }
+    let mut targets = Vec::new();
+    if let Some(availability) = sla.availability {
+        targets.push(format!("{availability}% availability"));
+    }
+    if let Some(p95) = sla.p95 {
+        targets.push(format!("p95 latency of at most {p95}ms"));
+    }
+    if !targets.is_empty() {
+        report.push_str(&format!("\n{} {}", bold("SLA:"), targets.join(", ")));
+    }
+    for (target, network) in networks {
+        report.push_str(&format!("\n{} {target}: {network}", bold("Network:")));
+    }
+    // The lifetime statistics are a bonus, so a broken file doesn't spoil the report
+    for (target, lifetime) in lifetime::read(output_path).unwrap_or_default() {
+        report.push_str(&format!(
+            "\n{} {target}: {}",
+            bold("All-time uptime:"),
+            lifetime.describe()
+        ));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    /// Write a result file of `(timestamp, latency)` rows, with an empty latency for a failure.
+    fn write_results(dir: &Path, name: &str, rows: &[(&str, &str)]) {
+        let mut contents = "# target: 192.0.2.1\nTimestamp,Latency(ms)\n".to_string();
+        for (timestamp, latency) in rows {
+            contents.push_str(&format!("{timestamp},{latency}\n"));
+        }
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+
+    #[test]
+    fn months_have_availability_and_nearest_rank_p95() {
+        let cases: &[(u64, &[f64], f64, Option<f64>)] = &[
+            (0, &[], 0.0, None),
+            (4, &[], 0.0, None),
+            (4, &[10.0, 20.0], 50.0, Some(20.0)),
+            (1, &[7.5], 100.0, Some(7.5)),
+        ];
+        for &(sent, latencies, availability, p95) in cases {
+            let mut month = Month {
+                sent,
+                latencies: latencies.to_vec(),
+                throughput: Vec::new(),
+            };
+            assert_eq!(month.availability(), availability, "{sent} {latencies:?}");
+            assert_eq!(month.p95(), p95, "{sent} {latencies:?}");
+        }
+        // The 95th percentile of 20 latencies is the 19th, of 21 the 20th
+        for (count, p95) in [(20, 19.0), (21, 20.0), (100, 95.0)] {
+            let mut month = Month {
+                sent: count,
+                latencies: (1..=count).rev().map(|ms| ms as f64).collect(),
+                throughput: Vec::new(),
+            };
+            assert_eq!(month.p95(), Some(p95), "{count} probes");
+        }
+    }
+
+    #[test]
+    fn timestamps_are_parsed_as_written() {
+        let cases = [
+            (
+                "2023-05-31 17:10:38.662942381 -05:00:00",
+                Some(datetime!(2023-05-31 17:10:38.662942381 -5)),
+            ),
+            (
+                "2024-02-29 23:59:59.5 +05:30:00",
+                Some(datetime!(2024-02-29 23:59:59.5 +5:30)),
+            ),
+            (
+                "2024-01-01 0:00:00 +00:00:00",
+                Some(datetime!(2024-01-01 0:00 UTC)),
+            ),
+            ("2023-02-29 00:00:00.0 +00:00:00", None),
+            ("2024-13-01 00:00:00.0 +00:00:00", None),
+            ("2024-01-01 24:00:00.0 +00:00:00", None),
+            ("2024-01-01 00:00:00.0", None),
+            ("2024-01-01 00:00:00.0 05:00:00", None),
+            ("", None),
+        ];
+        for (timestamp, parsed) in cases {
+            assert_eq!(parse_timestamp(timestamp), parsed, "{timestamp}");
+        }
+    }
+
+    #[test]
+    fn outages_run_from_the_first_failure_to_the_next_success() {
+        let at = |second: u8| {
+            datetime!(2024-01-01 0:00 UTC)
+                .replace_second(second)
+                .unwrap()
+        };
+        // Latencies of probes a second apart, and the outages in them as start and end second
+        // and failures
+        type Case = (&'static [Option<f64>], &'static [(u8, u8, u64)]);
+        let cases: &[Case] = &[
+            (&[Some(1.0), Some(2.0)], &[]),
+            (&[Some(1.0), None, None, Some(2.0)], &[(1, 3, 2)]),
+            (&[None, Some(1.0), None], &[(0, 1, 1), (2, 2, 1)]),
+            (&[Some(1.0), None, None, None], &[(1, 3, 3)]),
+        ];
+        for &(latencies, expected) in cases {
+            let probes: Vec<_> = latencies
+                .iter()
+                .enumerate()
+                .map(|(second, &latency)| (at(second as u8), latency))
+                .collect();
+            let found: Vec<_> = outages(&probes)
+                .iter()
+                .map(|outage| (outage.start, outage.end, outage.failures))
+                .collect();
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|&(start, end, failures)| (at(start), at(end), failures))
+                .collect();
+            assert_eq!(found, expected, "{latencies:?}");
+        }
+    }
+
+    #[test]
+    fn reports_split_months_at_their_boundary_and_outages_at_runs() {
+        let dir = tempfile::tempdir().unwrap();
+        // An outage over New Year's that outlasts the first run
+        write_results(
+            dir.path(),
+            "result_12-31-2023@23-59-57_3f9a1c07.csv",
+            &[
+                ("2023-12-31 23:59:57.0 +00:00:00", "10.000"),
+                ("2023-12-31 23:59:58.0 +00:00:00", "30.000"),
+                ("2023-12-31 23:59:59.0 +00:00:00", ""),
+                ("2024-01-01 00:00:00.0 +00:00:00", ""),
+            ],
+        );
+        write_results(
+            dir.path(),
+            "result_01-01-2024@00-00-05_8b2e6d10.csv",
+            &[
+                ("2024-01-01 00:00:05.0 +00:00:00", ""),
+                ("2024-01-01 00:00:06.0 +00:00:00", "20.000"),
+                ("2024-01-01 00:00:07.0 +00:00:00", "40.000"),
+            ],
+        );
+        let files = read_results(dir.path()).unwrap();
+        let outages: Vec<Vec<_>> = files
+            .iter()
+            .map(|file| {
+                outages(&file.probes())
+                    .iter()
+                    .map(|outage| (outage.start, outage.end, outage.failures))
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            outages,
+            [
+                vec![(
+                    datetime!(2023-12-31 23:59:59 UTC),
+                    datetime!(2024-01-01 0:00 UTC),
+                    2
+                )],
+                vec![(
+                    datetime!(2024-01-01 0:00:05 UTC),
+                    datetime!(2024-01-01 0:00:06 UTC),
+                    1
+                )],
+            ]
+        );
+
+        let cases = [
+            (
+                Sla {
+                    availability: None,
+                    p95: None,
+                },
+                [
+                    ["2023-12", "3", "66.667%", "30.000", "-", "-", "-", "-"],
+                    ["2024-01", "4", "50.000%", "40.000", "-", "-", "-", "-"],
+                ],
+            ),
+            (
+                Sla {
+                    availability: Some(60.0),
+                    p95: Some(35.0),
+                },
+                [
+                    [
+                        "2023-12", "3", "66.667%", "30.000", "-", "met", "83.3%", "16.7%",
+                    ],
+                    [
+                        "2024-01", "4", "50.000%", "40.000", "-", "missed", "125.0%", "0.0%",
+                    ],
+                ],
+            ),
+            (
+                Sla {
+                    availability: Some(100.0),
+                    p95: None,
+                },
+                [
+                    [
+                        "2023-12", "3", "66.667%", "30.000", "-", "missed", "inf%", "0.0%",
+                    ],
+                    [
+                        "2024-01", "4", "50.000%", "40.000", "-", "missed", "inf%", "0.0%",
+                    ],
+                ],
+            ),
+        ];
+        for (sla, months) in &cases {
+            // Missed months are colored
+            let report: String = run(dir.path(), sla)
+                .unwrap()
+                .split('\x1b')
+                .enumerate()
+                .map(|(i, part)| {
+                    if i == 0 {
+                        part
+                    } else {
+                        part.split_once('m').unwrap().1
+                    }
+                })
+                .collect();
+            let rows: Vec<Vec<&str>> = report
+                .lines()
+                .skip(1)
+                .take(2)
+                .map(|row| row.split_whitespace().collect())
+                .collect();
+            assert_eq!(rows, months);
+        }
+        let empty = tempfile::tempdir().unwrap();
+        assert!(run(empty.path(), &cases[0].0).is_err());
+    }
+}
@@ -0,0 +1,1300 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::alert::{AlertRule, AlertTemplate, Channel, RateLimit, WindowRule};
+use crate::columns::Columns;
+use crate::encrypt::Recipient;
+use crate::engine::{parse_duration, FlushPolicy};
+use crate::log_file::parse_delimiter;
+use crate::portal::PortalCheck;
+use crate::probe::{parse_target, ProbeKind};
+use crate::sign::Key;
+use crate::sink::SinkKind;
+use crate::targets::SettingsBuilder;
+use crate::theme::Theme;
+use crate::writer::OverflowPolicy;
+use crate::{MIN_DELAY_SECS, MIN_FAST_DELAY_SECS};
+use clap::ArgMatches;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+// Time for the rest of a write to land once a change to the config file is seen, so a file being
+// written in several steps is only read once
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Probe settings that can be changed while running by reloading the config file.
+#[derive(Clone, PartialEq)]
+pub struct ProbeSettings {
+    pub addr: String,
+    pub kind: ProbeKind,
+    /// Time to wait for a reply (ms)
+    pub timeout: u64,
+    pub delay: Duration,
+    /// Fraction of the delay by which each probe is randomly postponed
+    pub interval_jitter: f64,
+    /// Send probes at multiples of the delay on the wall clock rather than from the start
+    pub align: bool,
+    pub num_bytes: u8,
+    pub ttl: u32,
+    /// Consecutive failed pings before the target is considered down
+    pub down_after: u32,
+    /// Go down on the first probe failing for lack of a route instead of waiting for `down_after`
+    /// failures
+    pub down_on_no_route: bool,
+    /// Times a timed-out probe is sent again before it counts as failed
+    pub retries: u32,
+    /// Days before certificate expiry at which TLS probes start warning
+    pub cert_warn_days: u32,
+    /// Clock offset beyond which NTP probes warn (ms)
+    pub max_offset: u64,
+    /// Check for genuine internet access after each successful probe
+    pub portal_check: Option<PortalCheck>,
+    /// Escalation policy: each rule alerts once per outage when its threshold is reached
+    pub alerts: Vec<AlertRule>,
+    /// Custom message bodies for alert channels
+    pub alert_templates: Vec<AlertTemplate>,
+    pub alert_rate_limits: Vec<RateLimit>,
+    /// Window in which an identical alert isn't sent again
+    pub alert_dedup: Duration,
+    /// Alerts on statistics over rolling windows
+    pub window_alerts: Vec<WindowRule>,
+    /// Channel told when the public address moves to another network
+    pub isp_change_alert: Option<Channel>,
+    /// Channel told when every external target is DOWN at once
+    pub internet_alert: Option<Channel>,
+}
+
+/// Which setting to change when the timeout doesn't fit within the delay.
+#[derive(Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoAdjust {
+    /// Lower the timeout to half the delay
+    Timeout,
+    /// Raise the delay to twice the timeout
+    Delay,
+}
+
+impl AutoAdjust {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AutoAdjust::Timeout => "timeout",
+            AutoAdjust::Delay => "delay",
+        }
+    }
+}
+
+impl FromStr for AutoAdjust {
+    type Err = String;
+
+    fn from_str(setting: &str) -> Result<Self, Self::Err> {
+        match setting {
+            "timeout" => Ok(AutoAdjust::Timeout),
+            "delay" => Ok(AutoAdjust::Delay),
+            _ => Err(format!(
+                "unknown setting \"{setting}\" (expected timeout or delay)"
+            )),
+        }
+    }
+}
+
+/// Settings given on the command line, in `NUM_*` environment variables or in a `--config` TOML
+/// file. Anything left unset falls back to the next source in that order, then to the defaults.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    pub address: Option<String>,
+    pub probe: Option<ProbeKind>,
+    pub output: Option<PathBuf>,
+    pub timeout: Option<u64>,
+    pub delay: Option<f64>,
+    pub interval_jitter: Option<String>,
+    pub align: Option<bool>,
+    pub fast: Option<bool>,
+    pub auto_adjust: Option<AutoAdjust>,
+    pub num_bytes: Option<u8>,
+    pub ttl: Option<u32>,
+    pub down_after: Option<u32>,
+    pub down_on_no_route: Option<bool>,
+    pub retries: Option<u32>,
+    pub cert_warn_days: Option<u32>,
+    pub max_offset: Option<u64>,
+    pub portal_check: Option<String>,
+    pub alert: Option<Vec<String>>,
+    pub alert_template: Option<Vec<String>>,
+    pub alert_rate_limit: Option<Vec<String>>,
+    pub alert_dedup: Option<String>,
+    pub window_alert: Option<Vec<String>>,
+    pub isp_change_alert: Option<String>,
+    pub internet_alert: Option<String>,
+    /// What to do when probes fall behind schedule: skip, delay or burst
+    pub missed_ticks: Option<String>,
+    pub wifi: Option<bool>,
+    pub nic_counters: Option<bool>,
+    pub asn_lookup: Option<bool>,
+    pub columns: Option<String>,
+    pub delimiter: Option<String>,
+    pub sink: Option<Vec<String>>,
+    pub resume: Option<bool>,
+    pub sync: Option<bool>,
+    /// Age recipient to encrypt the result and event files to, as `age:<recipient>`
+    pub encrypt: Option<String>,
+    /// File with the key to sign the result CSVs with
+    pub sign: Option<PathBuf>,
+    pub flush_every: Option<String>,
+    pub write_overflow: Option<String>,
+    pub max_write_failures: Option<u32>,
+    /// Name of the color theme
+    pub theme: Option<String>,
+    /// Overrides of the theme's colors, e.g. `success=cyan,failure=yellow`
+    pub colors: Option<String>,
+    /// Name of this machine in results and alerts, instead of its hostname
+    pub probe_name: Option<String>,
+    /// Monthly availability target (%), for `num report`
+    pub sla_availability: Option<f64>,
+    /// Monthly p95 latency target (ms), for `num report`
+    pub sla_p95: Option<f64>,
+    /// Overrides for particular targets, as `[targets."<address>"]` sections of the config file
+    pub targets: Option<BTreeMap<String, TargetSettings>>,
+}
+
+/// How the schedule is kept and results are recorded, which only takes effect at startup.
+pub struct RecordingSettings {
+    pub missed_tick_behavior: MissedTickBehavior,
+    pub wifi: bool,
+    pub nic_counters: bool,
+    pub asn_lookup: bool,
+    /// Columns of the result CSV, or None for the defaults of the probe type
+    pub columns: Option<Columns>,
+    pub delimiter: u8,
+    /// Where results are recorded, without repeats
+    pub sinks: Vec<SinkKind>,
+    pub resume: bool,
+    pub sync_writes: bool,
+    pub encrypt: Option<Recipient>,
+    pub sign: Option<Key>,
+    pub flush_policy: FlushPolicy,
+    pub write_overflow: OverflowPolicy,
+    pub max_write_failures: u32,
+}
+
+/// Settings a `[targets."<address>"]` section of the config file can override for that target, so
+/// that one file can serve monitors of several targets.
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TargetSettings {
+    pub timeout: Option<u64>,
+    pub delay: Option<f64>,
+    pub num_bytes: Option<u8>,
+    pub down_after: Option<u32>,
+    pub retries: Option<u32>,
+    pub alert: Option<Vec<String>>,
+    pub window_alert: Option<Vec<String>>,
+}
+
+impl Settings {
+    /// Collect the settings that were explicitly passed on the command line.
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        Settings {
+            address: matches.get_one::<String>("ADDRESS").cloned(),
+            probe: matches.get_one::<ProbeKind>("probe").copied(),
+            output: matches.get_one::<PathBuf>("output").cloned(),
+            timeout: matches.get_one::<u64>("timeout").copied(),
+            delay: matches.get_one::<f64>("delay").copied(),
+            interval_jitter: matches.get_one::<String>("interval-jitter").cloned(),
+            align: matches.get_flag("align").then_some(true),
+            fast: matches.get_flag("fast").then_some(true),
+            auto_adjust: matches.get_one::<AutoAdjust>("auto-adjust").copied(),
+            num_bytes: matches.get_one::<u8>("num-bytes").copied(),
+            ttl: matches.get_one::<u32>("ttl").copied(),
+            down_after: matches.get_one::<u32>("down-after").copied(),
+            down_on_no_route: matches.get_flag("down-on-no-route").then_some(true),
+            retries: matches.get_one::<u32>("retries").copied(),
+            cert_warn_days: matches.get_one::<u32>("cert-warn-days").copied(),
+            max_offset: matches.get_one::<u64>("max-offset").copied(),
+            portal_check: matches.get_one::<String>("portal-check").cloned(),
+            alert: matches
+                .get_many::<AlertRule>("alert")
+                .map(|rules| rules.map(AlertRule::to_string).collect()),
+            alert_template: matches
+                .get_many::<AlertTemplate>("alert-template")
+                .map(|templates| templates.map(AlertTemplate::to_string).collect()),
+            alert_rate_limit: matches
+                .get_many::<RateLimit>("alert-rate-limit")
+                .map(|limits| limits.map(RateLimit::to_string).collect()),
+            alert_dedup: matches.get_one::<String>("alert-dedup").cloned(),
+            window_alert: matches
+                .get_many::<WindowRule>("window-alert")
+                .map(|rules| rules.map(WindowRule::to_string).collect()),
+            isp_change_alert: matches
+                .get_one::<Channel>("isp-change-alert")
+                .map(Channel::to_string),
+            internet_alert: matches
+                .get_one::<Channel>("internet-alert")
+                .map(Channel::to_string),
+            missed_ticks: matches.get_one::<String>("missed-ticks").cloned(),
+            wifi: matches.get_flag("wifi").then_some(true),
+            nic_counters: matches.get_flag("nic-counters").then_some(true),
+            asn_lookup: matches.get_flag("asn-lookup").then_some(true),
+            columns: raw_values(matches, "columns").and_then(|mut values| values.pop()),
+            delimiter: raw_values(matches, "delimiter").and_then(|mut values| values.pop()),
+            sink: raw_values(matches, "sink"),
+            resume: matches.get_flag("resume").then_some(true),
+            sync: matches.get_flag("sync").then_some(true),
+            encrypt: raw_values(matches, "encrypt").and_then(|mut values| values.pop()),
+            sign: raw_values(matches, "sign")
+                .and_then(|mut values| values.pop())
+                .map(PathBuf::from),
+            flush_every: raw_values(matches, "flush-every").and_then(|mut values| values.pop()),
+            write_overflow: raw_values(matches, "write-overflow")
+                .and_then(|mut values| values.pop()),
+            max_write_failures: matches.get_one::<u32>("max-write-failures").copied(),
+            theme: matches.get_one::<String>("theme").cloned(),
+            colors: matches.get_one::<String>("colors").cloned(),
+            probe_name: matches.get_one::<String>("probe-name").cloned(),
+            // Only `num report` takes the SLA on the command line
+            sla_availability: None,
+            sla_p95: None,
+            targets: None,
+        }
+    }
+
+    /// Collect the settings given in `NUM_*` environment variables.
+    pub fn from_env() -> Result<Self, String> {
+        Self::from_vars(&|name| std::env::var(name).ok())
+    }
+
+    /// Collect the settings from environment variables looked up with `var`. Lists are separated
+    /// by semicolons.
+    fn from_vars(var: &dyn Fn(&str) -> Option<String>) -> Result<Self, String> {
+        let list = |key: &str| {
+            var(&env_var_name(key)).map(|value| {
+                value
+                    .split(';')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+        };
+        Ok(Settings {
+            address: env_value(var, "address")?,
+            probe: env_value(var, "probe")?,
+            output: env_value(var, "output")?,
+            timeout: env_value(var, "timeout")?,
+            delay: env_value(var, "delay")?,
+            interval_jitter: env_value(var, "interval-jitter")?,
+            align: env_value(var, "align")?,
+            fast: env_value(var, "fast")?,
+            auto_adjust: env_value(var, "auto-adjust")?,
+            num_bytes: env_value(var, "num-bytes")?,
+            ttl: env_value(var, "ttl")?,
+            down_after: env_value(var, "down-after")?,
+            down_on_no_route: env_value(var, "down-on-no-route")?,
+            retries: env_value(var, "retries")?,
+            cert_warn_days: env_value(var, "cert-warn-days")?,
+            max_offset: env_value(var, "max-offset")?,
+            portal_check: env_value(var, "portal-check")?,
+            alert: list("alert"),
+            alert_template: list("alert-template"),
+            alert_rate_limit: list("alert-rate-limit"),
+            alert_dedup: env_value(var, "alert-dedup")?,
+            window_alert: list("window-alert"),
+            isp_change_alert: env_value(var, "isp-change-alert")?,
+            internet_alert: env_value(var, "internet-alert")?,
+            missed_ticks: env_value(var, "missed-ticks")?,
+            wifi: env_value(var, "wifi")?,
+            nic_counters: env_value(var, "nic-counters")?,
+            asn_lookup: env_value(var, "asn-lookup")?,
+            columns: env_value(var, "columns")?,
+            delimiter: env_value(var, "delimiter")?,
+            sink: list("sink"),
+            resume: env_value(var, "resume")?,
+            sync: env_value(var, "sync")?,
+            encrypt: env_value(var, "encrypt")?,
+            sign: env_value(var, "sign")?,
+            flush_every: env_value(var, "flush-every")?,
+            write_overflow: env_value(var, "write-overflow")?,
+            max_write_failures: env_value(var, "max-write-failures")?,
+            theme: env_value(var, "theme")?,
+            colors: env_value(var, "colors")?,
+            probe_name: env_value(var, "probe-name")?,
+            sla_availability: env_value(var, "sla-availability")?,
+            sla_p95: env_value(var, "sla-p95")?,
+            targets: None,
+        })
+    }
+
+    /// The value used for every setting that has a default.
+    pub fn defaults() -> Self {
+        Settings {
+            probe: Some(ProbeKind::default()),
+            timeout: Some(1000),
+            delay: Some(120.0),
+            interval_jitter: Some("0%".to_string()),
+            align: Some(false),
+            fast: Some(false),
+            num_bytes: Some(4),
+            ttl: Some(128),
+            down_after: Some(1),
+            down_on_no_route: Some(false),
+            retries: Some(0),
+            cert_warn_days: Some(14),
+            max_offset: Some(1000),
+            alert_dedup: Some("15m".to_string()),
+            missed_ticks: Some("skip".to_string()),
+            wifi: Some(false),
+            nic_counters: Some(false),
+            asn_lookup: Some(false),
+            delimiter: Some(",".to_string()),
+            sink: Some(vec!["csv".to_string()]),
+            resume: Some(false),
+            sync: Some(false),
+            write_overflow: Some("block".to_string()),
+            max_write_failures: Some(20),
+            theme: Some("default".to_string()),
+            ..Settings::default()
+        }
+    }
+
+    /// Every setting by its config file key, with its value formatted for display if set.
+    pub fn entries(&self) -> Vec<(&'static str, Option<String>)> {
+        let list = |items: &Option<Vec<String>>| items.as_ref().map(|items| items.join("; "));
+        vec![
+            ("address", self.address.clone()),
+            ("probe", self.probe.map(|kind| kind.name().to_string())),
+            (
+                "output",
+                self.output.as_ref().map(|path| path.display().to_string()),
+            ),
+            ("timeout", self.timeout.map(|timeout| timeout.to_string())),
+            ("delay", self.delay.map(|delay| delay.to_string())),
+            ("interval-jitter", self.interval_jitter.clone()),
+            ("align", self.align.map(|align| align.to_string())),
+            ("fast", self.fast.map(|fast| fast.to_string())),
+            (
+                "auto-adjust",
+                self.auto_adjust.map(|setting| setting.name().to_string()),
+            ),
+            ("num-bytes", self.num_bytes.map(|bytes| bytes.to_string())),
+            ("ttl", self.ttl.map(|ttl| ttl.to_string())),
+            ("down-after", self.down_after.map(|count| count.to_string())),
+            (
+                "down-on-no-route",
+                self.down_on_no_route.map(|enabled| enabled.to_string()),
+            ),
+            ("retries", self.retries.map(|count| count.to_string())),
+            (
+                "cert-warn-days",
+                self.cert_warn_days.map(|days| days.to_string()),
+            ),
+            (
+                "max-offset",
+                self.max_offset.map(|offset| offset.to_string()),
+            ),
+            ("portal-check", self.portal_check.clone()),
+            ("alert", list(&self.alert)),
+            ("alert-template", list(&self.alert_template)),
+            ("alert-rate-limit", list(&self.alert_rate_limit)),
+            ("alert-dedup", self.alert_dedup.clone()),
+            ("window-alert", list(&self.window_alert)),
+            ("isp-change-alert", self.isp_change_alert.clone()),
+            ("internet-alert", self.internet_alert.clone()),
+            ("missed-ticks", self.missed_ticks.clone()),
+            ("wifi", self.wifi.map(|enabled| enabled.to_string())),
+            (
+                "nic-counters",
+                self.nic_counters.map(|enabled| enabled.to_string()),
+            ),
+            (
+                "asn-lookup",
+                self.asn_lookup.map(|enabled| enabled.to_string()),
+            ),
+            ("columns", self.columns.clone()),
+            ("delimiter", self.delimiter.clone()),
+            ("sink", list(&self.sink)),
+            ("resume", self.resume.map(|enabled| enabled.to_string())),
+            ("sync", self.sync.map(|enabled| enabled.to_string())),
+            ("encrypt", self.encrypt.clone()),
+            (
+                "sign",
+                self.sign.as_ref().map(|path| path.display().to_string()),
+            ),
+            ("flush-every", self.flush_every.clone()),
+            ("write-overflow", self.write_overflow.clone()),
+            (
+                "max-write-failures",
+                self.max_write_failures.map(|count| count.to_string()),
+            ),
+            ("theme", self.theme.clone()),
+            ("colors", self.colors.clone()),
+            ("probe-name", self.probe_name.clone()),
+            (
+                "sla-availability",
+                self.sla_availability.map(|target| target.to_string()),
+            ),
+            ("sla-p95", self.sla_p95.map(|target| target.to_string())),
+        ]
+    }
+
+    /// Read settings from a TOML config file.
+    pub async fn load(path: &Path) -> Result<Self, String> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| format!("Could not read config file {}: {e}", path.display()))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Invalid config file {}: {e}", path.display()))
+    }
+
+    /// Fill in anything unset in `self` from `fallback`.
+    pub fn or(self, fallback: Settings) -> Self {
+        Settings {
+            address: self.address.or(fallback.address),
+            probe: self.probe.or(fallback.probe),
+            output: self.output.or(fallback.output),
+            timeout: self.timeout.or(fallback.timeout),
+            delay: self.delay.or(fallback.delay),
+            interval_jitter: self.interval_jitter.or(fallback.interval_jitter),
+            align: self.align.or(fallback.align),
+            fast: self.fast.or(fallback.fast),
+            auto_adjust: self.auto_adjust.or(fallback.auto_adjust),
+            num_bytes: self.num_bytes.or(fallback.num_bytes),
+            ttl: self.ttl.or(fallback.ttl),
+            down_after: self.down_after.or(fallback.down_after),
+            down_on_no_route: self.down_on_no_route.or(fallback.down_on_no_route),
+            retries: self.retries.or(fallback.retries),
+            cert_warn_days: self.cert_warn_days.or(fallback.cert_warn_days),
+            max_offset: self.max_offset.or(fallback.max_offset),
+            portal_check: self.portal_check.or(fallback.portal_check),
+            alert: self.alert.or(fallback.alert),
+            alert_template: self.alert_template.or(fallback.alert_template),
+            alert_rate_limit: self.alert_rate_limit.or(fallback.alert_rate_limit),
+            alert_dedup: self.alert_dedup.or(fallback.alert_dedup),
+            window_alert: self.window_alert.or(fallback.window_alert),
+            isp_change_alert: self.isp_change_alert.or(fallback.isp_change_alert),
+            internet_alert: self.internet_alert.or(fallback.internet_alert),
+            missed_ticks: self.missed_ticks.or(fallback.missed_ticks),
+            wifi: self.wifi.or(fallback.wifi),
+            nic_counters: self.nic_counters.or(fallback.nic_counters),
+            asn_lookup: self.asn_lookup.or(fallback.asn_lookup),
+            columns: self.columns.or(fallback.columns),
+            delimiter: self.delimiter.or(fallback.delimiter),
+            sink: self.sink.or(fallback.sink),
+            resume: self.resume.or(fallback.resume),
+            sync: self.sync.or(fallback.sync),
+            encrypt: self.encrypt.or(fallback.encrypt),
+            sign: self.sign.or(fallback.sign),
+            flush_every: self.flush_every.or(fallback.flush_every),
+            write_overflow: self.write_overflow.or(fallback.write_overflow),
+            max_write_failures: self.max_write_failures.or(fallback.max_write_failures),
+            theme: self.theme.or(fallback.theme),
+            colors: self.colors.or(fallback.colors),
+            probe_name: self.probe_name.or(fallback.probe_name),
+            sla_availability: self.sla_availability.or(fallback.sla_availability),
+            sla_p95: self.sla_p95.or(fallback.sla_p95),
+            targets: self.targets.or(fallback.targets),
+        }
+    }
+
+    /// Return the settings of the `[targets."<address>"]` section for `address`, or none if there
+    /// is no such section.
+    pub fn target_section(&self, address: Option<&str>) -> Settings {
+        let Some(section) = address.and_then(|address| self.targets.as_ref()?.get(address)) else {
+            return Settings::default();
+        };
+        let section = section.clone();
+        Settings {
+            timeout: section.timeout,
+            delay: section.delay,
+            num_bytes: section.num_bytes,
+            down_after: section.down_after,
+            retries: section.retries,
+            alert: section.alert,
+            window_alert: section.window_alert,
+            ..Settings::default()
+        }
+    }
+
+    /// With `auto-adjust` set, make a timeout that doesn't fit within the delay fit by changing
+    /// the chosen setting. Returns the adjusted settings and a warning describing the change, if
+    /// one was made.
+    pub fn adjusted(&self) -> (Settings, Option<String>) {
+        let mut adjusted = self.clone();
+        let settings = self.clone().or(Settings::defaults());
+        let (timeout, delay) = (settings.timeout.unwrap(), settings.delay.unwrap());
+        if (timeout as f64) < delay * 1000.0 || !delay.is_finite() {
+            return (adjusted, None);
+        }
+        let warning = match settings.auto_adjust {
+            None => return (adjusted, None),
+            Some(AutoAdjust::Timeout) => {
+                let safe_timeout = ((delay * 1000.0 / 2.0) as u64).max(1);
+                adjusted.timeout = Some(safe_timeout);
+                format!("Timeout of {timeout}ms doesn't fit the {delay}s delay, lowered to {safe_timeout}ms")
+            }
+            Some(AutoAdjust::Delay) => {
+                let safe_delay = timeout as f64 * 2.0 / 1000.0;
+                adjusted.delay = Some(safe_delay);
+                format!("Delay of {delay}s doesn't leave room for the {timeout}ms timeout, raised to {safe_delay}s")
+            }
+        };
+        (adjusted, Some(warning))
+    }
+
+    /// Apply defaults and check that the resulting probe settings are usable.
+    pub fn probe_settings(&self) -> Result<ProbeSettings, String> {
+        let settings = self.clone().or(Settings::defaults());
+        let addr = settings.address.clone().ok_or(
+            "No address given (pass ADDRESS, set NUM_ADDRESS or set address in the config file)",
+        )?;
+        // A target such as tls://example.com:443 names its probe type
+        let (kind, addr) = match parse_target(&addr)? {
+            (Some(kind), host) => {
+                if self.probe.is_some_and(|probe| probe != kind) {
+                    return Err(format!(
+                        "Target {addr} is probed with {}, not {}",
+                        kind.name(),
+                        self.probe.unwrap().name()
+                    ));
+                }
+                (kind, host)
+            }
+            (None, host) => (settings.probe.unwrap(), host),
+        };
+        // Every setting below has a default
+        let timeout = settings.timeout.unwrap();
+        let delay_secs = settings.delay.unwrap();
+        let fast_mode = settings.fast.unwrap();
+        let num_bytes = settings.num_bytes.unwrap();
+        let ttl = settings.ttl.unwrap();
+        let down_after = settings.down_after.unwrap();
+        let min_delay_secs = if fast_mode {
+            MIN_FAST_DELAY_SECS
+        } else {
+            MIN_DELAY_SECS
+        };
+        if !(delay_secs >= min_delay_secs && delay_secs.is_finite()) {
+            return Err(format!(
+                "Delay must be at least {min_delay_secs}s{}",
+                if fast_mode {
+                    ""
+                } else {
+                    " (use --fast for shorter delays)"
+                }
+            ));
+        }
+        let delay = Duration::try_from_secs_f64(delay_secs)
+            .map_err(|_| format!("Delay of {delay_secs}s is too long"))?;
+        // Need to check as otherwise timer will de-sync
+        if Duration::from_millis(timeout) >= delay {
+            return Err(
+                "Delay must be greater than the timeout (or pass --auto-adjust to fix it up)"
+                    .to_string(),
+            );
+        }
+        let jitter = settings.interval_jitter.unwrap();
+        let interval_jitter = parse_percent(&jitter)
+            .filter(|jitter| (0.0..=0.5).contains(jitter))
+            .ok_or(format!(
+                "Invalid interval jitter \"{jitter}\" (expected a percentage up to 50%, e.g. 10%)"
+            ))?;
+        // A postponed probe is followed by one that isn't, so the shortest gap is shortened by
+        // the whole jitter
+        if Duration::from_millis(timeout) >= delay.mul_f64(1.0 - interval_jitter) {
+            return Err(format!(
+                "Delay less the {jitter} interval jitter must be greater than the timeout"
+            ));
+        }
+        // The command line enforces these ranges, but the config file doesn't
+        if !(1..=24).contains(&num_bytes) {
+            return Err("Number of bytes must be between 1 and 24".to_string());
+        }
+        if ttl == 0 || down_after == 0 {
+            return Err("TTL and down-after must be at least 1".to_string());
+        }
+        let portal_check = settings
+            .portal_check
+            .as_deref()
+            .map(PortalCheck::parse)
+            .transpose()?;
+        let alerts = settings
+            .alert
+            .iter()
+            .flatten()
+            .map(|rule| rule.parse())
+            .collect::<Result<_, _>>()?;
+        let alert_templates = settings
+            .alert_template
+            .iter()
+            .flatten()
+            .map(|template| template.parse())
+            .collect::<Result<_, _>>()?;
+        let alert_rate_limits = settings
+            .alert_rate_limit
+            .iter()
+            .flatten()
+            .map(|limit| limit.parse())
+            .collect::<Result<_, _>>()?;
+        let dedup = settings.alert_dedup.unwrap();
+        let alert_dedup = parse_duration(&dedup).ok_or(format!(
+            "Invalid alert dedup window \"{dedup}\" (expected e.g. 15m)"
+        ))?;
+        let window_alerts = settings
+            .window_alert
+            .iter()
+            .flatten()
+            .map(|rule| rule.parse())
+            .collect::<Result<_, _>>()?;
+        let isp_change_alert = settings
+            .isp_change_alert
+            .map(|channel| channel.parse())
+            .transpose()?;
+        let internet_alert = settings
+            .internet_alert
+            .map(|channel| channel.parse())
+            .transpose()?;
+        Ok(ProbeSettings {
+            addr,
+            kind,
+            timeout,
+            delay,
+            interval_jitter,
+            align: settings.align.unwrap(),
+            num_bytes,
+            ttl,
+            down_after,
+            down_on_no_route: settings.down_on_no_route.unwrap(),
+            retries: settings.retries.unwrap(),
+            cert_warn_days: settings.cert_warn_days.unwrap(),
+            max_offset: settings.max_offset.unwrap(),
+            portal_check,
+            alerts,
+            alert_templates,
+            alert_rate_limits,
+            alert_dedup,
+            window_alerts,
+            isp_change_alert,
+            internet_alert,
+        })
+    }
+
+    /// Apply defaults and look up the color theme, with its colors overridden.
+    pub fn theme(&self) -> Result<Theme, String> {
+        let settings = self.clone().or(Settings::defaults());
+        let name = settings.theme.unwrap();
+        let theme = Theme::preset(&name).ok_or(format!(
+            "Unknown theme \"{name}\" (expected {})",
+            Theme::PRESETS.join(" or ")
+        ))?;
+        theme.with_overrides(settings.colors.as_deref().unwrap_or_default())
+    }
+
+    /// Apply defaults and parse the settings of how results are recorded.
+    pub fn recording_settings(&self) -> Result<RecordingSettings, String> {
+        let settings = self.clone().or(Settings::defaults());
+        let invalid = |key: &'static str| move |e: String| format!("Invalid {key}: {e}");
+        let missed_ticks = settings.missed_ticks.unwrap();
+        let missed_tick_behavior = match missed_ticks.as_str() {
+            "skip" => MissedTickBehavior::Skip,
+            "delay" => MissedTickBehavior::Delay,
+            "burst" => MissedTickBehavior::Burst,
+            _ => {
+                return Err(format!(
+                    "Invalid missed-ticks: unknown behavior \"{missed_ticks}\" (expected skip, delay or burst)"
+                ))
+            }
+        };
+        let mut sinks = Vec::new();
+        for sink in settings.sink.unwrap() {
+            let sink = sink.parse().map_err(invalid("sink"))?;
+            if !sinks.contains(&sink) {
+                sinks.push(sink);
+            }
+        }
+        Ok(RecordingSettings {
+            missed_tick_behavior,
+            wifi: settings.wifi.unwrap(),
+            nic_counters: settings.nic_counters.unwrap(),
+            asn_lookup: settings.asn_lookup.unwrap(),
+            columns: settings
+                .columns
+                .map(|columns| columns.parse())
+                .transpose()
+                .map_err(invalid("columns"))?,
+            delimiter: parse_delimiter(&settings.delimiter.unwrap())
+                .map_err(invalid("delimiter"))?,
+            sinks,
+            resume: settings.resume.unwrap(),
+            sync_writes: settings.sync.unwrap(),
+            encrypt: settings
+                .encrypt
+                .map(|recipient| recipient.parse())
+                .transpose()
+                .map_err(invalid("encrypt"))?,
+            sign: settings
+                .sign
+                .map(|path| Key::load(&path.to_string_lossy()))
+                .transpose()
+                .map_err(invalid("sign"))?,
+            flush_policy: settings
+                .flush_every
+                .map(|policy| policy.parse())
+                .transpose()
+                .map_err(invalid("flush-every"))?
+                .unwrap_or(FlushPolicy::EveryRow),
+            write_overflow: settings
+                .write_overflow
+                .unwrap()
+                .parse()
+                .map_err(invalid("write-overflow"))?,
+            max_write_failures: match settings.max_write_failures.unwrap() {
+                0 => return Err("Max-write-failures must be at least 1".to_string()),
+                count => count,
+            },
+        })
+    }
+}
+
+/// Values of a command line option as given, before parsing.
+fn raw_values(matches: &ArgMatches, id: &str) -> Option<Vec<String>> {
+    let values = matches.get_raw(id)?;
+    Some(
+        values
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect(),
+    )
+}
+
+/// Parse a percentage such as `10%` (the `%` is optional) into a fraction.
+pub fn parse_percent(percent: &str) -> Option<f64> {
+    let percent = percent.trim();
+    let value: f64 = percent.strip_suffix('%').unwrap_or(percent).parse().ok()?;
+    value.is_finite().then_some(value / 100.0)
+}
+
+/// Where the value of a setting comes from.
+pub enum Source<'a> {
+    /// Changed by `auto-adjust`
+    AutoAdjust,
+    CommandLine,
+    Environment,
+    ConfigFile(&'a Path),
+    /// The section of the config file for the target
+    TargetSection(&'a Path, &'a str),
+    Default,
+}
+
+impl Source<'_> {
+    fn describe(&self, key: &str) -> String {
+        match self {
+            Source::AutoAdjust => "auto-adjust".to_string(),
+            Source::CommandLine => "command line".to_string(),
+            Source::Environment => format!("environment ({})", env_var_name(key)),
+            Source::ConfigFile(path) => format!("config file ({})", path.display()),
+            Source::TargetSection(path, address) => {
+                format!("config file ({}, targets.\"{address}\")", path.display())
+            }
+            Source::Default => "default".to_string(),
+        }
+    }
+}
+
+/// Describe the effective value of every setting and its source, one per line, given the sources
+/// in order of precedence.
+pub fn describe_sources(sources: &[(Source, &Settings)]) -> String {
+    let entries: Vec<_> = sources
+        .iter()
+        .map(|(source, settings)| (source, settings.entries()))
+        .collect();
+    let keys: Vec<&str> = entries[0].1.iter().map(|(key, _)| *key).collect();
+    let width = keys.iter().map(|key| key.len()).max().unwrap_or_default();
+    let mut lines = Vec::new();
+    for (index, key) in keys.into_iter().enumerate() {
+        let line = entries
+            .iter()
+            .find_map(|(source, entries)| {
+                let value = entries[index].1.as_ref()?;
+                Some(format!(
+                    "{key:<width$} = {value} [{}]",
+                    source.describe(key)
+                ))
+            })
+            .unwrap_or_else(|| format!("{key:<width$}   (unset)"));
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+/// Name of the environment variable for a setting, e.g. `NUM_DOWN_AFTER` for `down-after`.
+pub fn env_var_name(key: &str) -> String {
+    format!("NUM_{}", key.replace('-', "_").to_uppercase())
+}
+
+/// Parse the environment variable for the setting `key`, if set.
+fn env_value<T: FromStr>(
+    var: &dyn Fn(&str) -> Option<String>,
+    key: &str,
+) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    let name = env_var_name(key);
+    var(&name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| format!("Invalid {name} \"{value}\": {e}"))
+        })
+        .transpose()
+}
+
+/// Tracks a config file so it can be re-read when it changes, with command line and environment
+/// settings keeping precedence over the file. Changes are reported by the OS (inotify, FSEvents,
+/// kqueue, etc.), or, if it can't watch the file, noticed by polling its modification time.
+pub struct ConfigWatcher {
+    path: PathBuf,
+    overrides: Settings,
+    modified: Option<SystemTime>,
+    /// The file as last loaded successfully
+    file: Settings,
+    /// Kept to go on receiving `changes`
+    _watcher: Option<RecommendedWatcher>,
+    changes: Option<mpsc::UnboundedReceiver<()>>,
+    /// Why the file couldn't be watched, if it couldn't
+    watch_error: Option<String>,
+}
+
+impl ConfigWatcher {
+    pub async fn new(path: PathBuf, overrides: Settings, file: Settings) -> Self {
+        let modified = Self::modified_time(&path).await;
+        let (watcher, changes, watch_error) = match Self::watch(&path) {
+            Ok((watcher, changes)) => (Some(watcher), Some(changes), None),
+            Err(e) => (
+                None,
+                None,
+                Some(format!("Could not watch {}: {e}", path.display())),
+            ),
+        };
+        ConfigWatcher {
+            path,
+            overrides,
+            modified,
+            file,
+            _watcher: watcher,
+            changes,
+            watch_error,
+        }
+    }
+
+    /// Watch the directory of the file, as configuration management and editors often replace the
+    /// file rather than write to it.
+    fn watch(path: &Path) -> notify::Result<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+        let (changes_tx, changes) = mpsc::unbounded_channel();
+        let name = path.file_name().map(ToOwned::to_owned);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let Ok(event) = event else {
+                    return;
+                };
+                // Reading the file is an event too, which mustn't trigger another reload
+                let written = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+                if written
+                    && event
+                        .paths
+                        .iter()
+                        .any(|path| path.file_name() == name.as_deref())
+                {
+                    let _ = changes_tx.send(());
+                }
+            })?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        Ok((watcher, changes))
+    }
+
+    async fn modified_time(path: &Path) -> Option<SystemTime> {
+        tokio::fs::metadata(path).await.ok()?.modified().ok()
+    }
+
+    /// Return why the file can't be watched, in which case `changed` polls it instead.
+    pub fn watch_error(&self) -> Option<&str> {
+        self.watch_error.as_deref()
+    }
+
+    /// Wait until the OS reports the file changed. Never returns if it can't be watched.
+    pub async fn next_change(&mut self) {
+        let Some(changes) = &mut self.changes else {
+            return std::future::pending().await;
+        };
+        if changes.recv().await.is_none() {
+            return std::future::pending().await;
+        }
+        tokio::time::sleep(WATCH_DEBOUNCE).await;
+        while changes.try_recv().is_ok() {}
+    }
+
+    /// Check whether the file's modification time changed since it was last seen, if it can't be
+    /// watched. Otherwise `next_change` reports changes as they happen.
+    pub async fn changed(&mut self) -> bool {
+        if self.changes.is_some() {
+            return false;
+        }
+        let modified = Self::modified_time(&self.path).await;
+        let changed = modified.is_some() && modified != self.modified;
+        self.modified = modified;
+        changed
+    }
+
+    /// Re-read the file and merge it with the command line and environment settings, along with
+    /// a warning if the timeout or delay had to be adjusted.
+    pub async fn load(&mut self) -> Result<(ProbeSettings, Option<String>), String> {
+        self.modified = Self::modified_time(&self.path).await;
+        let file = Settings::load(&self.path).await?;
+        let address = self.overrides.address.clone().or(file.address.clone());
+        let section = file.target_section(address.as_deref());
+        let (settings, warning) = self
+            .overrides
+            .clone()
+            .or(section)
+            .or(file.clone())
+            .adjusted();
+        let probe = settings.probe_settings()?;
+        self.file = file;
+        Ok((probe, warning))
+    }
+
+    /// Return a function working out the settings of targets added next to the main one, as of
+    /// the last load.
+    pub fn added_target_settings_builder(&self) -> SettingsBuilder {
+        let (overrides, file) = (self.overrides.clone(), self.file.clone());
+        Box::new(move |target| added_target_settings(&overrides, &file, target))
+    }
+}
+
+/// Work out the settings of a target added next to the main one: those of the main target, apart
+/// from the address and the target's own section of the config file.
+pub fn added_target_settings(
+    overrides: &Settings,
+    file: &Settings,
+    target: &str,
+) -> Result<ProbeSettings, String> {
+    let overrides = Settings {
+        address: Some(target.to_string()),
+        ..overrides.clone()
+    };
+    let section = file.target_section(Some(target));
+    let (settings, _) = overrides.or(section).or(file.clone()).adjusted();
+    settings.probe_settings()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::style::Color;
+
+    fn env(vars: &'static [(&'static str, &'static str)]) -> Result<Settings, String> {
+        Settings::from_vars(&|name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        })
+    }
+
+    #[test]
+    fn command_line_beats_environment_beats_file_beats_defaults() {
+        let cli = Settings {
+            timeout: Some(500),
+            ..Settings::default()
+        };
+        let env = env(&[
+            ("NUM_TIMEOUT", "700"),
+            ("NUM_TTL", "32"),
+            ("NUM_ALERT", "desktop:1; email:30m:ops@example.com"),
+        ])
+        .unwrap();
+        let file: Settings =
+            toml::from_str("address = \"example.com\"\ntimeout = 900\nttl = 16\ndown-after = 3")
+                .unwrap();
+        let sources = describe_sources(&[
+            (Source::CommandLine, &cli),
+            (Source::Environment, &env),
+            (Source::ConfigFile(Path::new("num.toml")), &file),
+            (Source::Default, &Settings::defaults()),
+        ]);
+        let lines: Vec<&str> = sources.lines().collect();
+        assert!(lines.contains(&"timeout            = 500 [command line]"));
+        assert!(lines.contains(&"ttl                = 32 [environment (NUM_TTL)]"));
+        assert!(lines.contains(&"down-after         = 3 [config file (num.toml)]"));
+        assert!(lines.contains(&"num-bytes          = 4 [default]"));
+        assert!(lines.contains(&"output               (unset)"));
+
+        let probe = cli.or(env).or(file).probe_settings().unwrap();
+        assert_eq!(probe.addr, "example.com");
+        assert_eq!((probe.timeout, probe.ttl, probe.down_after), (500, 32, 3));
+        assert_eq!(probe.alerts.len(), 2);
+    }
+
+    #[test]
+    fn recording_settings_follow_the_same_precedence() {
+        let cli = Settings {
+            sink: Some(vec!["json".to_string()]),
+            ..Settings::default()
+        };
+        let env = env(&[
+            ("NUM_SINK", "cbor"),
+            ("NUM_DELIMITER", "tab"),
+            ("NUM_SYNC", "true"),
+        ])
+        .unwrap();
+        let file: Settings = toml::from_str(
+            "delimiter = \";\"\nmissed-ticks = \"burst\"\nwrite-overflow = \"spill\"",
+        )
+        .unwrap();
+        let recording = cli.or(env).or(file).recording_settings().unwrap();
+        assert!(recording.sinks == [SinkKind::Json]);
+        assert_eq!(recording.delimiter, b'\t');
+        assert!(recording.sync_writes && !recording.resume);
+        assert_eq!(recording.missed_tick_behavior, MissedTickBehavior::Burst);
+        assert!(matches!(recording.write_overflow, OverflowPolicy::Spill));
+        assert!(matches!(recording.flush_policy, FlushPolicy::EveryRow));
+
+        let invalid = Settings {
+            missed_ticks: Some("never".to_string()),
+            ..Settings::default()
+        };
+        assert!(invalid.recording_settings().is_err());
+    }
+
+    #[test]
+    fn themes_come_from_any_source_with_the_command_line_winning() {
+        let file: Settings =
+            toml::from_str("theme = \"colorblind\"\ncolors = \"info=white\"").unwrap();
+        let theme = env(&[("NUM_COLORS", "success=cyan")])
+            .unwrap()
+            .or(file.clone())
+            .theme()
+            .unwrap();
+        assert_eq!(
+            (theme.success, theme.failure),
+            (Color::Cyan, Color::AnsiValue(208))
+        );
+        let cli = Settings {
+            theme: Some("default".to_string()),
+            ..Settings::default()
+        };
+        assert_eq!(cli.or(file).theme().unwrap().info, Color::White);
+        let unknown = Settings {
+            theme: Some("sepia".to_string()),
+            ..Settings::default()
+        };
+        assert!(unknown.theme().is_err());
+    }
+
+    #[test]
+    fn target_uris_choose_the_probe_type() {
+        let target = |address: &str, probe: Option<ProbeKind>| {
+            Settings {
+                address: Some(address.to_string()),
+                probe,
+                ..Settings::default()
+            }
+            .probe_settings()
+            .map(|probe| (probe.kind, probe.addr))
+        };
+        assert_eq!(
+            target("tls://example.com:8443/", None),
+            Ok((ProbeKind::Tls, "example.com:8443".to_string()))
+        );
+        assert_eq!(
+            target("tcp://[2001:db8::1]:22", Some(ProbeKind::Tcp)),
+            Ok((ProbeKind::Tcp, "[2001:db8::1]:22".to_string()))
+        );
+        assert!(target("tls://example.com", Some(ProbeKind::Ntp)).is_err());
+        assert!(target("gopher://example.com", None).is_err());
+    }
+
+    #[test]
+    fn urls_and_ipv6_targets_give_their_host() {
+        let target = |address: &str| {
+            Settings {
+                address: Some(address.to_string()),
+                ..Settings::default()
+            }
+            .probe_settings()
+            .map(|probe| probe.addr)
+        };
+        let host = |host: &str| Ok(host.to_string());
+        assert_eq!(
+            target("https://example.com:8443/status?full"),
+            host("example.com:8443")
+        );
+        assert_eq!(target("http://user@example.com"), host("example.com"));
+        assert_eq!(target("2001:db8::1"), host("2001:db8::1"));
+        assert_eq!(target("[2001:db8::1]"), host("2001:db8::1"));
+        assert_eq!(target("[2001:db8::1]:443"), host("[2001:db8::1]:443"));
+        assert_eq!(target("example.com:443"), host("example.com:443"));
+        for invalid in [
+            "2001:db8::g",
+            "[2001:db8::1",
+            "[2001:db8::1]443",
+            "example.com:https",
+            "https://",
+        ] {
+            assert!(target(invalid).is_err(), "{invalid} was accepted");
+        }
+    }
+
+    #[test]
+    fn target_sections_override_the_rest_of_the_file() {
+        let file: Settings = toml::from_str(
+            r#"
+            delay = 60
+            down-after = 2
+            alert = ["email:10m:ops@example.com"]
+
+            [targets."192.168.1.1"]
+            delay = 5
+            down-after = 3
+            "#,
+        )
+        .unwrap();
+        let gateway = Settings {
+            address: Some("192.168.1.1".to_string()),
+            down_after: Some(4),
+            ..Settings::default()
+        };
+        let section = file.target_section(gateway.address.as_deref());
+        let probe = gateway
+            .or(section)
+            .or(file.clone())
+            .probe_settings()
+            .unwrap();
+        assert_eq!(probe.delay, Duration::from_secs(5));
+        assert_eq!((probe.down_after, probe.alerts.len()), (4, 1));
+
+        let remote = Settings {
+            address: Some("example.org".to_string()),
+            ..Settings::default()
+        };
+        let section = file.target_section(remote.address.as_deref());
+        let probe = remote.or(section).or(file).probe_settings().unwrap();
+        assert_eq!(
+            (probe.delay, probe.down_after),
+            (Duration::from_secs(60), 2)
+        );
+    }
+
+    #[test]
+    fn delays_too_long_for_a_duration_are_rejected() {
+        let settings = Settings {
+            address: Some("example.com".to_string()),
+            delay: Some(1e20),
+            ..Settings::default()
+        };
+        assert_eq!(
+            settings.probe_settings().err().unwrap(),
+            "Delay of 100000000000000000000s is too long"
+        );
+    }
+
+    #[test]
+    fn auto_adjust_makes_the_timeout_fit_the_delay() {
+        let settings = Settings {
+            address: Some("example.com".to_string()),
+            delay: Some(0.5),
+            fast: Some(true),
+            ..Settings::default()
+        };
+        assert!(settings.probe_settings().is_err());
+        assert!(settings.adjusted().1.is_none());
+
+        let lower_timeout = Settings {
+            auto_adjust: Some(AutoAdjust::Timeout),
+            ..settings.clone()
+        };
+        let (adjusted, warning) = lower_timeout.adjusted();
+        assert_eq!(
+            warning.unwrap(),
+            "Timeout of 1000ms doesn't fit the 0.5s delay, lowered to 250ms"
+        );
+        assert_eq!(adjusted.probe_settings().unwrap().timeout, 250);
+
+        let raise_delay = Settings {
+            auto_adjust: Some(AutoAdjust::Delay),
+            ..settings
+        };
+        let probe = raise_delay.adjusted().0.probe_settings().unwrap();
+        assert_eq!((probe.timeout, probe.delay), (1000, Duration::from_secs(2)));
+    }
+
+    #[tokio::test]
+    async fn reloads_reach_added_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("num.toml");
+        std::fs::write(&path, "address = \"192.0.2.1\"\ndown-after = 2\n").unwrap();
+        let file = Settings::load(&path).await.unwrap();
+        let mut watcher = ConfigWatcher::new(path.clone(), Settings::default(), file).await;
+        let settings_for = watcher.added_target_settings_builder();
+        assert_eq!(settings_for("192.0.2.2").unwrap().down_after, 2);
+
+        let reloaded =
+            "address = \"192.0.2.1\"\ndown-after = 2\n\n[targets.\"192.0.2.2\"]\ndown-after = 5\n";
+        std::fs::write(&path, reloaded).unwrap();
+        let (probe, _) = watcher.load().await.unwrap();
+        assert_eq!((probe.addr.as_str(), probe.down_after), ("192.0.2.1", 2));
+        let settings_for = watcher.added_target_settings_builder();
+        let added = settings_for("192.0.2.2").unwrap();
+        assert_eq!((added.addr.as_str(), added.down_after), ("192.0.2.2", 5));
+    }
+
+    #[tokio::test]
+    async fn changes_to_the_config_file_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("num.toml");
+        std::fs::write(&path, "address = \"192.0.2.1\"\n").unwrap();
+        let mut watcher =
+            ConfigWatcher::new(path.clone(), Settings::default(), Settings::default()).await;
+        assert_eq!(watcher.watch_error(), None);
+        // Replaced the way configuration management does it, and read back
+        std::fs::write(dir.path().join("num.toml.new"), "address = \"192.0.2.2\"\n").unwrap();
+        std::fs::rename(dir.path().join("num.toml.new"), &path).unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(5), watcher.next_change()).await;
+        assert!(changed.is_ok());
+        watcher.load().await.unwrap();
+        let changed = tokio::time::timeout(Duration::from_secs(1), watcher.next_change()).await;
+        assert!(changed.is_err(), "reading the file isn't a change");
+    }
+
+    #[test]
+    fn invalid_environment_values_are_rejected() {
+        assert_eq!(
+            env(&[("NUM_TIMEOUT", "soon")]).err().unwrap(),
+            "Invalid NUM_TIMEOUT \"soon\": invalid digit found in string"
+        );
+    }
+}
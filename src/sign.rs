@@ -0,0 +1,190 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use ring::hmac;
+use std::fmt::Write;
+
+/// Start of the comment lines holding signatures
+pub const PREFIX: &str = "# signature: ";
+
+// Shortest key accepted, as a shorter one could be guessed
+const MIN_KEY_LEN: usize = 16;
+
+/// Secret key result files are signed with, read from the file given to `--sign`.
+#[derive(Clone)]
+pub struct Key(hmac::Key);
+
+impl Key {
+    /// Read a key from a file, ignoring surrounding whitespace.
+    pub fn load(path: &str) -> Result<Key, String> {
+        let contents = std::fs::read(path).map_err(|e| format!("could not read {path}: {e}"))?;
+        let key = contents.trim_ascii();
+        if key.len() < MIN_KEY_LEN {
+            return Err(format!(
+                "the key in {path} is too short (expected at least {MIN_KEY_LEN} bytes)"
+            ));
+        }
+        Ok(Key(hmac::Key::new(hmac::HMAC_SHA256, key)))
+    }
+}
+
+/// A chain of signatures, each an HMAC-SHA256 of the one before and the lines written since. As
+/// every signature depends on all of the file before it, changing, removing or reordering lines
+/// anywhere but after the last signature makes the rest of the chain fail to verify.
+#[derive(Clone)]
+pub struct Chain {
+    key: Key,
+    previous: Vec<u8>,
+}
+
+impl Chain {
+    pub fn new(key: &Key) -> Self {
+        Chain {
+            key: key.clone(),
+            previous: Vec::new(),
+        }
+    }
+
+    /// Return the signature line (with its newline) for the lines written since the last one,
+    /// without moving the chain on, so the same lines can be signed again if writing them fails.
+    pub fn sign(&self, lines: &[u8]) -> (String, Vec<u8>) {
+        let mut context = hmac::Context::with_key(&self.key.0);
+        context.update(&self.previous);
+        context.update(lines);
+        let tag = context.sign().as_ref().to_vec();
+        let mut line = PREFIX.to_string();
+        for byte in &tag {
+            let _ = write!(line, "{byte:02x}");
+        }
+        line.push('\n');
+        (line, tag)
+    }
+
+    /// Start over for a new file.
+    pub fn restart(&mut self) {
+        self.previous.clear();
+    }
+
+    /// Move the chain on once a signature has been written.
+    pub fn advance(&mut self, tag: Vec<u8>) {
+        self.previous = tag;
+    }
+}
+
+/// A signature that doesn't hold, or lines that aren't signed, with the line numbers involved.
+#[derive(Debug, PartialEq)]
+pub enum Failure {
+    /// The lines from `first` up to the signature on `last` were changed
+    Mismatch { first: usize, last: usize },
+    /// The lines from `first` to `last` come after the last signature
+    Unsigned { first: usize, last: usize },
+}
+
+/// Check the signatures of a file written with `--sign`.
+pub fn verify(key: &Key, contents: &[u8]) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    let mut chain = Chain::new(key);
+    let mut start = 0;
+    let mut first = 1;
+    let mut number = 0;
+    let mut offset = 0;
+    for line in contents.split_inclusive(|byte| *byte == b'\n') {
+        number += 1;
+        let end = offset;
+        offset += line.len();
+        let Some(signature) = line
+            .strip_prefix(PREFIX.as_bytes())
+            .and_then(|signature| signature.strip_suffix(b"\n"))
+        else {
+            continue;
+        };
+        let (_, tag) = chain.sign(&contents[start..end]);
+        let written = decode_hex(signature);
+        if written.as_ref() != Some(&tag) {
+            failures.push(Failure::Mismatch {
+                first,
+                last: number,
+            });
+        }
+        // Going on from the signature as written keeps a change from failing every later one, while
+        // lines removed along with their signature still fail the next
+        chain.advance(written.unwrap_or_default());
+        start = offset;
+        first = number + 1;
+    }
+    if start < contents.len() {
+        failures.push(Failure::Unsigned {
+            first,
+            last: number,
+        });
+    }
+    failures
+}
+
+fn decode_hex(text: &[u8]) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    text.chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_lines_break_the_chain() {
+        let key = Key(hmac::Key::new(hmac::HMAC_SHA256, b"0123456789abcdef"));
+        let mut chain = Chain::new(&key);
+        let mut contents = Vec::new();
+        for lines in ["Timestamp,Seq\n1,0\n", "2,1\n3,2\n", "4,3\n"] {
+            let (signature, tag) = chain.sign(lines.as_bytes());
+            chain.advance(tag);
+            contents.extend(lines.bytes().chain(signature.bytes()));
+        }
+        assert_eq!(verify(&key, &contents), []);
+
+        let changed = String::from_utf8(contents.clone())
+            .unwrap()
+            .replace("3,2", "3,7");
+        assert_eq!(
+            verify(&key, changed.as_bytes()),
+            [Failure::Mismatch { first: 4, last: 6 }]
+        );
+
+        // Lines removed along with their signature fail the signature after them
+        let text = String::from_utf8(contents.clone()).unwrap();
+        let lines: Vec<&str> = text.split_inclusive('\n').collect();
+        let removed = [&lines[..3], &lines[6..]].concat().concat();
+        assert_eq!(
+            verify(&key, removed.as_bytes()),
+            [Failure::Mismatch { first: 4, last: 5 }]
+        );
+
+        let mut unsigned = contents.clone();
+        unsigned.extend(b"5,4\n");
+        assert_eq!(
+            verify(&key, &unsigned),
+            [Failure::Unsigned { first: 9, last: 9 }]
+        );
+
+        let other = Key(hmac::Key::new(hmac::HMAC_SHA256, b"fedcba9876543210"));
+        assert_eq!(verify(&other, &contents).len(), 3);
+    }
+}
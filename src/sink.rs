@@ -0,0 +1,411 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::cbor;
+use crate::columns::{Column, Columns};
+use crate::encrypt::Recipient;
+use crate::engine::FlushPolicy;
+use crate::log_file::{format_row, LogFile};
+use crate::sign::Key;
+use serde_json::{Map, Number, Value};
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+
+/// Future returned by `OutputSink` methods, boxed so that sinks can be kept as trait objects.
+pub type SinkFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A kind of output sink, chosen with `--sink`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SinkKind {
+    /// Delimited text file, `result_*.csv` (or `.tsv`)
+    Csv,
+    /// One JSON object per line, `result_*.jsonl`
+    Json,
+    /// Compact binary records, `result_*.cbor`
+    Cbor,
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    fn from_str(kind: &str) -> Result<Self, Self::Err> {
+        match kind {
+            "csv" => Ok(SinkKind::Csv),
+            "json" => Ok(SinkKind::Json),
+            "cbor" => Ok(SinkKind::Cbor),
+            _ => Err(format!(
+                "unknown sink \"{kind}\" (expected csv, json or cbor)"
+            )),
+        }
+    }
+}
+
+/// The result of one probe, as the values of the chosen result columns.
+#[derive(Clone)]
+pub struct ProbeRecord {
+    pub fields: Vec<(Column, String)>,
+}
+
+/// A destination for probe results. Write failures are reported rather than returned, so a sink
+/// can keep records buffered and retry, and the engine can give up once storage has been failing
+/// for too long.
+pub trait OutputSink: Send + Sync {
+    /// Record (or buffer) the result of a probe.
+    fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()>;
+
+    /// Write anything buffered.
+    fn flush(&mut self) -> SinkFuture<'_, ()>;
+
+    /// Write anything buffered at the end of the run.
+    fn close(&mut self) -> SinkFuture<'_, ()>;
+
+    /// Reopen the sink's file at its path so external log rotation takes effect.
+    fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>>;
+
+    /// Replace the run metadata written at the top of the file if it has to be recreated.
+    fn set_metadata(&mut self, metadata: &[(&str, String)]);
+
+    /// Describe the problem if writes are currently failing.
+    fn error(&self) -> Option<String>;
+
+    /// Return the number of write attempts that have failed in a row.
+    fn consecutive_failures(&self) -> u32;
+}
+
+/// Create a sink of the given kind writing to `<path_stem>.<extension>`, or to
+/// `<path_stem>.<extension>.age` if encrypted to `recipient`. CSVs are signed with `sign_key` if
+/// given.
+#[allow(clippy::too_many_arguments)]
+pub async fn create(
+    kind: SinkKind,
+    path_stem: PathBuf,
+    metadata: &[(&str, String)],
+    columns: &Columns,
+    delimiter: u8,
+    sync_writes: bool,
+    recipient: Option<&Recipient>,
+    sign_key: Option<&Key>,
+    flush_policy: FlushPolicy,
+) -> io::Result<Box<dyn OutputSink>> {
+    let path = |extension: &str| match recipient {
+        Some(_) => path_stem.with_extension(format!("{extension}.age")),
+        None => path_stem.with_extension(extension),
+    };
+    Ok(match kind {
+        SinkKind::Csv => {
+            let extension = if delimiter == b'\t' { "tsv" } else { "csv" };
+            let header = csv_header(metadata, columns, delimiter);
+            let mut log = LogFile::create(path(extension), &header, sync_writes, recipient).await?;
+            if let Some(key) = sign_key {
+                log.sign(key);
+            }
+            Box::new(CsvSink {
+                log: BufferedLog { log, flush_policy },
+                columns: columns.clone(),
+                delimiter,
+            })
+        }
+        SinkKind::Json => {
+            let log = LogFile::create(
+                path("jsonl"),
+                &json_header(metadata),
+                sync_writes,
+                recipient,
+            )
+            .await?;
+            Box::new(JsonSink {
+                log: BufferedLog { log, flush_policy },
+            })
+        }
+        SinkKind::Cbor => {
+            let log = LogFile::create_binary(
+                path("cbor"),
+                cbor_header(metadata, columns),
+                sync_writes,
+                recipient,
+            )
+            .await?;
+            Box::new(CborSink {
+                log: BufferedLog { log, flush_policy },
+                columns: columns.clone(),
+            })
+        }
+    })
+}
+
+/// Header of a CSV: the run metadata as `#` comment lines, so the file describes itself even
+/// without its `config_*.json`, followed by the column names.
+pub fn csv_header(metadata: &[(&str, String)], columns: &Columns, delimiter: u8) -> String {
+    let mut header: String = metadata
+        .iter()
+        .map(|(key, value)| format!("# {key}: {value}\n"))
+        .collect();
+    header.push_str(&columns.header(delimiter));
+    header
+}
+
+/// First line of a JSON lines file: an object holding the run metadata.
+pub fn json_header(metadata: &[(&str, String)]) -> String {
+    let metadata: Map<String, Value> = metadata
+        .iter()
+        .map(|(key, value)| (key.to_string(), Value::from(value.as_str())))
+        .collect();
+    serde_json::json!({ "metadata": metadata }).to_string()
+}
+
+/// A record as a JSON object keyed by column name. Values of numeric columns are written as
+/// numbers, other values as strings, and empty ones as null.
+pub fn json_record<'a>(fields: impl Iterator<Item = (Column, &'a str)>) -> String {
+    let object: Map<String, Value> = fields
+        .map(|(column, value)| {
+            let value = if value.is_empty() {
+                Value::Null
+            } else if column.is_numeric() {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(Number::from_f64)
+                    .map_or_else(|| Value::from(value), Value::Number)
+            } else {
+                Value::from(value)
+            };
+            (column.name().to_string(), value)
+        })
+        .collect();
+    Value::Object(object).to_string()
+}
+
+/// Start of a CBOR result file: the run metadata and the names of the columns, as in `--columns`.
+fn cbor_header(metadata: &[(&str, String)], columns: &Columns) -> Vec<u8> {
+    let names: Vec<&str> = columns.0.iter().map(Column::name).collect();
+    cbor::header(metadata, &names)
+}
+
+/// A log file flushed according to the `--flush-every` policy.
+struct BufferedLog {
+    log: LogFile,
+    flush_policy: FlushPolicy,
+}
+
+impl BufferedLog {
+    async fn push(&mut self, row: impl Into<Vec<u8>>) {
+        self.log.push(row);
+        let flush_due = match self.flush_policy {
+            FlushPolicy::EveryRow => true,
+            FlushPolicy::Interval(interval) => self.log.since_last_flush() >= interval,
+            FlushPolicy::Rows(rows) => self.log.pending_rows() >= rows,
+        };
+        if flush_due {
+            self.log.flush().await;
+        }
+    }
+
+    async fn reopen(&mut self) -> Result<(), String> {
+        self.log
+            .reopen()
+            .await
+            .map_err(|e| format!("{}: {e}", self.log.path().display()))
+    }
+
+    fn error(&self) -> Option<String> {
+        self.log.last_error().map(|e| {
+            format!(
+                "{}: {e} ({} rows buffered)",
+                self.log.path().display(),
+                self.log.pending_rows()
+            )
+        })
+    }
+}
+
+/// Writes each record as a delimited row with the chosen columns.
+struct CsvSink {
+    log: BufferedLog,
+    columns: Columns,
+    delimiter: u8,
+}
+
+impl OutputSink for CsvSink {
+    fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()> {
+        let row = format_row(record.fields.iter().map(|(_, value)| value), self.delimiter);
+        Box::pin(self.log.push(row))
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.flush())
+    }
+
+    fn close(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.close())
+    }
+
+    fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(self.log.reopen())
+    }
+
+    fn set_metadata(&mut self, metadata: &[(&str, String)]) {
+        self.log
+            .log
+            .set_header(csv_header(metadata, &self.columns, self.delimiter));
+    }
+
+    fn error(&self) -> Option<String> {
+        self.log.error()
+    }
+
+    fn consecutive_failures(&self) -> u32 {
+        self.log.log.consecutive_failures()
+    }
+}
+
+/// Writes each record as a JSON object keyed by column name.
+struct JsonSink {
+    log: BufferedLog,
+}
+
+impl OutputSink for JsonSink {
+    fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()> {
+        let fields = record
+            .fields
+            .iter()
+            .map(|(column, value)| (*column, value.as_str()));
+        Box::pin(self.log.push(json_record(fields)))
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.flush())
+    }
+
+    fn close(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.close())
+    }
+
+    fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(self.log.reopen())
+    }
+
+    fn set_metadata(&mut self, metadata: &[(&str, String)]) {
+        self.log.log.set_header(json_header(metadata));
+    }
+
+    fn error(&self) -> Option<String> {
+        self.log.error()
+    }
+
+    fn consecutive_failures(&self) -> u32 {
+        self.log.log.consecutive_failures()
+    }
+}
+
+/// Writes each record as a CBOR array of its values, for long runs on little storage.
+struct CborSink {
+    log: BufferedLog,
+    columns: Columns,
+}
+
+impl OutputSink for CborSink {
+    fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()> {
+        let values = record.fields.iter().map(|(_, value)| value.as_str());
+        Box::pin(self.log.push(cbor::record(values)))
+    }
+
+    fn flush(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.flush())
+    }
+
+    fn close(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(self.log.log.close())
+    }
+
+    fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(self.log.reopen())
+    }
+
+    fn set_metadata(&mut self, metadata: &[(&str, String)]) {
+        self.log
+            .log
+            .set_header(cbor_header(metadata, &self.columns));
+    }
+
+    fn error(&self) -> Option<String> {
+        self.log.error()
+    }
+
+    fn consecutive_failures(&self) -> u32 {
+        self.log.log.consecutive_failures()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn json_sink_writes_one_object_per_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let columns = Columns(vec![
+            Column::Timestamp,
+            Column::Target,
+            Column::Rtt,
+            Column::Error,
+        ]);
+        let mut sink = create(
+            SinkKind::Json,
+            dir.path().join("result_test"),
+            &[("run_id", "test".to_string())],
+            &columns,
+            b',',
+            false,
+            None,
+            None,
+            FlushPolicy::EveryRow,
+        )
+        .await
+        .unwrap();
+        for (rtt, error) in [("1.500", ""), ("", "timeout")] {
+            let record = ProbeRecord {
+                fields: vec![
+                    (
+                        Column::Timestamp,
+                        "2024-01-01 0:00:00.0 +00:00:00".to_string(),
+                    ),
+                    (Column::Target, "00123".to_string()),
+                    (Column::Rtt, rtt.to_string()),
+                    (Column::Error, error.to_string()),
+                ],
+            };
+            sink.write(&record).await;
+        }
+        sink.close().await;
+        let contents = std::fs::read_to_string(dir.path().join("result_test.jsonl")).unwrap();
+        let lines: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["metadata"]["run_id"], "test");
+        assert_eq!(lines[1]["target"], "00123");
+        assert_eq!(lines[1]["rtt"], 1.5);
+        assert_eq!(lines[1]["error"], Value::Null);
+        assert_eq!(lines[2]["rtt"], Value::Null);
+        assert_eq!(lines[2]["error"], "timeout");
+        assert_eq!(lines.len(), 3);
+    }
+}
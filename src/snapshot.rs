@@ -0,0 +1,87 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::asn::Network;
+use crate::probe::ProbeKind;
+use schemars::{schema_for, JsonSchema};
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Version of the config snapshot format, bumped on incompatible changes. Version 1 was the original
+/// unversioned format with string-typed timeout/delay fields, version 2 lacked the start time,
+/// source address and address history, version 3 lacked the run ID, version 4 lacked the host's
+/// boot ID and boot time, and version 5 lacked the target's network.
+pub const SNAPSHOT_VERSION: u32 = 6;
+
+/// An address a target resolved to, from when probes started going to it.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct ResolvedAddress {
+    pub target: String,
+    pub address: IpAddr,
+    /// When probes started going to this address
+    pub since: String,
+}
+
+/// Runtime configuration of a monitoring run, written to `config_*.json` in the output directory.
+#[derive(Serialize, JsonSchema)]
+pub struct ConfigSnapshot {
+    /// Format version of this file
+    pub version: u32,
+    /// Target exactly as given by the user (IP address or hostname)
+    pub target: String,
+    /// IP address the target resolved to
+    pub address: IpAddr,
+    /// ID of the run, also found in its file names, events and alerts
+    pub run_id: String,
+    /// When the run started
+    pub started: String,
+    /// Local address the probes are sent from, if known
+    pub source_address: Option<IpAddr>,
+    /// Every address probed during the run, oldest first
+    pub address_history: Vec<ResolvedAddress>,
+    /// ID the monitoring host's kernel picked at boot, where available (Linux)
+    pub boot_id: Option<String>,
+    /// When the monitoring host booted, where available (Linux)
+    pub host_booted: Option<String>,
+    /// Network the target's address belongs to, with `--asn-lookup`
+    pub network: Option<Network>,
+    /// Number of payload bytes per ping
+    pub num_bytes: usize,
+    /// Time to wait for a reply (ms)
+    pub timeout_ms: u64,
+    /// How the target is probed
+    pub probe: ProbeKind,
+    /// Network interface the probes leave through, if known
+    pub interface: Option<String>,
+    /// Time to Live of sent pings (ICMP probes only)
+    pub ttl: u32,
+    /// Time between pings (s)
+    pub delay_s: f64,
+}
+
+impl ConfigSnapshot {
+    /// Serialize the snapshot as minified JSON.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Generate the JSON Schema describing `config_*.json` files.
+pub fn schema() -> String {
+    serde_json::to_string_pretty(&schema_for!(ConfigSnapshot)).unwrap()
+}
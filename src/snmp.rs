@@ -0,0 +1,524 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::pipeline::Output;
+use crate::probe::split_host_port;
+use crate::tui::format_duration;
+use ring::{digest, hmac};
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+const DEFAULT_PORT: u16 = 162;
+/// NET-SNMP-MIB::netSnmpPlaypen, meant for experiments, as the default base of the trap OIDs
+pub const DEFAULT_OID: &str = "1.3.6.1.4.1.8072.9999.9999";
+/// SNMPv2-MIB::sysUpTime.0, the first variable of every trap
+const SYS_UP_TIME: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+/// SNMPv2-MIB::snmpTrapOID.0, the second variable of every trap, naming the notification
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+// Bytes of the passphrase hashed into a key, by RFC 3414
+const PASSWORD_TO_KEY_BYTES: usize = 1024 * 1024;
+
+// BER tags
+const INTEGER: u8 = 0x02;
+const OCTET_STRING: u8 = 0x04;
+const OBJECT_IDENTIFIER: u8 = 0x06;
+const SEQUENCE: u8 = 0x30;
+const TIMETICKS: u8 = 0x43;
+const SNMPV2_TRAP: u8 = 0xa7;
+
+/// An object identifier, e.g. `1.3.6.1.4.1.8072.9999.9999`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Oid(Vec<u32>);
+
+impl FromStr for Oid {
+    type Err = String;
+
+    fn from_str(oid: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid OID \"{oid}\" (expected e.g. {DEFAULT_OID})");
+        let arcs = oid
+            .trim_start_matches('.')
+            .split('.')
+            .map(|arc| arc.parse::<u32>().map_err(|_| invalid()))
+            .collect::<Result<Vec<_>, _>>()?;
+        if arcs.len() < 2 || arcs[0] > 2 || (arcs[0] < 2 && arcs[1] >= 40) {
+            return Err(invalid());
+        }
+        Ok(Oid(arcs))
+    }
+}
+
+impl fmt::Display for Oid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arcs: Vec<String> = self.0.iter().map(u32::to_string).collect();
+        write!(f, "{}", arcs.join("."))
+    }
+}
+
+impl Oid {
+    fn child(&self, arcs: &[u32]) -> Oid {
+        Oid([self.0.as_slice(), arcs].concat())
+    }
+}
+
+/// Hash function of the SNMPv3 user's authentication key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuthProtocol {
+    /// HMAC-SHA-96 (RFC 3414)
+    Sha,
+    /// HMAC-SHA-192 with SHA-256 (RFC 7860)
+    Sha256,
+}
+
+impl AuthProtocol {
+    fn digest(self) -> &'static digest::Algorithm {
+        match self {
+            AuthProtocol::Sha => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+            AuthProtocol::Sha256 => &digest::SHA256,
+        }
+    }
+
+    fn hmac(self) -> hmac::Algorithm {
+        match self {
+            AuthProtocol::Sha => hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            AuthProtocol::Sha256 => hmac::HMAC_SHA256,
+        }
+    }
+
+    /// Length of the truncated HMAC carried in messages
+    fn tag_len(self) -> usize {
+        match self {
+            AuthProtocol::Sha => 12,
+            AuthProtocol::Sha256 => 24,
+        }
+    }
+
+    /// The key derived from `passphrase` for the engine `engine_id`, as in RFC 3414 A.2.
+    fn localized_key(self, passphrase: &str, engine_id: &[u8]) -> Vec<u8> {
+        let mut context = digest::Context::new(self.digest());
+        let repeated: Vec<u8> = passphrase
+            .bytes()
+            .cycle()
+            .take(PASSWORD_TO_KEY_BYTES)
+            .collect();
+        context.update(&repeated);
+        let key = context.finish();
+        let mut context = digest::Context::new(self.digest());
+        context.update(key.as_ref());
+        context.update(engine_id);
+        context.update(key.as_ref());
+        context.finish().as_ref().to_vec()
+    }
+}
+
+/// Authentication of SNMPv3 traps, as `PROTOCOL:PASSPHRASE` with PROTOCOL `sha` or `sha256`.
+#[derive(Clone)]
+pub struct Auth {
+    pub protocol: AuthProtocol,
+    pub passphrase: String,
+}
+
+impl FromStr for Auth {
+    type Err = String;
+
+    fn from_str(auth: &str) -> Result<Self, Self::Err> {
+        let (protocol, passphrase) = auth
+            .split_once(':')
+            .ok_or("invalid authentication (expected PROTOCOL:PASSPHRASE, e.g. sha:secret123)")?;
+        let protocol = match protocol.to_ascii_lowercase().as_str() {
+            "sha" => AuthProtocol::Sha,
+            "sha256" => AuthProtocol::Sha256,
+            _ => {
+                return Err(format!(
+                    "invalid authentication protocol \"{protocol}\" (expected sha or sha256)"
+                ))
+            }
+        };
+        // Agents refuse shorter ones, by RFC 3414
+        if passphrase.len() < 8 {
+            return Err(
+                "invalid authentication passphrase (expected at least 8 characters)".into(),
+            );
+        }
+        Ok(Auth {
+            protocol,
+            passphrase: passphrase.to_string(),
+        })
+    }
+}
+
+/// Parse an SNMP engine ID given in hex, e.g. `80001f88046e756d`.
+pub fn parse_engine_id(engine_id: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("invalid engine ID \"{engine_id}\" (expected 5 to 32 bytes in hex)");
+    let hex = engine_id.trim_start_matches("0x");
+    if !hex.len().is_multiple_of(2) || !(10..=64).contains(&hex.len()) {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(hex.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect()
+}
+
+/// Who traps are sent as.
+#[derive(Clone)]
+pub enum Security {
+    /// SNMPv2c with a community string
+    Community(String),
+    /// SNMPv3 as a user of the User-based Security Model, authenticated or not. The engine ID is
+    /// num's own, as the sender of traps is the authoritative engine.
+    User {
+        name: String,
+        engine_id: Vec<u8>,
+        auth: Option<Auth>,
+    },
+}
+
+/// Destination and contents of the traps.
+#[derive(Clone)]
+pub struct TrapConfig {
+    /// Manager receiving the traps, as `host[:port]`
+    pub destination: String,
+    pub security: Security,
+    /// Base of the OIDs of the notifications and their variables
+    pub oid: Oid,
+}
+
+impl TrapConfig {
+    /// Check a `host[:port]` destination.
+    pub fn parse_destination(destination: &str) -> Result<String, String> {
+        split_host_port(destination, DEFAULT_PORT)
+            .map(|_| destination.to_string())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// A notification to send. The numbers match the event IDs of `--event-log`.
+#[derive(Debug, PartialEq)]
+struct Trap {
+    /// Last arc of the notification's OID, under `<base>.0`: 1 target DOWN, 2 target back UP,
+    /// 3 internet DOWN, 4 internet back UP
+    notification: u32,
+    description: String,
+}
+
+/// The trap to send for `output` of the monitor of `target`, if any.
+fn trap(output: &Output, target: &str) -> Option<Trap> {
+    let (notification, description) = match output {
+        Output::OutageStarted { .. } => (1, format!("{target} is DOWN")),
+        Output::OutageEnded { duration, .. } => (
+            2,
+            format!("{target} is back UP after {}", format_duration(*duration)),
+        ),
+        Output::Event(event) if event.name == "internet_down" => {
+            (3, format!("Internet is DOWN: {}", event.details))
+        }
+        Output::Event(event) if event.name == "internet_up" => {
+            (4, format!("Internet is UP: {}", event.details))
+        }
+        _ => return None,
+    };
+    Some(Trap {
+        notification,
+        description,
+    })
+}
+
+fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let len = value.len();
+    if len < 0x80 {
+        encoded.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|b| *b == 0)
+            .collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend(bytes);
+    }
+    encoded.extend(value);
+    encoded
+}
+
+fn integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Drop leading bytes that only repeat the sign
+    let mut start = 0;
+    while start < 7 {
+        let (byte, next) = (bytes[start], bytes[start + 1]);
+        if (byte == 0 && next & 0x80 == 0) || (byte == 0xff && next & 0x80 != 0) {
+            start += 1;
+        } else {
+            break;
+        }
+    }
+    tlv(tag, &bytes[start..])
+}
+
+fn octet_string(value: &[u8]) -> Vec<u8> {
+    tlv(OCTET_STRING, value)
+}
+
+fn object_identifier(oid: &Oid) -> Vec<u8> {
+    let arcs = &oid.0;
+    let mut encoded = Vec::new();
+    for arc in std::iter::once(arcs[0] * 40 + arcs[1]).chain(arcs[2..].iter().copied()) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        encoded.extend(groups.into_iter().rev());
+    }
+    tlv(OBJECT_IDENTIFIER, &encoded)
+}
+
+fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    tlv(SEQUENCE, &items.concat())
+}
+
+/// Sends traps to one manager.
+pub struct TrapSender {
+    config: TrapConfig,
+    /// Localized authentication key, for authenticated SNMPv3 traps
+    key: Option<hmac::Key>,
+    start: Instant,
+    /// Counts up with every start, as the engine boots of SNMPv3 must, without keeping any state
+    boots: i64,
+    request_id: i32,
+}
+
+impl TrapSender {
+    pub fn new(config: TrapConfig) -> Self {
+        let key = match &config.security {
+            Security::User {
+                engine_id,
+                auth: Some(auth),
+                ..
+            } => {
+                let key = auth.protocol.localized_key(&auth.passphrase, engine_id);
+                Some(hmac::Key::new(auth.protocol.hmac(), &key))
+            }
+            _ => None,
+        };
+        let boots = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |since| since.as_secs() as i64)
+            .clamp(1, i32::MAX as i64);
+        TrapSender {
+            config,
+            key,
+            start: Instant::now(),
+            boots,
+            request_id: std::process::id() as i32 & 0x7fff_ffff,
+        }
+    }
+
+    /// Encode an SNMPv2-Trap PDU for `trap` of `target`.
+    fn pdu(&self, trap: &Trap, target: &str) -> Vec<u8> {
+        let up_time = (self.start.elapsed().as_millis() / 10) as i64 & 0xffff_ffff;
+        let varbind = |oid: &Oid, value: Vec<u8>| sequence(&[object_identifier(oid), value]);
+        let variables = sequence(&[
+            varbind(&Oid(SYS_UP_TIME.to_vec()), integer(TIMETICKS, up_time)),
+            varbind(
+                &Oid(SNMP_TRAP_OID.to_vec()),
+                object_identifier(&self.config.oid.child(&[0, trap.notification])),
+            ),
+            varbind(
+                &self.config.oid.child(&[1, 1]),
+                octet_string(target.as_bytes()),
+            ),
+            varbind(
+                &self.config.oid.child(&[1, 2]),
+                octet_string(trap.description.as_bytes()),
+            ),
+        ]);
+        let fields = [
+            integer(INTEGER, self.request_id as i64),
+            integer(INTEGER, 0),
+            integer(INTEGER, 0),
+            variables,
+        ];
+        tlv(SNMPV2_TRAP, &fields.concat())
+    }
+
+    /// Encode the message carrying `trap` of `target`.
+    fn message(&self, trap: &Trap, target: &str) -> Vec<u8> {
+        let pdu = self.pdu(trap, target);
+        let (name, engine_id, auth) = match &self.config.security {
+            Security::Community(community) => {
+                return sequence(&[integer(INTEGER, 1), octet_string(community.as_bytes()), pdu]);
+            }
+            Security::User {
+                name,
+                engine_id,
+                auth,
+            } => (name, engine_id, auth),
+        };
+        let tag_len = auth.as_ref().map_or(0, |auth| auth.protocol.tag_len());
+        let header = sequence(&[
+            integer(INTEGER, self.request_id as i64),
+            integer(INTEGER, 65507),
+            // Only the authentication flag, as traps aren't encrypted or answered
+            octet_string(&[u8::from(auth.is_some())]),
+            integer(INTEGER, 3),
+        ]);
+        let security = sequence(&[
+            octet_string(engine_id),
+            integer(INTEGER, self.boots),
+            integer(INTEGER, self.start.elapsed().as_secs() as i64),
+            octet_string(name.as_bytes()),
+            octet_string(&vec![0; tag_len]),
+            octet_string(&[]),
+        ]);
+        let scoped_pdu = sequence(&[octet_string(engine_id), octet_string(&[]), pdu]);
+        let scoped_pdu_len = scoped_pdu.len();
+        let mut message = sequence(&[
+            integer(INTEGER, 3),
+            header,
+            octet_string(&security),
+            scoped_pdu,
+        ]);
+        if let Some(key) = &self.key {
+            // The HMAC is computed with the authentication parameters zeroed, then put in their
+            // place, which is right before the empty privacy parameters ending the security ones
+            let tag = hmac::sign(key, &message);
+            let at = message.len() - scoped_pdu_len - 2 - tag_len;
+            message[at..at + tag_len].copy_from_slice(&tag.as_ref()[..tag_len]);
+        }
+        message
+    }
+
+    async fn send(&mut self, trap: &Trap, target: &str) -> Result<(), String> {
+        self.request_id = self.request_id.wrapping_add(1) & 0x7fff_ffff;
+        let destination = &self.config.destination;
+        let (host, port) = split_host_port(destination, DEFAULT_PORT).map_err(|e| e.to_string())?;
+        let address = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("Could not resolve {destination}: {e}"))?
+            .next()
+            .ok_or(format!("Could not resolve {destination}"))?;
+        let bind = if address.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)
+            .await
+            .map_err(|e| format!("{destination}: {e}"))?;
+        socket
+            .send_to(&self.message(trap, target), address)
+            .await
+            .map_err(|e| format!("{destination}: {e}"))?;
+        Ok(())
+    }
+}
+
+/// Consumer sending a trap for every outage and recovery of `target`, reporting failed sends to
+/// `failures`.
+pub async fn send_traps(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    config: TrapConfig,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    let mut sender = TrapSender::new(config);
+    while let Some(output) = outputs.recv().await {
+        let Some(trap) = trap(&output, &target) else {
+            continue;
+        };
+        if let Err(e) = sender.send(&trap, &target).await {
+            let _ = failures.send(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::local_now;
+
+    #[test]
+    fn traps_are_encoded_and_authenticated() {
+        assert_eq!(
+            object_identifier(&"1.3.6.1.4.1.8072.9999".parse().unwrap()),
+            [0x06, 0x09, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xbf, 0x08, 0xce, 0x0f]
+        );
+        assert_eq!(integer(INTEGER, 128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(integer(INTEGER, -1), [0x02, 0x01, 0xff]);
+        assert_eq!(tlv(OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+        assert!("1.50.3".parse::<Oid>().is_err());
+
+        // RFC 3414 A.3.2
+        let engine_id = parse_engine_id("000000000000000000000002").unwrap();
+        assert_eq!(
+            AuthProtocol::Sha.localized_key("maplesyrup", &engine_id),
+            parse_engine_id("6695febc9288e36282235fc7151f128497b38f3f").unwrap()
+        );
+
+        let trap = trap(&Output::OutageStarted { at: local_now() }, "1.1.1.1").unwrap();
+        assert_eq!(trap.description, "1.1.1.1 is DOWN");
+        let sender = TrapSender::new(TrapConfig {
+            destination: "nms.example.com".to_string(),
+            security: Security::Community("public".to_string()),
+            oid: DEFAULT_OID.parse().unwrap(),
+        });
+        let message = sender.message(&trap, "1.1.1.1");
+        assert_eq!(message[0], SEQUENCE);
+        assert_eq!(message[3..14], *b"\x02\x01\x01\x04\x06public");
+        let notification = object_identifier(&"1.3.6.1.4.1.8072.9999.9999.0.1".parse().unwrap());
+        assert!(message
+            .windows(notification.len())
+            .any(|w| w == notification));
+
+        let auth: Auth = "sha:maplesyrup".parse().unwrap();
+        let sender = TrapSender::new(TrapConfig {
+            destination: "nms.example.com".to_string(),
+            security: Security::User {
+                name: "num".to_string(),
+                engine_id: engine_id.clone(),
+                auth: Some(auth),
+            },
+            oid: DEFAULT_OID.parse().unwrap(),
+        });
+        let message = sender.message(&trap, "1.1.1.1");
+        // The tag is the HMAC of the message with the tag zeroed
+        let key = hmac::Key::new(
+            hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY,
+            &AuthProtocol::Sha.localized_key("maplesyrup", &engine_id),
+        );
+        let at = message
+            .windows(16)
+            .position(|w| w[..2] == [OCTET_STRING, 12] && w[14..] == [OCTET_STRING, 0])
+            .unwrap()
+            + 2;
+        let mut zeroed = message.clone();
+        zeroed[at..at + 12].fill(0);
+        assert_ne!(message, zeroed);
+        assert_eq!(
+            hmac::sign(&key, &zeroed).as_ref()[..12],
+            message[at..at + 12]
+        );
+    }
+}
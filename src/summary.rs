@@ -0,0 +1,148 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::engine::Stats;
+use crate::tui::format_duration;
+use serde::Serialize;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// Final statistics of a run, printed on exit and written to `summary_<start time>.json`.
+#[derive(Serialize)]
+pub struct Summary {
+    pub target: String,
+    pub address: IpAddr,
+    pub run_id: String,
+    pub started: String,
+    pub ended: String,
+    pub runtime_s: f64,
+    pub transmitted: u64,
+    pub received: u64,
+    pub loss_percent: f64,
+    pub rtt_min_ms: Option<f64>,
+    pub rtt_avg_ms: Option<f64>,
+    pub rtt_max_ms: Option<f64>,
+    pub rtt_mdev_ms: Option<f64>,
+    /// Number of times the target went down
+    pub outages: u64,
+    /// Number of replies that arrived after their probe timed out
+    pub late_replies: u64,
+    /// Mean and greatest lateness of probes compared with their schedule
+    pub tick_drift_avg_ms: f64,
+    pub tick_drift_max_ms: f64,
+    /// Number of probes sent noticeably behind schedule
+    pub late_ticks: u64,
+    /// Slowest, mean and fastest download speed measured with `--throughput-url`
+    pub throughput_min_mbps: Option<f64>,
+    pub throughput_avg_mbps: Option<f64>,
+    pub throughput_max_mbps: Option<f64>,
+    /// Why monitoring stopped: "interrupted", or the error it stopped on
+    pub exit_reason: String,
+}
+
+impl Summary {
+    pub fn new(
+        target: &str,
+        address: IpAddr,
+        run_id: &str,
+        started: OffsetDateTime,
+        ended: OffsetDateTime,
+        stats: &Stats,
+        exit_reason: &str,
+    ) -> Self {
+        let ms = |rtt: Option<Duration>| rtt.map(|rtt| rtt.as_secs_f64() * 1000.0);
+        Summary {
+            target: target.to_string(),
+            address,
+            run_id: run_id.to_string(),
+            started: started.to_string(),
+            ended: ended.to_string(),
+            runtime_s: stats.runtime.as_secs_f64(),
+            transmitted: stats.sent,
+            received: stats.received,
+            loss_percent: stats.loss_percent(),
+            rtt_min_ms: ms(stats.min_rtt),
+            rtt_avg_ms: ms(stats.avg_rtt()),
+            rtt_max_ms: ms(stats.max_rtt),
+            rtt_mdev_ms: ms(stats.mdev_rtt()),
+            outages: stats.outages,
+            late_replies: stats.late_replies,
+            tick_drift_avg_ms: stats.drift.avg().as_secs_f64() * 1000.0,
+            tick_drift_max_ms: stats.drift.max.as_secs_f64() * 1000.0,
+            late_ticks: stats.drift.late,
+            throughput_min_mbps: stats.throughput.min_mbps,
+            throughput_avg_mbps: stats.throughput.avg_mbps(),
+            throughput_max_mbps: stats.throughput.max_mbps,
+            exit_reason: exit_reason.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl fmt::Display for Summary {
+    /// Format the statistics like `ping` does when it exits.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "--- {} ({}) statistics ---", self.target, self.address)?;
+        writeln!(
+            f,
+            "{} probes transmitted, {} received, {:.3}% loss, time {}",
+            self.transmitted,
+            self.received,
+            self.loss_percent,
+            format_duration(Duration::from_secs_f64(self.runtime_s))
+        )?;
+        if let (Some(min), Some(avg), Some(max), Some(mdev)) = (
+            self.rtt_min_ms,
+            self.rtt_avg_ms,
+            self.rtt_max_ms,
+            self.rtt_mdev_ms,
+        ) {
+            writeln!(
+                f,
+                "rtt min/avg/max/mdev = {min:.3}/{avg:.3}/{max:.3}/{mdev:.3} ms"
+            )?;
+        }
+        if let (Some(min), Some(avg), Some(max)) = (
+            self.throughput_min_mbps,
+            self.throughput_avg_mbps,
+            self.throughput_max_mbps,
+        ) {
+            writeln!(
+                f,
+                "throughput min/avg/max = {min:.3}/{avg:.3}/{max:.3} Mbps"
+            )?;
+        }
+        write!(f, "{} outage(s)", self.outages)?;
+        if self.late_replies > 0 {
+            write!(f, ", {} late replies", self.late_replies)?;
+        }
+        // Only worth a line when the schedule slipped
+        if self.late_ticks > 0 {
+            write!(
+                f,
+                "\n{} probes sent late, tick drift avg/max = {:.3}/{:.3} ms",
+                self.late_ticks, self.tick_drift_avg_ms, self.tick_drift_max_ms
+            )?;
+        }
+        Ok(())
+    }
+}
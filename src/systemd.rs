@@ -0,0 +1,201 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::alert;
+use crate::api::Health;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::watch;
+
+/// How long systemd waits for a watchdog ping before restarting the monitor.
+const WATCHDOG_SEC: u64 = 60;
+
+/// A service unit running the monitor.
+pub struct Unit {
+    /// The `num` executable and its arguments
+    pub program: Vec<String>,
+    /// Directory relative paths in the arguments are resolved from
+    pub working_directory: PathBuf,
+    /// Whether it is a user unit rather than a system one
+    pub user: bool,
+    /// Account a system unit runs as, instead of root
+    pub run_as: Option<String>,
+}
+
+impl Unit {
+    /// Render the unit file. The monitor reports readiness and pings the watchdog (see
+    /// `notify_health`), and can open raw sockets for ICMP without running as root.
+    pub fn render(&self) -> String {
+        let exec_start: Vec<String> = self.program.iter().map(|arg| quote(arg)).collect();
+        let mut service = format!(
+            "Type=notify\n\
+             NotifyAccess=main\n\
+             ExecStart={}\n\
+             WorkingDirectory={}\n\
+             Restart=on-failure\n\
+             RestartSec=5\n\
+             WatchdogSec={WATCHDOG_SEC}\n",
+            exec_start.join(" "),
+            // Taken as is rather than unquoted, so only specifiers need escaping
+            self.working_directory.to_string_lossy().replace('%', "%%"),
+        );
+        if !self.user {
+            if let Some(user) = &self.run_as {
+                service.push_str(&format!("User={user}\n"));
+            }
+            service.push_str("AmbientCapabilities=CAP_NET_RAW\n");
+        }
+        let wanted_by = if self.user {
+            "default.target"
+        } else {
+            "multi-user.target"
+        };
+        format!(
+            "[Unit]\n\
+             Description=num network uptime monitor\n\
+             Wants=network-online.target\n\
+             After=network-online.target\n\
+             \n\
+             [Service]\n\
+             {service}\
+             \n\
+             [Install]\n\
+             WantedBy={wanted_by}\n"
+        )
+    }
+}
+
+/// Quote an argument for a unit file, where `%` starts a specifier and `$` a variable.
+fn quote(arg: &str) -> String {
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    if escaped.is_empty() || escaped.contains(char::is_whitespace) || escaped != arg {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+/// Write the unit as `name.service` (to `/etc/systemd/system`, or `~/.config/systemd/user` for a
+/// user unit), replacing an earlier version, and reload systemd and enable and (re)start it
+/// unless `enable` is false. Returns the path of the unit file.
+pub async fn install(unit: &Unit, name: &str, enable: bool) -> Result<PathBuf, String> {
+    let dir = if unit.user {
+        let home = std::env::var_os("HOME").ok_or("HOME is not set")?;
+        Path::new(&home).join(".config/systemd/user")
+    } else {
+        PathBuf::from("/etc/systemd/system")
+    };
+    tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| format!("Could not create {}: {e}", dir.display()))?;
+    let path = dir.join(format!("{name}.service"));
+    tokio::fs::write(&path, unit.render())
+        .await
+        .map_err(|e| format!("Could not write {}: {e}", path.display()))?;
+    let systemctl = |args: &[&str]| {
+        let mut command = Command::new("systemctl");
+        if unit.user {
+            command.arg("--user");
+        }
+        command.args(args);
+        command
+    };
+    if enable {
+        alert::run(&mut systemctl(&["daemon-reload"]), None).await?;
+        let service = format!("{name}.service");
+        alert::run(&mut systemctl(&["enable", &service]), None).await?;
+        alert::run(&mut systemctl(&["restart", &service]), None).await?;
+    }
+    Ok(path)
+}
+
+/// When started by a `Type=notify` unit, tell systemd over `$NOTIFY_SOCKET` that the monitor is
+/// ready once it has a first probe result, then ping the watchdog at half its interval for as
+/// long as the ping loop keeps ticking.
+pub async fn notify_health(mut health: watch::Receiver<Health>) {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let socket = PathBuf::from(socket);
+    if health.wait_for(|health| health.ready).await.is_err() {
+        return;
+    }
+    let _ = notify(&socket, "READY=1");
+    let Some(watchdog) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    let mut interval = tokio::time::interval(watchdog / 2);
+    loop {
+        interval.tick().await;
+        if health.borrow().alive() {
+            let _ = notify(&socket, "WATCHDOG=1");
+        }
+    }
+}
+
+/// Send `state` to the notification socket, which may be in the abstract namespace (`@name`).
+fn notify(socket: &Path, state: &str) -> io::Result<()> {
+    let path = socket.to_string_lossy();
+    let addr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name.as_bytes())?,
+        None => SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unit_quotes_arguments_and_grants_raw_sockets() {
+        let unit = Unit {
+            program: [
+                "/usr/bin/num",
+                "--container",
+                "1.1.1.1",
+                "-o",
+                "/var/lib/num logs",
+            ]
+            .map(str::to_string)
+            .to_vec(),
+            working_directory: PathBuf::from("/srv/num \"50%\" $HOME\\"),
+            user: false,
+            run_as: Some("num".to_string()),
+        };
+        let rendered = unit.render();
+        assert!(rendered
+            .contains("ExecStart=/usr/bin/num --container 1.1.1.1 -o \"/var/lib/num logs\"\n"));
+        assert!(rendered.contains("WorkingDirectory=/srv/num \"50%%\" $HOME\\\n"));
+        assert!(rendered.contains("User=num\nAmbientCapabilities=CAP_NET_RAW\n"));
+        assert!(rendered.ends_with("WantedBy=multi-user.target\n"));
+        assert_eq!(quote("50%"), "\"50%%\"");
+    }
+}
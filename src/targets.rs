@@ -0,0 +1,393 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::{local_now, Engine, EngineConfig, LinkState};
+use crate::health::STATE_FILE_NAME;
+use crate::pipeline::{self, Fanout, Schedule};
+use crate::probe::parse_target;
+use crate::settings::ProbeSettings;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{self, JoinHandle};
+use tokio::time::MissedTickBehavior;
+
+/// Builds the engine config for a target from its probe settings, output directory, and whether to
+/// resume from the state saved there.
+pub type ConfigBuilder = Box<dyn Fn(ProbeSettings, Option<PathBuf>, bool) -> EngineConfig + Send>;
+
+/// Works out the probe settings for a target from the settings of the main one.
+pub type SettingsBuilder = Box<dyn Fn(&str) -> Result<ProbeSettings, String> + Send>;
+
+/// A target read from a list such as `--targets-file`.
+#[derive(Debug, PartialEq)]
+pub struct ListedTarget {
+    pub target: String,
+    /// Name of the target's results directory instead of its address
+    pub label: Option<String>,
+}
+
+/// Parse a list of targets, one per line with an optional label after it, e.g.
+/// `192.168.1.1 router`. Blank lines and anything after `#` are ignored, and so are repeats of a
+/// target.
+pub fn parse_list(contents: &str) -> Result<Vec<ListedTarget>, String> {
+    let mut targets: Vec<ListedTarget> = Vec::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace();
+        let Some(target) = words.next() else {
+            continue;
+        };
+        let label = words.next().map(str::to_string);
+        if words.next().is_some() {
+            return Err(format!(
+                "Line {}: expected a target and an optional label",
+                number + 1
+            ));
+        }
+        parse_target(target).map_err(|e| format!("Line {}: {e}", number + 1))?;
+        if targets.iter().any(|listed| listed.target == target) {
+            continue;
+        }
+        if let Some(label) = label.as_ref().filter(|label| {
+            targets
+                .iter()
+                .any(|listed| listed.label.as_ref() == Some(label))
+        }) {
+            return Err(format!(
+                "Line {}: label {label} is used more than once",
+                number + 1
+            ));
+        }
+        targets.push(ListedTarget {
+            target: target.to_string(),
+            label,
+        });
+    }
+    Ok(targets)
+}
+
+/// Targets added at runtime with `num ctl add-target`. Each one is monitored by its own engine in
+/// its own task, writing to a subdirectory `targets/<target>` of the output directory. They aren't
+/// shown in the TUI.
+pub struct ExtraTargets {
+    settings_for: SettingsBuilder,
+    config_for: ConfigBuilder,
+    output_path: Option<PathBuf>,
+    missed_tick_behavior: MissedTickBehavior,
+    /// Where each target reports going DOWN (true) or no longer being DOWN (false)
+    states: mpsc::UnboundedSender<(String, bool)>,
+    running: BTreeMap<String, Running>,
+    /// Targets whose engine is being created
+    starting: BTreeSet<String>,
+    /// Removed targets still writing out their results
+    stopping: Vec<JoinHandle<()>>,
+}
+
+/// A target being added, whose engine is yet to be created.
+pub struct Starting {
+    target: String,
+    label: Option<String>,
+    config: EngineConfig,
+    output_path: Option<PathBuf>,
+    schedule: Schedule,
+    missed_tick_behavior: MissedTickBehavior,
+    states: mpsc::UnboundedSender<(String, bool)>,
+}
+
+/// A target whose ping loop is running, to be handed to `ExtraTargets::finish`.
+pub struct Started {
+    running: Running,
+    description: String,
+}
+
+struct Running {
+    label: Option<String>,
+    stop: oneshot::Sender<()>,
+    reopen_logs: mpsc::UnboundedSender<()>,
+    reconfigure: mpsc::UnboundedSender<Result<ProbeSettings, String>>,
+    task: JoinHandle<()>,
+}
+
+impl ExtraTargets {
+    pub fn new(
+        settings_for: SettingsBuilder,
+        config_for: ConfigBuilder,
+        output_path: Option<PathBuf>,
+        missed_tick_behavior: MissedTickBehavior,
+        states: mpsc::UnboundedSender<(String, bool)>,
+    ) -> Self {
+        ExtraTargets {
+            settings_for,
+            config_for,
+            output_path,
+            missed_tick_behavior,
+            states,
+            running: BTreeMap::new(),
+            starting: BTreeSet::new(),
+            stopping: Vec::new(),
+        }
+    }
+
+    /// Start monitoring `target`, returning a description of where its results go. The results
+    /// directory is named after `label` if given, otherwise after the target.
+    pub async fn add(&mut self, target: &str, label: Option<&str>) -> Result<String, String> {
+        let started = self.start(target, label)?.run().await;
+        self.finish(target, started)
+    }
+
+    /// Begin adding `target`. Its engine is created by `Starting::run`, which can take seconds
+    /// (e.g. to resolve its name), so it can be left to a task of its own, and the result handed
+    /// to `finish`.
+    pub fn start(&mut self, target: &str, label: Option<&str>) -> Result<Starting, String> {
+        if self.running.contains_key(target) || self.starting.contains(target) {
+            return Err(format!("{target} is already monitored"));
+        }
+        let probe = (self.settings_for)(target)?;
+        let output_path = self.output_path.as_ref().map(|output_path| {
+            output_path
+                .join("targets")
+                .join(directory_name(label.unwrap_or(target)))
+        });
+        // A target that was added before carries on from where it was removed
+        let resume = output_path
+            .as_ref()
+            .is_some_and(|path| path.join(STATE_FILE_NAME).exists());
+        let schedule = Schedule::of(&probe);
+        let config = (self.config_for)(probe, output_path.clone(), resume);
+        self.starting.insert(target.to_string());
+        Ok(Starting {
+            target: target.to_string(),
+            label: label.map(str::to_string),
+            config,
+            output_path,
+            schedule,
+            missed_tick_behavior: self.missed_tick_behavior,
+            states: self.states.clone(),
+        })
+    }
+
+    /// Finish adding a target begun with `start`, returning a description of where its results go.
+    pub fn finish(
+        &mut self,
+        target: &str,
+        started: Result<Started, String>,
+    ) -> Result<String, String> {
+        self.starting.remove(target);
+        let started = started?;
+        self.running.insert(target.to_string(), started.running);
+        Ok(started.description)
+    }
+
+    /// Stop monitoring `target`. Its results are written out in the background, after which the
+    /// reply is sent to `done`.
+    pub fn remove(&mut self, target: &str, done: oneshot::Sender<Result<String, String>>) {
+        let Some(running) = self.running.remove(target) else {
+            let _ = done.send(Err(format!("{target} is not an added target")));
+            return;
+        };
+        let _ = running.stop.send(());
+        let target = target.to_string();
+        self.stopping.retain(|task| !task.is_finished());
+        self.stopping.push(task::spawn(async move {
+            let _ = running.task.await;
+            let _ = done.send(Ok(format!("stopped monitoring {target}")));
+        }));
+    }
+
+    /// Whether `target` is an added target that's being monitored.
+    pub fn contains(&self, target: &str) -> bool {
+        self.running.contains_key(target)
+    }
+
+    /// Return the added targets, in order, with their labels.
+    pub fn names(&self) -> Vec<String> {
+        self.running
+            .iter()
+            .map(|(target, running)| match &running.label {
+                Some(label) => format!("{target} ({label})"),
+                None => target.clone(),
+            })
+            .collect()
+    }
+
+    /// Have every added target reopen its output files.
+    pub fn reopen_logs(&self) {
+        for running in self.running.values() {
+            let _ = running.reopen_logs.send(());
+        }
+    }
+
+    /// Apply a reloaded config file: targets added from now on get their settings from
+    /// `settings_for`, and so do the running ones, which log the outcome as a `config_reloaded` or
+    /// `config_reload_failed` event.
+    pub fn reconfigure(&mut self, settings_for: SettingsBuilder) {
+        for (target, running) in &self.running {
+            let _ = running.reconfigure.send(settings_for(target));
+        }
+        self.settings_for = settings_for;
+    }
+
+    /// Stop every added target, once their results are written.
+    pub async fn stop_all(&mut self) {
+        for (_, running) in std::mem::take(&mut self.running) {
+            let _ = running.stop.send(());
+            let _ = running.task.await;
+        }
+        for task in std::mem::take(&mut self.stopping) {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Starting {
+    /// Create the target's engine and start its ping loop.
+    pub async fn run(self) -> Result<Started, String> {
+        if let Some(path) = &self.output_path {
+            tokio::fs::create_dir_all(path)
+                .await
+                .map_err(|e| format!("Could not create {}: {e}", path.display()))?;
+        }
+        let engine = Engine::new(self.config).await?;
+        let (stop, stop_rx) = oneshot::channel();
+        let (reopen_logs, reopen_rx) = mpsc::unbounded_channel();
+        let (reconfigure, reconfigure_rx) = mpsc::unbounded_channel();
+        let task = task::spawn(monitor(
+            engine,
+            self.schedule,
+            self.missed_tick_behavior,
+            (self.target.clone(), self.states),
+            stop_rx,
+            reopen_rx,
+            reconfigure_rx,
+        ));
+        let target = self.target;
+        Ok(Started {
+            running: Running {
+                label: self.label,
+                stop,
+                reopen_logs,
+                reconfigure,
+                task,
+            },
+            description: match self.output_path {
+                Some(path) => format!("monitoring {target}, results in {}", path.display()),
+                None => format!("monitoring {target}"),
+            },
+        })
+    }
+}
+
+/// Name of a target's subdirectory, with anything but letters, digits, `.` and `-` replaced.
+fn directory_name(target: &str) -> String {
+    target
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Ping loop of an added target, a reduced version of the main one: results and events only go
+/// to the target's files, and the only consumer is alert delivery.
+async fn monitor(
+    mut engine: Engine,
+    schedule: Schedule,
+    missed_tick_behavior: MissedTickBehavior,
+    (target, states): (String, mpsc::UnboundedSender<(String, bool)>),
+    mut stop: oneshot::Receiver<()>,
+    mut reopen_logs: mpsc::UnboundedReceiver<()>,
+    mut reconfigure: mpsc::UnboundedReceiver<Result<ProbeSettings, String>>,
+) {
+    let (schedule_tx, schedule_rx) = watch::channel(schedule);
+    let mut ticks = pipeline::spawn_scheduler(schedule_rx, missed_tick_behavior);
+    let (alert_tx, mut alert_rx) = mpsc::unbounded_channel::<String>();
+    let mut fanout = Fanout::new();
+    fanout.add(|outputs| pipeline::deliver_alerts(outputs, alert_tx));
+    let mut portal_results = engine.take_portal_results();
+    let mut network_lookups = engine.take_network_lookups();
+    let mut down = false;
+    let exit_reason = loop {
+        tokio::select! {
+            Some(tick) = ticks.recv() => engine.start_probe(tick).await,
+            Some(probed) = engine.next_probed() => {
+                let _ = engine.record_probe(probed).await;
+                if down != (engine.stats().state == LinkState::Down) {
+                    down = !down;
+                    let _ = states.send((target.clone(), down));
+                }
+                if engine.storage_failed() {
+                    break engine.get_storage_error().unwrap_or_default();
+                }
+            }
+            Some(result) = portal_results.recv() => engine.record_portal_result(result).await,
+            Some(lookup) = network_lookups.recv() => engine.record_network(lookup).await,
+            Some(()) = reopen_logs.recv() => engine.reopen_logs().await,
+            Some(probe) = reconfigure.recv() => {
+                let result = match probe {
+                    Ok(probe) => engine.reconfigure(&probe).await.map(|()| Schedule::of(&probe)),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(schedule) => {
+                        schedule_tx.send_if_modified(|current| std::mem::replace(current, schedule) != schedule);
+                    }
+                    Err(e) => engine.log_event(local_now(), "config_reload_failed", &e).await,
+                }
+            }
+            Some(error) = alert_rx.recv() => engine.record_alert_failure(&error).await,
+            _ = &mut stop => break "removed".to_string(),
+        }
+        for output in engine.take_outputs() {
+            fanout.send(output);
+        }
+    };
+    engine.close().await;
+    engine.write_state_file().await;
+    let summary = engine.summary(&exit_reason);
+    let _ = engine.write_summary(&summary).await;
+    fanout.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target_lists_skip_comments_and_repeats() {
+        let listed = |target: &str, label: Option<&str>| ListedTarget {
+            target: target.to_string(),
+            label: label.map(str::to_string),
+        };
+        let contents = "# LAN\n192.168.1.1 router\n\n[2001:db8::1]:443  # web\n192.168.1.1\n";
+        assert_eq!(
+            parse_list(contents),
+            Ok(vec![
+                listed("192.168.1.1", Some("router")),
+                listed("[2001:db8::1]:443", None),
+            ])
+        );
+        assert!(parse_list("10.0.0.1 a\n10.0.0.2 a").is_err());
+        assert!(parse_list("10.0.0.1 router extra").is_err());
+        assert!(parse_list("example.com:https").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::probe::split_host_port;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// Times a TCP connection to a port of the target, for services behind firewalls that drop ICMP.
+pub struct TcpProbe {
+    addr: SocketAddr,
+    timeout: Duration,
+}
+
+impl TcpProbe {
+    /// Create a probe for `target` (`host:port`) at `ip_addr`.
+    pub fn new(target: &str, ip_addr: IpAddr, timeout: Duration) -> io::Result<Self> {
+        let (_, port) = split_host_port(target, 0)?;
+        if port == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("TCP probes need a port (e.g. {target}:443)"),
+            ));
+        }
+        Ok(TcpProbe {
+            addr: SocketAddr::new(ip_addr, port),
+            timeout,
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Open a connection and return how long the handshake took. The connection is closed right
+    /// away. Fails with `ErrorKind::TimedOut` if it isn't established in time.
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        let start = Instant::now();
+        tokio::time::timeout(self.timeout, TcpStream::connect(self.addr))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timed out"))??;
+        Ok(start.elapsed())
+    }
+}
@@ -0,0 +1,191 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crossterm::style::{self, Attribute, Color, ContentStyle, StyledContent, Stylize};
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::io::{stdout, IsTerminal};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+// Whether ANSI styling (colors and attributes) is emitted; decided once at startup
+static STYLING_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Whether output is restricted to plain ASCII characters; decided once at startup
+static ASCII_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Width of the horizontal rules separating TUI sections
+const RULE_WIDTH: usize = 48;
+
+// Active color theme; set once at startup
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// Colors used to convey status throughout the TUI.
+#[derive(Clone, Copy)]
+pub struct Theme {
+    pub success: Color,
+    pub failure: Color,
+    pub info: Color,
+}
+
+impl Theme {
+    /// Names of the built-in themes, in the order they are presented to the user.
+    pub const PRESETS: [&'static str; 2] = ["default", "colorblind"];
+
+    /// Look up a built-in theme by name.
+    pub fn preset(name: &str) -> Option<Theme> {
+        match name {
+            "default" => Some(Theme {
+                success: Color::Green,
+                failure: Color::Red,
+                info: Color::Blue,
+            }),
+            // Blue/orange pair that stays distinguishable under common color vision deficiencies
+            "colorblind" => Some(Theme {
+                success: Color::Blue,
+                failure: Color::AnsiValue(208),
+                info: Color::Cyan,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Override individual colors from a comma separated list of `role=color` pairs, e.g.
+    /// `success=cyan,failure=yellow`. Roles are `success`, `failure`, and `info`.
+    pub fn with_overrides(mut self, overrides: &str) -> Result<Theme, String> {
+        for pair in overrides.split(',').filter(|pair| !pair.is_empty()) {
+            let (role, color_name) = pair.split_once('=').ok_or_else(|| {
+                format!("Invalid color override \"{pair}\" (expected role=color)")
+            })?;
+            let color = Color::try_from(color_name.trim())
+                .map_err(|_| format!("Unknown color \"{color_name}\""))?;
+            match role.trim() {
+                "success" => self.success = color,
+                "failure" => self.failure = color,
+                "info" => self.info = color,
+                _ => return Err(format!("Unknown color role \"{role}\"")),
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// Set the theme used by `success`, `failure`, and `info`.
+pub fn set_theme(theme: Theme) {
+    let _ = THEME.set(theme);
+}
+
+fn theme() -> Theme {
+    *THEME.get_or_init(|| Theme::preset("default").unwrap())
+}
+
+/// Color `content` to indicate success.
+pub fn success<D: Display>(content: D) -> StyledContent<D> {
+    style::style(content).with(theme().success)
+}
+
+/// Color `content` to indicate failure.
+pub fn failure<D: Display>(content: D) -> StyledContent<D> {
+    style::style(content).with(theme().failure)
+}
+
+/// Color `content` as informational text.
+pub fn info<D: Display>(content: D) -> StyledContent<D> {
+    style::style(content).with(theme().info)
+}
+
+/// Decide whether to emit ANSI styling. `auto` disables styling when NO_COLOR is set to a non-empty
+/// value or when stdout is not a terminal (e.g. piped or redirected to a file).
+pub fn init_styling(color_choice: &str) {
+    let enabled = match color_choice {
+        "always" => true,
+        "never" => false,
+        _ => {
+            std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && stdout().is_terminal()
+                && terminal_supports_ansi()
+        }
+    };
+    STYLING_ENABLED.store(enabled, Ordering::Relaxed);
+    // Colors are gated by crossterm itself, attributes (bold, etc.) are gated by `styled`/`bold`
+    style::force_color_output(enabled);
+}
+
+/// Check whether the terminal understands ANSI escape sequences. On Windows this also attempts to
+/// enable virtual terminal processing, which legacy consoles (e.g. older Windows Server) lack.
+pub fn terminal_supports_ansi() -> bool {
+    #[cfg(windows)]
+    {
+        crossterm::ansi_support::supports_ansi()
+    }
+    #[cfg(not(windows))]
+    {
+        std::env::var("TERM").map_or(true, |term| term != "dumb")
+    }
+}
+
+/// Strip attributes from stylized content if styling is disabled.
+pub fn styled<D: Display>(mut content: StyledContent<D>) -> StyledContent<D> {
+    if !STYLING_ENABLED.load(Ordering::Relaxed) {
+        *content.style_mut() = ContentStyle::new();
+    }
+    content
+}
+
+/// Generate bold text if styling is enabled, plain text otherwise.
+pub fn bold(text: &str) -> String {
+    if STYLING_ENABLED.load(Ordering::Relaxed) {
+        format!("{}{text}{}", Attribute::Bold, Attribute::Reset)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Restrict TUI output to plain ASCII characters (for serial consoles and limited fonts).
+pub fn set_ascii_only(enabled: bool) {
+    ASCII_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether output is restricted to plain ASCII characters.
+pub fn ascii_only() -> bool {
+    ASCII_ONLY.load(Ordering::Relaxed)
+}
+
+/// Generate a horizontal rule for separating TUI sections.
+pub fn rule() -> String {
+    let glyph = if ASCII_ONLY.load(Ordering::Relaxed) {
+        '-'
+    } else {
+        '─'
+    };
+    glyph.to_string().repeat(RULE_WIDTH)
+}
+
+/// Replace non-ASCII characters in user-supplied text (hostnames, paths) with `?` when in ASCII-only
+/// mode.
+pub fn ascii_safe(text: &str) -> Cow<'_, str> {
+    if ASCII_ONLY.load(Ordering::Relaxed) && !text.is_ascii() {
+        Cow::Owned(
+            text.chars()
+                .map(|c| if c.is_ascii() { c } else { '?' })
+                .collect(),
+        )
+    } else {
+        Cow::Borrowed(text)
+    }
+}
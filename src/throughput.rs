@@ -0,0 +1,77 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::http::{status_code, HttpUrl};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+// Downloads are cut off after this long, so slow links don't tie up the connection for ages
+const MAX_DOWNLOAD_TIME: Duration = Duration::from_secs(15);
+
+/// Result of a download.
+pub struct Throughput {
+    /// Body bytes received
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl Throughput {
+    pub fn mbps(&self) -> f64 {
+        self.bytes as f64 * 8.0 / self.elapsed.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+    }
+}
+
+/// Measure throughput by downloading `url` for up to 15 seconds.
+pub async fn measure(url: &HttpUrl) -> Result<Throughput, String> {
+    let failed = |e: std::io::Error| format!("{}: {e}", url.as_str());
+    let start = Instant::now();
+    let (status_line, mut stream) = tokio::time::timeout(MAX_DOWNLOAD_TIME, url.get())
+        .await
+        .map_err(|_| format!("{} timed out", url.as_str()))?
+        .map_err(failed)?;
+    if status_code(&status_line) != "200" {
+        return Err(format!("{} answered \"{status_line}\"", url.as_str()));
+    }
+    let deadline = start + MAX_DOWNLOAD_TIME;
+    let mut header = String::new();
+    loop {
+        let read = tokio::time::timeout_at(deadline.into(), stream.read_line(&mut header))
+            .await
+            .map_err(|_| format!("{} timed out", url.as_str()))?
+            .map_err(failed)?;
+        if read <= 2 {
+            break;
+        }
+        header.clear();
+    }
+    // Time the body only, so connection setup doesn't count against the link's bandwidth
+    let body_start = Instant::now();
+    let mut bytes = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match tokio::time::timeout_at(deadline.into(), stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(read)) => bytes += read as u64,
+            Ok(Err(e)) => return Err(failed(e)),
+        }
+    }
+    Ok(Throughput {
+        bytes,
+        elapsed: body_start.elapsed(),
+    })
+}
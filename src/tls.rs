@@ -0,0 +1,181 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::probe::split_host_port;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use time::OffsetDateTime;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::{Resumption, WebPkiServerVerifier};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    crypto, ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Port used when the target doesn't name one.
+const DEFAULT_PORT: u16 = 443;
+
+/// How long each phase of the last TLS probe took. A phase that wasn't reached (or failed) is None.
+#[derive(Clone, Copy, Default)]
+pub struct TlsTiming {
+    pub connect: Option<Duration>,
+    pub handshake: Option<Duration>,
+}
+
+/// Opens a TCP connection and performs a TLS handshake with the target, timing both separately so
+/// network slowness can be told apart from a slow or broken TLS server.
+pub struct TlsProbe {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    timeout: Duration,
+    last_timing: TlsTiming,
+    /// Expiry of the certificate presented in the last handshake, filled in by the verifier
+    cert_not_after: Arc<Mutex<Option<OffsetDateTime>>>,
+}
+
+impl TlsProbe {
+    /// Create a probe for `target` (`host` or `host:port`, default port 443) at `ip_addr`. The
+    /// certificate is verified against the host name using the bundled Mozilla root certificates.
+    pub fn new(target: &str, ip_addr: IpAddr, timeout: Duration) -> io::Result<Self> {
+        let (host, port) = split_host_port(target, DEFAULT_PORT)?;
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{host}: {e}")))?;
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let provider = Arc::new(crypto::ring::default_provider());
+        let cert_not_after = Arc::new(Mutex::new(None));
+        let verifier = ExpiryRecorder {
+            inner: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+                .build()
+                .map_err(io::Error::other)?,
+            not_after: cert_not_after.clone(),
+        };
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        // Every probe does a full handshake, so the timing and certificate are always current
+        config.resumption = Resumption::disabled();
+        Ok(TlsProbe {
+            addr: SocketAddr::new(ip_addr, port),
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            timeout,
+            last_timing: TlsTiming::default(),
+            cert_not_after,
+        })
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Timing of the most recent probe.
+    pub fn last_timing(&self) -> TlsTiming {
+        self.last_timing
+    }
+
+    /// Expiry time of the certificate presented during the last probe, even if it failed
+    /// verification (e.g. because it already expired).
+    pub fn cert_not_after(&self) -> Option<OffsetDateTime> {
+        *self.cert_not_after.lock().unwrap()
+    }
+
+    /// Connect and complete a handshake within the timeout, returning the total time taken. Fails
+    /// with `ErrorKind::TimedOut` if the timeout runs out first.
+    pub async fn ping(&mut self) -> io::Result<Duration> {
+        self.last_timing = TlsTiming::default();
+        *self.cert_not_after.lock().unwrap() = None;
+        let start = Instant::now();
+        let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let stream = tokio::time::timeout(self.timeout, TcpStream::connect(self.addr))
+            .await
+            .map_err(|_| timed_out())??;
+        let connected = Instant::now();
+        self.last_timing.connect = Some(connected - start);
+        let remaining = self.timeout.saturating_sub(connected - start);
+        let mut stream = tokio::time::timeout(
+            remaining,
+            self.connector.connect(self.server_name.clone(), stream),
+        )
+        .await
+        .map_err(|_| timed_out())??;
+        self.last_timing.handshake = Some(connected.elapsed());
+        let total = start.elapsed();
+        // Close politely; the result is already known, so a failure here doesn't matter
+        let _ = tokio::time::timeout(Duration::from_secs(1), stream.shutdown()).await;
+        Ok(total)
+    }
+}
+
+/// Verifies server certificates as usual, but first notes when the presented certificate expires.
+#[derive(Debug)]
+struct ExpiryRecorder {
+    inner: Arc<WebPkiServerVerifier>,
+    not_after: Arc<Mutex<Option<OffsetDateTime>>>,
+}
+
+impl ServerCertVerifier for ExpiryRecorder {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if let Ok((_, cert)) = X509Certificate::from_der(end_entity) {
+            *self.not_after.lock().unwrap() = Some(cert.validity().not_after.to_datetime());
+        }
+        self.inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
@@ -0,0 +1,426 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::engine::{local_now, Event, LinkState, Stats, Transition};
+use crate::settings::ProbeSettings;
+use crate::theme::{ascii_safe, bold, failure, info, rule, styled, success};
+use crossterm::style::{StyledContent, Stylize};
+use crossterm::{cursor, terminal, ExecutableCommand};
+use std::io::{stdout, Stdout, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use time::format_description::FormatItem;
+use time::{format_description, OffsetDateTime};
+
+// Format string for user-presented timestamp
+const DT_FMT: &str = "[month]/[day]/[year] [hour]:[minute]:[second]";
+
+// How often the TUI is redrawn, independent of the ping delay
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// State of the monitor after a ping, sent from the probe loop to the renderer.
+#[derive(Clone)]
+pub struct PingUpdate {
+    pub time: OffsetDateTime,
+    pub address: IpAddr,
+    pub rtt: Option<Duration>,
+    pub stats: Stats,
+    pub running_since: Instant,
+    pub next_ping: Instant,
+    pub storage_error: Option<String>,
+    /// Network the target's address belongs to, with `--asn-lookup`
+    pub network: Option<String>,
+}
+
+/// Run configuration displayed in the TUI header.
+pub struct TuiConfig {
+    pub probe: ProbeSettings,
+    pub output_path: std::path::PathBuf,
+    pub run_id: String,
+    pub interactive: bool,
+}
+
+/// Messages sent from the probe loop to the renderer.
+pub enum TuiMessage {
+    Ping(Box<PingUpdate>),
+    /// The probe settings were changed by a config reload
+    Reconfigured(Box<ProbeSettings>),
+    StateChanged(Transition),
+    OutageStarted {
+        at: OffsetDateTime,
+    },
+    OutageEnded {
+        at: OffsetDateTime,
+        duration: Duration,
+    },
+    StorageDegraded(String),
+    Event(Event),
+}
+
+/// Spawn the renderer on its own thread so slow terminal writes can never delay pings. The renderer
+/// exits (restoring the terminal) once every sender for `updates` has been dropped.
+pub fn spawn(config: TuiConfig, updates: Receiver<TuiMessage>) -> JoinHandle<()> {
+    thread::spawn(move || run(config, updates))
+}
+
+/// Create the header text describing the run configuration and the target's network, if known.
+fn generate_header(config: &TuiConfig, network: Option<&str>) -> String {
+    [
+        generate_target_text(&config.probe.addr, network),
+        generate_path_text(&config.output_path, &config.run_id),
+        generate_delay_timeout_text(config.probe.delay, config.probe.timeout),
+        generate_bytes_ttl_text(config.probe.ttl, config.probe.num_bytes),
+    ]
+    .concat()
+}
+
+fn run(mut config: TuiConfig, updates: Receiver<TuiMessage>) {
+    let mut stdout = stdout();
+    let dt_fmt = format_description::parse(DT_FMT).unwrap();
+    if config.interactive {
+        stdout.execute(terminal::EnterAlternateScreen).unwrap();
+        stdout.execute(cursor::Hide).unwrap();
+    }
+    let mut network = None;
+    let mut header = generate_header(&config, None);
+    let mut latest: Option<PingUpdate> = None;
+    loop {
+        match updates.recv_timeout(REFRESH_INTERVAL) {
+            Ok(TuiMessage::Ping(update)) => {
+                if !config.interactive {
+                    let ping_text = generate_ping_text(
+                        config.probe.num_bytes,
+                        config.probe.ttl,
+                        &dt_fmt,
+                        &update,
+                    );
+                    writeln!(stdout, "{ping_text}").unwrap();
+                }
+                if update.network != network {
+                    network = update.network.clone();
+                    header = generate_header(&config, network.as_deref());
+                }
+                latest = Some(*update);
+            }
+            Ok(TuiMessage::Reconfigured(probe)) => {
+                config.probe = *probe;
+                header = generate_header(&config, network.as_deref());
+                if !config.interactive {
+                    writeln!(stdout, "{}", styled(info("Configuration reloaded"))).unwrap();
+                }
+            }
+            Ok(TuiMessage::StateChanged(transition)) => {
+                if !config.interactive {
+                    let text = format!(
+                        "Target is {} (was {})",
+                        transition.to.label(),
+                        transition.from.label()
+                    );
+                    let text = if transition.to == LinkState::Up {
+                        success(text)
+                    } else {
+                        failure(text)
+                    };
+                    writeln!(stdout, "{}", styled(text)).unwrap();
+                }
+            }
+            Ok(TuiMessage::OutageEnded { duration, .. }) => {
+                if !config.interactive {
+                    let text = format!("Outage ended after {}", format_duration(duration));
+                    writeln!(stdout, "{}", styled(info(text))).unwrap();
+                }
+            }
+            Ok(TuiMessage::StorageDegraded(error)) => {
+                if !config.interactive {
+                    let text = format!("Storage degraded: {}", ascii_safe(&error));
+                    writeln!(stdout, "{}", styled(failure(text))).unwrap();
+                }
+            }
+            Ok(TuiMessage::OutageStarted { .. } | TuiMessage::Event(_))
+            | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if let (true, Some(update)) = (config.interactive, &latest) {
+            stdout
+                .execute(cursor::MoveTo(0, 0))
+                .unwrap()
+                .execute(terminal::Clear(terminal::ClearType::All))
+                .unwrap();
+            display_tui(&stdout, &header, &config, &dt_fmt, update);
+            stdout.flush().unwrap();
+        }
+    }
+    // Leave the alternate screen to restore the user's original terminal contents
+    if config.interactive {
+        stdout.execute(cursor::Show).unwrap();
+        stdout.execute(terminal::LeaveAlternateScreen).unwrap();
+        println!("{}", styled(info("Exiting").bold()));
+    } else {
+        println!(); // Move down one line
+    }
+}
+
+/// Create stylized text representing the last time a ping failed. Red is used to indicate a failed
+/// ping and green represents no failed pings up to the current time.
+fn generate_last_failed_text(update: &PingUpdate, dt_fmt: &[FormatItem]) -> StyledContent<String> {
+    if let Some(last_failed_time) = update.stats.last_failure {
+        failure(last_failed_time.format(dt_fmt).unwrap())
+    } else {
+        success("N/A".to_string())
+    }
+}
+
+/// Create stylized text representing the last time a ping succeeded (and the latency of that ping).
+/// Red indicates no successful pings up to the current time while green represents a successful ping.
+fn generate_last_success_text(update: &PingUpdate, dt_fmt: &[FormatItem]) -> StyledContent<String> {
+    if let Some((last_successful_time, latency)) = update.stats.last_success {
+        success(format!(
+            "{} ({:.3}ms)",
+            last_successful_time.format(dt_fmt).unwrap(),
+            latency.as_secs_f64() * 1000.0
+        ))
+    } else {
+        failure("N/A".to_string())
+    }
+}
+
+/// Create stylized text representing data about the last ping performed. The text is red if the ping
+/// failed, and green otherwise.
+fn generate_ping_text(
+    num_bytes: u8,
+    ttl: u32,
+    dt_fmt: &[FormatItem],
+    update: &PingUpdate,
+) -> StyledContent<String> {
+    if let Some(rtt) = update.rtt {
+        success(format!(
+            "[{}] Reply from {}: bytes={} time={:.3}ms TTL={}",
+            update.time.format(dt_fmt).unwrap(),
+            update.address,
+            num_bytes,
+            rtt.as_secs_f64() * 1000.0,
+            ttl
+        ))
+    } else {
+        failure(format!(
+            "[{}] Ping failed.",
+            update.time.format(dt_fmt).unwrap()
+        ))
+    }
+}
+
+/// Create stylized text representing the current UP/DOWN state and how long it has lasted, e.g.
+/// "DOWN for 4m32s". Updated on every redraw so ongoing outages count up in real time.
+fn generate_state_text(update: &PingUpdate) -> StyledContent<String> {
+    let duration = update
+        .stats
+        .state_since
+        .map(|since| format_duration(since.elapsed()))
+        .unwrap_or_default();
+    match update.stats.state {
+        LinkState::Up => success(format!("UP for {duration}")),
+        LinkState::Down => failure(format!("DOWN for {duration}")),
+        LinkState::Captive => failure(format!("CAPTIVE for {duration}")),
+        LinkState::Unknown => info("UNKNOWN".to_string()),
+    }
+}
+
+/// Create text describing the most recent UP/DOWN transition, e.g. "DOWN -> UP at <time>".
+fn generate_transition_text(update: &PingUpdate, dt_fmt: &[FormatItem]) -> String {
+    match update.stats.last_transition {
+        Some(transition) => format!(
+            "{} -> {} at {}",
+            transition.from.label(),
+            transition.to.label(),
+            transition.at.format(dt_fmt).unwrap()
+        ),
+        None => "N/A".to_string(),
+    }
+}
+
+/// Generate text summarizing the number of pings sent/received/lost, how long the monitor has been
+/// running, the round trip times of successful pings, and the all-time uptime if it is kept.
+fn generate_counters_text(update: &PingUpdate) -> String {
+    let stats = &update.stats;
+    let mut text = format!(
+        "{} {}, {} {}, {} {} ({:.1}%), {} {}\n",
+        bold("Sent:"),
+        stats.sent,
+        bold("Received:"),
+        stats.received,
+        bold("Lost:"),
+        stats.sent - stats.received,
+        stats.loss_percent(),
+        bold("Runtime:"),
+        format_duration(update.running_since.elapsed())
+    );
+    if let (Some(min), Some(avg), Some(max)) = (stats.min_rtt, stats.avg_rtt(), stats.max_rtt) {
+        text.push_str(&format!(
+            "{} {:.3}/{:.3}/{:.3}ms\n",
+            bold("RTT min/avg/max:"),
+            min.as_secs_f64() * 1000.0,
+            avg.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0
+        ));
+    }
+    if let Some(lifetime) = stats.lifetime {
+        text.push_str(&format!(
+            "{} {}\n",
+            bold("All-time uptime:"),
+            lifetime.describe()
+        ));
+    }
+    text
+}
+
+/// Format a duration compactly (e.g. `1h02m05s`, `4m32s`, `9s`).
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{hours}h{minutes:02}m{seconds:02}s")
+    } else if minutes > 0 {
+        format!("{minutes}m{seconds:02}s")
+    } else {
+        format!("{seconds}s")
+    }
+}
+
+/// Generate text showing the current time and the countdown to the next ping.
+fn generate_clock_text(update: &PingUpdate, dt_fmt: &[FormatItem]) -> String {
+    let now = local_now();
+    let remaining = update
+        .next_ping
+        .saturating_duration_since(Instant::now())
+        .as_secs();
+    format!(
+        "{} {}, {} {remaining}s\n",
+        bold("Time:"),
+        now.format(dt_fmt).unwrap(),
+        bold("Next ping in:")
+    )
+}
+
+/// Generate stylized text representing the target of the ping calls and its network
+fn generate_target_text(addr: &str, network: Option<&str>) -> String {
+    match network {
+        Some(network) => format!(
+            "{} {} ({})\n",
+            bold("Target:"),
+            ascii_safe(addr),
+            ascii_safe(network)
+        ),
+        None => format!("{} {}\n", bold("Target:"), ascii_safe(addr)),
+    }
+}
+
+/// Generate stylized text representing the output path of the logs/config files and the run ID in
+/// their names
+fn generate_path_text(output_path: &Path, run_id: &str) -> String {
+    format!(
+        "{} {} (run {run_id})\n",
+        bold("Output path:"),
+        ascii_safe(&output_path.display().to_string())
+    )
+}
+
+/// Generate stylized text representing the delay and timeout of the current run  
+fn generate_delay_timeout_text(delay: Duration, timeout: u64) -> String {
+    format!(
+        "{} {}s, {} {timeout}ms\n",
+        bold("Delay:"),
+        delay.as_secs_f64(),
+        bold("Timeout:")
+    )
+}
+
+/// Generate stylized text representing the number of bytes and ttl of the current run
+fn generate_bytes_ttl_text(ttl: u32, num_bytes: u8) -> String {
+    format!(
+        "{} {num_bytes}, {} {ttl}\n",
+        bold("Num. Bytes:"),
+        bold("TTL:")
+    )
+}
+
+/// Display a simple TUI (Terminal User Interface) to the user with basic statistics of the app
+/// state.
+fn display_tui(
+    mut stdout: &Stdout,
+    header: &str,
+    config: &TuiConfig,
+    dt_fmt: &[FormatItem],
+    update: &PingUpdate,
+) {
+    stdout.write_all(header.as_ref()).unwrap();
+    stdout
+        .write_all(generate_clock_text(update, dt_fmt).as_ref())
+        .unwrap();
+    stdout
+        .write_all(generate_counters_text(update).as_ref())
+        .unwrap();
+    if let Some(storage_error) = &update.storage_error {
+        writeln!(
+            stdout,
+            "{} {}",
+            bold("Storage:"),
+            failure(format!("DEGRADED ({})", ascii_safe(storage_error)))
+        )
+        .unwrap();
+    }
+    writeln!(stdout, "{}", rule()).unwrap();
+    writeln!(
+        stdout,
+        "{} {}",
+        bold("Status:"),
+        generate_state_text(update)
+    )
+    .unwrap();
+    writeln!(
+        stdout,
+        "{} {}",
+        bold("Last state change:"),
+        generate_transition_text(update, dt_fmt)
+    )
+    .unwrap();
+    writeln!(
+        stdout,
+        "{} {}",
+        bold("Last successful ping:"),
+        generate_last_success_text(update, dt_fmt)
+    )
+    .unwrap();
+    writeln!(
+        stdout,
+        "{} {}",
+        bold("Last failed ping:"),
+        generate_last_failed_text(update, dt_fmt)
+    )
+    .unwrap();
+    writeln!(stdout, "{}", rule()).unwrap();
+    writeln!(stdout, "{}", bold("Last Ping Status:")).unwrap();
+    writeln!(
+        stdout,
+        "{}",
+        generate_ping_text(config.probe.num_bytes, config.probe.ttl, dt_fmt, update)
+    )
+    .unwrap();
+}
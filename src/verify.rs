@@ -0,0 +1,398 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::atomic_write::write_atomic;
+use crate::columns::Column;
+#[cfg(unix)]
+use crate::control::CONTROL_SOCKET_NAME;
+use crate::health::StateFile;
+use crate::report::{detect_delimiter, parse_timestamp};
+use crate::sign::{self, Failure, Key};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use time::OffsetDateTime;
+
+// Sequence numbers wrap, so duplicates are only looked for among this many recent rows
+const SEQ_WINDOW: usize = 1000;
+// Problems listed per file before the rest are only counted
+const MAX_LISTED: usize = 20;
+
+#[derive(Debug, PartialEq)]
+struct Problem {
+    line: usize,
+    message: String,
+    // Whether --repair removes the line
+    damaged: bool,
+}
+
+#[derive(Debug)]
+struct Check {
+    problems: Vec<Problem>,
+    repaired: String,
+}
+
+/// Check one result file. Comments and the header are kept as they are; damaged rows (truncated,
+/// wrong number of fields, unreadable timestamp or exact duplicates) are left out of `repaired`,
+/// while timestamps going backwards and reused sequence numbers are only reported, as clock steps
+/// and restarts cause them in healthy files.
+fn check(contents: &str) -> Check {
+    let mut problems = Vec::new();
+    let mut repaired = String::with_capacity(contents.len());
+    let mut headers: Option<Vec<String>> = None;
+    let mut delimiter = b',';
+    let mut previous: Option<OffsetDateTime> = None;
+    let mut recent: VecDeque<((String, String), usize)> = VecDeque::new();
+    let mut seen: HashMap<(String, String), (usize, String)> = HashMap::new();
+    for (index, line) in contents.split_inclusive('\n').enumerate() {
+        let number = index + 1;
+        let mut problem = |message: String, damaged: bool| {
+            problems.push(Problem {
+                line: number,
+                message,
+                damaged,
+            })
+        };
+        if !line.ends_with('\n') {
+            problem("truncated".to_string(), true);
+            continue;
+        }
+        let text = line.trim_end_matches(['\n', '\r']);
+        if text.starts_with('#') || text.is_empty() {
+            repaired.push_str(line);
+            continue;
+        }
+        let Some(headers) = &headers else {
+            delimiter = detect_delimiter(text);
+            let names = split(text, delimiter).unwrap_or_default();
+            for name in &names {
+                if Column::from_header(name).is_none() {
+                    problem(format!("unknown column \"{name}\" in the header"), false);
+                }
+            }
+            headers = Some(names);
+            repaired.push_str(line);
+            continue;
+        };
+        let Some(fields) = split(text, delimiter) else {
+            problem("unreadable row".to_string(), true);
+            continue;
+        };
+        if fields.len() != headers.len() {
+            problem(
+                format!(
+                    "{} fields where the header has {}",
+                    fields.len(),
+                    headers.len()
+                ),
+                true,
+            );
+            continue;
+        }
+        let field = |header: &str| {
+            headers
+                .iter()
+                .position(|name| name == header)
+                .map(|column| fields[column].clone())
+        };
+        if let Some(timestamp) = field("Timestamp") {
+            let Some(parsed) = parse_timestamp(&timestamp) else {
+                problem(format!("unreadable timestamp \"{timestamp}\""), true);
+                continue;
+            };
+            if previous.is_some_and(|previous| parsed < previous) {
+                problem(
+                    format!("timestamp {timestamp} is earlier than the row before"),
+                    false,
+                );
+            }
+            previous = Some(parsed);
+        }
+        if let Some(seq) = field("Seq") {
+            let key = (field("Run").unwrap_or_default(), seq);
+            if let Some((first, earlier)) = seen.get(&key) {
+                if earlier == text {
+                    problem(format!("duplicate of line {first}"), true);
+                    continue;
+                }
+                problem(
+                    format!("sequence number {} reused from line {first}", key.1),
+                    false,
+                );
+            }
+            recent.push_back((key.clone(), number));
+            seen.insert(key, (number, text.to_string()));
+            if recent.len() > SEQ_WINDOW {
+                let (oldest, line) = recent.pop_front().unwrap();
+                if seen
+                    .get(&oldest)
+                    .is_some_and(|(seen_line, _)| *seen_line == line)
+                {
+                    seen.remove(&oldest);
+                }
+            }
+        }
+        repaired.push_str(line);
+    }
+    Check { problems, repaired }
+}
+
+fn split(line: &str, delimiter: u8) -> Option<Vec<String>> {
+    csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .has_headers(false)
+        .from_reader(line.as_bytes())
+        .records()
+        .next()?
+        .ok()
+        .map(|record| record.iter().map(str::to_string).collect())
+}
+
+/// Describe the signatures of a file that don't hold.
+fn signature_problems(key: &Key, contents: &[u8]) -> Vec<Problem> {
+    let failures = sign::verify(key, contents);
+    if failures.is_empty() {
+        return Vec::new();
+    }
+    if !contents
+        .split(|byte| *byte == b'\n')
+        .any(|line| line.starts_with(sign::PREFIX.as_bytes()))
+    {
+        return vec![Problem {
+            line: 1,
+            message: "not signed".to_string(),
+            damaged: false,
+        }];
+    }
+    failures
+        .into_iter()
+        .map(|failure| match failure {
+            Failure::Mismatch { first, last } => Problem {
+                line: last,
+                message: format!("signature doesn't match {}", lines(first, last)),
+                damaged: false,
+            },
+            Failure::Unsigned { first, last } => Problem {
+                line: first,
+                message: format!("no signature after {}", lines(first, last)),
+                damaged: false,
+            },
+        })
+        .collect()
+}
+
+/// Return the ID of the run of a monitor still writing to `output_path`, going by its control
+/// socket answering or its state file being fresh. The ID is empty if the state file can't tell.
+fn running_monitor(output_path: &Path) -> Option<String> {
+    let state_file = StateFile::read(output_path).ok();
+    #[cfg(unix)]
+    let listening =
+        std::os::unix::net::UnixStream::connect(output_path.join(CONTROL_SOCKET_NAME)).is_ok();
+    #[cfg(not(unix))]
+    let listening = false;
+    let fresh = state_file.as_ref().is_some_and(StateFile::is_fresh);
+    (listening || fresh).then(|| state_file.map(|state| state.run_id).unwrap_or_default())
+}
+
+fn lines(first: usize, last: usize) -> String {
+    if first == last {
+        format!("line {first}")
+    } else {
+        format!("lines {first}-{last}")
+    }
+}
+
+/// Check every result file in `output_path` for truncated lines, rows that don't match the
+/// header, timestamps going backwards and duplicate sequence numbers, and their signatures if
+/// `key` is given. With `repair`, damaged rows are removed and the original file is kept next to it
+/// with a `.bak` extension, except in the files of a monitor still running, which would go on
+/// writing to the replaced file. Returns the report and whether any damage or signature that
+/// doesn't hold is left.
+pub async fn run(
+    output_path: &Path,
+    repair: bool,
+    key: Option<&Key>,
+) -> Result<(String, bool), String> {
+    let entries = std::fs::read_dir(output_path)
+        .map_err(|e| format!("Could not read {}: {e}", output_path.display()))?;
+    let mut paths: Vec<_> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name.starts_with("result_") && (name.ends_with(".csv") || name.ends_with(".tsv"))
+        })
+        .collect();
+    if paths.is_empty() {
+        return Err(format!(
+            "No result files found in {}",
+            output_path.display()
+        ));
+    }
+    paths.sort();
+    let mut report = String::new();
+    let running = repair.then(|| running_monitor(output_path)).flatten();
+    let mut damaged_left = false;
+    let mut in_use = false;
+    let mut unsigned = false;
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let bytes = std::fs::read(&path).map_err(|e| format!("Could not read {name}: {e}"))?;
+        let contents = String::from_utf8_lossy(&bytes);
+        let mut check = check(&contents);
+        if let Some(key) = key {
+            let problems = signature_problems(key, &bytes);
+            unsigned |= !problems.is_empty();
+            check.problems.extend(problems);
+            check.problems.sort_by_key(|problem| problem.line);
+        }
+        if check.problems.is_empty() {
+            let _ = writeln!(report, "{name}: OK");
+            continue;
+        }
+        let damaged = check
+            .problems
+            .iter()
+            .filter(|problem| problem.damaged)
+            .count();
+        let _ = writeln!(
+            report,
+            "{name}: {} problems, {damaged} damaged rows",
+            check.problems.len()
+        );
+        for problem in check.problems.iter().take(MAX_LISTED) {
+            let _ = writeln!(report, "  line {}: {}", problem.line, problem.message);
+        }
+        if check.problems.len() > MAX_LISTED {
+            let _ = writeln!(report, "  and {} more", check.problems.len() - MAX_LISTED);
+        }
+        if damaged == 0 {
+            continue;
+        }
+        if !repair {
+            damaged_left = true;
+            continue;
+        }
+        if running
+            .as_ref()
+            .is_some_and(|run_id| run_id.is_empty() || name.contains(&format!("_{run_id}.")))
+        {
+            let _ = writeln!(
+                report,
+                "  not repaired, as the monitor is still writing to it"
+            );
+            damaged_left = true;
+            in_use = true;
+            continue;
+        }
+        let mut backup = path.clone().into_os_string();
+        backup.push(".bak");
+        std::fs::copy(&path, &backup).map_err(|e| format!("Could not back up {name}: {e}"))?;
+        write_atomic(&path, check.repaired.as_bytes())
+            .await
+            .map_err(|e| format!("Could not repair {name}: {e}"))?;
+        let _ = writeln!(
+            report,
+            "  removed {damaged} damaged rows, the original is in {name}.bak"
+        );
+    }
+    if in_use {
+        report.push_str("Stop the monitor before repairing the files it's writing to\n");
+    } else if damaged_left {
+        report.push_str("Run again with --repair to remove the damaged rows\n");
+    }
+    if unsigned {
+        report.push_str("Some rows aren't covered by a valid signature\n");
+    }
+    Ok((report.trim_end().to_string(), damaged_left || unsigned))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damaged_rows_are_removed_and_the_rest_reported() {
+        let contents = "# num: 1.0\n\
+            Timestamp,Run,Seq,Latency(ms)\n\
+            2024-01-01 00:00:01.0 +00:00:00,a,0,1.0\n\
+            2024-01-01 00:00:02.0 +00:00:00,a,1,1.0\n\
+            2024-01-01 00:00:02.0 +00:00:00,a,1,1.0\n\
+            2024-01-01 00:00:00.0 +00:00:00,a,1,2.0\n\
+            2024-01-01 00:00:03.0 +00:00:00,a\n\
+            yesterday,a,3,1.0\n\
+            2024-01-01 00:00:04.0 +00:00:00,b,0,1.0\n\
+            2024-01-01 00:00:05.0 +00:00:00,b,1,1";
+        let check = check(contents);
+        let problems: Vec<_> = check
+            .problems
+            .iter()
+            .map(|problem| (problem.line, problem.message.as_str(), problem.damaged))
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                (5, "duplicate of line 4", true),
+                (
+                    6,
+                    "timestamp 2024-01-01 00:00:00.0 +00:00:00 is earlier than the row before",
+                    false
+                ),
+                (6, "sequence number 1 reused from line 4", false),
+                (7, "2 fields where the header has 4", true),
+                (8, "unreadable timestamp \"yesterday\"", true),
+                (10, "truncated", true),
+            ]
+        );
+        assert_eq!(
+            check.repaired,
+            "# num: 1.0\n\
+            Timestamp,Run,Seq,Latency(ms)\n\
+            2024-01-01 00:00:01.0 +00:00:00,a,0,1.0\n\
+            2024-01-01 00:00:02.0 +00:00:00,a,1,1.0\n\
+            2024-01-01 00:00:00.0 +00:00:00,a,1,2.0\n\
+            2024-01-01 00:00:04.0 +00:00:00,b,0,1.0\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn files_of_a_running_monitor_are_not_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let damaged = "Timestamp,Seq\n2024-01-01 00:00:01.0 +00:00:00,0\n2024-01-01";
+        for run_id in ["0ld0ld00", "5f3a9c01"] {
+            std::fs::write(dir.path().join(format!("result_x_{run_id}.csv")), damaged).unwrap();
+        }
+        let state = serde_json::json!({
+            "pid": 1,
+            "target": "1.1.1.1",
+            "run_id": "5f3a9c01",
+            "state": "up",
+            "updated": 0,
+            "expires": u64::MAX,
+            "checkpoint": null,
+        });
+        std::fs::write(dir.path().join("state.json"), state.to_string()).unwrap();
+        let (report, damaged_left) = run(dir.path(), true, None).await.unwrap();
+        assert!(damaged_left);
+        assert!(report.contains("result_x_0ld0ld00.csv.bak"));
+        assert!(report.contains(
+            "result_x_5f3a9c01.csv: 1 problems, 1 damaged rows\n  line 3: truncated\n  not repaired"
+        ));
+        let running = std::fs::read_to_string(dir.path().join("result_x_5f3a9c01.csv")).unwrap();
+        assert_eq!(running, damaged);
+    }
+}
@@ -0,0 +1,251 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const NL80211_CMD_GET_STATION: u8 = 17;
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_STA_INFO: u16 = 21;
+const NL80211_STA_INFO_SIGNAL: u16 = 7;
+const NL80211_STA_INFO_TX_BITRATE: u16 = 8;
+const NL80211_RATE_INFO_BITRATE: u16 = 1;
+const NL80211_RATE_INFO_BITRATE32: u16 = 5;
+const NLMSG_HEADER_LEN: usize = 16;
+const GENL_HEADER_LEN: usize = 4;
+
+/// Radio conditions of a wireless link, as reported by the driver for the access point.
+pub struct WifiSample {
+    pub signal_dbm: Option<i8>,
+    pub tx_bitrate_mbps: Option<f64>,
+}
+
+/// Sample the signal strength and transmit bitrate of wireless interface `interface` through
+/// nl80211. Returns None if the interface isn't wireless or isn't associated. Blocks for up to a
+/// second on each netlink request.
+pub fn sample(interface: &str) -> Option<WifiSample> {
+    if !Path::new("/sys/class/net")
+        .join(interface)
+        .join("wireless")
+        .exists()
+    {
+        return None;
+    }
+    let index = std::ffi::CString::new(interface)
+        .ok()
+        // SAFETY: the name is a valid NUL-terminated string for the duration of the call
+        .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) })
+        .filter(|&index| index != 0)?;
+    let mut netlink = Netlink::open().ok()?;
+    let family = netlink.family_id("nl80211").ok()?;
+    let replies = netlink
+        .request(
+            family,
+            libc::NLM_F_DUMP as u16,
+            NL80211_CMD_GET_STATION,
+            &[(NL80211_ATTR_IFINDEX, &index.to_ne_bytes())],
+        )
+        .ok()?;
+    // In station mode the only station is the access point
+    let station_info = replies
+        .iter()
+        .find_map(|reply| find_attr(reply, NL80211_ATTR_STA_INFO))?;
+    let signal_dbm = find_attr(station_info, NL80211_STA_INFO_SIGNAL)
+        .and_then(|signal| signal.first())
+        .map(|&signal| signal as i8);
+    let tx_bitrate_mbps = find_attr(station_info, NL80211_STA_INFO_TX_BITRATE).and_then(|rate| {
+        // Bitrates are in units of 100 kbit/s
+        find_attr(rate, NL80211_RATE_INFO_BITRATE32)
+            .and_then(|rate| Some(u32::from_ne_bytes(rate.try_into().ok()?)))
+            .or_else(|| {
+                find_attr(rate, NL80211_RATE_INFO_BITRATE)
+                    .and_then(|rate| Some(u16::from_ne_bytes(rate.try_into().ok()?).into()))
+            })
+            .map(|rate| f64::from(rate) / 10.0)
+    });
+    Some(WifiSample {
+        signal_dbm,
+        tx_bitrate_mbps,
+    })
+}
+
+/// A generic netlink socket.
+struct Netlink {
+    socket: OwnedFd,
+    sequence: u32,
+}
+
+impl Netlink {
+    fn open() -> io::Result<Self> {
+        // SAFETY: plain socket calls; the descriptor is owned by the returned OwnedFd
+        unsafe {
+            let fd = libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_GENERIC,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let socket = OwnedFd::from_raw_fd(fd);
+            // The kernel answers immediately, so this only guards against a wedged driver
+            let timeout = libc::timeval {
+                tv_sec: 1,
+                tv_usec: 0,
+            };
+            if libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                mem::size_of::<libc::timeval>() as u32,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Netlink {
+                socket,
+                sequence: 0,
+            })
+        }
+    }
+
+    /// Look up the ID of a generic netlink family by name.
+    fn family_id(&mut self, name: &str) -> io::Result<u16> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        let replies = self.request(
+            GENL_ID_CTRL,
+            0,
+            CTRL_CMD_GETFAMILY,
+            &[(CTRL_ATTR_FAMILY_NAME, &name)],
+        )?;
+        replies
+            .iter()
+            .find_map(|reply| find_attr(reply, CTRL_ATTR_FAMILY_ID))
+            .and_then(|id| Some(u16::from_ne_bytes(id.try_into().ok()?)))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "family not found"))
+    }
+
+    /// Send a request and collect the attributes of every reply message.
+    fn request(
+        &mut self,
+        family: u16,
+        flags: u16,
+        command: u8,
+        attrs: &[(u16, &[u8])],
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.sequence += 1;
+        let mut payload = vec![command, 1, 0, 0];
+        for (kind, value) in attrs {
+            payload.extend_from_slice(&((4 + value.len()) as u16).to_ne_bytes());
+            payload.extend_from_slice(&kind.to_ne_bytes());
+            payload.extend_from_slice(value);
+            payload.resize(payload.len().next_multiple_of(4), 0);
+        }
+        let mut message = Vec::with_capacity(NLMSG_HEADER_LEN + payload.len());
+        message.extend_from_slice(&((NLMSG_HEADER_LEN + payload.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&family.to_ne_bytes());
+        message.extend_from_slice(&(libc::NLM_F_REQUEST as u16 | flags).to_ne_bytes());
+        message.extend_from_slice(&self.sequence.to_ne_bytes());
+        message.extend_from_slice(&0u32.to_ne_bytes());
+        message.extend_from_slice(&payload);
+        // SAFETY: the buffer is valid for reads of its length
+        if unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        } < 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+
+        let dump = flags & libc::NLM_F_DUMP as u16 != 0;
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            // SAFETY: the buffer is valid for writes of its length
+            let received = unsafe {
+                libc::recv(
+                    self.socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if received < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut messages = &buf[..received as usize];
+            while messages.len() >= NLMSG_HEADER_LEN {
+                let len = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+                let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+                if len < NLMSG_HEADER_LEN || len > messages.len() {
+                    break;
+                }
+                let body = &messages[NLMSG_HEADER_LEN..len];
+                match kind as i32 {
+                    libc::NLMSG_DONE => return Ok(replies),
+                    libc::NLMSG_ERROR => {
+                        let error = body
+                            .get(..4)
+                            .map_or(0, |error| i32::from_ne_bytes(error.try_into().unwrap()));
+                        if error != 0 {
+                            return Err(io::Error::from_raw_os_error(-error));
+                        }
+                        return Ok(replies);
+                    }
+                    _ if body.len() >= GENL_HEADER_LEN => {
+                        replies.push(body[GENL_HEADER_LEN..].to_vec());
+                    }
+                    _ => {}
+                }
+                messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+            }
+            if !dump {
+                return Ok(replies);
+            }
+        }
+    }
+}
+
+/// Find the payload of the netlink attribute of type `kind` in `attrs`.
+fn find_attr(mut attrs: &[u8], kind: u16) -> Option<&[u8]> {
+    while attrs.len() >= 4 {
+        let len = u16::from_ne_bytes([attrs[0], attrs[1]]) as usize;
+        // The top bits flag nested and byte-order attributes
+        let attr_kind = u16::from_ne_bytes([attrs[2], attrs[3]]) & 0x3fff;
+        if len < 4 || len > attrs.len() {
+            return None;
+        }
+        if attr_kind == kind {
+            return Some(&attrs[4..len]);
+        }
+        attrs = &attrs[len.next_multiple_of(4).min(attrs.len())..];
+    }
+    None
+}
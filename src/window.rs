@@ -0,0 +1,115 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Probe results of the recent past, for statistics over rolling windows.
+pub struct ResultWindow {
+    /// How far back results are kept
+    horizon: Duration,
+    /// Send time and round trip time (None if the probe failed) of each result, oldest first
+    results: VecDeque<(Instant, Option<Duration>)>,
+    /// Send time of the first result ever pushed
+    first: Option<Instant>,
+}
+
+impl ResultWindow {
+    pub fn new(horizon: Duration) -> Self {
+        ResultWindow {
+            horizon,
+            results: VecDeque::new(),
+            first: None,
+        }
+    }
+
+    /// Keep results for at least `horizon` from now on.
+    pub fn set_horizon(&mut self, horizon: Duration) {
+        self.horizon = horizon;
+    }
+
+    /// Whether results have been collected for all of `period` before `now`.
+    pub fn covers(&self, period: Duration, now: Instant) -> bool {
+        self.first
+            .is_some_and(|first| now.saturating_duration_since(first) >= period)
+    }
+
+    /// Add the result of a probe sent at `sent`, forgetting results older than the horizon.
+    pub fn push(&mut self, sent: Instant, rtt: Option<Duration>) {
+        while self
+            .results
+            .front()
+            .is_some_and(|&(oldest, _)| sent - oldest > self.horizon)
+        {
+            self.results.pop_front();
+        }
+        self.results.push_back((sent, rtt));
+        self.first.get_or_insert(sent);
+    }
+
+    /// Results of probes sent within `period` before `now`, newest first.
+    fn within(
+        &self,
+        period: Duration,
+        now: Instant,
+    ) -> impl Iterator<Item = Option<Duration>> + '_ {
+        self.results
+            .iter()
+            .rev()
+            .take_while(move |&&(at, _)| now.saturating_duration_since(at) <= period)
+            .map(|&(_, rtt)| rtt)
+    }
+
+    /// Percentage of failed probes sent within `period` before `now`, or None if there were none.
+    pub fn loss_percent(&self, period: Duration, now: Instant) -> Option<f64> {
+        let (sent, failed) = self
+            .within(period, now)
+            .fold((0u64, 0u64), |(sent, failed), rtt| {
+                (sent + 1, failed + u64::from(rtt.is_none()))
+            });
+        (sent > 0).then(|| failed as f64 * 100.0 / sent as f64)
+    }
+
+    /// Mean round trip time of successful probes sent within `period` before `now`.
+    pub fn avg_rtt(&self, period: Duration, now: Instant) -> Option<Duration> {
+        let (received, total) = self
+            .within(period, now)
+            .flatten()
+            .fold((0u32, Duration::ZERO), |(received, total), rtt| {
+                (received + 1, total + rtt)
+            });
+        (received > 0).then(|| total / received)
+    }
+
+    /// Round trip time that `percentile`% of successful probes sent within `period` before `now`
+    /// stayed within (nearest-rank method).
+    pub fn rtt_percentile(
+        &self,
+        percentile: u8,
+        period: Duration,
+        now: Instant,
+    ) -> Option<Duration> {
+        let mut rtts: Vec<Duration> = self.within(period, now).flatten().collect();
+        if rtts.is_empty() {
+            return None;
+        }
+        rtts.sort_unstable();
+        let rank = (f64::from(percentile) / 100.0 * rtts.len() as f64).ceil() as usize;
+        Some(rtts[rank.clamp(1, rtts.len()) - 1])
+    }
+}
@@ -0,0 +1,466 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use crate::columns::Column;
+use crate::sink::{OutputSink, ProbeRecord, SinkFuture};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::{self, JoinHandle};
+
+/// Number of results the writer task can fall behind by before the overflow policy applies.
+pub const QUEUE_CAPACITY: usize = 1024;
+
+// Spilled results read back at a time, so catching up doesn't hold them all in memory
+const SPILL_BATCH: usize = 256;
+
+/// What to do with a result when the writer task has fallen too far behind, chosen with
+/// `--write-overflow`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Wait until there is room in the queue, delaying the next probe
+    Block,
+    /// Discard the oldest queued result
+    DropOldest,
+    /// Keep results in a file in the temporary directory until the writer catches up
+    Spill,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "spill" => Ok(OverflowPolicy::Spill),
+            _ => Err(format!(
+                "unknown overflow policy \"{policy}\" (expected block, drop-oldest or spill)"
+            )),
+        }
+    }
+}
+
+enum Command {
+    Write(ProbeRecord),
+    Flush,
+    SetMetadata(Vec<(String, String)>),
+    Reopen(oneshot::Sender<Result<(), String>>),
+}
+
+/// Results that overflowed the queue, one JSON array of `[column, value]` pairs per line. Once
+/// anything is spilled, later results are spilled too until the writer has caught up, so they are
+/// written in order. The file has no name, so no one else can open it, and it's gone once closed.
+struct Spill {
+    file: File,
+    /// Results spilled and not read back yet
+    records: usize,
+    /// Where the first of them starts
+    read_from: u64,
+}
+
+impl Spill {
+    fn create() -> io::Result<Self> {
+        Ok(Spill {
+            file: tempfile::tempfile()?,
+            records: 0,
+            read_from: 0,
+        })
+    }
+
+    fn push(&mut self, record: &ProbeRecord) -> io::Result<()> {
+        let fields: Vec<(&str, &str)> = record
+            .fields
+            .iter()
+            .map(|(column, value)| (column.name(), value.as_str()))
+            .collect();
+        self.file.seek(SeekFrom::End(0))?;
+        writeln!(self.file, "{}", serde_json::to_string(&fields)?)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Read back up to `limit` of the results spilled, oldest first, emptying the file once all of
+    /// them are.
+    fn take(&mut self, limit: usize) -> io::Result<Vec<ProbeRecord>> {
+        self.file.seek(SeekFrom::Start(self.read_from))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut records = Vec::with_capacity(limit.min(self.records));
+        let mut line = String::new();
+        while records.len() < limit {
+            line.clear();
+            let read = reader.read_line(&mut line)?;
+            if read == 0 {
+                break;
+            }
+            self.read_from += read as u64;
+            let fields: Vec<(String, String)> = serde_json::from_str(&line)?;
+            records.push(ProbeRecord {
+                fields: fields
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((Column::from_name(&name)?, value)))
+                    .collect(),
+            });
+        }
+        self.records -= records.len();
+        if self.records == 0 {
+            self.file.set_len(0)?;
+            self.read_from = 0;
+        }
+        Ok(records)
+    }
+}
+
+struct State {
+    queue: VecDeque<Command>,
+    /// Number of `Command::Write`s in the queue
+    queued_records: usize,
+    spill: Option<Spill>,
+    /// Results discarded since the writer last caught up
+    dropped: u64,
+    closing: bool,
+    /// The wrapped sink's error and failure count, as of its last write
+    error: Option<String>,
+    consecutive_failures: u32,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Wakes the writer task when there is something to do
+    work: Notify,
+    /// Wakes a blocked `write` when there is room in the queue
+    room: Notify,
+}
+
+/// Wraps a sink so its writes happen in a task of its own, behind a queue of at most `capacity`
+/// results. A slow disk or a stalled network filesystem then can't delay probes or the UI until
+/// the queue is full, after which the overflow policy decides.
+pub struct QueuedSink {
+    shared: Arc<Shared>,
+    policy: OverflowPolicy,
+    capacity: usize,
+    task: Option<JoinHandle<()>>,
+}
+
+impl QueuedSink {
+    pub fn spawn(sink: Box<dyn OutputSink>, policy: OverflowPolicy, capacity: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                queue: VecDeque::new(),
+                queued_records: 0,
+                spill: None,
+                dropped: 0,
+                closing: false,
+                error: None,
+                consecutive_failures: 0,
+            }),
+            work: Notify::new(),
+            room: Notify::new(),
+        });
+        let task = task::spawn(write_queued(sink, shared.clone()));
+        QueuedSink {
+            shared,
+            policy,
+            capacity,
+            task: Some(task),
+        }
+    }
+
+    fn send(&self, command: Command) {
+        self.shared.state.lock().unwrap().queue.push_back(command);
+        self.shared.work.notify_one();
+    }
+
+    async fn enqueue(&self, mut record: ProbeRecord) {
+        while let Err(blocked) = self.try_enqueue(record) {
+            self.shared.room.notified().await;
+            record = blocked;
+        }
+        self.shared.work.notify_one();
+    }
+
+    /// Queue `record`, applying the overflow policy if the queue is full. Gives the record back if
+    /// it has to wait for room.
+    fn try_enqueue(&self, record: ProbeRecord) -> Result<(), ProbeRecord> {
+        let mut state = self.shared.state.lock().unwrap();
+        let spilling = state.spill.as_ref().is_some_and(|spill| spill.records > 0);
+        if state.queued_records < self.capacity && !spilling {
+            state.queue.push_back(Command::Write(record));
+            state.queued_records += 1;
+            return Ok(());
+        }
+        match self.policy {
+            OverflowPolicy::Block => return Err(record),
+            OverflowPolicy::DropOldest => {
+                let oldest = state
+                    .queue
+                    .iter()
+                    .position(|command| matches!(command, Command::Write(_)));
+                if let Some(oldest) = oldest {
+                    state.queue.remove(oldest);
+                }
+                state.queue.push_back(Command::Write(record));
+            }
+            OverflowPolicy::Spill => {
+                if state.spill.is_none() {
+                    state.spill = Spill::create().ok();
+                }
+                let spilled = state.spill.as_mut().map(|spill| spill.push(&record));
+                if matches!(spilled, Some(Ok(()))) {
+                    return Ok(());
+                }
+            }
+        }
+        state.dropped += 1;
+        Ok(())
+    }
+}
+
+/// Writer task: hand the queued commands, then anything spilled, to the wrapped sink, until the
+/// queue is closed and empty.
+async fn write_queued(mut sink: Box<dyn OutputSink>, shared: Arc<Shared>) {
+    loop {
+        let work = shared.work.notified();
+        let (command, spilled) = {
+            let mut state = shared.state.lock().unwrap();
+            let command = state.queue.pop_front();
+            if let Some(Command::Write(_)) = command {
+                state.queued_records -= 1;
+                shared.room.notify_one();
+            }
+            let spilled = match (&command, state.spill.as_mut()) {
+                (None, Some(spill)) if spill.records > 0 => {
+                    spill.take(SPILL_BATCH).unwrap_or_default()
+                }
+                _ => Vec::new(),
+            };
+            if command.is_none() && spilled.is_empty() {
+                state.dropped = 0;
+                if state.closing {
+                    break;
+                }
+            }
+            (command, spilled)
+        };
+        match command {
+            Some(Command::Write(record)) => sink.write(&record).await,
+            Some(Command::Flush) => sink.flush().await,
+            Some(Command::SetMetadata(metadata)) => {
+                let metadata: Vec<(&str, String)> = metadata
+                    .iter()
+                    .map(|(key, value)| (key.as_str(), value.clone()))
+                    .collect();
+                sink.set_metadata(&metadata);
+            }
+            Some(Command::Reopen(reply)) => {
+                let _ = reply.send(sink.reopen().await);
+            }
+            None if spilled.is_empty() => work.await,
+            None => {
+                for record in &spilled {
+                    sink.write(record).await;
+                }
+            }
+        }
+        let mut state = shared.state.lock().unwrap();
+        state.error = sink.error();
+        state.consecutive_failures = sink.consecutive_failures();
+    }
+    sink.close().await;
+    let mut state = shared.state.lock().unwrap();
+    state.error = sink.error();
+    state.consecutive_failures = sink.consecutive_failures();
+    state.spill = None;
+}
+
+impl OutputSink for QueuedSink {
+    fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()> {
+        Box::pin(self.enqueue(record.clone()))
+    }
+
+    /// Ask the writer to flush once it gets there, without waiting for it.
+    fn flush(&mut self) -> SinkFuture<'_, ()> {
+        self.send(Command::Flush);
+        Box::pin(async {})
+    }
+
+    /// Wait for the writer to write everything queued and close the wrapped sink.
+    fn close(&mut self) -> SinkFuture<'_, ()> {
+        Box::pin(async {
+            self.shared.state.lock().unwrap().closing = true;
+            self.shared.work.notify_one();
+            if let Some(task) = self.task.take() {
+                let _ = task.await;
+            }
+        })
+    }
+
+    fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>> {
+        Box::pin(async {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            self.send(Command::Reopen(reply_tx));
+            reply_rx
+                .await
+                .unwrap_or(Err("the writer has stopped".to_string()))
+        })
+    }
+
+    fn set_metadata(&mut self, metadata: &[(&str, String)]) {
+        self.send(Command::SetMetadata(
+            metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+        ));
+    }
+
+    /// Describe the wrapped sink's error, along with how far behind the writer is once the queue
+    /// has filled up and how many results were dropped.
+    fn error(&self) -> Option<String> {
+        let state = self.shared.state.lock().unwrap();
+        let spilled = state.spill.as_ref().map_or(0, |spill| spill.records);
+        let mut problems: Vec<String> = state.error.iter().cloned().collect();
+        if state.queued_records >= self.capacity || spilled > 0 {
+            problems.push(format!(
+                "writes are {} results behind",
+                state.queued_records + spilled
+            ));
+        }
+        if state.dropped > 0 {
+            problems.push(format!(
+                "{} results dropped because writes fell behind",
+                state.dropped
+            ));
+        }
+        (!problems.is_empty()).then(|| problems.join("; "))
+    }
+
+    fn consecutive_failures(&self) -> u32 {
+        self.shared.state.lock().unwrap().consecutive_failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::Semaphore;
+
+    /// Sink recording the RTT of each result, which stalls until allowed to write.
+    struct StalledSink {
+        writable: Arc<Semaphore>,
+        written: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl OutputSink for StalledSink {
+        fn write<'a>(&'a mut self, record: &'a ProbeRecord) -> SinkFuture<'a, ()> {
+            Box::pin(async move {
+                self.writable.acquire().await.unwrap().forget();
+                self.written
+                    .lock()
+                    .unwrap()
+                    .push(record.fields[0].1.clone());
+            })
+        }
+
+        fn flush(&mut self) -> SinkFuture<'_, ()> {
+            Box::pin(async {})
+        }
+
+        fn close(&mut self) -> SinkFuture<'_, ()> {
+            Box::pin(async {})
+        }
+
+        fn reopen(&mut self) -> SinkFuture<'_, Result<(), String>> {
+            Box::pin(async { Ok(()) })
+        }
+
+        fn set_metadata(&mut self, _: &[(&str, String)]) {}
+
+        fn error(&self) -> Option<String> {
+            None
+        }
+
+        fn consecutive_failures(&self) -> u32 {
+            0
+        }
+    }
+
+    /// Write ten results through a stalled sink behind a queue of three, then let it catch up.
+    async fn write_through_stall(policy: OverflowPolicy) -> (Vec<String>, Option<String>) {
+        let writable = Arc::new(Semaphore::new(0));
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let stalled = StalledSink {
+            writable: writable.clone(),
+            written: written.clone(),
+        };
+        let mut sink = QueuedSink::spawn(Box::new(stalled), policy, 3);
+        for rtt in 0..10 {
+            let record = ProbeRecord {
+                fields: vec![(Column::Rtt, rtt.to_string())],
+            };
+            sink.write(&record).await;
+        }
+        let error = sink.error();
+        writable.add_permits(100);
+        sink.close().await;
+        let written = written.lock().unwrap().clone();
+        (written, error)
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_the_oldest_or_spills_in_order() {
+        let (written, error) = write_through_stall(OverflowPolicy::DropOldest).await;
+        assert_eq!(written, ["7", "8", "9"]);
+        assert_eq!(
+            error.unwrap(),
+            "writes are 3 results behind; 7 results dropped because writes fell behind"
+        );
+
+        let (written, error) = write_through_stall(OverflowPolicy::Spill).await;
+        let expected: Vec<String> = (0..10).map(|rtt| rtt.to_string()).collect();
+        assert_eq!(written, expected);
+        assert_eq!(error.unwrap(), "writes are 10 results behind");
+    }
+
+    #[test]
+    fn spilled_results_are_read_back_in_batches() {
+        let record = |rtt: usize| ProbeRecord {
+            fields: vec![(Column::Rtt, rtt.to_string())],
+        };
+        let rtts = |records: Vec<ProbeRecord>| -> Vec<String> {
+            records
+                .into_iter()
+                .map(|record| record.fields[0].1.clone())
+                .collect()
+        };
+        let mut spill = Spill::create().unwrap();
+        for rtt in 0..3 {
+            spill.push(&record(rtt)).unwrap();
+        }
+        assert_eq!(rtts(spill.take(2).unwrap()), ["0", "1"]);
+        spill.push(&record(3)).unwrap();
+        assert_eq!(rtts(spill.take(2).unwrap()), ["2", "3"]);
+        assert_eq!(spill.records, 0);
+        assert_eq!(spill.file.metadata().unwrap().len(), 0);
+        spill.push(&record(4)).unwrap();
+        assert_eq!(rtts(spill.take(2).unwrap()), ["4"]);
+    }
+}
@@ -0,0 +1,228 @@
+/*
+ * num <https://github.com/adih-20/num>
+ * Copyright (C) 2023 Aditya Hadavale
+ *
+ * This program is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Affero General Public License as published by
+ * the Free Software Foundation, either version 3 of the License, or
+ * (at your option) any later version.
+ *
+ * This program is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU Affero General Public License for more details.
+ *
+ * You should have received a copy of the GNU Affero General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+use crate::engine::LinkState;
+use crate::metrics::Snapshot;
+use crate::pipeline::Output;
+use crate::probe::split_host_port;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::MissedTickBehavior;
+
+const DEFAULT_PORT: u16 = 10051;
+// A server that doesn't answer within this long is given up on until the next push
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+/// Header of every message of the Zabbix protocol, followed by the length of the data
+const HEADER: &[u8; 5] = b"ZBXD\x01";
+
+/// Where values are pushed and what they are called, like the arguments of `zabbix_sender`.
+#[derive(Clone)]
+pub struct Sender {
+    /// Zabbix server or proxy, as `host[:port]`
+    pub server: String,
+    /// Name of the host the items belong to in Zabbix
+    pub host: String,
+    /// Start of the item keys, e.g. `num` for `num.rtt[1.1.1.1]`
+    pub prefix: String,
+}
+
+impl Sender {
+    /// Check a `host[:port]` server address.
+    pub fn parse_server(server: &str) -> Result<String, String> {
+        split_host_port(server, DEFAULT_PORT)
+            .map(|_| server.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    /// The trapper items to send for `snapshot`, as key and value. The loss is that of the probes
+    /// sent since the `previous` snapshot, if any.
+    fn items(&self, snapshot: &Snapshot, previous: Option<&Snapshot>) -> Vec<(String, String)> {
+        let key = |item: &str| format!("{}.{item}[{}]", self.prefix, snapshot.target);
+        let (sent, received) = match previous {
+            Some(previous) => (
+                snapshot.sent - previous.sent,
+                snapshot.received - previous.received,
+            ),
+            None => (snapshot.sent, snapshot.received),
+        };
+        let up = u8::from(snapshot.state == LinkState::Up);
+        let mut items = vec![(key("up"), up.to_string())];
+        if let Some(rtt) = snapshot.last_rtt {
+            items.push((key("rtt"), format!("{:.6}", rtt.as_secs_f64())));
+        }
+        if let Some(avg) = snapshot.avg_rtt {
+            items.push((key("rtt_avg"), format!("{:.6}", avg.as_secs_f64())));
+        }
+        if sent > 0 {
+            let loss = 100.0 * (sent - received) as f64 / sent as f64;
+            items.push((key("loss"), format!("{loss:.2}")));
+        }
+        items.push((key("outages"), snapshot.outages.to_string()));
+        items
+    }
+
+    /// Frame a `sender data` request for `items`.
+    fn request(&self, items: &[(String, String)]) -> Vec<u8> {
+        let data: Vec<serde_json::Value> = items
+            .iter()
+            .map(|(key, value)| serde_json::json!({"host": self.host, "key": key, "value": value}))
+            .collect();
+        let body = serde_json::json!({"request": "sender data", "data": data}).to_string();
+        let mut message = HEADER.to_vec();
+        message.extend((body.len() as u64).to_le_bytes());
+        message.extend(body.as_bytes());
+        message
+    }
+
+    /// Send `items` to the server, returning why they weren't all accepted if they weren't.
+    async fn send(&self, items: &[(String, String)]) -> Result<(), String> {
+        let (host, port) =
+            split_host_port(&self.server, DEFAULT_PORT).map_err(|e| e.to_string())?;
+        let response = tokio::time::timeout(SEND_TIMEOUT, async {
+            let mut stream = TcpStream::connect((host, port)).await?;
+            stream.write_all(&self.request(items)).await?;
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .map_err(|_| format!("{} timed out", self.server))?
+        .map_err(|e| format!("{}: {e}", self.server))?;
+        check_response(&response).map_err(|e| format!("{}: {e}", self.server))
+    }
+}
+
+/// Check the server's answer to a `sender data` request, e.g.
+/// `{"response":"success","info":"processed: 5; failed: 0; total: 5; seconds spent: 0.000055"}`.
+fn check_response(response: &[u8]) -> Result<(), String> {
+    let body = response
+        .strip_prefix(HEADER.as_slice())
+        .filter(|rest| rest.len() >= 8)
+        .map(|rest| &rest[8..])
+        .ok_or("unexpected answer")?;
+    let body: serde_json::Value =
+        serde_json::from_slice(body).map_err(|e| format!("unexpected answer ({e})"))?;
+    let info = body["info"].as_str().unwrap_or_default();
+    if body["response"] != "success" {
+        return Err(format!("request failed ({info})"));
+    }
+    let failed = info
+        .split(';')
+        .find_map(|field| field.trim().strip_prefix("failed: "))
+        .and_then(|failed| failed.parse::<u64>().ok())
+        .unwrap_or(0);
+    if failed > 0 {
+        // Usually items that don't exist on the host or aren't of the trapper type
+        return Err(format!("{failed} values were rejected ({info})"));
+    }
+    Ok(())
+}
+
+/// Consumer pushing the latest values of `target` to Zabbix every `period`, once there is a probe
+/// result. Only the first of a run of failed pushes is reported to `failures`.
+pub async fn push(
+    mut outputs: mpsc::UnboundedReceiver<Output>,
+    target: String,
+    sender: Sender,
+    period: Duration,
+    failures: mpsc::UnboundedSender<String>,
+) {
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let (mut latest, mut previous) = (None, None);
+    let mut failing = false;
+    loop {
+        tokio::select! {
+            output = outputs.recv() => match output {
+                Some(Output::ProbeCompleted(update)) => {
+                    latest = Some(Snapshot::new(&target, &sender.host, &update));
+                }
+                Some(_) => {}
+                None => break,
+            },
+            _ = interval.tick() => {
+                let Some(snapshot) = latest.take() else {
+                    continue;
+                };
+                let items = sender.items(&snapshot, previous.as_ref());
+                match sender.send(&items).await {
+                    Ok(()) => failing = false,
+                    Err(e) => {
+                        if !std::mem::replace(&mut failing, true) {
+                            let _ = failures.send(e);
+                        }
+                    }
+                }
+                previous = Some(snapshot);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    #[test]
+    fn values_are_framed_as_sender_data() {
+        let sender = Sender {
+            server: "zabbix.example.com".to_string(),
+            host: "office".to_string(),
+            prefix: "num".to_string(),
+        };
+        let snapshot = |sent: u64, received: u64| Snapshot {
+            target: "1.1.1.1".to_string(),
+            probe_name: "office".to_string(),
+            state: LinkState::Up,
+            last_rtt: Some(Duration::from_millis(12)),
+            avg_rtt: None,
+            sent,
+            received,
+            outages: 2,
+            time: OffsetDateTime::UNIX_EPOCH,
+        };
+        let items = sender.items(&snapshot(30, 27), Some(&snapshot(10, 10)));
+        let item = |key: &str, value: &str| (key.to_string(), value.to_string());
+        assert_eq!(
+            items,
+            [
+                item("num.up[1.1.1.1]", "1"),
+                item("num.rtt[1.1.1.1]", "0.012000"),
+                item("num.loss[1.1.1.1]", "15.00"),
+                item("num.outages[1.1.1.1]", "2"),
+            ]
+        );
+        let request = sender.request(&items[..1]);
+        let body = r#"{"data":[{"host":"office","key":"num.up[1.1.1.1]","value":"1"}],"request":"sender data"}"#;
+        assert_eq!(&request[..5], b"ZBXD\x01");
+        assert_eq!(request[5..13], (body.len() as u64).to_le_bytes());
+        assert_eq!(&request[13..], body.as_bytes());
+
+        let response = |info: &str| {
+            let body = format!(r#"{{"response":"success","info":"{info}"}}"#);
+            let mut response = HEADER.to_vec();
+            response.extend((body.len() as u64).to_le_bytes());
+            response.extend(body.as_bytes());
+            response
+        };
+        assert!(check_response(&response("processed: 4; failed: 0; total: 4")).is_ok());
+        assert!(check_response(&response("processed: 3; failed: 1; total: 4")).is_err());
+    }
+}